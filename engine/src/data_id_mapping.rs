@@ -0,0 +1,384 @@
+use aurora_refiner_types::near_primitives::hash::CryptoHash;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+use crate::history::{PERSISTENT_HISTORY_SIZE, PRUNE_FREQUENCY};
+
+/// Where the data receipts are persisted unless configured otherwise: next to the engine storage
+/// at `engine_path`, in a folder with the same name followed by `_data_id_mapping`.
+pub fn default_path(engine_path: &Path) -> PathBuf {
+    let mut name = engine_path.file_name().unwrap_or_default().to_os_string();
+    name.push("_data_id_mapping");
+    engine_path.with_file_name(name)
+}
+
+/// Column family indexing the height of every data receipt by its data id.
+const HEIGHTS_CF: &str = "heights";
+
+/// Errors of the data id mapping.
+#[derive(Debug)]
+pub enum Error {
+    Db(rocksdb::Error),
+    /// The data of a data receipt stored in the DB cannot be decoded.
+    Corrupted {
+        data_id: CryptoHash,
+        error: std::io::Error,
+    },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Db(err) => write!(f, "{err}"),
+            Self::Corrupted { data_id, error } => {
+                write!(f, "corrupted data of data id {data_id}: {error}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<rocksdb::Error> for Error {
+    fn from(err: rocksdb::Error) -> Self {
+        Self::Db(err)
+    }
+}
+
+/// Data receipts sent to the engine, indexed by their data id. An action receipt lists the data
+/// it needs in `input_data_ids`; these are the results of the promises it is a callback of.
+///
+/// The data receipt and the action receipt that consumes it can be included in different blocks,
+/// so the mapping is persisted to survive a restart of the refiner in between. As in the
+/// transaction hash tracker, the keys in the DB are prefixed with the height of the block the data
+/// receipt was included in to make pruning cheap, and look-ups are done on the in-memory cache,
+/// which is filled from the DB on start-up. Unlike the tracker, a cache miss falls back to the
+/// DB, as the data cannot be recovered any other way: the height of the data receipt is looked up
+/// in a second column family keyed by data id, which gives the key of its data.
+pub struct DataIdMapping {
+    cache: lru::LruCache<CryptoHash, Entry>,
+    persistent_storage: rocksdb::DB,
+    last_prune_height: u64,
+}
+
+/// Data receipt in the cache.
+enum Entry {
+    Data(Option<Vec<u8>>),
+    /// The data was taken with `pop`. The DB entry is kept for rewinds, so the cache remembers it
+    /// is gone.
+    Taken,
+}
+
+impl DataIdMapping {
+    pub fn new<P: AsRef<Path>>(storage_path: P, cache_size: NonZeroUsize) -> Result<Self, Error> {
+        let mut result = Self {
+            cache: lru::LruCache::new(cache_size),
+            persistent_storage: open_db(storage_path)?,
+            last_prune_height: 0,
+        };
+        result.fill_cache(u64::MAX)?;
+
+        Ok(result)
+    }
+
+    /// Records the data of a data receipt included in the block at `block_height`.
+    pub fn put(
+        &mut self,
+        data_id: CryptoHash,
+        data: Option<Vec<u8>>,
+        block_height: u64,
+    ) -> Result<(), Error> {
+        let db_key = data_key(block_height, &data_id);
+        let db_value = borsh::to_vec(&data).expect("Serialization to Vec cannot fail");
+        let mut batch = rocksdb::WriteBatch::default();
+        batch.put(db_key, db_value);
+        batch.put_cf(
+            heights_cf(&self.persistent_storage),
+            data_id,
+            block_height.to_be_bytes(),
+        );
+        self.persistent_storage.write(batch)?;
+        self.cache.put(data_id, Entry::Data(data));
+
+        Ok(())
+    }
+
+    /// Takes the data of the data receipt with the given id, reading it from the DB if it is not
+    /// in the cache. The DB entry stays until it is pruned so that a rewind can restore it.
+    pub fn pop(&mut self, data_id: &CryptoHash) -> Result<Option<Option<Vec<u8>>>, Error> {
+        let entry = match self.cache.get_mut(data_id) {
+            Some(entry) => std::mem::replace(entry, Entry::Taken),
+            None => match self.read(data_id)? {
+                Some(data) => {
+                    self.cache.put(*data_id, Entry::Taken);
+                    Entry::Data(data)
+                }
+                // Unknown ids are not cached, they would push the data receipts out of the cache
+                None => Entry::Taken,
+            },
+        };
+
+        Ok(match entry {
+            Entry::Data(data) => Some(data),
+            Entry::Taken => None,
+        })
+    }
+
+    /// Puts back the data taken with `pop`, when the receipt that consumed it is reverted.
+    pub fn restore(&mut self, data_id: CryptoHash, data: Option<Vec<u8>>) {
+        self.cache.put(data_id, Entry::Data(data));
+    }
+
+    /// Reads the data receipt from the DB, using the index of the heights to find its key.
+    fn read(&self, data_id: &CryptoHash) -> Result<Option<Option<Vec<u8>>>, Error> {
+        let db = &self.persistent_storage;
+        let Some(height) = db.get_pinned_cf(heights_cf(db), data_id)? else {
+            return Ok(None);
+        };
+        let Ok(height) = <[u8; 8]>::try_from(&*height).map(u64::from_be_bytes) else {
+            return Err(Error::Corrupted {
+                data_id: *data_id,
+                error: std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid height"),
+            });
+        };
+        // The data may have been pruned since it was indexed
+        let Some(value) = db.get_pinned(data_key(height, data_id))? else {
+            return Ok(None);
+        };
+        tracing::debug!("Data id {data_id} not in the cache, read it from the DB");
+
+        borsh::from_slice(&value)
+            .map(Some)
+            .map_err(|error| Error::Corrupted {
+                data_id: *data_id,
+                error,
+            })
+    }
+
+    /// Prunes data receipts that are too old to still be needed.
+    pub fn on_block_end(&mut self, block_height: u64) -> Result<(), Error> {
+        let prune_height = block_height.saturating_sub(PERSISTENT_HISTORY_SIZE);
+
+        if prune_height.saturating_sub(self.last_prune_height) < PRUNE_FREQUENCY {
+            return Ok(());
+        }
+
+        delete_range(
+            &self.persistent_storage,
+            self.last_prune_height,
+            prune_height,
+        )?;
+        self.last_prune_height = prune_height;
+
+        Ok(())
+    }
+
+    /// Forgets the data receipts included in blocks above `block_height`.
+    pub fn rewind(&mut self, block_height: u64) -> Result<(), Error> {
+        delete_above(&self.persistent_storage, block_height)?;
+
+        self.cache.clear();
+        self.fill_cache(block_height)?;
+
+        Ok(())
+    }
+
+    /// Reads the most recent data receipts, up to `max_height`, from the DB into the cache.
+    fn fill_cache(&mut self, max_height: u64) -> Result<(), Error> {
+        let opts = {
+            let mut tmp = rocksdb::ReadOptions::default();
+            let db_key = [max_height.to_be_bytes().as_slice(), &[0xff_u8; 32]].concat();
+            tmp.set_iterate_upper_bound(db_key);
+            tmp
+        };
+        let iter = self
+            .persistent_storage
+            .iterator_opt(rocksdb::IteratorMode::End, opts)
+            .take(self.cache.cap().get());
+        let mut cache_data = iter.collect::<Result<Vec<_>, _>>()?;
+        // Consume the data from the DB in reverse order to get right LRU structure
+        cache_data.reverse();
+        for (k, v) in cache_data {
+            let data_id = k
+                .get(8..)
+                .and_then(|bytes| CryptoHash::try_from(bytes).ok());
+            let data = borsh::from_slice::<Option<Vec<u8>>>(&v).ok();
+            match (data_id, data) {
+                (Some(data_id), Some(data)) => {
+                    self.cache.put(data_id, Entry::Data(data));
+                }
+                _ => tracing::warn!("Invalid data id mapping DB entry: {}", hex::encode(k)),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Forgets the data receipts included in blocks above `block_height` in the DB stored at
+/// `storage_path`, without reading the remaining ones into memory.
+pub fn rewind_storage<P: AsRef<Path>>(storage_path: P, block_height: u64) -> Result<(), Error> {
+    let db = open_db(storage_path)?;
+    delete_above(&db, block_height)
+}
+
+/// Opens the DB, indexing the heights of the data receipts stored before the index existed.
+fn open_db<P: AsRef<Path>>(storage_path: P) -> Result<rocksdb::DB, Error> {
+    let mut opts = rocksdb::Options::default();
+    opts.create_if_missing(true);
+    opts.create_missing_column_families(true);
+    let db = rocksdb::DB::open_cf(&opts, storage_path, [HEIGHTS_CF])?;
+
+    let index_is_empty = db
+        .iterator_cf(heights_cf(&db), rocksdb::IteratorMode::Start)
+        .next()
+        .is_none();
+    if index_is_empty {
+        let mut batch = rocksdb::WriteBatch::default();
+        for entry in db.iterator(rocksdb::IteratorMode::Start) {
+            let (key, _) = entry?;
+            if let Some((height, data_id)) = key.split_first_chunk::<8>()
+                && data_id.len() == 32
+            {
+                batch.put_cf(heights_cf(&db), data_id, height);
+            }
+        }
+        db.write(batch)?;
+    }
+
+    Ok(db)
+}
+
+fn heights_cf(db: &rocksdb::DB) -> &rocksdb::ColumnFamily {
+    db.cf_handle(HEIGHTS_CF)
+        .expect("The column family is created when the DB is opened")
+}
+
+fn data_key(block_height: u64, data_id: &CryptoHash) -> Vec<u8> {
+    [&block_height.to_be_bytes(), data_id.as_ref()].concat()
+}
+
+fn delete_above(db: &rocksdb::DB, block_height: u64) -> Result<(), Error> {
+    let Some(start_height) = block_height.checked_add(1) else {
+        return Ok(());
+    };
+    delete_range(db, start_height, u64::MAX)
+}
+
+/// Deletes the data receipts included in blocks from `start_height` to `end_height`, and their
+/// heights from the index.
+fn delete_range(db: &rocksdb::DB, start_height: u64, end_height: u64) -> Result<(), Error> {
+    let start_key = [start_height.to_be_bytes().as_slice(), &[0x00_u8; 32]].concat();
+    let end_key = [end_height.to_be_bytes().as_slice(), &[0xff_u8; 32]].concat();
+    let mut batch = rocksdb::WriteBatch::default();
+
+    let opts = {
+        let mut tmp = rocksdb::ReadOptions::default();
+        tmp.set_iterate_upper_bound(end_key.clone());
+        tmp
+    };
+    let iter = db.iterator_opt(
+        rocksdb::IteratorMode::From(&start_key, rocksdb::Direction::Forward),
+        opts,
+    );
+    for entry in iter {
+        let (key, _) = entry?;
+        if let Some(data_id) = key.get(8..) {
+            batch.delete_cf(heights_cf(db), data_id);
+        }
+    }
+
+    batch.delete_range(start_key, end_key);
+    db.write(batch)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DataIdMapping, Error, data_key, heights_cf, open_db, rewind_storage};
+    use crate::history::DEFAULT_DATA_ID_CACHE_SIZE;
+    use aurora_refiner_types::near_primitives::hash::CryptoHash;
+    use std::num::NonZeroUsize;
+
+    #[test]
+    fn test_data_id_mapping_survives_restart() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let data_id_1 = CryptoHash([1; 32]);
+        let data_id_2 = CryptoHash([2; 32]);
+        let data_id_3 = CryptoHash([3; 32]);
+
+        let mut mapping = DataIdMapping::new(db_dir.path(), DEFAULT_DATA_ID_CACHE_SIZE).unwrap();
+        mapping.put(data_id_1, Some(vec![1]), 100).unwrap();
+        mapping.put(data_id_2, None, 100).unwrap();
+        mapping.put(data_id_3, Some(vec![3]), 101).unwrap();
+        drop(mapping);
+
+        let mut mapping = DataIdMapping::new(db_dir.path(), DEFAULT_DATA_ID_CACHE_SIZE).unwrap();
+        assert_eq!(mapping.pop(&data_id_1).unwrap(), Some(Some(vec![1])));
+        assert_eq!(mapping.pop(&data_id_2).unwrap(), Some(None));
+        assert_eq!(mapping.pop(&data_id_1).unwrap(), None);
+
+        // Rewinding below the height of the last data receipt removes it
+        mapping.rewind(100).unwrap();
+        assert_eq!(mapping.pop(&data_id_3).unwrap(), None);
+        drop(mapping);
+
+        let mut mapping = DataIdMapping::new(db_dir.path(), DEFAULT_DATA_ID_CACHE_SIZE).unwrap();
+        assert_eq!(mapping.pop(&data_id_1).unwrap(), Some(Some(vec![1])));
+        assert_eq!(mapping.pop(&data_id_3).unwrap(), None);
+        drop(mapping);
+
+        // The DB can be rewound without opening the mapping
+        rewind_storage(db_dir.path(), 99).unwrap();
+        let mut mapping = DataIdMapping::new(db_dir.path(), DEFAULT_DATA_ID_CACHE_SIZE).unwrap();
+        assert_eq!(mapping.pop(&data_id_2).unwrap(), None);
+    }
+
+    #[test]
+    fn test_data_id_mapping_reads_db_on_cache_miss() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let data_id_1 = CryptoHash([1; 32]);
+        let data_id_2 = CryptoHash([2; 32]);
+
+        // The cache only holds the most recent data receipt
+        let mut mapping = DataIdMapping::new(db_dir.path(), NonZeroUsize::MIN).unwrap();
+        mapping.put(data_id_1, Some(vec![1]), 100).unwrap();
+        mapping.put(data_id_2, Some(vec![2]), 101).unwrap();
+
+        assert_eq!(mapping.pop(&data_id_1).unwrap(), Some(Some(vec![1])));
+        assert_eq!(mapping.pop(&data_id_1).unwrap(), None);
+        assert_eq!(mapping.pop(&data_id_2).unwrap(), Some(Some(vec![2])));
+        assert_eq!(mapping.pop(&CryptoHash([3; 32])).unwrap(), None);
+    }
+
+    #[test]
+    fn test_data_id_mapping_does_not_cache_unknown_ids() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let data_id = CryptoHash([1; 32]);
+
+        let mut mapping = DataIdMapping::new(db_dir.path(), NonZeroUsize::MIN).unwrap();
+        mapping.put(data_id, Some(vec![1]), 100).unwrap();
+        assert_eq!(mapping.pop(&data_id).unwrap(), Some(Some(vec![1])));
+
+        // The data taken stays in the cache, so it is not read again from the DB
+        assert_eq!(mapping.pop(&CryptoHash([2; 32])).unwrap(), None);
+        assert_eq!(mapping.pop(&data_id).unwrap(), None);
+    }
+
+    #[test]
+    fn test_data_id_mapping_corrupted_data() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let data_id = CryptoHash([1; 32]);
+        let db = open_db(db_dir.path()).unwrap();
+        db.put(data_key(100, &data_id), [2]).unwrap();
+        db.put_cf(heights_cf(&db), data_id, 100_u64.to_be_bytes())
+            .unwrap();
+        drop(db);
+
+        let mut mapping = DataIdMapping::new(db_dir.path(), DEFAULT_DATA_ID_CACHE_SIZE).unwrap();
+        assert!(matches!(
+            mapping.pop(&data_id),
+            Err(Error::Corrupted { data_id: id, .. }) if id == data_id
+        ));
+    }
+}
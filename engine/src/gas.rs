@@ -1,3 +1,4 @@
+use crate::state_diff::AccountKey;
 use crate::types::{BlockId, EthCallRequest, convert_authorization_list};
 use aurora_engine::{
     engine::{Engine, EngineError, EngineErrorKind},
@@ -7,7 +8,7 @@ use aurora_engine_modexp::AuroraModExp;
 use aurora_engine_sdk::io::IO;
 use aurora_engine_transactions::NormalizedEthTransaction;
 use aurora_engine_types::{
-    H160, H256, U256, storage,
+    H160, H256, U256,
     types::{NearGas, Wei},
 };
 use engine_standalone_storage::{
@@ -194,30 +195,10 @@ impl<'db, 'input: 'db, 'output: 'db> IO for EngineStateOverride<'db, 'input, 'ou
     }
 }
 
-const STORAGE_VERSION: u8 = storage::VersionPrefix::V1 as u8;
-const STORAGE_PREFIX: u8 = storage::KeyPrefix::Storage as u8;
-
 fn deconstruct_storage_key(key: &[u8]) -> Option<(H160, H256)> {
-    let version = *key.first()?;
-    if version != STORAGE_VERSION {
-        panic!("Unexpected version");
-    }
-    if key.get(1)? == &STORAGE_PREFIX {
-        let key_len = key.len();
-        // Lengths are 54 or 58 bytes, depending on if the generation is present or not
-        if key_len == 54 {
-            let address = H160::from_slice(&key[2..22]);
-            let value = H256::from_slice(&key[22..54]);
-            Some((address, value))
-        } else if key_len == 58 {
-            let address = H160::from_slice(&key[2..22]);
-            let value = H256::from_slice(&key[26..58]);
-            Some((address, value))
-        } else {
-            panic!("Unexpected storage key length")
-        }
-    } else {
-        None
+    match AccountKey::decode(key)? {
+        AccountKey::Storage(address, slot) => Some((address, slot)),
+        _ => None,
     }
 }
 
@@ -0,0 +1,39 @@
+//! Retention of the NEAR receipt data persisted next to the engine storage: the data receipts
+//! of the data id mapping, and the receipt lineages of the transaction hash tracker.
+
+use std::num::NonZeroUsize;
+
+/// Number of receipt lineages the transaction hash tracker keeps in memory. At up to 128 bytes
+/// per lineage (four 32-byte hashes), this caps the memory footprint of the tracker at around
+/// 130 MB, which seems reasonable. One million entries should also be sufficient to
+/// ensure the cache never miss under normal conditions; it would require a receipt to be
+/// created and then not included in a block before one million other receipts we created first.
+/// With the maximum daily number of transactions ever observed on NEAR at just over two million,
+/// this means the receipt would not have been included in a block for at least 8 hours; an
+/// extremely unlikely event (typically receipts are included in the next block after they are
+/// created, less than 2 seconds later).
+pub const DEFAULT_CACHE_SIZE: NonZeroUsize = NonZeroUsize::new(1_000_000).unwrap();
+
+/// Number of data receipts kept in memory by the data id mapping unless configured otherwise.
+/// Unlike a receipt lineage, an entry holds the whole result of a promise, which can be large,
+/// and all of them are read from the DB on start-up. A data receipt is usually consumed by its
+/// callback in the next block, so few of them are pending at any time, and a cache miss falls
+/// back to the DB anyway.
+pub const DEFAULT_DATA_ID_CACHE_SIZE: NonZeroUsize = NonZeroUsize::new(1_000).unwrap();
+
+/// This is the number of block heights into the past the DBs remember receipts.
+/// With a 1 second block time, this corresponds to 5 days of transactions, or 10 "epochs"
+/// since NEAR epochs tend to be around 12 hours. Keeping data for 5 epochs is the standard for
+/// non-archival nearcore nodes, so 10 epochs should be more than enough for us. At NEAR's peak of
+/// two million transactions per day, and assuming each transaction has 5 receipts on average
+/// (likely an overestimate), then this will mean fifty million entries in the transaction hash
+/// tracker DB at most. With up to 137 bytes per entry (four 32-byte hashes, one 8-byte height and
+/// one byte of flags), this will cap the storage used by this DB at under 7 GB.
+pub const PERSISTENT_HISTORY_SIZE: u64 = 432_000;
+
+/// Range delete operations are cheap to write (they are essentially one tombstone key in the DB),
+/// however they are expensive to resolve during compaction. We cannot write one pruning
+/// operation per block and keep reasonable performance of the DB when compaction happens.
+/// Therefore, we will only prune old data once every `PRUNE_FREQUENCY` blocks. This means the
+/// DB may grow a little larger than `PERSISTENT_HISTORY_SIZE` sometimes.
+pub const PRUNE_FREQUENCY: u64 = 50_000;
@@ -1,18 +1,28 @@
 use aurora_engine_modexp::ModExpAlgorithm;
 use aurora_engine_types::{H256, account_id::AccountId};
-use aurora_refiner_types::{near_block::NEARBlock, near_primitives::hash::CryptoHash};
-use engine_standalone_storage::{Storage, error, sync::TransactionIncludedOutcome};
+use aurora_refiner_types::near_block::NEARBlock;
+use data_id_mapping::DataIdMapping;
+use engine_standalone_storage::{Storage, sync::TransactionIncludedOutcome};
+use engine_standalone_tracing::types::call_tracer::CallFrame;
 use std::collections::HashMap;
 use std::num::NonZeroUsize;
 use std::path::Path;
+use sync::{CommittedBlock, SyncError};
+use upgrade::{EngineVersion, UpgradeConfig, UpgradeTracker};
+use verification::DiffVerifier;
 
 mod batch_tx_processing;
+pub mod data_id_mapping;
 pub mod gas;
+pub mod history;
+pub mod state_diff;
 pub mod sync;
 #[cfg(test)]
 mod tests;
 pub mod tracing;
 pub mod types;
+pub mod upgrade;
+pub mod verification;
 
 pub type SharedStorage = std::sync::Arc<tokio::sync::RwLock<Storage>>;
 
@@ -20,7 +30,9 @@ pub struct EngineContext {
     pub storage: SharedStorage,
     pub engine_account_id: AccountId,
     chain_id: [u8; 32],
-    data_id_mapping: lru::LruCache<CryptoHash, Option<Vec<u8>>>,
+    data_id_mapping: DataIdMapping,
+    diff_verifier: Option<DiffVerifier>,
+    upgrade_tracker: UpgradeTracker,
 }
 
 impl EngineContext {
@@ -28,24 +40,91 @@ impl EngineContext {
         storage_path: P,
         engine_account_id: AccountId,
         chain_id: u64,
-    ) -> Result<Self, error::Error> {
-        let storage = Storage::open(storage_path)?;
+    ) -> Result<Self, SyncError> {
+        let data_id_mapping_path = data_id_mapping::default_path(storage_path.as_ref());
+        Self::new_with_data_id_mapping(
+            storage_path,
+            engine_account_id,
+            chain_id,
+            data_id_mapping_path,
+            history::DEFAULT_DATA_ID_CACHE_SIZE,
+        )
+    }
+
+    /// Same as `new`, but with the folder of the data receipts and the number of them kept in
+    /// memory set explicitly.
+    pub fn new_with_data_id_mapping<P: AsRef<Path>, Q: AsRef<Path>>(
+        storage_path: P,
+        engine_account_id: AccountId,
+        chain_id: u64,
+        data_id_mapping_path: Q,
+        data_id_cache_size: NonZeroUsize,
+    ) -> Result<Self, SyncError> {
+        let storage = Storage::open(&storage_path)?;
+        let data_id_mapping = DataIdMapping::new(data_id_mapping_path, data_id_cache_size)?;
+        let upgrade_tracker = UpgradeTracker::new(&storage, UpgradeConfig::default())?;
         let storage = std::sync::Arc::new(tokio::sync::RwLock::new(storage));
         let chain_id = aurora_engine_types::types::u256_to_arr(&(chain_id.into()));
         Ok(Self {
             storage,
             engine_account_id,
             chain_id,
-            data_id_mapping: lru::LruCache::new(NonZeroUsize::new(1000).unwrap()),
+            data_id_mapping,
+            diff_verifier: None,
+            upgrade_tracker,
         })
     }
+
+    /// Enables the strict verification of every computed diff against the NEAR state changes.
+    #[must_use]
+    pub fn with_diff_verifier(mut self, diff_verifier: DiffVerifier) -> Self {
+        self.diff_verifier = Some(diff_verifier);
+        self
+    }
+
+    /// Sets what to do when the engine contract is upgraded to an unknown version.
+    #[must_use]
+    pub fn with_upgrade_config(mut self, config: UpgradeConfig) -> Self {
+        self.upgrade_tracker.set_config(config);
+        self
+    }
+
+    /// Chain id of the engine.
+    pub fn chain_id(&self) -> u64 {
+        aurora_engine_types::U256::from_big_endian(&self.chain_id).as_u64()
+    }
+
+    /// Version of the engine contract as of the last block consumed.
+    pub fn engine_version(&self) -> &EngineVersion {
+        self.upgrade_tracker.active()
+    }
+
+    /// Returns the number of receipts whose diff diverged from NEAR since the last call.
+    /// Always zero if diff verification is disabled.
+    pub fn take_diff_divergences(&mut self) -> u64 {
+        self.diff_verifier
+            .as_mut()
+            .map_or(0, DiffVerifier::take_divergences)
+    }
+
+    /// Undoes what `consume_near_block` committed for a block, for when the caller fails to
+    /// process the block after the engine consumed it.
+    pub async fn revert_block(&mut self, committed: CommittedBlock) -> Result<(), SyncError> {
+        let mut storage = self.storage.as_ref().write().await;
+        committed.revert(
+            &mut storage,
+            &mut self.data_id_mapping,
+            Some(&mut self.upgrade_tracker),
+        )
+    }
 }
 
 pub async fn consume_near_block<M: ModExpAlgorithm>(
     block: &NEARBlock,
     context: &mut EngineContext,
     outcomes: Option<&mut HashMap<H256, TransactionIncludedOutcome>>,
-) -> Result<(), error::Error> {
+    traces: Option<&mut HashMap<H256, Vec<CallFrame>>>,
+) -> Result<CommittedBlock, SyncError> {
     let mut storage = context.storage.as_ref().write().await;
     sync::consume_near_block::<M>(
         &mut storage,
@@ -54,5 +133,8 @@ pub async fn consume_near_block<M: ModExpAlgorithm>(
         &context.engine_account_id,
         context.chain_id,
         outcomes,
+        traces,
+        context.diff_verifier.as_mut(),
+        Some(&mut context.upgrade_tracker),
     )
 }
@@ -0,0 +1,196 @@
+//! Decoding of the changes a block makes to the engine storage into changes of the state of the
+//! EVM accounts.
+//!
+//! The changed keys are the ones of the diffs of the transactions the engine committed for the
+//! block, and their values before and after the block are read from the engine storage. Keys that
+//! are not part of the state of an EVM account (engine configuration, bridge, ...) are ignored.
+
+use aurora_engine_sdk::io::IO;
+use aurora_engine_types::types::{Address, Wei};
+use aurora_engine_types::{H160, H256, U256, storage};
+use aurora_refiner_types::aurora_block::{AccountStateDiff, StorageChange, ValueChange};
+use aurora_refiner_types::utils::keccak256;
+use engine_standalone_storage::Storage;
+use std::collections::{BTreeMap, BTreeSet};
+
+const STORAGE_VERSION: u8 = storage::VersionPrefix::V1 as u8;
+const NONCE_PREFIX: u8 = storage::KeyPrefix::Nonce as u8;
+const BALANCE_PREFIX: u8 = storage::KeyPrefix::Balance as u8;
+const CODE_PREFIX: u8 = storage::KeyPrefix::Code as u8;
+const STORAGE_PREFIX: u8 = storage::KeyPrefix::Storage as u8;
+
+/// Key of the engine storage holding a part of the state of an EVM account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AccountKey {
+    Nonce(H160),
+    Balance(H160),
+    Code(H160),
+    Storage(H160, H256),
+}
+
+impl AccountKey {
+    /// Decodes a raw key of the engine storage: a version byte, a prefix byte and the address,
+    /// followed for storage slots by the optional generation of the contract and the slot.
+    pub(crate) fn decode(key: &[u8]) -> Option<Self> {
+        let (&version, key) = key.split_first()?;
+        let (&prefix, key) = key.split_first()?;
+        if version != STORAGE_VERSION || key.len() < 20 {
+            return None;
+        }
+        let (address, rest) = key.split_at(20);
+        let address = H160::from_slice(address);
+
+        match (prefix, rest.len()) {
+            (NONCE_PREFIX, 0) => Some(Self::Nonce(address)),
+            (BALANCE_PREFIX, 0) => Some(Self::Balance(address)),
+            (CODE_PREFIX, 0) => Some(Self::Code(address)),
+            // Lengths are 32 or 36 bytes, depending on if the generation is present or not
+            (STORAGE_PREFIX, 32) => Some(Self::Storage(address, H256::from_slice(rest))),
+            (STORAGE_PREFIX, 36) => Some(Self::Storage(address, H256::from_slice(&rest[4..]))),
+            _ => None,
+        }
+    }
+
+    const fn address(&self) -> H160 {
+        match self {
+            Self::Nonce(address)
+            | Self::Balance(address)
+            | Self::Code(address)
+            | Self::Storage(address, _) => *address,
+        }
+    }
+}
+
+/// Changes made by the block at `height` to the state of the EVM accounts, sorted by address.
+/// The block must already be consumed by the engine, which changed the given `keys`, see
+/// [`crate::sync::CommittedBlock::changed_keys`].
+pub fn block_state_diff(
+    storage: &Storage,
+    height: u64,
+    keys: &BTreeSet<Vec<u8>>,
+) -> Vec<AccountStateDiff> {
+    // The state before the block is the one seen by its first transaction, and the state after
+    // the block the one seen by the first transaction of the next block.
+    let old_values = read_values(storage, height, keys);
+    let new_values = read_values(storage, height + 1, keys);
+
+    let mut accounts: BTreeMap<H160, AccountStateDiff> = BTreeMap::new();
+    for ((key, old), new) in keys.iter().zip(old_values).zip(new_values) {
+        if old == new {
+            continue;
+        }
+        let Some(account_key) = AccountKey::decode(key) else {
+            continue;
+        };
+        let address = account_key.address();
+        let diff = accounts.entry(address).or_insert_with(|| AccountStateDiff {
+            address: Address::new(address),
+            nonce: None,
+            balance: None,
+            code_hash: None,
+            storage: Vec::new(),
+        });
+        let (old, new) = (old.as_deref(), new.as_deref());
+        match account_key {
+            AccountKey::Nonce(_) => {
+                diff.nonce = Some(ValueChange {
+                    old: to_u256(old),
+                    new: to_u256(new),
+                });
+            }
+            AccountKey::Balance(_) => {
+                diff.balance = Some(ValueChange {
+                    old: Wei::new(to_u256(old)),
+                    new: Wei::new(to_u256(new)),
+                });
+            }
+            AccountKey::Code(_) => {
+                diff.code_hash = Some(ValueChange {
+                    old: keccak256(old.unwrap_or_default()),
+                    new: keccak256(new.unwrap_or_default()),
+                });
+            }
+            AccountKey::Storage(_, slot) => diff.storage.push(StorageChange {
+                slot,
+                old: to_h256(old),
+                new: to_h256(new),
+            }),
+        }
+    }
+
+    accounts.into_values().collect()
+}
+
+/// Values of `keys` as seen by the first transaction of the block at `block_height`.
+fn read_values(
+    storage: &Storage,
+    block_height: u64,
+    keys: &BTreeSet<Vec<u8>>,
+) -> Vec<Option<Vec<u8>>> {
+    storage
+        .with_engine_access(block_height, 0, &[], |io| {
+            keys.iter()
+                .map(|key| io.read_storage(key).map(|value| value.as_ref().to_vec()))
+                .collect()
+        })
+        .result
+}
+
+/// Missing and malformed values are zero, like in the engine.
+fn to_u256(value: Option<&[u8]>) -> U256 {
+    value
+        .filter(|value| value.len() == 32)
+        .map_or_else(U256::zero, U256::from_big_endian)
+}
+
+fn to_h256(value: Option<&[u8]>) -> H256 {
+    value
+        .filter(|value| value.len() == 32)
+        .map_or_else(H256::zero, H256::from_slice)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AccountKey;
+    use aurora_engine_types::{H160, H256};
+
+    #[test]
+    fn test_decode_account_key() {
+        let address = H160::repeat_byte(1);
+        let slot = H256::repeat_byte(2);
+        let key = |prefix: u8, rest: &[u8]| {
+            let mut key = vec![7, prefix];
+            key.extend_from_slice(address.as_bytes());
+            key.extend_from_slice(rest);
+            key
+        };
+
+        assert_eq!(
+            AccountKey::decode(&key(1, &[])),
+            Some(AccountKey::Nonce(address))
+        );
+        assert_eq!(
+            AccountKey::decode(&key(2, &[])),
+            Some(AccountKey::Balance(address))
+        );
+        assert_eq!(
+            AccountKey::decode(&key(3, &[])),
+            Some(AccountKey::Code(address))
+        );
+        assert_eq!(
+            AccountKey::decode(&key(4, slot.as_bytes())),
+            Some(AccountKey::Storage(address, slot))
+        );
+        let mut with_generation = vec![0, 0, 0, 1];
+        with_generation.extend_from_slice(slot.as_bytes());
+        assert_eq!(
+            AccountKey::decode(&key(4, &with_generation)),
+            Some(AccountKey::Storage(address, slot))
+        );
+
+        // The generation of the contract and the engine configuration are not account state
+        assert_eq!(AccountKey::decode(&key(7, &[])), None);
+        assert_eq!(AccountKey::decode(&[7, 0, 83, 84, 65, 84, 69]), None);
+        assert_eq!(AccountKey::decode(&key(4, &[0; 10])), None);
+    }
+}
@@ -10,36 +10,229 @@ use aurora_refiner_types::near_primitives::{
 };
 use engine_standalone_storage::sync::types::TransactionKind;
 use engine_standalone_storage::{
-    BlockMetadata, Diff, Storage,
+    BlockMetadata, Diff, Storage, TransactionIncluded,
     sync::{
         self, ConsumeMessageOutcome, TransactionExecutionResult, TransactionIncludedOutcome,
         types::{self, Message},
     },
 };
-use lru::LruCache;
-use std::{cell::RefCell, collections::HashMap};
+use engine_standalone_tracing::{
+    sputnik,
+    types::call_tracer::{CallFrame, CallTracer},
+};
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashMap, HashSet},
+};
 use tracing::{debug, warn};
 
 use crate::batch_tx_processing::BatchIO;
+use crate::data_id_mapping::DataIdMapping;
+use crate::upgrade::UpgradeTracker;
+use crate::verification::{DiffVerifier, KeyDivergence};
+
+/// Key of the height of the last block fully consumed by the engine in the custom data of the
+/// engine storage. An empty value means that no block was consumed yet.
+pub const LAST_PROCESSED_HEIGHT_KEY: &[u8] = b"last_processed_height";
+
+/// Errors that can happen while the engine consumes a NEAR block.
+#[derive(Debug)]
+pub enum SyncError {
+    /// Reading from or writing to the engine storage failed.
+    Storage(engine_standalone_storage::Error),
+    /// Reading from or writing to the persisted data receipts failed.
+    DataIdMapping(crate::data_id_mapping::Error),
+    /// The diff computed for a receipt does not match the state changes observed on NEAR.
+    /// Only returned when diff verification is enabled and set to halt.
+    DiffDivergence {
+        block_height: u64,
+        receipt_id: H256,
+        keys: Vec<KeyDivergence>,
+    },
+    /// Writing the divergence report failed.
+    DivergenceReport(std::io::Error),
+    /// The engine contract was upgraded to a code not known to be compatible with the standalone
+    /// engine. Only returned when the upgrade policy is to halt.
+    IncompatibleUpgrade {
+        block_height: u64,
+        code_hash: Option<CryptoHash>,
+    },
+    /// The transactions of a block could not be reverted after the block failed, so the storage
+    /// is left with part of the block applied.
+    Revert(engine_standalone_storage::Error),
+    /// A value written by the standalone engine in the custom data of the storage cannot be
+    /// decoded.
+    CorruptedData(&'static str),
+}
+
+impl std::fmt::Display for SyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Storage(err) => write!(f, "engine storage error: {err:?}"),
+            Self::DataIdMapping(err) => write!(f, "data id mapping error: {err}"),
+            Self::DiffDivergence {
+                block_height,
+                receipt_id,
+                keys,
+            } => write!(
+                f,
+                "diff of receipt {receipt_id:?} at height {block_height} diverges from NEAR on {} keys",
+                keys.len()
+            ),
+            Self::DivergenceReport(err) => write!(f, "failed to write divergence report: {err}"),
+            Self::IncompatibleUpgrade {
+                block_height,
+                code_hash,
+            } => write!(
+                f,
+                "engine upgraded at height {block_height} to incompatible code {code_hash:?}"
+            ),
+            Self::Revert(err) => write!(f, "failed to revert block transactions: {err:?}"),
+            Self::CorruptedData(what) => write!(f, "corrupted {what} in the engine storage"),
+        }
+    }
+}
+
+impl std::error::Error for SyncError {}
+
+impl From<engine_standalone_storage::Error> for SyncError {
+    fn from(err: engine_standalone_storage::Error) -> Self {
+        Self::Storage(err)
+    }
+}
+
+impl From<rocksdb::Error> for SyncError {
+    fn from(err: rocksdb::Error) -> Self {
+        Self::DataIdMapping(err.into())
+    }
+}
+
+impl From<crate::data_id_mapping::Error> for SyncError {
+    fn from(err: crate::data_id_mapping::Error) -> Self {
+        Self::DataIdMapping(err)
+    }
+}
+
+/// What consuming a NEAR block changed in the engine storage, so that it can be undone.
+#[derive(Debug, Default)]
+pub struct CommittedBlock {
+    /// The transactions committed to the storage, in the order they were committed.
+    included_transactions: Vec<(H256, TransactionIncluded)>,
+    /// The promise data taken from the data id mapping by the transactions.
+    consumed_data: Vec<(CryptoHash, Option<Vec<u8>>)>,
+    /// The keys of the engine storage written by the diffs of the transactions.
+    changed_keys: BTreeSet<Vec<u8>>,
+    /// The last processed height before the block.
+    previous_height: Option<u64>,
+    /// Height of the block, if an engine upgrade was recorded for it.
+    upgrade_height: Option<u64>,
+}
+
+impl CommittedBlock {
+    /// Keys of the engine storage changed by the block.
+    pub const fn changed_keys(&self) -> &BTreeSet<Vec<u8>> {
+        &self.changed_keys
+    }
+
+    /// Records a transaction written to the storage, with its diff.
+    fn include(&mut self, tx_hash: H256, tx_included: TransactionIncluded, diff: &Diff) {
+        self.included_transactions.push((tx_hash, tx_included));
+        self.changed_keys
+            .extend(diff.iter().map(|(key, _)| key.clone()));
+    }
+
+    /// Reverts the transactions of the block and gives back the promise data they consumed, so
+    /// that the block can be consumed again from scratch, and removes the engine upgrade recorded
+    /// for it. The block metadata and the data receipts are kept; they are written again
+    /// identically.
+    pub fn revert(
+        self,
+        storage: &mut Storage,
+        data_id_mapping: &mut DataIdMapping,
+        upgrade_tracker: Option<&mut UpgradeTracker>,
+    ) -> Result<(), SyncError> {
+        for (tx_hash, tx_included) in self.included_transactions.iter().rev() {
+            storage
+                .revert_transaction_included(*tx_hash, tx_included)
+                .map_err(SyncError::Revert)?;
+        }
+        if let (Some(height), Some(upgrade_tracker)) = (self.upgrade_height, upgrade_tracker) {
+            upgrade_tracker.revert(storage, height)?;
+        }
+        set_last_processed_height(storage, self.previous_height).map_err(SyncError::Revert)?;
+        for (data_id, data) in self.consumed_data {
+            data_id_mapping.restore(data_id, data);
+        }
+
+        Ok(())
+    }
+}
 
-#[allow(clippy::cognitive_complexity, clippy::option_if_let_else)]
+/// Height of the last block fully consumed by the engine, if any. Blocks above it may have been
+/// left partially applied by an interrupted process.
+pub fn last_processed_height(storage: &Storage) -> Result<Option<u64>, SyncError> {
+    let Some(value) = storage.get_custom_data(LAST_PROCESSED_HEIGHT_KEY)? else {
+        return Ok(None);
+    };
+    if value.is_empty() {
+        return Ok(None);
+    }
+    let bytes = value
+        .as_slice()
+        .try_into()
+        .map_err(|_| SyncError::CorruptedData("last processed height"))?;
+    Ok(Some(u64::from_be_bytes(bytes)))
+}
+
+/// Records `height` as the last block fully consumed by the engine.
+pub fn set_last_processed_height(
+    storage: &mut Storage,
+    height: Option<u64>,
+) -> Result<(), engine_standalone_storage::Error> {
+    match height {
+        Some(height) => storage.set_custom_data(LAST_PROCESSED_HEIGHT_KEY, &height.to_be_bytes()),
+        None => storage.set_custom_data(LAST_PROCESSED_HEIGHT_KEY, &[]),
+    }
+}
+
+/// Executes the Aurora transactions of a NEAR block. The outcome of every transaction is added to
+/// `outcomes`, and the top-level call frames of its EVM execution to `traces`, if given.
+/// On error, nothing the transactions committed is left in the storage, unless the error is
+/// `SyncError::Revert`; on success, the returned `CommittedBlock` allows the caller to undo them.
+#[allow(
+    clippy::cognitive_complexity,
+    clippy::option_if_let_else,
+    clippy::too_many_arguments
+)]
 pub fn consume_near_block<M: ModExpAlgorithm>(
     storage: &mut Storage,
     message: &aurora_refiner_types::near_block::NEARBlock,
-    data_id_mapping: &mut LruCache<CryptoHash, Option<Vec<u8>>>,
+    data_id_mapping: &mut DataIdMapping,
     engine_account_id: &AccountId,
     chain_id: [u8; 32],
     mut outcomes: Option<&mut HashMap<H256, TransactionIncludedOutcome>>,
-) -> Result<(), engine_standalone_storage::Error> {
+    mut traces: Option<&mut HashMap<H256, Vec<CallFrame>>>,
+    mut diff_verifier: Option<&mut DiffVerifier>,
+    mut upgrade_tracker: Option<&mut UpgradeTracker>,
+) -> Result<CommittedBlock, SyncError> {
+    let previous_height = last_processed_height(storage)?;
+    // Checked first, so that nothing is written if the refiner must halt on the upgrade. It is
+    // only recorded once the transactions of the block are committed.
+    let upgrade = match upgrade_tracker.as_deref() {
+        Some(upgrade_tracker) => upgrade_tracker.detect(message, engine_account_id.as_ref())?,
+        None => None,
+    };
     let block_hash =
         add_block_data_from_near_block::<M>(storage, message, chain_id, engine_account_id)?;
     let near_block_hash = &message.block.header.hash;
+    let near_block_height = message.block.header.height;
 
     // Capture data receipts (for using in promises). Also, we create a mapping here because the
     // order of the `receipts` and `receipt_execution_outcomes` is different (probably BUG) and we
     // need to handle the behavior.
     // Note: Iterate local_receipts first, then receipts, to match Nearcore runtime execution order
     // (see nearcore/runtime/runtime/src/lib.rs:Runtime::process_receipts())
+    let mut data_receipts = Vec::new();
     let receipt_mapping = message
         .shards
         .iter()
@@ -51,7 +244,7 @@ pub fn consume_near_block<M: ModExpAlgorithm>(
                 if let near_primitives::views::ReceiptEnumView::Data { data_id, data, .. } =
                     &r.receipt
                 {
-                    data_id_mapping.put(*data_id, data.clone());
+                    data_receipts.push((*data_id, data.clone()));
                 }
 
                 Some((r.receipt_id, i))
@@ -60,6 +253,9 @@ pub fn consume_near_block<M: ModExpAlgorithm>(
             }
         })
         .collect::<HashMap<_, _>>();
+    for (data_id, data) in data_receipts {
+        data_id_mapping.put(data_id, data, near_block_height)?;
+    }
 
     // Get expected state changes based on data in the streamer message
     let aurora_state_changes = message
@@ -113,6 +309,10 @@ pub fn consume_near_block<M: ModExpAlgorithm>(
             })
     });
 
+    let mut consumed_data = Vec::new();
+    // The data is read while the transactions are processed, so a failed read fails the block
+    // once they are
+    let mut data_read_error = None;
     let transaction_messages = receipt_execution_outcomes.iter().filter_map(|outcome| {
         // Ignore failed transactions since they do not impact the engine state
         let execution_result_bytes = match &outcome.execution_outcome.outcome.status {
@@ -131,7 +331,16 @@ pub fn consume_near_block<M: ModExpAlgorithm>(
             } => {
                 let input_data: Vec<_> = input_data_ids
                     .iter()
-                    .map(|id| data_id_mapping.pop(id).flatten())
+                    .map(|id| {
+                        let data = data_id_mapping.pop(id).unwrap_or_else(|err| {
+                            data_read_error.get_or_insert(err);
+                            None
+                        });
+                        if let Some(data) = &data {
+                            consumed_data.push((*id, data.clone()));
+                        }
+                        data.flatten()
+                    })
                     .collect();
                 let maybe_tx = parse_actions(actions, &input_data);
 
@@ -150,6 +359,9 @@ pub fn consume_near_block<M: ModExpAlgorithm>(
             Some(tn) => tn,
             None => {
                 if expected_diffs.contains_key(&near_receipt_id) {
+                    // Executed as an unknown transaction, which computes an empty diff, so the
+                    // state changes are reported by the diff verifier and the ones from NEAR
+                    // are committed instead
                     warn!(
                         "Receipt {:?} not parsed as transaction, but has state changes",
                         near_receipt_id,
@@ -245,10 +457,70 @@ pub fn consume_near_block<M: ModExpAlgorithm>(
         Some((transaction_messages, execution_result_bytes))
     });
 
+    let mut committed = CommittedBlock {
+        previous_height,
+        ..Default::default()
+    };
+    let result = process_transactions::<M>(
+        storage,
+        message,
+        transaction_messages,
+        &expected_diffs,
+        &mut outcomes,
+        &mut traces,
+        &mut diff_verifier,
+        &mut committed,
+    )
+    .and_then(|()| data_read_error.map_or(Ok(()), |err| Err(SyncError::from(err))))
+    .and_then(|()| {
+        data_id_mapping
+            .on_block_end(near_block_height)
+            .map_err(SyncError::from)
+    })
+    .and_then(|()| match (upgrade, upgrade_tracker.as_deref_mut()) {
+        (Some(state), Some(upgrade_tracker)) => {
+            upgrade_tracker.record(storage, near_block_height, state)?;
+            committed.upgrade_height = Some(near_block_height);
+            Ok(())
+        }
+        _ => Ok(()),
+    })
+    .and_then(|()| {
+        // Written last, so that an interrupted block is above the last processed height
+        set_last_processed_height(storage, Some(near_block_height)).map_err(SyncError::from)
+    });
+    committed.consumed_data = consumed_data;
+    if let Err(err) = result {
+        // Undo the transactions of this block that were already committed
+        if let Err(revert_err) = committed.revert(storage, data_id_mapping, upgrade_tracker) {
+            warn!("Failed to revert block {near_block_height} after error: {err}");
+            return Err(revert_err);
+        }
+        return Err(err);
+    }
+
+    Ok(committed)
+}
+
+/// Executes the transactions of a block and commits their diffs. The hash and position of every
+/// transaction written to the storage is recorded in `committed`, so that the caller can revert
+/// them.
+fn process_transactions<'a, M: ModExpAlgorithm>(
+    storage: &mut Storage,
+    message: &aurora_refiner_types::near_block::NEARBlock,
+    transaction_messages: impl Iterator<Item = (TransactionBatch, Option<&'a Vec<u8>>)>,
+    expected_diffs: &HashMap<H256, Diff>,
+    outcomes: &mut Option<&mut HashMap<H256, TransactionIncludedOutcome>>,
+    traces: &mut Option<&mut HashMap<H256, Vec<CallFrame>>>,
+    diff_verifier: &mut Option<&mut DiffVerifier>,
+    committed: &mut CommittedBlock,
+) -> Result<(), SyncError> {
+    let mut processed_receipts = HashSet::new();
     for (t, result_bytes) in transaction_messages {
         let receipt_id = t.near_receipt_id();
         debug!("Processing receipt {:?}", receipt_id);
-        let tx_outcome = t.process::<M>(storage)?;
+        processed_receipts.insert(receipt_id);
+        let tx_outcome = t.process::<M>(storage, traces.as_deref_mut())?;
         let computed_result = match &tx_outcome {
             TransactionBatchOutcome::Single(tx_outcome) => tx_outcome
                 .maybe_result
@@ -291,6 +563,17 @@ pub fn consume_near_block<M: ModExpAlgorithm>(
                 ),
             }
         }
+        // Strictly verify the computed diff, key by key, if requested
+        if let Some(verifier) = diff_verifier.as_mut() {
+            let empty_diff = Diff::default();
+            verifier.verify(
+                message.block.header.height,
+                &message.block.header.hash,
+                receipt_id,
+                expected_diffs.get(&receipt_id).unwrap_or(&empty_diff),
+                tx_outcome.diff(),
+            )?;
+        }
         // Validate against expected diff
         match expected_diffs.get(&receipt_id) {
             None => {
@@ -303,12 +586,12 @@ pub fn consume_near_block<M: ModExpAlgorithm>(
             Some(expected_diff) => {
                 if expected_diff == tx_outcome.diff() {
                     // Diff was correct, so commit it to the storage
-                    tx_outcome.commit(storage)?;
+                    tx_outcome.commit(storage, committed)?;
                 } else {
                     // Diff was incorrect, so log a warning and commit
                     // the one from the Near block instead
                     warn!("Receipt {receipt_id:?} diff mismatch with computed diff");
-                    tx_outcome.update_diff(storage, expected_diff)?;
+                    tx_outcome.update_diff(storage, expected_diff, committed)?;
                 }
             }
         }
@@ -334,6 +617,26 @@ pub fn consume_near_block<M: ModExpAlgorithm>(
         }
     }
 
+    // The state changes of the receipts that were not executed at all cannot be reproduced,
+    // so they diverge from the (empty) diff computed for them
+    let mut unprocessed_receipts: Vec<_> = expected_diffs
+        .iter()
+        .filter(|(receipt_id, _)| !processed_receipts.contains(*receipt_id))
+        .collect();
+    unprocessed_receipts.sort_by_key(|(receipt_id, _)| **receipt_id);
+    for (receipt_id, expected_diff) in unprocessed_receipts {
+        warn!("Receipt {receipt_id:?} not executed, but has state changes");
+        if let Some(verifier) = diff_verifier.as_mut() {
+            verifier.verify(
+                message.block.header.height,
+                &message.block.header.hash,
+                *receipt_id,
+                expected_diff,
+                &Diff::default(),
+            )?;
+        }
+    }
+
     Ok(())
 }
 
@@ -426,16 +729,24 @@ impl TransactionBatch {
         }
     }
 
+    /// Executes the transactions. If `traces` is given, they are executed under the call tracer
+    /// and their call frames are added to it.
     #[allow(clippy::cognitive_complexity)]
     fn process<M: ModExpAlgorithm>(
         self,
         storage: &mut Storage,
+        mut traces: Option<&mut HashMap<H256, Vec<CallFrame>>>,
     ) -> Result<TransactionBatchOutcome, engine_standalone_storage::Error> {
+        let trace = traces.is_some();
         match self {
             Self::Single(tx) => {
-                match sync::consume_message::<M>(storage, Message::Transaction(Box::new(tx)))? {
+                let (outcome, call_stack) = maybe_traced(trace, || {
+                    sync::consume_message::<M>(storage, Message::Transaction(Box::new(tx)))
+                });
+                match outcome? {
                     ConsumeMessageOutcome::TransactionIncluded(tx_outcome) => {
                         debug!("COMPLETED {:?}", tx_outcome.hash);
+                        record_trace(traces, tx_outcome.hash, call_stack);
                         Ok(TransactionBatchOutcome::Single(tx_outcome))
                     }
                     // We sent a transaction message tagged as successful, so we can only get `TransactionIncluded` back
@@ -473,44 +784,8 @@ impl TransactionBatch {
                 for tx in non_last_actions {
                     let transaction_position = tx.position;
                     let local_engine_account_id = engine_account_id.clone();
-                    let (tx_hash, diff, result) = storage
-                        .with_engine_access(
-                            block_height,
-                            transaction_position,
-                            &tx.raw_input,
-                            |io| {
-                                let local_diff = RefCell::new(Diff::default());
-                                let batch_io = BatchIO {
-                                    fallback: io,
-                                    cumulative_diff: &cumulative_diff,
-                                    current_diff: &local_diff,
-                                };
-                                sync::execute_transaction::<_, M, _>(
-                                    &tx,
-                                    block_height,
-                                    &block_metadata,
-                                    local_engine_account_id,
-                                    batch_io,
-                                    |x| x.current_diff.borrow().clone(),
-                                )
-                            },
-                        )
-                        .result;
-                    cumulative_diff.append(diff.clone());
-                    let tx_outcome = TransactionIncludedOutcome {
-                        hash: tx_hash,
-                        info: tx,
-                        diff,
-                        maybe_result: result,
-                    };
-                    debug!("COMPLETED {:?}", tx_outcome.hash);
-                    non_last_outcomes.push(tx_outcome);
-                }
-                let last_outcome = match last_action {
-                    None => None,
-                    Some(tx) => {
-                        let transaction_position = tx.position;
-                        let (tx_hash, diff, result) = storage
+                    let ((tx_hash, diff, result), call_stack) = maybe_traced(trace, || {
+                        storage
                             .with_engine_access(
                                 block_height,
                                 transaction_position,
@@ -526,14 +801,56 @@ impl TransactionBatch {
                                         &tx,
                                         block_height,
                                         &block_metadata,
-                                        engine_account_id,
+                                        local_engine_account_id,
                                         batch_io,
                                         |x| x.current_diff.borrow().clone(),
                                     )
                                 },
                             )
-                            .result;
+                            .result
+                    });
+                    cumulative_diff.append(diff.clone());
+                    record_trace(traces.as_deref_mut(), tx_hash, call_stack);
+                    let tx_outcome = TransactionIncludedOutcome {
+                        hash: tx_hash,
+                        info: tx,
+                        diff,
+                        maybe_result: result,
+                    };
+                    debug!("COMPLETED {:?}", tx_outcome.hash);
+                    non_last_outcomes.push(tx_outcome);
+                }
+                let last_outcome = match last_action {
+                    None => None,
+                    Some(tx) => {
+                        let transaction_position = tx.position;
+                        let ((tx_hash, diff, result), call_stack) = maybe_traced(trace, || {
+                            storage
+                                .with_engine_access(
+                                    block_height,
+                                    transaction_position,
+                                    &tx.raw_input,
+                                    |io| {
+                                        let local_diff = RefCell::new(Diff::default());
+                                        let batch_io = BatchIO {
+                                            fallback: io,
+                                            cumulative_diff: &cumulative_diff,
+                                            current_diff: &local_diff,
+                                        };
+                                        sync::execute_transaction::<_, M, _>(
+                                            &tx,
+                                            block_height,
+                                            &block_metadata,
+                                            engine_account_id,
+                                            batch_io,
+                                            |x| x.current_diff.borrow().clone(),
+                                        )
+                                    },
+                                )
+                                .result
+                        });
                         cumulative_diff.append(diff.clone());
+                        record_trace(traces, tx_hash, call_stack);
                         let tx_outcome = TransactionIncludedOutcome {
                             hash: tx_hash,
                             info: tx,
@@ -554,6 +871,31 @@ impl TransactionBatch {
     }
 }
 
+/// Runs `f` under the call tracer if `trace` is set. Returns its result along with the top-level
+/// call frames recorded, one per EVM execution.
+fn maybe_traced<R>(trace: bool, f: impl FnOnce() -> R) -> (R, Vec<CallFrame>) {
+    if !trace {
+        return (f(), Vec::new());
+    }
+    let mut listener = CallTracer::default();
+    let result = sputnik::traced_call(&mut listener, f);
+    (result, listener.call_stack)
+}
+
+/// Adds the call frames of a transaction to `traces`. Transactions without EVM execution have
+/// none, and are left out.
+fn record_trace(
+    traces: Option<&mut HashMap<H256, Vec<CallFrame>>>,
+    tx_hash: H256,
+    call_stack: Vec<CallFrame>,
+) {
+    if let Some(traces) = traces
+        && !call_stack.is_empty()
+    {
+        traces.insert(tx_hash, call_stack);
+    }
+}
+
 enum TransactionBatchOutcome {
     Single(Box<TransactionIncludedOutcome>),
     Batch {
@@ -573,9 +915,17 @@ impl TransactionBatchOutcome {
         }
     }
 
-    fn commit(&self, storage: &mut Storage) -> Result<(), engine_standalone_storage::Error> {
+    fn commit(
+        &self,
+        storage: &mut Storage,
+        committed: &mut CommittedBlock,
+    ) -> Result<(), engine_standalone_storage::Error> {
         match self {
-            Self::Single(tx_outcome) => tx_outcome.commit(storage),
+            Self::Single(tx_outcome) => {
+                tx_outcome.commit(storage)?;
+                committed.include(tx_outcome.hash, tx_outcome.info, &tx_outcome.diff);
+                Ok(())
+            }
             Self::Batch {
                 non_last_outcomes,
                 last_outcome,
@@ -585,7 +935,8 @@ impl TransactionBatchOutcome {
                     .iter()
                     .chain(last_outcome.iter().map(|x| x.as_ref()));
                 for tx_outcome in all_outcomes {
-                    tx_outcome.commit(storage)?
+                    tx_outcome.commit(storage)?;
+                    committed.include(tx_outcome.hash, tx_outcome.info, &tx_outcome.diff);
                 }
                 Ok(())
             }
@@ -596,10 +947,17 @@ impl TransactionBatchOutcome {
         &self,
         storage: &mut Storage,
         expected_diff: &Diff,
+        committed: &mut CommittedBlock,
     ) -> Result<(), engine_standalone_storage::Error> {
         match self {
             Self::Single(tx_outcome) => {
-                storage.set_transaction_included(tx_outcome.hash, &tx_outcome.info, expected_diff)
+                storage.set_transaction_included(
+                    tx_outcome.hash,
+                    &tx_outcome.info,
+                    expected_diff,
+                )?;
+                committed.include(tx_outcome.hash, tx_outcome.info, expected_diff);
+                Ok(())
             }
             Self::Batch {
                 non_last_outcomes,
@@ -624,7 +982,8 @@ impl TransactionBatchOutcome {
                         tx_outcome.hash,
                         &tx_outcome.info,
                         expected_diff,
-                    )?
+                    )?;
+                    committed.include(tx_outcome.hash, tx_outcome.info, expected_diff);
                 }
                 Ok(())
             }
@@ -1,12 +1,20 @@
 use aurora_engine::parameters::TransactionStatus;
 use aurora_engine_modexp::AuroraModExp;
-use aurora_engine_types::{H256, account_id::AccountId};
-use aurora_refiner_types::near_block::NEARBlock;
+use aurora_engine_types::types::Address;
+use aurora_engine_types::{H256, U256, account_id::AccountId};
+use aurora_refiner_types::aurora_block::{AccountStateDiff, ValueChange};
+use aurora_refiner_types::near_block::{ExecutionOutcomeWithReceipt, NEARBlock};
+use aurora_refiner_types::near_primitives::views::{
+    ActionView, ExecutionStatusView, ReceiptEnumView,
+};
 use engine_standalone_storage::Storage;
 use engine_standalone_storage::json_snapshot::{self, types::JsonSnapshot};
 use engine_standalone_storage::sync::TransactionExecutionResult;
 use std::collections::HashMap;
-use std::num::NonZeroUsize;
+
+use crate::data_id_mapping::{self, DataIdMapping};
+use crate::history::DEFAULT_DATA_ID_CACHE_SIZE;
+use crate::verification::{DiffVerificationConfig, DiffVerifier};
 
 /// This test processes a real block from mainnet:
 /// <https://nearblocks.io/blocks/5SRtKoD8JppC3LRv8uCp5bS26wCd4wUXtT6M1yziUFdN>
@@ -21,17 +29,20 @@ fn test_random_value() {
         let file = std::fs::File::open("src/res/block_105089746.json").unwrap();
         serde_json::from_reader(file).unwrap()
     };
-    let mut data_id_mapping = lru::LruCache::new(NonZeroUsize::new(1000).unwrap());
     let mut outcomes_map = HashMap::new();
+    let mut traces = HashMap::new();
     let chain_id = aurora_engine_types::types::u256_to_arr(&(1313161554.into()));
 
-    crate::sync::consume_near_block::<AuroraModExp>(
+    let committed = crate::sync::consume_near_block::<AuroraModExp>(
         &mut test_context.storage,
         &block,
-        &mut data_id_mapping,
+        &mut test_context.data_id_mapping,
         &test_context.engine_account_id,
         chain_id,
         Some(&mut outcomes_map),
+        Some(&mut traces),
+        None,
+        None,
     )
     .unwrap();
 
@@ -54,6 +65,33 @@ fn test_random_value() {
     };
     assert_eq!(output, expected_output, "Failed to reproduce random value");
 
+    // The transaction is traced along the way, its top-level call returning the same value
+    let call_stack = traces.remove(&expected_tx_hash).unwrap();
+    assert_eq!(call_stack.len(), 1);
+    assert_eq!(call_stack[0].output, expected_output);
+
+    // The only change to the state of the accounts is the nonce of the sender. The other keys
+    // updated by the block are deleted without having been set, or are not account state.
+    let state_diff = crate::state_diff::block_state_diff(
+        &test_context.storage,
+        block.block.header.height,
+        committed.changed_keys(),
+    );
+    let sender = Address::decode("4bfcff9a964925adf801c866f6ada98bd7ec40ca").unwrap();
+    assert_eq!(
+        state_diff,
+        vec![AccountStateDiff {
+            address: sender,
+            nonce: Some(ValueChange {
+                old: U256::zero(),
+                new: U256::one(),
+            }),
+            balance: None,
+            code_hash: None,
+            storage: Vec::new(),
+        }]
+    );
+
     test_context.close()
 }
 
@@ -67,17 +105,19 @@ fn test_empty_submit_input() {
         let file = std::fs::File::open("src/res/block_71771951.json").unwrap();
         serde_json::from_reader(file).unwrap()
     };
-    let mut data_id_mapping = lru::LruCache::new(NonZeroUsize::new(1000).unwrap());
     let mut outcomes_map = HashMap::new();
     let chain_id = aurora_engine_types::types::u256_to_arr(&(1313161554.into()));
 
     crate::sync::consume_near_block::<AuroraModExp>(
         &mut test_context.storage,
         &block,
-        &mut data_id_mapping,
+        &mut test_context.data_id_mapping,
         &test_context.engine_account_id,
         chain_id,
         Some(&mut outcomes_map),
+        None,
+        None,
+        None,
     )
     .unwrap();
 
@@ -97,16 +137,18 @@ fn test_batched_transactions() {
         let file = std::fs::File::open("src/res/block_66381607.json").unwrap();
         serde_json::from_reader(file).unwrap()
     };
-    let mut data_id_mapping = lru::LruCache::new(NonZeroUsize::new(1000).unwrap());
     let mut outcomes_map = HashMap::new();
     let chain_id = aurora_engine_types::types::u256_to_arr(&(1313161554.into()));
     crate::sync::consume_near_block::<AuroraModExp>(
         &mut test_context.storage,
         &block,
-        &mut data_id_mapping,
+        &mut test_context.data_id_mapping,
         &test_context.engine_account_id,
         chain_id,
         Some(&mut outcomes_map),
+        None,
+        None,
+        None,
     )
     .unwrap();
 
@@ -176,29 +218,121 @@ fn test_batched_transactions() {
     test_context.close()
 }
 
+/// The state changes of a receipt the standalone engine cannot execute, because it does not
+/// parse as a transaction or because it is not seen as a receipt of the engine, cannot be
+/// reproduced. They must be reported as divergences by the diff verifier.
+#[test]
+fn test_state_changes_of_unexecuted_receipts_are_verified() {
+    let chain_id = aurora_engine_types::types::u256_to_arr(&(1313161554.into()));
+    let read_block = || -> NEARBlock {
+        let file = std::fs::File::open("src/res/block_105089746.json").unwrap();
+        serde_json::from_reader(file).unwrap()
+    };
+    let unparsed_block = {
+        let mut block = read_block();
+        let outcome = engine_outcome(&mut block);
+        if let ReceiptEnumView::Action { actions, .. } = &mut outcome.receipt.receipt
+            && let ActionView::FunctionCall { method_name, .. } = &mut actions[0]
+        {
+            *method_name = "unknown_method".to_string();
+        }
+        block
+    };
+    let unseen_block = {
+        let mut block = read_block();
+        let outcome = engine_outcome(&mut block);
+        outcome.receipt.receiver_id = "other.near".parse().unwrap();
+        block
+    };
+
+    for block in [unparsed_block, unseen_block] {
+        let mut test_context =
+            TestContext::load_snapshot("src/res/contract.aurora.block66381606.minimal.json");
+        let report_dir = tempfile::tempdir().unwrap();
+        let report_path = report_dir.path().join("divergences.jsonl");
+        let mut verifier = DiffVerifier::new(&DiffVerificationConfig {
+            report_path: Some(report_path.clone()),
+            halt: false,
+        })
+        .unwrap();
+
+        crate::sync::consume_near_block::<AuroraModExp>(
+            &mut test_context.storage,
+            &block,
+            &mut test_context.data_id_mapping,
+            &test_context.engine_account_id,
+            chain_id,
+            None,
+            None,
+            Some(&mut verifier),
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(verifier.take_divergences(), 1);
+        let report = std::fs::read_to_string(&report_path).unwrap();
+        let record: serde_json::Value = serde_json::from_str(report.trim_end()).unwrap();
+        assert_eq!(record["block_height"], block.block.header.height);
+        assert!(
+            record["keys"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .all(|key| key["computed"]["kind"] == "unchanged")
+        );
+
+        test_context.close();
+    }
+}
+
+/// The execution outcome of the successful receipt of the engine in `block`.
+fn engine_outcome(block: &mut NEARBlock) -> &mut ExecutionOutcomeWithReceipt {
+    block
+        .shards
+        .iter_mut()
+        .flat_map(|shard| shard.receipt_execution_outcomes.iter_mut())
+        .find(|outcome| {
+            outcome.receipt.receiver_id.as_str() == "aurora"
+                && matches!(
+                    outcome.execution_outcome.outcome.status,
+                    ExecutionStatusView::SuccessValue(_)
+                )
+        })
+        .unwrap()
+}
+
 struct TestContext {
     storage: Storage,
-    storage_path: tempfile::TempDir,
+    data_id_mapping: DataIdMapping,
+    dir: tempfile::TempDir,
     engine_account_id: AccountId,
 }
 
 impl TestContext {
     fn load_snapshot(snapshot_path: &str) -> Self {
         let engine_account_id = "aurora".parse().unwrap();
-        let storage_path = tempfile::tempdir().unwrap();
-        let mut storage = Storage::open(storage_path.path()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let storage_path = dir.path().join("engine");
+        let mut storage = Storage::open(&storage_path).unwrap();
         storage.set_engine_account_id(&engine_account_id).unwrap();
         let snapshot = JsonSnapshot::load_from_file(snapshot_path).unwrap();
         json_snapshot::initialize_engine_state(&storage, snapshot).unwrap();
+        let data_id_mapping = DataIdMapping::new(
+            data_id_mapping::default_path(&storage_path),
+            DEFAULT_DATA_ID_CACHE_SIZE,
+        )
+        .unwrap();
         Self {
             storage,
-            storage_path,
+            data_id_mapping,
+            dir,
             engine_account_id,
         }
     }
 
     fn close(self) {
         drop(self.storage);
-        self.storage_path.close().unwrap();
+        drop(self.data_id_mapping);
+        self.dir.close().unwrap();
     }
 }
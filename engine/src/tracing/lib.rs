@@ -1,10 +1,14 @@
 use aurora_engine_modexp::AuroraModExp;
 use aurora_engine_types::H256;
+use aurora_refiner_types::aurora_block;
 use engine_standalone_storage::{
     Storage,
     sync::{self, TransactionIncludedOutcome},
 };
-use engine_standalone_tracing::{sputnik, types::call_tracer::CallTracer};
+use engine_standalone_tracing::{
+    sputnik,
+    types::call_tracer::{CallFrame, CallTracer, CallType},
+};
 
 pub struct DebugTraceTransactionRequest {
     pub tx_hash: H256,
@@ -35,3 +39,28 @@ pub fn trace_transaction(
     })?;
     Ok((listener, outcome))
 }
+
+/// Converts a call frame recorded by the `CallTracer` into the call frame of the refined blocks.
+pub fn to_refined_call_frame(frame: CallFrame) -> aurora_block::CallFrame {
+    let call_type = match frame.call_type {
+        CallType::Call => aurora_block::CallType::Call,
+        CallType::StaticCall => aurora_block::CallType::StaticCall,
+        CallType::DelegateCall => aurora_block::CallType::DelegateCall,
+        CallType::CallCode => aurora_block::CallType::CallCode,
+        CallType::Create => aurora_block::CallType::Create,
+        CallType::Create2 => aurora_block::CallType::Create2,
+        CallType::SelfDestruct => aurora_block::CallType::SelfDestruct,
+    };
+    aurora_block::CallFrame {
+        call_type,
+        from: frame.from,
+        to: frame.to,
+        value: frame.value,
+        gas: frame.gas,
+        gas_used: frame.gas_used,
+        input: frame.input,
+        output: frame.output,
+        error: frame.error,
+        calls: frame.calls.into_iter().map(to_refined_call_frame).collect(),
+    }
+}
@@ -0,0 +1,443 @@
+use aurora_engine_types::borsh::BorshDeserialize;
+use aurora_engine_types::parameters::engine::UpgradeParams;
+use aurora_refiner_types::near_block::NEARBlock;
+use aurora_refiner_types::near_primitives::hash::CryptoHash;
+use aurora_refiner_types::near_primitives::views::{
+    ActionView, ExecutionStatusView, ReceiptEnumView,
+};
+use engine_standalone_storage::Storage;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::sync::SyncError;
+
+/// Version of the engine contract the standalone engine is compiled against.
+pub const STANDALONE_ENGINE_VERSION: &str = "3.10.1";
+
+/// Key of the upgrade history in the custom data of the engine storage.
+const UPGRADE_HISTORY_KEY: &[u8] = b"engine_upgrade_history";
+
+/// What to do when the engine contract is upgraded to a code that is not known to be compatible
+/// with the standalone engine.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UpgradePolicy {
+    /// Stop consuming blocks before the block with the upgrade.
+    Halt,
+    /// Log a warning and keep consuming blocks with the logic of the standalone engine.
+    #[default]
+    Warn,
+    /// Keep consuming blocks with the logic of the standalone engine.
+    Continue,
+}
+
+/// Configuration of the detection of the engine contract upgrades.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct UpgradeConfig {
+    #[serde(default)]
+    pub policy: UpgradePolicy,
+    /// Versions of the engine contract the standalone engine is compatible with, by hash of their
+    /// code (base58, as shown by NEAR).
+    #[serde(default)]
+    pub compatible_versions: HashMap<CryptoHash, String>,
+}
+
+/// Version of the engine contract running on chain.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct EngineVersion {
+    /// Hash of the code of the contract. Unknown until an upgrade is seen, or if the upgrade
+    /// deploys code staged before the refiner started.
+    pub code_hash: Option<CryptoHash>,
+    /// Version of the contract. Unknown if the code is not one of the compatible versions.
+    pub version: Option<String>,
+    /// Height of the block with the upgrade, if any.
+    pub block_height: Option<u64>,
+}
+
+impl Default for EngineVersion {
+    /// Until an upgrade is seen, the contract is assumed to be the version the standalone engine
+    /// is compiled against.
+    fn default() -> Self {
+        Self {
+            code_hash: None,
+            version: Some(STANDALONE_ENGINE_VERSION.to_string()),
+            block_height: None,
+        }
+    }
+}
+
+/// Upgrades of the engine contract as of a block.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct UpgradeState {
+    active: EngineVersion,
+    /// Hash of the code staged with `stage_upgrade`, if it was seen.
+    staged_code_hash: Option<CryptoHash>,
+}
+
+/// Call of the engine contract that replaces its code. These methods are not transactions of the
+/// standalone engine, so they are parsed here.
+#[derive(Debug, Clone)]
+pub enum UpgradeCall {
+    /// `stage_upgrade`, which stages code to be deployed later.
+    Stage { code_hash: CryptoHash },
+    /// `deploy_upgrade`, which deploys the staged code.
+    DeployStaged,
+    /// `upgrade`, which deploys the code in its arguments, if they could be decoded.
+    Upgrade(Option<UpgradeParams>),
+}
+
+impl UpgradeCall {
+    /// Parses a call of the method `method_name`. Returns `None` for the other methods.
+    pub fn parse(method_name: &str, args: &[u8]) -> Option<Self> {
+        match method_name {
+            // The staged code is passed as raw bytes
+            "stage_upgrade" => Some(Self::Stage {
+                code_hash: CryptoHash::hash_bytes(args),
+            }),
+            "deploy_upgrade" => Some(Self::DeployStaged),
+            "upgrade" => Some(Self::Upgrade(UpgradeParams::try_from_slice(args).ok())),
+            _ => None,
+        }
+    }
+}
+
+/// Detects the upgrades of the engine contract, and applies the upgrade policy to them.
+/// The state after every block with an upgrade is persisted in the engine storage, by height, so
+/// that it can be rewound with the rest of the storage. It is only recorded once the transactions
+/// of the block are committed, and removed if the block is reverted.
+pub struct UpgradeTracker {
+    config: UpgradeConfig,
+    history: BTreeMap<u64, UpgradeState>,
+}
+
+impl UpgradeTracker {
+    pub fn new(storage: &Storage, config: UpgradeConfig) -> Result<Self, SyncError> {
+        let history = read_history(storage)?;
+
+        Ok(Self { config, history })
+    }
+
+    pub(crate) fn set_config(&mut self, config: UpgradeConfig) {
+        self.config = config;
+    }
+
+    /// Version of the engine contract as of the last block consumed.
+    pub fn active(&self) -> &EngineVersion {
+        &self.state().active
+    }
+
+    /// Upgrades as of the last block consumed.
+    fn state(&self) -> &UpgradeState {
+        let (_, state) = self
+            .history
+            .last_key_value()
+            .expect("The history starts at height 0");
+        state
+    }
+
+    /// Looks for the successful upgrades of `engine_account_id` in `block`, and returns the state
+    /// after the block if it has any, to be recorded with `record`. The version they deploy
+    /// becomes the active version, unless it is incompatible and the policy is to halt, in which
+    /// case an error is returned.
+    pub(crate) fn detect(
+        &self,
+        block: &NEARBlock,
+        engine_account_id: &str,
+    ) -> Result<Option<UpgradeState>, SyncError> {
+        let block_height = block.block.header.height;
+        let mut staged_code_hash = None;
+        let mut upgrade = None;
+
+        let actions = block
+            .shards
+            .iter()
+            .flat_map(|shard| shard.receipt_execution_outcomes.iter())
+            .filter(|outcome| outcome.receipt.receiver_id.as_str() == engine_account_id)
+            .filter(|outcome| {
+                !matches!(
+                    outcome.execution_outcome.outcome.status,
+                    ExecutionStatusView::Unknown | ExecutionStatusView::Failure(_)
+                )
+            })
+            .filter_map(|outcome| match &outcome.receipt.receipt {
+                ReceiptEnumView::Action { actions, .. } => Some(actions),
+                _ => None,
+            })
+            .flatten();
+        for action in actions {
+            match action {
+                ActionView::DeployContract { code } => {
+                    upgrade = Some(Some(CryptoHash::hash_bytes(code)));
+                }
+                ActionView::FunctionCall {
+                    method_name, args, ..
+                } => match UpgradeCall::parse(method_name, args) {
+                    Some(UpgradeCall::Stage { code_hash }) => staged_code_hash = Some(code_hash),
+                    Some(UpgradeCall::DeployStaged) => {
+                        upgrade = Some(staged_code_hash.or(self.state().staged_code_hash));
+                    }
+                    Some(UpgradeCall::Upgrade(params)) => {
+                        upgrade = Some(params.map(|params| CryptoHash::hash_bytes(&params.code)));
+                    }
+                    None => {}
+                },
+                _ => {}
+            }
+        }
+
+        if upgrade.is_none() && staged_code_hash.is_none() {
+            return Ok(None);
+        }
+        let mut state = self.state().clone();
+        if let Some(code_hash) = upgrade {
+            let version = code_hash
+                .and_then(|code_hash| self.config.compatible_versions.get(&code_hash))
+                .cloned();
+            if version.is_none() {
+                match self.config.policy {
+                    UpgradePolicy::Halt => {
+                        return Err(SyncError::IncompatibleUpgrade {
+                            block_height,
+                            code_hash,
+                        });
+                    }
+                    UpgradePolicy::Warn => tracing::warn!(
+                        "Engine upgraded at height {block_height} to code {code_hash:?}, which is not known to be compatible with the standalone engine {STANDALONE_ENGINE_VERSION}"
+                    ),
+                    UpgradePolicy::Continue => {}
+                }
+            }
+            tracing::info!(
+                "Engine upgraded at height {block_height} to code {code_hash:?}, version {version:?}"
+            );
+
+            state.active = EngineVersion {
+                code_hash,
+                version,
+                block_height: Some(block_height),
+            };
+        }
+        if staged_code_hash.is_some() {
+            state.staged_code_hash = staged_code_hash;
+        }
+
+        Ok(Some(state))
+    }
+
+    /// Records the state after the block at `block_height`, as returned by `detect`.
+    pub(crate) fn record(
+        &mut self,
+        storage: &mut Storage,
+        block_height: u64,
+        state: UpgradeState,
+    ) -> Result<(), SyncError> {
+        // Consuming the same block again overwrites its entry
+        let mut history = self.history.clone();
+        history.insert(block_height, state);
+        write_history(storage, &history)?;
+        self.history = history;
+
+        Ok(())
+    }
+
+    /// Removes the state recorded for the block at `block_height`, when the block is reverted.
+    pub(crate) fn revert(
+        &mut self,
+        storage: &mut Storage,
+        block_height: u64,
+    ) -> Result<(), SyncError> {
+        // The entry at height 0 is always kept
+        if block_height == 0 || !self.history.contains_key(&block_height) {
+            return Ok(());
+        }
+        let mut history = self.history.clone();
+        history.remove(&block_height);
+        write_history(storage, &history)?;
+        self.history = history;
+
+        Ok(())
+    }
+}
+
+/// Removes the upgrades seen above `height` from the engine storage.
+pub fn rewind_upgrades(storage: &mut Storage, height: u64) -> Result<(), SyncError> {
+    let mut history = read_history(storage)?;
+    if history.keys().any(|block_height| *block_height > height) {
+        // The entry at height 0 is always kept
+        history.retain(|block_height, _| *block_height <= height);
+        write_history(storage, &history)?;
+    }
+
+    Ok(())
+}
+
+/// Upgrade history persisted in the engine storage. It always has an entry at height 0, with the
+/// state before any upgrade was seen.
+fn read_history(storage: &Storage) -> Result<BTreeMap<u64, UpgradeState>, SyncError> {
+    let Some(value) = storage.get_custom_data(UPGRADE_HISTORY_KEY)? else {
+        return Ok(BTreeMap::from([(0, UpgradeState::default())]));
+    };
+    serde_json::from_slice::<BTreeMap<u64, UpgradeState>>(&value)
+        .ok()
+        .filter(|history| history.contains_key(&0))
+        .ok_or(SyncError::CorruptedData("engine upgrade history"))
+}
+
+fn write_history(
+    storage: &mut Storage,
+    history: &BTreeMap<u64, UpgradeState>,
+) -> Result<(), SyncError> {
+    let value = serde_json::to_vec(history).expect("Serialization to Vec cannot fail");
+    storage.set_custom_data(UPGRADE_HISTORY_KEY, &value)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        EngineVersion, STANDALONE_ENGINE_VERSION, UPGRADE_HISTORY_KEY, UpgradeConfig,
+        UpgradePolicy, UpgradeTracker, rewind_upgrades,
+    };
+    use crate::sync::SyncError;
+    use aurora_refiner_types::near_block::NEARBlock;
+    use aurora_refiner_types::near_primitives::hash::CryptoHash;
+    use aurora_refiner_types::near_primitives::views::{ActionView, ReceiptEnumView};
+    use engine_standalone_storage::Storage;
+
+    /// Detects and records the upgrades of `block`, as done once its transactions are committed.
+    fn consume(
+        tracker: &mut UpgradeTracker,
+        storage: &mut Storage,
+        block: &NEARBlock,
+        account_id: &str,
+    ) -> Result<(), SyncError> {
+        if let Some(state) = tracker.detect(block, account_id)? {
+            tracker.record(storage, block.block.header.height, state)?;
+        }
+        Ok(())
+    }
+
+    /// Block with a successful receipt to the engine, its actions replaced by `action`.
+    fn upgrade_block(action: ActionView) -> (NEARBlock, String) {
+        let file = std::fs::File::open("src/res/block_105089746.json").unwrap();
+        let mut block: NEARBlock = serde_json::from_reader(file).unwrap();
+        let outcome = block
+            .shards
+            .iter_mut()
+            .flat_map(|shard| shard.receipt_execution_outcomes.iter_mut())
+            .find(|outcome| matches!(outcome.receipt.receipt, ReceiptEnumView::Action { .. }))
+            .unwrap();
+        if let ReceiptEnumView::Action { actions, .. } = &mut outcome.receipt.receipt {
+            *actions = vec![action];
+        }
+        let account_id = outcome.receipt.receiver_id.to_string();
+
+        (block, account_id)
+    }
+
+    #[test]
+    fn test_upgrade_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = Storage::open(dir.path()).unwrap();
+        let code = vec![0, 97, 115, 109];
+        let code_hash = CryptoHash::hash_bytes(&code);
+        let (block, account_id) = upgrade_block(ActionView::DeployContract { code });
+
+        let config = |policy, compatible: bool| UpgradeConfig {
+            policy,
+            compatible_versions: compatible
+                .then(|| (code_hash, "3.10.2".to_string()))
+                .into_iter()
+                .collect(),
+        };
+
+        let mut tracker =
+            UpgradeTracker::new(&storage, config(UpgradePolicy::Halt, false)).unwrap();
+        assert_eq!(tracker.active(), &EngineVersion::default());
+        let err = consume(&mut tracker, &mut storage, &block, &account_id).unwrap_err();
+        assert!(matches!(
+            err,
+            SyncError::IncompatibleUpgrade { code_hash: Some(hash), .. } if hash == code_hash
+        ));
+        assert_eq!(tracker.active(), &EngineVersion::default());
+
+        let mut tracker = UpgradeTracker::new(&storage, config(UpgradePolicy::Halt, true)).unwrap();
+        consume(&mut tracker, &mut storage, &block, &account_id).unwrap();
+        let expected = EngineVersion {
+            code_hash: Some(code_hash),
+            version: Some("3.10.2".to_string()),
+            block_height: Some(block.block.header.height),
+        };
+        assert_eq!(tracker.active(), &expected);
+
+        // The active version is read back from the storage
+        let tracker = UpgradeTracker::new(&storage, config(UpgradePolicy::Warn, false)).unwrap();
+        assert_eq!(tracker.active(), &expected);
+    }
+
+    #[test]
+    fn test_rewind_upgrades() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = Storage::open(dir.path()).unwrap();
+        let code = vec![0, 97, 115, 109];
+        let (block, account_id) = upgrade_block(ActionView::DeployContract { code });
+        let height = block.block.header.height;
+
+        let mut tracker = UpgradeTracker::new(&storage, UpgradeConfig::default()).unwrap();
+        consume(&mut tracker, &mut storage, &block, &account_id).unwrap();
+        assert_eq!(tracker.active().block_height, Some(height));
+
+        // The upgrade is kept when rewinding to its block
+        rewind_upgrades(&mut storage, height).unwrap();
+        let tracker = UpgradeTracker::new(&storage, UpgradeConfig::default()).unwrap();
+        assert_eq!(tracker.active().block_height, Some(height));
+
+        rewind_upgrades(&mut storage, height - 1).unwrap();
+        let tracker = UpgradeTracker::new(&storage, UpgradeConfig::default()).unwrap();
+        assert_eq!(tracker.active(), &EngineVersion::default());
+    }
+
+    #[test]
+    fn test_revert_upgrade() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = Storage::open(dir.path()).unwrap();
+        let code = vec![0, 97, 115, 109];
+        let (block, account_id) = upgrade_block(ActionView::DeployContract { code });
+        let height = block.block.header.height;
+
+        let mut tracker = UpgradeTracker::new(&storage, UpgradeConfig::default()).unwrap();
+        consume(&mut tracker, &mut storage, &block, &account_id).unwrap();
+        tracker.revert(&mut storage, height).unwrap();
+        assert_eq!(tracker.active(), &EngineVersion::default());
+
+        let tracker = UpgradeTracker::new(&storage, UpgradeConfig::default()).unwrap();
+        assert_eq!(tracker.active(), &EngineVersion::default());
+    }
+
+    #[test]
+    fn test_corrupted_upgrade_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = Storage::open(dir.path()).unwrap();
+
+        storage
+            .set_custom_data(UPGRADE_HISTORY_KEY, b"not json")
+            .unwrap();
+        let result = UpgradeTracker::new(&storage, UpgradeConfig::default());
+        assert!(matches!(result, Err(SyncError::CorruptedData(_))));
+    }
+
+    #[test]
+    fn test_standalone_engine_version_matches_dependency() {
+        let manifest =
+            std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/../Cargo.toml")).unwrap();
+        let tags: Vec<&str> = manifest
+            .lines()
+            .filter(|line| line.contains("github.com/aurora-is-near/aurora-engine.git"))
+            .filter_map(|line| line.split("tag = \"").nth(1)?.split('"').next())
+            .collect();
+        assert!(!tags.is_empty());
+        for tag in tags {
+            assert_eq!(tag, STANDALONE_ENGINE_VERSION);
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use aurora_engine_types::H256;
+use aurora_refiner_types::near_primitives::hash::CryptoHash;
+use engine_standalone_storage::{Diff, DiffValue};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::sync::SyncError;
+
+/// Configuration of the strict verification of the diffs computed by the standalone engine
+/// against the state changes observed on NEAR.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct DiffVerificationConfig {
+    /// File where every divergence is appended as a JSON line.
+    #[serde(default)]
+    pub report_path: Option<PathBuf>,
+    /// Stop consuming blocks at the first divergence.
+    #[serde(default)]
+    pub halt: bool,
+}
+
+/// State of a single key in a diff.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "kind", content = "value")]
+pub enum KeyChange {
+    /// The key is not part of the diff.
+    Unchanged,
+    /// The key is deleted.
+    Deleted,
+    /// The key is set to the given (hex-encoded) value.
+    Modified(String),
+}
+
+impl From<Option<&DiffValue>> for KeyChange {
+    fn from(value: Option<&DiffValue>) -> Self {
+        match value.map(DiffValue::value) {
+            None => Self::Unchanged,
+            Some(None) => Self::Deleted,
+            Some(Some(bytes)) => Self::Modified(hex::encode(bytes)),
+        }
+    }
+}
+
+/// A key for which the standalone engine and NEAR disagree.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct KeyDivergence {
+    pub key: String,
+    pub expected: KeyChange,
+    pub computed: KeyChange,
+}
+
+/// Entry of the divergence report.
+#[derive(Serialize, Debug)]
+struct DivergenceRecord<'a> {
+    block_height: u64,
+    block_hash: String,
+    receipt_id: H256,
+    keys: &'a [KeyDivergence],
+}
+
+/// Compares, key by key, the diff computed by the standalone engine for a receipt
+/// with the diff NEAR observed for the same receipt.
+pub struct DiffVerifier {
+    report: Option<std::fs::File>,
+    halt: bool,
+    divergences: u64,
+}
+
+impl DiffVerifier {
+    pub fn new(config: &DiffVerificationConfig) -> std::io::Result<Self> {
+        let report = config
+            .report_path
+            .as_ref()
+            .map(|path| {
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+            })
+            .transpose()?;
+
+        Ok(Self {
+            report,
+            halt: config.halt,
+            divergences: 0,
+        })
+    }
+
+    /// Checks the diff of one receipt. Divergences are counted and reported; an error is only
+    /// returned if the verifier is configured to halt or the report cannot be written.
+    pub fn verify(
+        &mut self,
+        block_height: u64,
+        block_hash: &CryptoHash,
+        receipt_id: H256,
+        expected: &Diff,
+        computed: &Diff,
+    ) -> Result<(), SyncError> {
+        let keys = compare_diffs(expected, computed);
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        self.divergences += 1;
+        tracing::warn!(
+            "Receipt {receipt_id:?} at height {block_height}: {} keys diverge from NEAR state changes",
+            keys.len(),
+        );
+
+        if let Some(report) = self.report.as_mut() {
+            let record = DivergenceRecord {
+                block_height,
+                block_hash: block_hash.to_string(),
+                receipt_id,
+                keys: &keys,
+            };
+            let mut line = serde_json::to_vec(&record).expect("Serialization to Vec cannot fail");
+            line.push(b'\n');
+            report
+                .write_all(&line)
+                .map_err(SyncError::DivergenceReport)?;
+        }
+
+        if self.halt {
+            return Err(SyncError::DiffDivergence {
+                block_height,
+                receipt_id,
+                keys,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Returns the number of receipts with a divergent diff seen since the last call.
+    pub const fn take_divergences(&mut self) -> u64 {
+        std::mem::replace(&mut self.divergences, 0)
+    }
+}
+
+/// Returns the keys that are not changed in the same way by both diffs.
+pub fn compare_diffs(expected: &Diff, computed: &Diff) -> Vec<KeyDivergence> {
+    let keys: BTreeSet<&Vec<u8>> = expected
+        .iter()
+        .chain(computed.iter())
+        .map(|(key, _)| key)
+        .collect();
+
+    keys.into_iter()
+        .filter_map(|key| {
+            let expected = KeyChange::from(expected.get(key));
+            let computed = KeyChange::from(computed.get(key));
+            (expected != computed).then(|| KeyDivergence {
+                key: hex::encode(key),
+                expected,
+                computed,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DiffVerificationConfig, DiffVerifier, KeyChange, compare_diffs};
+    use aurora_engine_types::H256;
+    use aurora_refiner_types::near_primitives::hash::CryptoHash;
+    use engine_standalone_storage::Diff;
+
+    #[test]
+    fn test_compare_diffs() {
+        let mut expected = Diff::default();
+        expected.modify(vec![1], vec![10]);
+        expected.modify(vec![2], vec![20]);
+        expected.delete(vec![3]);
+        let mut computed = Diff::default();
+        computed.modify(vec![1], vec![10]);
+        computed.modify(vec![2], vec![21]);
+        computed.modify(vec![4], vec![40]);
+
+        assert!(compare_diffs(&expected, &expected).is_empty());
+
+        let divergences = compare_diffs(&expected, &computed);
+        let summary: Vec<_> = divergences
+            .iter()
+            .map(|d| (d.key.as_str(), &d.expected, &d.computed))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "02",
+                    &KeyChange::Modified("14".into()),
+                    &KeyChange::Modified("15".into())
+                ),
+                ("03", &KeyChange::Deleted, &KeyChange::Unchanged),
+                (
+                    "04",
+                    &KeyChange::Unchanged,
+                    &KeyChange::Modified("28".into())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_divergence_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let report_path = dir.path().join("divergences.jsonl");
+        let config = DiffVerificationConfig {
+            report_path: Some(report_path.clone()),
+            halt: false,
+        };
+        let mut verifier = DiffVerifier::new(&config).unwrap();
+        let mut expected = Diff::default();
+        expected.modify(vec![1], vec![10]);
+
+        verifier
+            .verify(
+                100,
+                &CryptoHash::default(),
+                H256::zero(),
+                &expected,
+                &expected,
+            )
+            .unwrap();
+        verifier
+            .verify(
+                101,
+                &CryptoHash::default(),
+                H256::zero(),
+                &expected,
+                &Diff::default(),
+            )
+            .unwrap();
+        assert_eq!(verifier.take_divergences(), 1);
+        assert_eq!(verifier.take_divergences(), 0);
+
+        let report = std::fs::read_to_string(&report_path).unwrap();
+        let lines: Vec<serde_json::Value> = report
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["block_height"], 101);
+        assert_eq!(lines[0]["keys"][0]["key"], "01");
+        assert_eq!(lines[0]["keys"][0]["computed"]["kind"], "unchanged");
+
+        let mut verifier = DiffVerifier::new(&DiffVerificationConfig {
+            report_path: None,
+            halt: true,
+        })
+        .unwrap();
+        let result = verifier.verify(
+            102,
+            &CryptoHash::default(),
+            H256::zero(),
+            &expected,
+            &Diff::default(),
+        );
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,227 @@
+//! Start-up reconciliation of the refiner stores.
+//!
+//! The refiner writes to three independent stores: the engine storage, the transaction tracker
+//! and the output storage. They are written in that order for every block, and the output is
+//! written asynchronously, so a crash can leave the first two ahead of the output. Restarting
+//! from the last output height would then apply the same transactions to the engine twice.
+//! Before the refiner starts, the high-water mark of every store is compared and the stores
+//! that are ahead of the output are rolled back to it.
+
+use std::path::Path;
+
+use aurora_refiner_lib::tx_hash_tracker::TxHashTracker;
+use serde::Deserialize;
+
+use crate::config::EngineConfig;
+
+/// What to do when the engine storage or the transaction tracker is ahead of the output.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DivergencePolicy {
+    /// Rewind the stores that are ahead to the last block written to the output.
+    #[default]
+    Rewind,
+    /// Refuse to start and let the operator decide.
+    Halt,
+}
+
+/// Height of the last block persisted by each store. `None` means the store has no record of
+/// any block, which is the case for new stores and for stores created by older versions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StoreHeights {
+    pub engine: Option<u64>,
+    pub tx_tracker: Option<u64>,
+    pub output: Option<u64>,
+}
+
+/// Actions needed to bring all the stores to the same height.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reconciliation {
+    pub rewind_engine: bool,
+    pub rewind_tx_tracker: bool,
+    pub to_height: u64,
+}
+
+impl Reconciliation {
+    const fn is_needed(&self) -> bool {
+        self.rewind_engine || self.rewind_tx_tracker
+    }
+}
+
+/// Compares the store heights and decides which stores must be rewound. Returns an error
+/// if the stores cannot be made consistent automatically.
+pub fn plan(
+    heights: StoreHeights,
+    policy: DivergencePolicy,
+) -> anyhow::Result<Option<Reconciliation>> {
+    // Without output the refiner would start over from genesis, on top of the state of the
+    // blocks the other stores already hold.
+    let Some(output) = heights.output else {
+        if heights.engine.is_some() || heights.tx_tracker.is_some() {
+            anyhow::bail!(
+                "The output storage has no blocks, but the engine storage ({:?}) or the \
+                transaction tracker ({:?}) has. Restore the output storage, or start the refiner \
+                with `run --height` after the last block it holds.",
+                heights.engine,
+                heights.tx_tracker,
+            );
+        }
+        return Ok(None);
+    };
+
+    for (name, height) in [
+        ("engine storage", heights.engine),
+        ("transaction tracker", heights.tx_tracker),
+    ] {
+        if let Some(height) = height
+            && height < output
+        {
+            anyhow::bail!(
+                "The {name} is at height {height}, behind the output storage at height {output}. \
+                Blocks above {height} were refined with state that is no longer available. \
+                Run `rewind --to-height {height}` to refine them again, or restore the {name} \
+                from a snapshot taken at height {output} or later."
+            );
+        }
+    }
+
+    let reconciliation = Reconciliation {
+        rewind_engine: heights.engine.is_some_and(|height| height > output),
+        rewind_tx_tracker: heights.tx_tracker.is_some_and(|height| height > output),
+        to_height: output,
+    };
+
+    if !reconciliation.is_needed() {
+        return Ok(None);
+    }
+
+    if policy == DivergencePolicy::Halt {
+        anyhow::bail!(
+            "The stores are ahead of the output storage at height {output} (engine storage: {:?}, \
+            transaction tracker: {:?}), most likely because the refiner stopped before it wrote \
+            the last blocks. Run `rewind --to-height {output}` or set `on_store_divergence` to \
+            `Rewind` to roll them back automatically.",
+            heights.engine,
+            heights.tx_tracker,
+        );
+    }
+
+    Ok(Some(reconciliation))
+}
+
+/// Reads the high-water mark of every store and rewinds the ones that are ahead of the output,
+/// whose height is the resume height of the output sinks.
+pub fn reconcile<P: AsRef<Path>>(
+    config: &EngineConfig,
+    tx_tracker_path: P,
+    output: Option<u64>,
+) -> anyhow::Result<()> {
+    let heights = StoreHeights {
+        engine: aurora_refiner_lib::storage::last_block_height(&config.refiner.engine_path)?,
+        tx_tracker: TxHashTracker::last_block_height(&tx_tracker_path)?,
+        output,
+    };
+    tracing::info!("Store heights on start-up: {heights:?}");
+
+    let Some(reconciliation) = plan(heights, config.refiner.on_store_divergence)? else {
+        return Ok(());
+    };
+    let to_height = reconciliation.to_height;
+
+    if reconciliation.rewind_engine {
+        tracing::warn!("Engine storage is ahead of the output, rewinding it to {to_height}");
+        aurora_refiner_lib::storage::rewind_storage(
+            &config.refiner.engine_path,
+            config.data_id_mapping_path(),
+            to_height,
+        )?;
+    }
+    if reconciliation.rewind_tx_tracker {
+        tracing::warn!("Transaction tracker is ahead of the output, rewinding it to {to_height}");
+        let mut tx_tracker = TxHashTracker::new(&tx_tracker_path, to_height)?;
+        tx_tracker.rewind(to_height)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DivergencePolicy, Reconciliation, StoreHeights, plan};
+
+    const fn heights(
+        engine: Option<u64>,
+        tx_tracker: Option<u64>,
+        output: Option<u64>,
+    ) -> StoreHeights {
+        StoreHeights {
+            engine,
+            tx_tracker,
+            output,
+        }
+    }
+
+    #[test]
+    fn test_stores_in_sync() {
+        let result = plan(
+            heights(Some(100), Some(100), Some(100)),
+            DivergencePolicy::Rewind,
+        );
+        assert_eq!(result.unwrap(), None);
+
+        // Unknown heights are not checked
+        let result = plan(heights(None, None, Some(100)), DivergencePolicy::Halt);
+        assert_eq!(result.unwrap(), None);
+        let result = plan(heights(None, None, None), DivergencePolicy::Halt);
+        assert_eq!(result.unwrap(), None);
+    }
+
+    #[test]
+    fn test_stores_without_output() {
+        for policy in [DivergencePolicy::Rewind, DivergencePolicy::Halt] {
+            let result = plan(heights(Some(120), Some(120), None), policy);
+            let error = result.unwrap_err().to_string();
+            assert!(error.contains("output storage has no blocks"), "{error}");
+
+            let result = plan(heights(None, Some(120), None), policy);
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn test_stores_ahead_of_output() {
+        let result = plan(
+            heights(Some(105), Some(100), Some(100)),
+            DivergencePolicy::Rewind,
+        );
+        assert_eq!(
+            result.unwrap(),
+            Some(Reconciliation {
+                rewind_engine: true,
+                rewind_tx_tracker: false,
+                to_height: 100,
+            })
+        );
+
+        let result = plan(
+            heights(Some(105), Some(104), Some(100)),
+            DivergencePolicy::Halt,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_stores_behind_output() {
+        for policy in [DivergencePolicy::Rewind, DivergencePolicy::Halt] {
+            let result = plan(heights(Some(90), Some(100), Some(100)), policy);
+            let error = result.unwrap_err().to_string();
+            assert!(error.contains("engine storage is at height 90"), "{error}");
+
+            let result = plan(heights(Some(100), Some(95), Some(100)), policy);
+            let error = result.unwrap_err().to_string();
+            assert!(
+                error.contains("transaction tracker is at height 95"),
+                "{error}"
+            );
+        }
+    }
+}
@@ -1,3 +1,6 @@
+use std::num::NonZeroU64;
+use std::path::PathBuf;
+
 use clap::{Parser, Subcommand};
 
 #[derive(Parser)]
@@ -24,4 +27,29 @@ pub enum Command {
         #[clap(short, long)]
         total: Option<u64>,
     },
+    /// Roll the engine storage, the transaction tracker and the output back to a height.
+    /// Running the refiner afterwards continues from the block right after that height.
+    Rewind {
+        /// Height of the last block that is kept.
+        #[clap(long)]
+        to_height: u64,
+    },
+    /// Export a range of the output storage to Parquet files.
+    Export {
+        /// Height of the first block to export.
+        #[clap(long)]
+        from_height: u64,
+        /// Height of the last block to export.
+        #[clap(long)]
+        to_height: u64,
+        /// Folder where the Parquet tables are written.
+        #[clap(long)]
+        path: PathBuf,
+        /// [Optional] Number of block heights in each partition.
+        #[clap(long)]
+        partition_size: Option<NonZeroU64>,
+        /// [Optional] Maximum number of block heights in each file.
+        #[clap(long)]
+        file_size: Option<NonZeroU64>,
+    },
 }
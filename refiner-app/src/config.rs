@@ -1,18 +1,97 @@
 use aurora_engine_types::account_id::AccountId;
+use aurora_refiner_lib::RefinerOptions;
+use aurora_refiner_lib::filter::BlockFilter;
+use aurora_standalone_engine::upgrade::UpgradeConfig;
+use aurora_standalone_engine::verification::DiffVerificationConfig;
 use serde::de::Error;
 use serde::{Deserialize, Deserializer};
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 
+use crate::checkpoint::DivergencePolicy;
+use crate::sinks::OutputSinkConfig;
 use crate::store::OutputStoreConfig;
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct Config {
     pub refiner: Refiner,
     pub output_storage: OutputStoreConfig,
+    /// Additional outputs, written to alongside the output storage.
+    #[serde(default)]
+    pub sinks: Vec<OutputSinkConfig>,
+    /// Only keep the transactions matching this filter in the output.
+    #[serde(default)]
+    pub output_filter: Option<BlockFilter>,
+    /// Other engine accounts (silos) refined from the same input, each with its own stores.
+    #[serde(default)]
+    pub silos: Vec<EngineConfig>,
     pub input_mode: InputMode,
     pub socket_server: Option<SocketServer>,
 }
 
+impl Config {
+    /// Configuration of every engine to refine: the main one, followed by the silos.
+    pub fn engines(&self) -> anyhow::Result<Vec<EngineConfig>> {
+        let main = EngineConfig {
+            refiner: self.refiner.clone(),
+            output_storage: self.output_storage.clone(),
+            sinks: self.sinks.clone(),
+            output_filter: self.output_filter.clone(),
+        };
+        let engines: Vec<_> = std::iter::once(main)
+            .chain(self.silos.iter().cloned())
+            .collect();
+
+        // Engines sharing a store would overwrite each other's data
+        for (i, engine) in engines.iter().enumerate() {
+            for other in &engines[..i] {
+                if engine.refiner.engine_path == other.refiner.engine_path
+                    || engine.tx_tracker_path() == other.tx_tracker_path()
+                    || engine.data_id_mapping_path() == other.data_id_mapping_path()
+                    || engine.output_storage.path == other.output_storage.path
+                {
+                    anyhow::bail!(
+                        "Engines {} and {} must not share their engine, tx tracker, data id mapping or output paths",
+                        other.refiner.engine_account_id,
+                        engine.refiner.engine_account_id
+                    );
+                }
+            }
+        }
+
+        Ok(engines)
+    }
+}
+
+/// Everything needed to refine one engine account.
+#[derive(Deserialize, Clone, Debug)]
+pub struct EngineConfig {
+    pub refiner: Refiner,
+    pub output_storage: OutputStoreConfig,
+    #[serde(default)]
+    pub sinks: Vec<OutputSinkConfig>,
+    #[serde(default)]
+    pub output_filter: Option<BlockFilter>,
+}
+
+impl EngineConfig {
+    pub fn tx_tracker_path(&self) -> PathBuf {
+        self.refiner
+            .tx_tracker_path
+            .clone()
+            .unwrap_or_else(|| self.refiner.engine_path.join("tx_tracker"))
+    }
+
+    pub fn data_id_mapping_path(&self) -> PathBuf {
+        self.refiner
+            .data_id_mapping_path
+            .clone()
+            .unwrap_or_else(|| {
+                aurora_standalone_engine::data_id_mapping::default_path(&self.refiner.engine_path)
+            })
+    }
+}
+
 #[derive(Deserialize, Clone, Debug)]
 pub struct Refiner {
     pub chain_id: u64,
@@ -21,6 +100,31 @@ pub struct Refiner {
     pub engine_account_id: AccountId,
     #[serde(default)]
     pub tx_tracker_path: Option<PathBuf>,
+    /// Recompute the block hashes of an engine storage created before the chain id was stored
+    /// when they do not match the configured chain id. Only for a DB known to have been refined
+    /// for the configured chain.
+    #[serde(default)]
+    pub migrate_block_hashes: bool,
+    /// Where the NEAR data receipts (promise results) are persisted. Defaults to a folder next to
+    /// the engine storage.
+    #[serde(default)]
+    pub data_id_mapping_path: Option<PathBuf>,
+    /// What to do on start-up when the engine storage or the transaction tracker
+    /// is ahead of the output storage.
+    #[serde(default)]
+    pub on_store_divergence: DivergencePolicy,
+    /// Number of NEAR data receipts (promise results) kept in memory. The others are read from
+    /// `data_id_mapping_path`, so this only affects how fast a callback finds its data.
+    #[serde(default)]
+    pub data_id_cache_size: Option<NonZeroUsize>,
+    /// Compare the diff of every transaction with the state changes observed on NEAR, key by key.
+    #[serde(default)]
+    pub diff_verification: Option<DiffVerificationConfig>,
+    #[serde(flatten)]
+    pub options: RefinerOptions,
+    /// What to do when the engine contract is upgraded to a version not known to be compatible.
+    #[serde(default)]
+    pub engine_upgrades: UpgradeConfig,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -98,4 +202,49 @@ mod tests {
 
         assert_eq!(actual_near_account_id, expected_near_account_id);
     }
+
+    #[test]
+    fn test_silos() {
+        let silo = r#"{
+            "refiner": {
+                "chain_id": 1,
+                "engine_path": "silo/engine",
+                "engine_account_id": "silo.aurora"
+            },
+            "output_storage": {
+                "path": "silo/refiner",
+                "batch_size": 10
+            }
+        }"#;
+        let config = |silo: &str| {
+            serde_json::from_str::<Config>(&format!(
+                r#"{{
+                "refiner": {{
+                    "chain_id": 0,
+                    "engine_path": "main/engine",
+                    "engine_account_id": "aurora"
+                }},
+                "output_storage": {{
+                    "path": "main/refiner",
+                    "batch_size": 10
+                }},
+                "input_mode": {{ "DataLake": {{ "network": "Mainnet" }} }},
+                "silos": [{silo}]
+            }}"#
+            ))
+            .unwrap()
+        };
+
+        let engines = config(silo).engines().unwrap();
+        assert_eq!(engines.len(), 2);
+        assert_eq!(engines[0].refiner.engine_account_id.as_str(), "aurora");
+        assert_eq!(engines[1].refiner.engine_account_id.as_str(), "silo.aurora");
+        assert_eq!(
+            engines[1].tx_tracker_path(),
+            PathBuf::from("silo/engine/tx_tracker")
+        );
+
+        let shared_output = silo.replace("silo/refiner", "main/refiner");
+        assert!(config(&shared_output).engines().is_err());
+    }
 }
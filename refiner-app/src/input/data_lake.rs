@@ -1,70 +1,116 @@
-use aurora_refiner_lib::BlockWithMetadata;
+use async_trait::async_trait;
+use aurora_refiner_lib::source::BlockSource;
 use aurora_refiner_types::near_block::NEARBlock;
 use near_lake_framework::{LakeBuilder, near_lake_primitives};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use crate::config::DataLakeConfig;
 
-/// Spawns a task that reads blocks from the NEAR Data Lake stream and sends them to the channel.
-/// Returns a channel to send NEAR blocks to the task and a handle to the task.
-pub fn get_near_data_lake_stream(
-    block_height: u64,
-    config: &DataLakeConfig,
-) -> (
-    tokio::sync::mpsc::Receiver<BlockWithMetadata<NEARBlock, ()>>,
-    tokio::task::JoinHandle<()>,
-) {
-    tracing::info!(
-        "get_near_data_lake_stream: starting data lake stream, block_height: {block_height:?}..."
-    );
-
-    let lake = match config.network {
-        crate::config::Network::Mainnet => LakeBuilder::default().mainnet(),
-        crate::config::Network::Testnet => LakeBuilder::default().testnet(),
+/// Block source reading blocks from the NEAR Data Lake.
+pub struct DataLakeSource {
+    config: DataLakeConfig,
+    receiver: Option<tokio::sync::mpsc::Receiver<NEARBlock>>,
+    task: Option<tokio::task::JoinHandle<()>>,
+    /// Height of the latest block streamed from the lake, zero until the first one.
+    head_height: Arc<AtomicU64>,
+}
+
+impl DataLakeSource {
+    pub fn new(config: DataLakeConfig) -> Self {
+        Self {
+            config,
+            receiver: None,
+            task: None,
+            head_height: Arc::default(),
+        }
     }
-    .start_block_height(block_height)
-    .build()
-    .expect("Failed to build Lake");
+}
 
-    let (sender, receiver) = tokio::sync::mpsc::channel(1000);
+#[async_trait]
+impl BlockSource for DataLakeSource {
+    async fn start(&mut self, height: u64) -> anyhow::Result<()> {
+        tracing::info!("DataLakeSource: starting data lake stream, block_height: {height}...");
 
-    let task_handle = tokio::spawn(async move {
-        tracing::info!("get_near_data_lake_stream: data lake stream started");
+        let lake = match self.config.network {
+            crate::config::Network::Mainnet => LakeBuilder::default().mainnet(),
+            crate::config::Network::Testnet => LakeBuilder::default().testnet(),
+        }
+        .start_block_height(height)
+        .build()
+        .map_err(|err| anyhow::anyhow!("Failed to build Lake: {err:?}"))?;
 
-        let context = DataLakeContext { sender };
+        let (sender, receiver) = tokio::sync::mpsc::channel(1000);
+        let head_height = self.head_height.clone();
 
-        if let Err(err) = lake
-            .run_with_context_async(
-                |block, context: &DataLakeContext| {
-                    let sender = context.sender.clone();
-                    async move {
-                        let block_with_meta = BlockWithMetadata::new(
-                            aurora_refiner_types::conversion::data_lake::convert(
+        let task_handle = tokio::spawn(async move {
+            tracing::info!("DataLakeSource: data lake stream started");
+
+            // The blocks are read ahead of the refiner, up to the capacity of the channel
+            let context = DataLakeContext {
+                sender,
+                head_height,
+            };
+
+            if let Err(err) = lake
+                .run_with_context_async(
+                    |block, context: &DataLakeContext| {
+                        let sender = context.sender.clone();
+                        let head_height = context.head_height.clone();
+                        async move {
+                            let block = aurora_refiner_types::conversion::data_lake::convert(
                                 block.streamer_message().clone(),
-                            ),
-                            (),
-                        );
+                            );
+                            head_height.fetch_max(block.block.header.height, Ordering::Relaxed);
 
-                        if sender.send(block_with_meta).await.is_err() {
-                            tracing::warn!("Receiver dropped, stopping data lake stream");
-                            return Err("Channel closed".into());
+                            if sender.send(block).await.is_err() {
+                                tracing::warn!("Receiver dropped, stopping data lake stream");
+                                return Err("Channel closed".into());
+                            }
+
+                            Ok::<(), Box<dyn std::error::Error>>(())
                         }
+                    },
+                    &context,
+                )
+                .await
+            {
+                tracing::error!("DataLakeSource: data lake stream failed: {err}");
+            }
+        });
 
-                        Ok::<(), Box<dyn std::error::Error>>(())
-                    }
-                },
-                &context,
-            )
-            .await
-        {
-            tracing::error!("get_near_data_lake_stream: data lake stream failed: {err}");
-        }
-    });
+        self.receiver = Some(receiver);
+        self.task = Some(task_handle);
 
-    (receiver, task_handle)
+        Ok(())
+    }
+
+    async fn next_block(&mut self) -> anyhow::Result<Option<NEARBlock>> {
+        let receiver = self
+            .receiver
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Data lake stream is not started"))?;
+        Ok(receiver.recv().await)
+    }
+
+    async fn head_height(&mut self) -> Option<u64> {
+        Some(self.head_height.load(Ordering::Relaxed)).filter(|height| *height > 0)
+    }
+
+    async fn shutdown(&mut self) -> anyhow::Result<()> {
+        // Dropping the receiver makes the data lake stream stop at the next block
+        self.receiver = None;
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        tracing::info!("DataLakeSource: data lake stream stopped");
+        Ok(())
+    }
 }
 
 struct DataLakeContext {
-    sender: tokio::sync::mpsc::Sender<BlockWithMetadata<NEARBlock, ()>>,
+    sender: tokio::sync::mpsc::Sender<NEARBlock>,
+    head_height: Arc<AtomicU64>,
 }
 
 impl near_lake_framework::LakeContextExt for DataLakeContext {
@@ -1,58 +1,92 @@
-use aurora_refiner_lib::BlockWithMetadata;
+use async_trait::async_trait;
+use aurora_refiner_lib::source::BlockSource;
 use aurora_refiner_types::near_block::NEARBlock;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use crate::config::NearcoreConfig;
 
-pub async fn get_nearcore_stream(
-    block_height: u64,
-    config: &NearcoreConfig,
-    mut shutdown_rx: tokio::sync::broadcast::Receiver<()>,
-) -> anyhow::Result<(
-    tokio::sync::mpsc::Receiver<BlockWithMetadata<NEARBlock, ()>>,
-    tokio::task::JoinHandle<()>,
-)> {
-    tracing::info!(
-        "get_nearcore_stream: starting nearcore stream, block_height: {block_height:?}..."
-    );
-
-    let (sender, receiver) = tokio::sync::mpsc::channel(1000);
-
-    let indexer_config = near_indexer::IndexerConfig {
-        home_dir: std::path::PathBuf::from(&config.path),
-        sync_mode: near_indexer::SyncModeEnum::BlockHeight(block_height),
-        await_for_node_synced: near_indexer::AwaitForNodeSyncedEnum::StreamWhileSyncing,
-        finality: near_indexer::near_primitives::types::Finality::Final,
-        validate_genesis: true,
-    };
-    let indexer = near_indexer::Indexer::new(indexer_config).await?;
-    tracing::info!("get_nearcore_stream: nearcore indexer started");
-
-    let task_handle = tokio::spawn(async move {
-        // Regular NEAR indexer process starts here
+/// Block source reading blocks from a nearcore node running in the same process.
+pub struct NearcoreSource {
+    config: NearcoreConfig,
+    indexer: Option<near_indexer::Indexer>,
+    receiver: Option<tokio::sync::mpsc::Receiver<NEARBlock>>,
+    task: Option<tokio::task::JoinHandle<()>>,
+    /// Height of the latest block streamed by the node, zero until the first one.
+    head_height: Arc<AtomicU64>,
+}
+
+impl NearcoreSource {
+    pub fn new(config: NearcoreConfig) -> Self {
+        Self {
+            config,
+            indexer: None,
+            receiver: None,
+            task: None,
+            head_height: Arc::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl BlockSource for NearcoreSource {
+    async fn start(&mut self, height: u64) -> anyhow::Result<()> {
+        tracing::info!("NearcoreSource: starting nearcore stream, block_height: {height}...");
+
+        let indexer_config = near_indexer::IndexerConfig {
+            home_dir: self.config.path.clone(),
+            sync_mode: near_indexer::SyncModeEnum::BlockHeight(height),
+            await_for_node_synced: near_indexer::AwaitForNodeSyncedEnum::StreamWhileSyncing,
+            finality: near_indexer::near_primitives::types::Finality::Final,
+            validate_genesis: true,
+        };
+        let indexer = near_indexer::Indexer::new(indexer_config).await?;
+        tracing::info!("NearcoreSource: nearcore indexer started");
+
+        // Regular NEAR indexer process starts here. The blocks are read ahead of the refiner
+        // to know the height of the latest block streamed by the node.
         let mut stream = indexer.streamer();
-        tracing::info!("get_nearcore_stream: nearcore stream started");
-        loop {
-            tokio::select! {
-                Some(block) = stream.recv() => {
-                    sender
-                        .send(BlockWithMetadata::new(
-                            aurora_refiner_types::conversion::nearcore::convert(block),
-                            ())
-                        )
-                        .await
-                        .expect("Failed to send block to the channel from nearcore stream");
-                }
-                _ = shutdown_rx.recv() => {
-                    // Explicitly close the channel, so the tx side should stop sending blocks
-                    stream.close();
-                    tracing::info!("get_nearcore_stream: Received shutdown signal");
+        let (sender, receiver) = tokio::sync::mpsc::channel(1000);
+        let head_height = self.head_height.clone();
+        let task_handle = tokio::spawn(async move {
+            while let Some(message) = stream.recv().await {
+                head_height.fetch_max(message.block.header.height, Ordering::Relaxed);
+                let block = aurora_refiner_types::conversion::nearcore::convert(message);
+                if sender.send(block).await.is_err() {
+                    tracing::warn!("Receiver dropped, stopping nearcore stream");
                     break;
                 }
             }
-        }
-    });
+        });
+
+        self.receiver = Some(receiver);
+        self.task = Some(task_handle);
+        self.indexer = Some(indexer);
+        tracing::info!("NearcoreSource: nearcore stream started");
 
-    tracing::info!("get_nearcore_stream: nearcore stream finished");
+        Ok(())
+    }
 
-    Ok((receiver, task_handle))
+    async fn next_block(&mut self) -> anyhow::Result<Option<NEARBlock>> {
+        let receiver = self
+            .receiver
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Nearcore stream is not started"))?;
+        Ok(receiver.recv().await)
+    }
+
+    async fn head_height(&mut self) -> Option<u64> {
+        Some(self.head_height.load(Ordering::Relaxed)).filter(|height| *height > 0)
+    }
+
+    async fn shutdown(&mut self) -> anyhow::Result<()> {
+        self.receiver = None;
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        // Dropping the indexer releases the node it runs
+        self.indexer = None;
+        tracing::info!("NearcoreSource: nearcore stream stopped");
+        Ok(())
+    }
 }
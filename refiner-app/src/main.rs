@@ -1,20 +1,24 @@
+mod checkpoint;
 mod cli;
 mod config;
 mod conversion;
 mod input;
+mod sinks;
 mod socket;
 mod store;
 use anyhow::anyhow;
-use std::{
-    fs,
-    path::{Path, PathBuf},
-};
+use std::{fs, path::Path};
 
 use clap::Parser;
 use cli::Cli;
 
-use aurora_refiner_lib::signal_handlers;
-use store::{get_output_stream, load_last_block_height};
+use aurora_refiner_lib::sink::{self, BlockSink, SinkConfig};
+use aurora_refiner_lib::source::{self, SourceHandle};
+use aurora_refiner_lib::{BlockWithMetadata, RunRefinerError, signal_handlers};
+use aurora_refiner_types::aurora_block::AuroraBlock;
+use aurora_standalone_engine::EngineContext;
+use aurora_standalone_engine::verification::DiffVerifier;
+use store::{FileSink, load_last_block_height};
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
 fn setup_logs() {
@@ -42,6 +46,21 @@ async fn main() -> anyhow::Result<()> {
 
     match args.command {
         cli::Command::Run { height, total } => run_refiner_app(height, total, &config).await?,
+        cli::Command::Rewind { to_height } => rewind_refiner_app(to_height, &config).await?,
+        cli::Command::Export {
+            from_height,
+            to_height,
+            path,
+            partition_size,
+            file_size,
+        } => {
+            let parquet_config = sinks::parquet::ParquetSinkConfig {
+                path,
+                partition_size,
+                file_size,
+            };
+            export_parquet(from_height, to_height, parquet_config, &config).await?;
+        }
     }
 
     tracing::info!("refiner-app finished");
@@ -53,41 +72,204 @@ async fn run_refiner_app(
     total: Option<u64>,
     config: &config::Config,
 ) -> anyhow::Result<()> {
-    let engine_account_id = config.refiner.engine_account_id.clone();
-
-    // Load last block
-    let (last_block, next_block) = if let Some(height) = height {
-        (height.checked_sub(1), height)
-    } else {
-        let last_block = load_last_block_height(&config.output_storage.path).await;
-        let next_block = last_block.map(|x| x + 1).unwrap_or(0);
-        (last_block, next_block)
-    };
-
     // Broadcast shutdown channel
-    let (shutdown_tx, mut shutdown_rx_refiner) = tokio::sync::broadcast::channel(16);
+    let (shutdown_tx, _) = tokio::sync::broadcast::channel(16);
     let shutdown_rx_input_stream = shutdown_tx.subscribe();
-    let shutdown_rx_output_stream = shutdown_tx.subscribe();
     let mut shutdown_rx_socket = shutdown_tx.subscribe();
 
+    let mut refiners = Vec::new();
+    for engine_config in config.engines()? {
+        let refiner = prepare_engine(engine_config, height, total, shutdown_tx.subscribe()).await?;
+        refiners.push(refiner);
+    }
+
+    // The input starts at the first block needed by any of the engines
+    let next_block = refiners
+        .iter()
+        .map(|refiner| refiner.next_block)
+        .min()
+        .unwrap_or_default();
+
     // Build input stream
-    let (input_stream, task_input_stream) = match &config.input_mode {
+    let SourceHandle {
+        blocks: input_stream,
+        task: task_input_stream,
+        ..
+    } = match &config.input_mode {
         config::InputMode::DataLake(config) => {
-            input::data_lake::get_near_data_lake_stream(next_block, config)
+            let data_lake = input::data_lake::DataLakeSource::new(config.clone());
+            source::spawn_source(data_lake, next_block, shutdown_rx_input_stream).await?
         }
         config::InputMode::Nearcore(config) => {
-            input::nearcore::get_nearcore_stream(next_block, config, shutdown_rx_input_stream)
-                .await?
+            let nearcore = input::nearcore::NearcoreSource::new(config.clone());
+            source::spawn_source(nearcore, next_block, shutdown_rx_input_stream).await?
         }
     };
 
-    // Build output stream
-    let (output_stream, task_output_stream) = get_output_stream(
-        total,
-        config.output_storage.clone(),
-        shutdown_rx_output_stream,
+    // Every engine refines the same blocks, converted once
+    let (inputs, task_fan_out) = source::fan_out(input_stream, refiners.len());
+
+    // The socket server serves the main engine
+    let socket_storage = refiners[0].ctx.storage.clone();
+
+    let refiner_futures: Vec<_> = refiners
+        .into_iter()
+        .zip(inputs)
+        .map(|(refiner, input)| {
+            let mut shutdown_rx_refiner = shutdown_tx.subscribe();
+            let refiner_shutdown_tx = shutdown_tx.clone();
+            async move {
+                let EngineRefiner {
+                    config,
+                    ctx,
+                    last_block,
+                    output_stream,
+                    task_output_stream,
+                    ..
+                } = refiner;
+                let tx_tracker_path = config.tx_tracker_path();
+                let result = aurora_refiner_lib::run_refiner::<&Path, ()>(
+                    ctx,
+                    tx_tracker_path.as_ref(),
+                    input,
+                    output_stream,
+                    last_block,
+                    &config.refiner.options,
+                    &mut shutdown_rx_refiner,
+                )
+                .await;
+                // Stop the other tasks, nothing is refined anymore
+                let _ = refiner_shutdown_tx.send(());
+                let output_result = task_output_stream.await;
+                (config.refiner.engine_account_id, result, output_result)
+            }
+        })
+        .collect();
+
+    // The signal handlers only return when a signal is received, so they run in their own task
+    // which is aborted if the refiner stops on its own.
+    let task_signals = tokio::spawn(signal_handlers::handle_all_signals(shutdown_tx));
+
+    let (input_result, fan_out_result, _, refiner_results) = tokio::join!(
+        // Wait for input stream to finish
+        task_input_stream,
+        // Wait for the input to be dispatched to every engine
+        task_fan_out,
+        // Run socket server
+        async {
+            if let Some(socket_config) = &config.socket_server {
+                socket::start_socket_server(
+                    socket_storage,
+                    Path::new(&socket_config.path),
+                    &mut shutdown_rx_socket,
+                )
+                .await
+            }
+        },
+        // Run Refiners, and wait for their output streams to finish
+        futures::future::join_all(refiner_futures),
     );
 
+    if task_signals.is_finished() {
+        match task_signals.await {
+            Ok(Err(err)) => tracing::error!("Signal handler failed: {:?}", err),
+            Err(err) => tracing::error!("Signal handler task failed: {:?}", err),
+            Ok(Ok(())) => {}
+        }
+    } else {
+        task_signals.abort();
+    }
+    match input_result {
+        Ok(Err(err)) => tracing::error!("Input stream failed: {:?}", err),
+        Err(err) => tracing::error!("Input stream task failed: {:?}", err),
+        Ok(Ok(())) => {}
+    }
+
+    // Without the fan-out, the engines stop early on a partial input
+    let mut result = match fan_out_result {
+        Err(err) => {
+            tracing::error!("Fan-out task failed: {:?}", err);
+            Err(anyhow!("Fan-out task failed: {err}"))
+        }
+        Ok(()) => Ok(()),
+    };
+    for (engine_account_id, refiner_result, output_result) in refiner_results {
+        // A sink failure closes the output, which the refiner may report as a normal stop
+        let output_error = match output_result {
+            Ok(Err(err)) => {
+                tracing::error!("Output stream of {engine_account_id} failed: {:?}", err);
+                Some(anyhow!(
+                    "Output stream of {engine_account_id} failed: {err}"
+                ))
+            }
+            Err(err) => {
+                tracing::error!(
+                    "Output stream task of {engine_account_id} failed: {:?}",
+                    err
+                );
+                Some(anyhow!(
+                    "Output stream task of {engine_account_id} failed: {err}"
+                ))
+            }
+            Ok(Ok(())) => None,
+        };
+        if let Some(err) = output_error
+            && result.is_ok()
+        {
+            result = Err(err);
+        }
+
+        match refiner_result {
+            // The output stream stops by itself once `total` blocks are stored
+            Err(RunRefinerError::OutputClosed { height }) if total.is_some() => {
+                tracing::info!(
+                    "Output stream of {engine_account_id} finished, block {height} was not stored"
+                );
+            }
+            Err(err) => {
+                tracing::error!("Refiner of {engine_account_id} failed: {err}");
+                if result.is_ok() {
+                    result = Err(anyhow!("Refiner of {engine_account_id} failed: {err}"));
+                }
+            }
+            Ok(()) => {}
+        }
+    }
+
+    result
+}
+
+/// An engine ready to be refined, with its output stream running.
+struct EngineRefiner {
+    config: config::EngineConfig,
+    ctx: EngineContext,
+    last_block: Option<u64>,
+    next_block: u64,
+    output_stream: tokio::sync::mpsc::Sender<BlockWithMetadata<AuroraBlock, ()>>,
+    task_output_stream: tokio::task::JoinHandle<anyhow::Result<()>>,
+}
+
+/// Opens the stores of an engine and starts its output stream.
+async fn prepare_engine(
+    config: config::EngineConfig,
+    height: Option<u64>,
+    total: Option<u64>,
+    shutdown_rx_output_stream: tokio::sync::broadcast::Receiver<()>,
+) -> anyhow::Result<EngineRefiner> {
+    let engine_account_id = config.refiner.engine_account_id.clone();
+
+    // Build output sinks, the refiner resumes after the last block stored by all of them
+    let sinks = build_sinks(&config, &shutdown_rx_output_stream).await?;
+    let output_height = sink::last_committed_height(&sinks).await?;
+
+    // Load last block
+    let (last_block, next_block) = if let Some(height) = height {
+        (height.checked_sub(1), height)
+    } else {
+        let next_block = output_height.map(|x| x + 1).unwrap_or(0);
+        (output_height, next_block)
+    };
+
     // Init storage
     let engine_path = Path::new(&config.refiner.engine_path);
 
@@ -98,63 +280,144 @@ async fn run_refiner_app(
         engine_path,
         &engine_account_id,
         config.refiner.chain_id,
+        config.refiner.migrate_block_hashes,
     );
+    // A block interrupted midway is left partially applied, it must be processed again from scratch
+    aurora_refiner_lib::storage::discard_unfinished_blocks(
+        engine_path,
+        config.data_id_mapping_path(),
+    )?;
 
-    let tx_tracker_path = config
-        .refiner
-        .tx_tracker_path
-        .as_ref()
-        .map(PathBuf::from)
-        .unwrap_or_else(|| engine_path.join("tx_tracker"));
+    // Bring the stores back in sync in case the refiner stopped in the middle of a block.
+    // When the start height is given explicitly the operator is in charge of consistency.
+    if height.is_none() {
+        checkpoint::reconcile(&config, config.tx_tracker_path(), output_height)?;
+    }
 
-    let ctx = aurora_standalone_engine::EngineContext::new(
+    let ctx = EngineContext::new_with_data_id_mapping(
         engine_path,
         engine_account_id.clone(),
         config.refiner.chain_id,
+        config.data_id_mapping_path(),
+        config
+            .refiner
+            .data_id_cache_size
+            .unwrap_or(aurora_standalone_engine::history::DEFAULT_DATA_ID_CACHE_SIZE),
     )
-    .map_err(|err| anyhow!("Failed to create engine context: {err:?}"))?;
+    .map_err(|err| anyhow!("Failed to create engine context for {engine_account_id}: {err}"))?;
+    let ctx = match &config.refiner.diff_verification {
+        Some(verification_config) => {
+            tracing::info!("Diff verification enabled: {verification_config:?}");
+            ctx.with_diff_verifier(DiffVerifier::new(verification_config)?)
+        }
+        None => ctx,
+    };
+    let ctx = ctx.with_upgrade_config(config.refiner.engine_upgrades.clone());
 
-    let socket_storage = ctx.storage.clone();
+    // Build output stream, once nothing can fail anymore so that no sink is left running
+    let (output_stream, task_output_stream) = sink::spawn_sinks(
+        sinks,
+        config.output_filter.clone(),
+        total,
+        shutdown_rx_output_stream,
+    );
 
-    let (signals_result, input_result, output_result, ..) = tokio::join!(
-        // Handle all signals
-        signal_handlers::handle_all_signals(shutdown_tx),
-        // Wait for input stream to finish
-        task_input_stream,
-        // Wait for output stream to finish
+    Ok(EngineRefiner {
+        config,
+        ctx,
+        last_block,
+        next_block,
+        output_stream,
         task_output_stream,
-        // Run socket server
-        async {
-            if let Some(socket_config) = &config.socket_server {
-                socket::start_socket_server(
-                    socket_storage,
-                    Path::new(&socket_config.path),
-                    &mut shutdown_rx_socket,
-                )
-                .await
-            }
-        },
-        // Run Refiner
-        aurora_refiner_lib::run_refiner::<&Path, ()>(
-            ctx,
-            config.refiner.chain_id,
-            tx_tracker_path.as_ref(),
-            input_stream,
-            output_stream,
-            last_block,
-            &mut shutdown_rx_refiner,
-        ),
-    );
+    })
+}
 
-    if let Err(err) = signals_result {
-        tracing::error!("Signal handler failed: {:?}", err);
+async fn rewind_refiner_app(to_height: u64, config: &config::Config) -> anyhow::Result<()> {
+    let engines = config.engines()?;
+    for engine_config in &engines {
+        if let Some(last_block) = load_last_block_height(&engine_config.output_storage.path).await?
+            && last_block < to_height
+        {
+            return Err(anyhow!(
+                "Cannot rewind {} to height {to_height}, the last refined block is {last_block}",
+                engine_config.refiner.engine_account_id
+            ));
+        }
     }
-    if let Err(err) = input_result {
-        tracing::error!("Input stream failed: {:?}", err);
+
+    for engine_config in &engines {
+        let engine_account_id = &engine_config.refiner.engine_account_id;
+        tracing::info!("Rewinding refiner of {engine_account_id} to height {to_height}");
+
+        aurora_refiner_lib::storage::rewind_storage(
+            &engine_config.refiner.engine_path,
+            engine_config.data_id_mapping_path(),
+            to_height,
+        )?;
+
+        let mut tx_tracker = aurora_refiner_lib::tx_hash_tracker::TxHashTracker::new(
+            engine_config.tx_tracker_path(),
+            to_height,
+        )?;
+        tx_tracker.rewind(to_height)?;
+        drop(tx_tracker);
+
+        store::rewind(&engine_config.output_storage, to_height).await?;
+        for sink_config in &engine_config.sinks {
+            sink_config.rewind(to_height).await?;
+        }
     }
-    if let Err(err) = output_result {
-        tracing::error!("Output stream failed: {:?}", err);
+
+    Ok(())
+}
+
+async fn export_parquet(
+    from_height: u64,
+    to_height: u64,
+    parquet_config: sinks::parquet::ParquetSinkConfig,
+    config: &config::Config,
+) -> anyhow::Result<()> {
+    tracing::info!(
+        "Exporting blocks {from_height} to {to_height} to {}",
+        parquet_config.path.display()
+    );
+
+    let mut parquet_sink = sinks::parquet::ParquetSink::open(&parquet_config)?;
+    let mut reader = store::BlockReader::new(&config.output_storage);
+    for height in from_height..=to_height {
+        let block = reader
+            .read(height)
+            .await?
+            .ok_or_else(|| anyhow!("Block {height} is missing from the output storage"))?;
+        parquet_sink.write_block(&block).await?;
     }
+    parquet_sink.flush().await?;
 
     Ok(())
 }
+
+/// Number of refined blocks that can be queued for each sink.
+const SINK_BUFFER_SIZE: usize = 1000;
+
+async fn build_sinks(
+    config: &config::EngineConfig,
+    shutdown_rx: &tokio::sync::broadcast::Receiver<()>,
+) -> anyhow::Result<Vec<SinkConfig>> {
+    let file_sink = FileSink::new(config.output_storage.clone());
+    let stored_height = file_sink.stored_height();
+    let mut sinks = vec![SinkConfig {
+        name: "file".to_string(),
+        sink: Box::new(file_sink),
+        buffer_size: SINK_BUFFER_SIZE,
+    }];
+    for sink_config in &config.sinks {
+        sinks.push(SinkConfig {
+            name: sink_config.name().to_string(),
+            sink: sink_config
+                .build(&config.output_storage, &stored_height, shutdown_rx)
+                .await?,
+            buffer_size: SINK_BUFFER_SIZE,
+        });
+    }
+    Ok(sinks)
+}
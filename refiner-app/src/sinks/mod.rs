@@ -0,0 +1,64 @@
+//! Optional output sinks, written to in addition to the output storage.
+
+use aurora_refiner_lib::sink::BlockSink;
+use serde::Deserialize;
+use tokio::sync::{broadcast, watch};
+
+use crate::store::OutputStoreConfig;
+
+pub mod parquet;
+pub mod sqlite;
+pub mod stream;
+pub mod webhook;
+
+#[derive(Deserialize, Clone, Debug)]
+pub enum OutputSinkConfig {
+    Sqlite(sqlite::SqliteSinkConfig),
+    Parquet(parquet::ParquetSinkConfig),
+    Stream(stream::StreamSinkConfig),
+    Webhook(webhook::WebhookSinkConfig),
+}
+
+impl OutputSinkConfig {
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Sqlite(_) => "sqlite",
+            Self::Parquet(_) => "parquet",
+            Self::Stream(_) => "stream",
+            Self::Webhook(_) => "webhook",
+        }
+    }
+
+    /// Opens the sink. `output_storage` is where the blocks already refined are stored, and
+    /// `stored_height` follows the height of the last block stored there. `shutdown_rx` fires
+    /// when the refiner is asked to stop.
+    pub async fn build(
+        &self,
+        output_storage: &OutputStoreConfig,
+        stored_height: &watch::Receiver<Option<u64>>,
+        shutdown_rx: &broadcast::Receiver<()>,
+    ) -> anyhow::Result<Box<dyn BlockSink>> {
+        Ok(match self {
+            Self::Sqlite(config) => Box::new(sqlite::SqliteSink::open(config)?),
+            Self::Parquet(config) => Box::new(parquet::ParquetSink::open(config)?),
+            Self::Stream(config) => Box::new(
+                stream::StreamSink::start(config, output_storage, stored_height.clone()).await?,
+            ),
+            Self::Webhook(config) => Box::new(webhook::WebhookSink::new(
+                config,
+                shutdown_rx.resubscribe(),
+            )?),
+        })
+    }
+
+    /// Removes every block above `height` from the sink.
+    pub async fn rewind(&self, height: u64) -> anyhow::Result<()> {
+        match self {
+            Self::Sqlite(config) => sqlite::SqliteSink::open(config)?.rewind(height).await,
+            Self::Parquet(config) => parquet::ParquetSink::open(config)?.rewind(height).await,
+            // The stream does not keep any block
+            Self::Stream(_) => Ok(()),
+            Self::Webhook(config) => webhook::rewind(config, height).await,
+        }
+    }
+}
@@ -0,0 +1,780 @@
+//! Output sink writing the refined blocks, transactions and logs to Parquet files, for analytics.
+//!
+//! Every table is stored in its own folder, partitioned by block height:
+//! `<path>/<table>/<partition start>/<first height>-<last height>.parquet`. A file is written
+//! once it spans `file_size` block heights, once the blocks of a partition are complete, or when
+//! the refiner stops, so a partition can hold several files. Files are never modified afterwards,
+//! except when rewinding.
+//!
+//! For each file range the `transactions` and `logs` files are written before the `blocks` one,
+//! so the `blocks` table defines which blocks are stored. At most `file_size` blocks are buffered
+//! in memory. They are lost if the refiner crashes; the sink only reports the blocks written to a
+//! file, so the refiner resumes from there.
+//!
+//! Hashes and addresses are stored as fixed size binary, U256 values as decimal strings.
+
+use std::fs::File;
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use arrow_array::builder::FixedSizeBinaryBuilder;
+use arrow_array::{
+    ArrayRef, BinaryArray, BooleanArray, RecordBatch, RecordBatchReader, StringArray, UInt8Array,
+    UInt32Array, UInt64Array,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use async_trait::async_trait;
+use aurora_engine_types::U256;
+use aurora_refiner_lib::sink::BlockSink;
+use aurora_refiner_types::aurora_block::{AuroraBlock, NearBlock};
+use parquet::arrow::ArrowWriter;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use serde::Deserialize;
+
+pub const DEFAULT_PARTITION_SIZE: NonZeroU64 = NonZeroU64::new(10_000).unwrap();
+pub const DEFAULT_FILE_SIZE: NonZeroU64 = NonZeroU64::new(100).unwrap();
+
+const EXTENSION: &str = "parquet";
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ParquetSinkConfig {
+    /// Folder where the tables are written.
+    pub path: PathBuf,
+    /// Number of block heights in each partition.
+    #[serde(default)]
+    pub partition_size: Option<NonZeroU64>,
+    /// Maximum number of block heights in each file.
+    #[serde(default)]
+    pub file_size: Option<NonZeroU64>,
+}
+
+/// Tables written by the sink, in the order their files are written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Table {
+    Transactions,
+    Logs,
+    Blocks,
+}
+
+impl Table {
+    const ALL: [Self; 3] = [Self::Transactions, Self::Logs, Self::Blocks];
+
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Transactions => "transactions",
+            Self::Logs => "logs",
+            Self::Blocks => "blocks",
+        }
+    }
+
+    /// Column holding the height of the block of each row.
+    const fn height_column(self) -> &'static str {
+        match self {
+            Self::Blocks => "height",
+            Self::Transactions | Self::Logs => "block_height",
+        }
+    }
+
+    fn schema(self) -> SchemaRef {
+        let hash = DataType::FixedSizeBinary(32);
+        let address = DataType::FixedSizeBinary(20);
+        let fields = match self {
+            Self::Blocks => vec![
+                Field::new("height", DataType::UInt64, false),
+                Field::new("hash", hash.clone(), false),
+                Field::new("parent_hash", hash.clone(), false),
+                Field::new("chain_id", DataType::UInt64, false),
+                Field::new("timestamp", DataType::UInt64, false),
+                Field::new("gas_used", DataType::UInt64, false),
+                Field::new("size", DataType::UInt64, false),
+                Field::new("miner", address, false),
+                Field::new("transactions_root", hash.clone(), false),
+                Field::new("state_root", hash.clone(), false),
+                Field::new("receipts_root", hash.clone(), false),
+                Field::new("logs_bloom", DataType::FixedSizeBinary(256), false),
+                Field::new("transaction_count", DataType::UInt32, false),
+                Field::new("near_hash", hash.clone(), true),
+                Field::new("near_parent_hash", hash, true),
+                Field::new("near_author", DataType::Utf8, true),
+            ],
+            Self::Transactions => vec![
+                Field::new("block_height", DataType::UInt64, false),
+                Field::new("transaction_index", DataType::UInt32, false),
+                Field::new("hash", hash.clone(), false),
+                Field::new("block_hash", hash.clone(), false),
+                Field::new("chain_id", DataType::UInt64, false),
+                Field::new("from", address.clone(), false),
+                Field::new("to", address.clone(), true),
+                Field::new("contract_address", address, true),
+                Field::new("nonce", DataType::UInt64, false),
+                Field::new("gas_price", DataType::Utf8, false),
+                Field::new("effective_gas_price", DataType::Utf8, false),
+                Field::new("gas_limit", DataType::UInt64, false),
+                Field::new("gas_used", DataType::UInt64, false),
+                Field::new("max_priority_fee_per_gas", DataType::Utf8, false),
+                Field::new("max_fee_per_gas", DataType::Utf8, false),
+                Field::new("value", DataType::Utf8, false),
+                Field::new("input", DataType::Binary, false),
+                Field::new("output", DataType::Binary, false),
+                Field::new("tx_type", DataType::UInt8, false),
+                Field::new("status", DataType::Boolean, false),
+                Field::new("v", DataType::UInt64, false),
+                Field::new("r", DataType::Utf8, false),
+                Field::new("s", DataType::Utf8, false),
+                Field::new("near_receipt_id", hash.clone(), false),
+                Field::new("near_transaction_hash", hash, true),
+                Field::new("near_action_index", DataType::UInt64, false),
+            ],
+            Self::Logs => vec![
+                Field::new("block_height", DataType::UInt64, false),
+                Field::new("transaction_index", DataType::UInt32, false),
+                Field::new("log_index", DataType::UInt32, false),
+                Field::new("address", address, false),
+                Field::new("topic0", hash.clone(), true),
+                Field::new("topic1", hash.clone(), true),
+                Field::new("topic2", hash.clone(), true),
+                Field::new("topic3", hash, true),
+                Field::new("data", DataType::Binary, false),
+            ],
+        };
+        Arc::new(Schema::new(fields))
+    }
+}
+
+struct BlockRow {
+    height: u64,
+    hash: [u8; 32],
+    parent_hash: [u8; 32],
+    chain_id: u64,
+    timestamp: u64,
+    gas_used: u64,
+    size: u64,
+    miner: [u8; 20],
+    transactions_root: [u8; 32],
+    state_root: [u8; 32],
+    receipts_root: [u8; 32],
+    logs_bloom: [u8; 256],
+    transaction_count: u32,
+    near_hash: Option<[u8; 32]>,
+    near_parent_hash: Option<[u8; 32]>,
+    near_author: Option<String>,
+}
+
+struct TransactionRow {
+    block_height: u64,
+    transaction_index: u32,
+    hash: [u8; 32],
+    block_hash: [u8; 32],
+    chain_id: u64,
+    from: [u8; 20],
+    to: Option<[u8; 20]>,
+    contract_address: Option<[u8; 20]>,
+    nonce: u64,
+    gas_price: String,
+    effective_gas_price: String,
+    gas_limit: u64,
+    gas_used: u64,
+    max_priority_fee_per_gas: String,
+    max_fee_per_gas: String,
+    value: String,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    tx_type: u8,
+    status: bool,
+    v: u64,
+    r: String,
+    s: String,
+    near_receipt_id: [u8; 32],
+    near_transaction_hash: Option<[u8; 32]>,
+    near_action_index: u64,
+}
+
+struct LogRow {
+    block_height: u64,
+    transaction_index: u32,
+    log_index: u32,
+    address: [u8; 20],
+    topics: [Option<[u8; 32]>; 4],
+    data: Vec<u8>,
+}
+
+fn decimal(value: U256) -> String {
+    value.to_string()
+}
+
+/// Rows of the blocks not written to a file yet.
+#[derive(Default)]
+struct Rows {
+    blocks: Vec<BlockRow>,
+    transactions: Vec<TransactionRow>,
+    logs: Vec<LogRow>,
+}
+
+impl Rows {
+    fn push(&mut self, block: &AuroraBlock) -> anyhow::Result<()> {
+        let (near_hash, near_parent_hash, near_author) = match &block.near_metadata {
+            NearBlock::ExistingBlock(header) => (
+                Some(header.near_hash.0),
+                Some(header.near_parent_hash.0),
+                Some(header.author.to_string()),
+            ),
+            NearBlock::SkipBlock => (None, None, None),
+        };
+        self.blocks.push(BlockRow {
+            height: block.height,
+            hash: block.hash.0,
+            parent_hash: block.parent_hash.0,
+            chain_id: block.chain_id,
+            timestamp: block.timestamp,
+            gas_used: block.gas_used,
+            size: block.size,
+            miner: block.miner.raw().0,
+            transactions_root: block.transactions_root.0,
+            state_root: block.state_root.0,
+            receipts_root: block.receipts_root.0,
+            logs_bloom: block.logs_bloom.0,
+            transaction_count: u32::try_from(block.transactions.len())?,
+            near_hash,
+            near_parent_hash,
+            near_author,
+        });
+
+        for tx in &block.transactions {
+            self.transactions.push(TransactionRow {
+                block_height: block.height,
+                transaction_index: tx.transaction_index,
+                hash: tx.hash.0,
+                block_hash: tx.block_hash.0,
+                chain_id: tx.chain_id,
+                from: tx.from.raw().0,
+                to: tx.to.map(|address| address.raw().0),
+                contract_address: tx.contract_address.map(|address| address.raw().0),
+                nonce: tx.nonce,
+                gas_price: decimal(tx.gas_price),
+                effective_gas_price: decimal(tx.effective_gas_price),
+                gas_limit: tx.gas_limit,
+                gas_used: tx.gas_used,
+                max_priority_fee_per_gas: decimal(tx.max_priority_fee_per_gas),
+                max_fee_per_gas: decimal(tx.max_fee_per_gas),
+                value: decimal(tx.value.raw()),
+                input: tx.input.clone(),
+                output: tx.output.clone(),
+                tx_type: tx.tx_type,
+                status: tx.status,
+                v: tx.v,
+                r: decimal(tx.r),
+                s: decimal(tx.s),
+                near_receipt_id: tx.near_metadata.receipt_hash.0,
+                near_transaction_hash: tx.near_metadata.transaction_hash.map(|hash| hash.0),
+                near_action_index: u64::try_from(tx.near_metadata.action_index)?,
+            });
+
+            for (log_index, log) in tx.indexed_logs() {
+                // EVM logs have at most 4 topics
+                let mut topics = log.topics.iter().copied();
+                self.logs.push(LogRow {
+                    block_height: block.height,
+                    transaction_index: tx.transaction_index,
+                    log_index,
+                    address: log.address.raw().0,
+                    topics: std::array::from_fn(|_| topics.next()),
+                    data: log.data.clone(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drops the rows of the blocks at or above `height`. The rows are pushed by increasing
+    /// height, so the cut is found by binary search.
+    fn truncate(&mut self, height: u64) {
+        self.blocks
+            .truncate(self.blocks.partition_point(|row| row.height < height));
+        self.transactions.truncate(
+            self.transactions
+                .partition_point(|row| row.block_height < height),
+        );
+        self.logs
+            .truncate(self.logs.partition_point(|row| row.block_height < height));
+    }
+
+    fn height_range(&self) -> Option<(u64, u64)> {
+        Some((self.blocks.first()?.height, self.blocks.last()?.height))
+    }
+
+    fn record_batch(&self, table: Table) -> anyhow::Result<RecordBatch> {
+        let columns = match table {
+            Table::Blocks => {
+                let rows = &self.blocks;
+                vec![
+                    u64_column(rows.iter().map(|row| row.height)),
+                    binary_column(rows.iter().map(|row| Some(row.hash)))?,
+                    binary_column(rows.iter().map(|row| Some(row.parent_hash)))?,
+                    u64_column(rows.iter().map(|row| row.chain_id)),
+                    u64_column(rows.iter().map(|row| row.timestamp)),
+                    u64_column(rows.iter().map(|row| row.gas_used)),
+                    u64_column(rows.iter().map(|row| row.size)),
+                    binary_column(rows.iter().map(|row| Some(row.miner)))?,
+                    binary_column(rows.iter().map(|row| Some(row.transactions_root)))?,
+                    binary_column(rows.iter().map(|row| Some(row.state_root)))?,
+                    binary_column(rows.iter().map(|row| Some(row.receipts_root)))?,
+                    binary_column(rows.iter().map(|row| Some(row.logs_bloom)))?,
+                    Arc::new(UInt32Array::from_iter_values(
+                        rows.iter().map(|row| row.transaction_count),
+                    )),
+                    binary_column(rows.iter().map(|row| row.near_hash))?,
+                    binary_column(rows.iter().map(|row| row.near_parent_hash))?,
+                    Arc::new(StringArray::from_iter(
+                        rows.iter().map(|row| row.near_author.as_deref()),
+                    )),
+                ]
+            }
+            Table::Transactions => {
+                let rows = &self.transactions;
+                vec![
+                    u64_column(rows.iter().map(|row| row.block_height)),
+                    Arc::new(UInt32Array::from_iter_values(
+                        rows.iter().map(|row| row.transaction_index),
+                    )),
+                    binary_column(rows.iter().map(|row| Some(row.hash)))?,
+                    binary_column(rows.iter().map(|row| Some(row.block_hash)))?,
+                    u64_column(rows.iter().map(|row| row.chain_id)),
+                    binary_column(rows.iter().map(|row| Some(row.from)))?,
+                    binary_column(rows.iter().map(|row| row.to))?,
+                    binary_column(rows.iter().map(|row| row.contract_address))?,
+                    u64_column(rows.iter().map(|row| row.nonce)),
+                    string_column(rows.iter().map(|row| &row.gas_price)),
+                    string_column(rows.iter().map(|row| &row.effective_gas_price)),
+                    u64_column(rows.iter().map(|row| row.gas_limit)),
+                    u64_column(rows.iter().map(|row| row.gas_used)),
+                    string_column(rows.iter().map(|row| &row.max_priority_fee_per_gas)),
+                    string_column(rows.iter().map(|row| &row.max_fee_per_gas)),
+                    string_column(rows.iter().map(|row| &row.value)),
+                    Arc::new(BinaryArray::from_iter_values(
+                        rows.iter().map(|row| &row.input),
+                    )),
+                    Arc::new(BinaryArray::from_iter_values(
+                        rows.iter().map(|row| &row.output),
+                    )),
+                    Arc::new(UInt8Array::from_iter_values(
+                        rows.iter().map(|row| row.tx_type),
+                    )),
+                    Arc::new(BooleanArray::from_iter(
+                        rows.iter().map(|row| Some(row.status)),
+                    )),
+                    u64_column(rows.iter().map(|row| row.v)),
+                    string_column(rows.iter().map(|row| &row.r)),
+                    string_column(rows.iter().map(|row| &row.s)),
+                    binary_column(rows.iter().map(|row| Some(row.near_receipt_id)))?,
+                    binary_column(rows.iter().map(|row| row.near_transaction_hash))?,
+                    u64_column(rows.iter().map(|row| row.near_action_index)),
+                ]
+            }
+            Table::Logs => {
+                let rows = &self.logs;
+                vec![
+                    u64_column(rows.iter().map(|row| row.block_height)),
+                    Arc::new(UInt32Array::from_iter_values(
+                        rows.iter().map(|row| row.transaction_index),
+                    )),
+                    Arc::new(UInt32Array::from_iter_values(
+                        rows.iter().map(|row| row.log_index),
+                    )),
+                    binary_column(rows.iter().map(|row| Some(row.address)))?,
+                    binary_column(rows.iter().map(|row| row.topics[0]))?,
+                    binary_column(rows.iter().map(|row| row.topics[1]))?,
+                    binary_column(rows.iter().map(|row| row.topics[2]))?,
+                    binary_column(rows.iter().map(|row| row.topics[3]))?,
+                    Arc::new(BinaryArray::from_iter_values(
+                        rows.iter().map(|row| &row.data),
+                    )),
+                ]
+            }
+        };
+
+        Ok(RecordBatch::try_new(table.schema(), columns)?)
+    }
+}
+
+fn u64_column(values: impl Iterator<Item = u64>) -> ArrayRef {
+    Arc::new(UInt64Array::from_iter_values(values))
+}
+
+fn string_column<'a>(values: impl Iterator<Item = &'a String>) -> ArrayRef {
+    Arc::new(StringArray::from_iter_values(values))
+}
+
+fn binary_column<const N: usize>(
+    values: impl Iterator<Item = Option<[u8; N]>>,
+) -> anyhow::Result<ArrayRef> {
+    let mut builder = FixedSizeBinaryBuilder::new(i32::try_from(N)?);
+    for value in values {
+        match value {
+            Some(value) => builder.append_value(value)?,
+            None => builder.append_null(),
+        }
+    }
+    Ok(Arc::new(builder.finish()))
+}
+
+/// A Parquet file holding the rows of the blocks from `first` to `last` of a table.
+struct TableFile {
+    first: u64,
+    last: u64,
+    path: PathBuf,
+}
+
+fn file_name(first: u64, last: u64) -> String {
+    format!("{first}-{last}.{EXTENSION}")
+}
+
+/// Lists the files of `table`, ordered by height.
+fn list_files(path: &Path, table: Table) -> anyhow::Result<Vec<TableFile>> {
+    let table_path = path.join(table.name());
+    let mut files = Vec::new();
+    if !table_path.exists() {
+        return Ok(files);
+    }
+
+    for partition in std::fs::read_dir(&table_path)? {
+        let partition = partition?;
+        if !partition.file_type()?.is_dir() {
+            continue;
+        }
+        for file in std::fs::read_dir(partition.path())? {
+            let file = file?;
+            let name = file.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            if name.ends_with(".PARTIAL") {
+                // Leftover of a file that was being written
+                std::fs::remove_file(file.path())?;
+                continue;
+            }
+            let Some((first, last)) = name
+                .strip_suffix(&format!(".{EXTENSION}"))
+                .and_then(|name| name.split_once('-'))
+                .and_then(|(first, last)| Some((first.parse().ok()?, last.parse().ok()?)))
+            else {
+                continue;
+            };
+            files.push(TableFile {
+                first,
+                last,
+                path: file.path(),
+            });
+        }
+    }
+    files.sort_by_key(|file| file.first);
+
+    Ok(files)
+}
+
+fn write_file(
+    path: &Path,
+    first: u64,
+    last: u64,
+    schema: SchemaRef,
+    batches: &[RecordBatch],
+) -> anyhow::Result<()> {
+    std::fs::create_dir_all(path)?;
+    let tmp_path = path.join(format!("{}.PARTIAL", file_name(first, last)));
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut writer = ArrowWriter::try_new(File::create(&tmp_path)?, schema, Some(properties))?;
+    for batch in batches {
+        writer.write(batch)?;
+    }
+    writer.close()?;
+    std::fs::rename(tmp_path, path.join(file_name(first, last)))?;
+
+    Ok(())
+}
+
+/// Keeps only the rows of `file` below `height`, in a new file.
+fn truncate_file(file: &TableFile, table: Table, height: u64) -> anyhow::Result<()> {
+    let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&file.path)?)?.build()?;
+    let schema = reader.schema();
+    let mut batches = Vec::new();
+    for batch in reader {
+        let batch = batch?;
+        let heights = batch
+            .column_by_name(table.height_column())
+            .and_then(|column| column.as_any().downcast_ref::<UInt64Array>())
+            .ok_or_else(|| anyhow::anyhow!("Invalid file {}", file.path.display()))?;
+        let mask: BooleanArray = heights
+            .iter()
+            .map(|row_height| row_height.map(|row_height| row_height < height))
+            .collect();
+        batches.push(arrow_select::filter::filter_record_batch(&batch, &mask)?);
+    }
+
+    let folder = file
+        .path
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("Invalid file {}", file.path.display()))?;
+    write_file(folder, file.first, height - 1, schema, &batches)?;
+    std::fs::remove_file(&file.path)?;
+
+    Ok(())
+}
+
+/// Removes the rows of every block at or above `height` from the tables. The `blocks` table
+/// goes first, so an interrupted truncation is completed the next time the sink is opened.
+fn truncate_tables(path: &Path, height: u64) -> anyhow::Result<()> {
+    for table in Table::ALL.into_iter().rev() {
+        for file in list_files(path, table)? {
+            if file.first >= height {
+                std::fs::remove_file(&file.path)?;
+            } else if file.last >= height {
+                truncate_file(&file, table, height)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn last_stored_height(path: &Path) -> anyhow::Result<Option<u64>> {
+    Ok(list_files(path, Table::Blocks)?
+        .last()
+        .map(|file| file.last))
+}
+
+pub struct ParquetSink {
+    path: PathBuf,
+    partition_size: u64,
+    file_size: u64,
+    /// Height of the last block written to a file.
+    committed: Option<u64>,
+    pending: Rows,
+}
+
+impl ParquetSink {
+    pub fn open(config: &ParquetSinkConfig) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&config.path)?;
+        let committed = last_stored_height(&config.path)?;
+        // Removes what a crash may have left above the last stored block
+        truncate_tables(&config.path, committed.map_or(0, |height| height + 1))?;
+
+        Ok(Self {
+            path: config.path.clone(),
+            partition_size: config
+                .partition_size
+                .unwrap_or(DEFAULT_PARTITION_SIZE)
+                .get(),
+            file_size: config.file_size.unwrap_or(DEFAULT_FILE_SIZE).get(),
+            committed,
+            pending: Rows::default(),
+        })
+    }
+
+    /// Removes every block above `height`.
+    pub async fn rewind(&mut self, height: u64) -> anyhow::Result<()> {
+        self.truncate(height.saturating_add(1)).await
+    }
+
+    async fn truncate(&mut self, height: u64) -> anyhow::Result<()> {
+        self.pending.truncate(height);
+        if self.committed.is_some_and(|committed| committed >= height) {
+            let path = self.path.clone();
+            tokio::task::spawn_blocking(move || truncate_tables(&path, height)).await??;
+            self.committed = height.checked_sub(1);
+        }
+        Ok(())
+    }
+
+    const fn partition(&self, height: u64) -> u64 {
+        height - height % self.partition_size
+    }
+
+    /// Writes the pending rows to a new file in each table.
+    async fn write_pending(&mut self) -> anyhow::Result<()> {
+        let Some((first, last)) = self.pending.height_range() else {
+            return Ok(());
+        };
+        let rows = std::mem::take(&mut self.pending);
+        let partition_path = self.partition(first).to_string();
+        let path = self.path.clone();
+
+        tokio::task::spawn_blocking(move || {
+            for table in Table::ALL {
+                let batch = rows.record_batch(table)?;
+                let folder = path.join(table.name()).join(&partition_path);
+                write_file(&folder, first, last, table.schema(), &[batch])?;
+            }
+            anyhow::Ok(())
+        })
+        .await??;
+        self.committed = Some(last);
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlockSink for ParquetSink {
+    async fn write_block(&mut self, block: &AuroraBlock) -> anyhow::Result<()> {
+        // The block may be written again after a restart
+        self.truncate(block.height).await?;
+
+        if let Some((first, _)) = self.pending.height_range()
+            && self.partition(first) != self.partition(block.height)
+        {
+            self.write_pending().await?;
+        }
+        self.pending.push(block)?;
+
+        // Bounds the memory used, and the blocks lost on a crash
+        if let Some((first, last)) = self.pending.height_range()
+            && last - first + 1 >= self.file_size
+        {
+            self.write_pending().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        self.write_pending().await
+    }
+
+    async fn last_committed_height(&self) -> anyhow::Result<Option<u64>> {
+        Ok(self.committed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ParquetSink, ParquetSinkConfig, Table, list_files};
+    use arrow_array::{Array, FixedSizeBinaryArray, StringArray, UInt64Array};
+    use aurora_refiner_lib::sink::BlockSink;
+    use aurora_refiner_types::test_utils::read_aurora_block;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use std::num::NonZeroU64;
+    use std::path::Path;
+
+    fn read_heights(path: &Path, table: Table) -> Vec<u64> {
+        let mut heights = Vec::new();
+        for file in list_files(path, table).unwrap() {
+            let reader =
+                ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&file.path).unwrap())
+                    .unwrap()
+                    .build()
+                    .unwrap();
+            for batch in reader {
+                let batch = batch.unwrap();
+                let column = batch.column_by_name(table.height_column()).unwrap();
+                let column = column.as_any().downcast_ref::<UInt64Array>().unwrap();
+                heights.extend(column.values().iter().copied());
+            }
+        }
+        heights
+    }
+
+    #[tokio::test]
+    async fn test_parquet_sink() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = ParquetSinkConfig {
+            path: tmp_dir.path().to_path_buf(),
+            partition_size: NonZeroU64::new(10),
+            file_size: None,
+        };
+        let mut sink = ParquetSink::open(&config).unwrap();
+        for height in 5..25 {
+            sink.write_block(&read_aurora_block(height)).await.unwrap();
+        }
+        // The last partition is only written on flush
+        assert_eq!(sink.last_committed_height().await.unwrap(), Some(19));
+        sink.flush().await.unwrap();
+        assert_eq!(sink.last_committed_height().await.unwrap(), Some(24));
+
+        assert_eq!(
+            read_heights(tmp_dir.path(), Table::Blocks),
+            (5..25).collect::<Vec<_>>()
+        );
+        let tx_heights = read_heights(tmp_dir.path(), Table::Transactions);
+        assert_eq!(tx_heights.len(), 40);
+        assert!(tmp_dir.path().join("blocks/10/10-19.parquet").exists());
+
+        // Transactions keep their hashes and U256 values
+        let block = read_aurora_block(5);
+        let file = &list_files(tmp_dir.path(), Table::Transactions).unwrap()[0];
+        let batch =
+            ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&file.path).unwrap())
+                .unwrap()
+                .build()
+                .unwrap()
+                .next()
+                .unwrap()
+                .unwrap();
+        let hashes = batch.column_by_name("hash").unwrap();
+        let hashes = hashes
+            .as_any()
+            .downcast_ref::<FixedSizeBinaryArray>()
+            .unwrap();
+        assert_eq!(hashes.value(0), block.transactions[0].hash.as_bytes());
+        let r = batch.column_by_name("r").unwrap();
+        let r = r.as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(r.value(0), block.transactions[0].r.to_string());
+        assert!(batch.column_by_name("to").unwrap().is_valid(0));
+
+        // Restarting in the middle of a file rewrites it without the blocks above
+        let mut sink = ParquetSink::open(&config).unwrap();
+        sink.write_block(&read_aurora_block(12)).await.unwrap();
+        sink.flush().await.unwrap();
+        assert_eq!(
+            read_heights(tmp_dir.path(), Table::Blocks),
+            (5..13).collect::<Vec<_>>()
+        );
+        assert_eq!(read_heights(tmp_dir.path(), Table::Transactions).len(), 16);
+        assert!(tmp_dir.path().join("blocks/10/10-11.parquet").exists());
+        assert!(tmp_dir.path().join("blocks/10/12-12.parquet").exists());
+
+        sink.rewind(8).await.unwrap();
+        assert_eq!(sink.last_committed_height().await.unwrap(), Some(8));
+        assert_eq!(
+            read_heights(tmp_dir.path(), Table::Blocks),
+            (5..9).collect::<Vec<_>>()
+        );
+        assert!(
+            !tmp_dir
+                .path()
+                .join("logs/10")
+                .read_dir()
+                .unwrap()
+                .any(|_| true)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_parquet_sink_file_size() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = ParquetSinkConfig {
+            path: tmp_dir.path().to_path_buf(),
+            partition_size: NonZeroU64::new(10),
+            file_size: NonZeroU64::new(3),
+        };
+        let mut sink = ParquetSink::open(&config).unwrap();
+        for height in 0..8 {
+            sink.write_block(&read_aurora_block(height)).await.unwrap();
+        }
+
+        // Files are written within the partition, only the blocks on disk are reported
+        assert_eq!(sink.last_committed_height().await.unwrap(), Some(5));
+        assert_eq!(
+            read_heights(tmp_dir.path(), Table::Blocks),
+            (0..6).collect::<Vec<_>>()
+        );
+        assert!(tmp_dir.path().join("blocks/0/0-2.parquet").exists());
+        assert!(tmp_dir.path().join("blocks/0/3-5.parquet").exists());
+
+        sink.flush().await.unwrap();
+        assert_eq!(sink.last_committed_height().await.unwrap(), Some(7));
+        assert!(tmp_dir.path().join("blocks/0/6-7.parquet").exists());
+    }
+}
@@ -0,0 +1,442 @@
+//! Output sink storing the refined blocks in a SQLite database, for services that only need
+//! to look up a block or a transaction.
+//!
+//! Blocks, transactions and logs are stored in normalized tables. Hashes and addresses are
+//! stored as text, in the same format as in the JSON output. Each block is committed in its
+//! own database transaction, together with the height of the last block stored.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use aurora_engine_types::H256;
+use aurora_engine_types::types::Address;
+use aurora_refiner_lib::sink::BlockSink;
+use aurora_refiner_types::aurora_block::{AuroraBlock, AuroraTransaction, NearBlock};
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::Deserialize;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS blocks (
+    height INTEGER PRIMARY KEY,
+    hash TEXT NOT NULL,
+    parent_hash TEXT NOT NULL,
+    chain_id INTEGER NOT NULL,
+    timestamp INTEGER NOT NULL,
+    gas_used INTEGER NOT NULL,
+    miner TEXT NOT NULL,
+    transactions_root TEXT NOT NULL,
+    state_root TEXT NOT NULL,
+    receipts_root TEXT NOT NULL,
+    near_hash TEXT,
+    near_parent_hash TEXT,
+    near_author TEXT
+);
+CREATE INDEX IF NOT EXISTS blocks_hash ON blocks (hash);
+
+CREATE TABLE IF NOT EXISTS transactions (
+    block_height INTEGER NOT NULL,
+    transaction_index INTEGER NOT NULL,
+    hash TEXT NOT NULL,
+    from_address TEXT NOT NULL,
+    to_address TEXT,
+    contract_address TEXT,
+    nonce INTEGER NOT NULL,
+    gas_used INTEGER NOT NULL,
+    status INTEGER NOT NULL,
+    near_receipt_id TEXT NOT NULL,
+    near_transaction_hash TEXT,
+    near_action_index INTEGER NOT NULL,
+    data TEXT NOT NULL,
+    PRIMARY KEY (block_height, transaction_index)
+);
+CREATE INDEX IF NOT EXISTS transactions_hash ON transactions (hash);
+CREATE INDEX IF NOT EXISTS transactions_near_receipt_id ON transactions (near_receipt_id);
+CREATE INDEX IF NOT EXISTS transactions_near_transaction_hash ON transactions (near_transaction_hash);
+CREATE INDEX IF NOT EXISTS transactions_from_address ON transactions (from_address);
+CREATE INDEX IF NOT EXISTS transactions_to_address ON transactions (to_address);
+
+CREATE TABLE IF NOT EXISTS logs (
+    block_height INTEGER NOT NULL,
+    transaction_index INTEGER NOT NULL,
+    log_index INTEGER NOT NULL,
+    address TEXT NOT NULL,
+    topic0 TEXT,
+    topic1 TEXT,
+    topic2 TEXT,
+    topic3 TEXT,
+    data BLOB NOT NULL,
+    PRIMARY KEY (block_height, transaction_index, log_index)
+);
+CREATE INDEX IF NOT EXISTS logs_address ON logs (address);
+
+CREATE TABLE IF NOT EXISTS sink_state (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    last_height INTEGER NOT NULL
+);
+";
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct SqliteSinkConfig {
+    /// Path to the database file, it is created if it does not exist.
+    pub path: PathBuf,
+}
+
+pub struct SqliteSink {
+    /// SQLite calls are blocking, they run on the blocking thread pool.
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteSink {
+    pub fn open(config: &SqliteSinkConfig) -> anyhow::Result<Self> {
+        if let Some(parent) = config.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let connection = open_connection(&config.path)?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Removes every block above `height`.
+    pub async fn rewind(&self, height: u64) -> anyhow::Result<()> {
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            delete_from(&transaction, height.saturating_add(1))?;
+            transaction.execute(
+                "UPDATE sink_state SET last_height = ?1 WHERE last_height > ?1",
+                params![height],
+            )?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn with_connection<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> anyhow::Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection
+                .lock()
+                .map_err(|_| anyhow::anyhow!("SQLite connection poisoned"))?;
+            f(&mut connection)
+        })
+        .await?
+    }
+}
+
+#[async_trait]
+impl BlockSink for SqliteSink {
+    async fn write_block(&mut self, block: &AuroraBlock) -> anyhow::Result<()> {
+        let block = BlockRow::new(block)?;
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            // The block may be written again after a restart
+            delete_from(&transaction, block.height)?;
+            block.insert(&transaction)?;
+            transaction.execute(
+                "INSERT INTO sink_state (id, last_height) VALUES (0, ?1)
+                 ON CONFLICT (id) DO UPDATE SET last_height = excluded.last_height",
+                params![block.height],
+            )?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        // Every block is committed as soon as it is written
+        Ok(())
+    }
+
+    async fn last_committed_height(&self) -> anyhow::Result<Option<u64>> {
+        self.with_connection(|connection| {
+            Ok(connection
+                .query_row(
+                    "SELECT last_height FROM sink_state WHERE id = 0",
+                    [],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+        .await
+    }
+}
+
+fn open_connection(path: &Path) -> anyhow::Result<Connection> {
+    let connection = Connection::open(path)?;
+    // Lets other processes read the database while blocks are written
+    connection.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+    connection.execute_batch(SCHEMA)?;
+    Ok(connection)
+}
+
+/// Deletes every block at or above `height`.
+fn delete_from(transaction: &rusqlite::Transaction<'_>, height: u64) -> rusqlite::Result<()> {
+    for table in ["logs", "transactions"] {
+        transaction.execute(
+            &format!("DELETE FROM {table} WHERE block_height >= ?1"),
+            params![height],
+        )?;
+    }
+    transaction.execute("DELETE FROM blocks WHERE height >= ?1", params![height])?;
+    Ok(())
+}
+
+fn hash(hash: &H256) -> String {
+    format!("{hash:#x}")
+}
+
+fn address(address: &Address) -> String {
+    format!("{:#x}", address.raw())
+}
+
+/// Columns of a block, extracted before the database is locked.
+struct BlockRow {
+    height: u64,
+    hash: String,
+    parent_hash: String,
+    chain_id: u64,
+    timestamp: u64,
+    gas_used: u64,
+    miner: String,
+    transactions_root: String,
+    state_root: String,
+    receipts_root: String,
+    near_hash: Option<String>,
+    near_parent_hash: Option<String>,
+    near_author: Option<String>,
+    transactions: Vec<TransactionRow>,
+}
+
+struct TransactionRow {
+    transaction_index: u32,
+    hash: String,
+    from_address: String,
+    to_address: Option<String>,
+    contract_address: Option<String>,
+    nonce: u64,
+    gas_used: u64,
+    status: bool,
+    near_receipt_id: String,
+    near_transaction_hash: Option<String>,
+    near_action_index: usize,
+    data: String,
+    logs: Vec<LogRow>,
+}
+
+struct LogRow {
+    log_index: u32,
+    address: String,
+    topics: [Option<String>; 4],
+    data: Vec<u8>,
+}
+
+impl BlockRow {
+    fn new(block: &AuroraBlock) -> anyhow::Result<Self> {
+        let (near_hash, near_parent_hash, near_author) = match &block.near_metadata {
+            NearBlock::ExistingBlock(header) => (
+                Some(header.near_hash.to_string()),
+                Some(header.near_parent_hash.to_string()),
+                Some(header.author.to_string()),
+            ),
+            NearBlock::SkipBlock => (None, None, None),
+        };
+
+        Ok(Self {
+            height: block.height,
+            hash: hash(&block.hash),
+            parent_hash: hash(&block.parent_hash),
+            chain_id: block.chain_id,
+            timestamp: block.timestamp,
+            gas_used: block.gas_used,
+            miner: address(&block.miner),
+            transactions_root: hash(&block.transactions_root),
+            state_root: hash(&block.state_root),
+            receipts_root: hash(&block.receipts_root),
+            near_hash,
+            near_parent_hash,
+            near_author,
+            transactions: block
+                .transactions
+                .iter()
+                .map(TransactionRow::new)
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+
+    fn insert(&self, transaction: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+        transaction.execute(
+            "INSERT INTO blocks (height, hash, parent_hash, chain_id, timestamp, gas_used, miner,
+                transactions_root, state_root, receipts_root, near_hash, near_parent_hash,
+                near_author)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            params![
+                self.height,
+                self.hash,
+                self.parent_hash,
+                self.chain_id,
+                self.timestamp,
+                self.gas_used,
+                self.miner,
+                self.transactions_root,
+                self.state_root,
+                self.receipts_root,
+                self.near_hash,
+                self.near_parent_hash,
+                self.near_author,
+            ],
+        )?;
+
+        for tx in &self.transactions {
+            transaction.execute(
+                "INSERT INTO transactions (block_height, transaction_index, hash, from_address,
+                    to_address, contract_address, nonce, gas_used, status, near_receipt_id,
+                    near_transaction_hash, near_action_index, data)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+                params![
+                    self.height,
+                    tx.transaction_index,
+                    tx.hash,
+                    tx.from_address,
+                    tx.to_address,
+                    tx.contract_address,
+                    tx.nonce,
+                    tx.gas_used,
+                    tx.status,
+                    tx.near_receipt_id,
+                    tx.near_transaction_hash,
+                    tx.near_action_index,
+                    tx.data,
+                ],
+            )?;
+
+            for log in &tx.logs {
+                let [topic0, topic1, topic2, topic3] = &log.topics;
+                transaction.execute(
+                    "INSERT INTO logs (block_height, transaction_index, log_index, address,
+                        topic0, topic1, topic2, topic3, data)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                    params![
+                        self.height,
+                        tx.transaction_index,
+                        log.log_index,
+                        log.address,
+                        topic0,
+                        topic1,
+                        topic2,
+                        topic3,
+                        log.data,
+                    ],
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl TransactionRow {
+    fn new(tx: &AuroraTransaction) -> anyhow::Result<Self> {
+        Ok(Self {
+            transaction_index: tx.transaction_index,
+            hash: hash(&tx.hash),
+            from_address: address(&tx.from),
+            to_address: tx.to.as_ref().map(address),
+            contract_address: tx.contract_address.as_ref().map(address),
+            nonce: tx.nonce,
+            gas_used: tx.gas_used,
+            status: tx.status,
+            near_receipt_id: tx.near_metadata.receipt_hash.to_string(),
+            near_transaction_hash: tx
+                .near_metadata
+                .transaction_hash
+                .map(|hash| hash.to_string()),
+            near_action_index: tx.near_metadata.action_index,
+            data: serde_json::to_string(tx)?,
+            logs: tx
+                .indexed_logs()
+                .map(|(log_index, log)| {
+                    // EVM logs have at most 4 topics
+                    let mut topics = log.topics.iter().map(|topic| hash(&H256::from(*topic)));
+                    LogRow {
+                        log_index,
+                        address: address(&log.address),
+                        topics: std::array::from_fn(|_| topics.next()),
+                        data: log.data.clone(),
+                    }
+                })
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SqliteSink, SqliteSinkConfig};
+    use aurora_refiner_lib::sink::BlockSink;
+    use aurora_refiner_types::test_utils::read_aurora_block;
+
+    #[tokio::test]
+    async fn test_sqlite_sink() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = SqliteSinkConfig {
+            path: tmp_dir.path().join("blocks.sqlite"),
+        };
+        let mut sink = SqliteSink::open(&config).unwrap();
+        assert_eq!(sink.last_committed_height().await.unwrap(), None);
+
+        let block = read_aurora_block(100);
+        let tx = &block.transactions[1];
+        let tx_hash = format!("{:#x}", tx.hash);
+        let receipt_id = tx.near_metadata.receipt_hash.to_string();
+        // Writing the same block twice replaces it
+        sink.write_block(&block).await.unwrap();
+        sink.write_block(&block).await.unwrap();
+        assert_eq!(
+            sink.last_committed_height().await.unwrap(),
+            Some(block.height)
+        );
+
+        let connection = rusqlite::Connection::open(&config.path).unwrap();
+        let count: u64 = connection
+            .query_row("SELECT COUNT(*) FROM transactions", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, block.transactions.len() as u64);
+
+        let (height, index, data): (u64, u32, String) = connection
+            .query_row(
+                "SELECT block_height, transaction_index, data FROM transactions WHERE hash = ?1",
+                [&tx_hash],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(height, block.height);
+        assert_eq!(index, tx.transaction_index);
+        assert_eq!(data, serde_json::to_string(tx).unwrap());
+
+        let found: String = connection
+            .query_row(
+                "SELECT hash FROM transactions WHERE near_receipt_id = ?1",
+                [&receipt_id],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(found, tx_hash);
+
+        sink.rewind(block.height - 1).await.unwrap();
+        assert_eq!(
+            sink.last_committed_height().await.unwrap(),
+            Some(block.height - 1)
+        );
+        let count: u64 = connection
+            .query_row("SELECT COUNT(*) FROM blocks", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+}
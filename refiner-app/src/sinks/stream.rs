@@ -0,0 +1,379 @@
+//! Output sink publishing the refined blocks as newline-delimited JSON to the subscribers
+//! connected to a Unix or TCP socket.
+//!
+//! A subscriber sends a single JSON line once connected: `{"from_height": <height>}` to receive
+//! every block from that height on, or `{}` (or an empty line) to only receive new blocks.
+//! Older blocks are read from the output storage, then the subscriber follows the live blocks.
+//!
+//! Every subscriber has its own buffer of live blocks. A subscriber that falls further behind
+//! is either disconnected or served from the output storage until it catches up, depending on
+//! the configured policy. Slow subscribers never slow the refiner down.
+
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use aurora_refiner_lib::sink::BlockSink;
+use aurora_refiner_types::aurora_block::AuroraBlock;
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::store::{self, OutputStoreConfig};
+
+pub const DEFAULT_BUFFER_SIZE: NonZeroUsize = NonZeroUsize::new(1000).unwrap();
+
+#[derive(Deserialize, Clone, Debug)]
+pub enum StreamAddress {
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+/// What to do with a subscriber whose buffer of live blocks is full.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SlowSubscriberPolicy {
+    /// Close the connection.
+    #[default]
+    Disconnect,
+    /// Send the blocks it missed from the output storage, then continue with the live blocks.
+    CatchUp,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct StreamSinkConfig {
+    pub listen: StreamAddress,
+    /// Number of live blocks buffered for each subscriber.
+    #[serde(default)]
+    pub buffer_size: Option<NonZeroUsize>,
+    #[serde(default)]
+    pub on_slow_subscriber: SlowSubscriberPolicy,
+}
+
+#[derive(Deserialize, Default)]
+struct SubscribeRequest {
+    from_height: Option<u64>,
+}
+
+/// A live block, serialized once for all the subscribers.
+struct StreamedBlock {
+    height: u64,
+    line: String,
+}
+
+/// What the server needs to start a subscriber. Only the server and the sink hold the sender,
+/// so the subscribers are disconnected once both are stopped.
+struct Subscription {
+    output_storage: OutputStoreConfig,
+    /// Height of the last block written to the output storage.
+    stored_height: watch::Receiver<Option<u64>>,
+    blocks: broadcast::Sender<Arc<StreamedBlock>>,
+    policy: SlowSubscriberPolicy,
+}
+
+pub struct StreamSink {
+    listen: StreamAddress,
+    output_storage: OutputStoreConfig,
+    blocks: broadcast::Sender<Arc<StreamedBlock>>,
+    server: JoinHandle<()>,
+}
+
+impl StreamSink {
+    /// `stored_height` follows the height of the last block written to `output_storage`.
+    pub async fn start(
+        config: &StreamSinkConfig,
+        output_storage: &OutputStoreConfig,
+        stored_height: watch::Receiver<Option<u64>>,
+    ) -> anyhow::Result<Self> {
+        let buffer_size = config.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE);
+        let (blocks, _) = broadcast::channel(buffer_size.get());
+        let subscription = Subscription {
+            output_storage: output_storage.clone(),
+            stored_height,
+            blocks: blocks.clone(),
+            policy: config.on_slow_subscriber,
+        };
+
+        let server = match &config.listen {
+            StreamAddress::Unix(path) => {
+                // Remove the socket file left by a previous run
+                if path.exists() {
+                    std::fs::remove_file(path)?;
+                }
+                let listener = UnixListener::bind(path)?;
+                info!("Stream sink listening on {}", path.display());
+                tokio::spawn(async move {
+                    loop {
+                        match listener.accept().await {
+                            Ok((stream, _)) => spawn_subscriber(stream, &subscription),
+                            Err(err) => warn!("Stream sink failed to accept connection: {err:?}"),
+                        }
+                    }
+                })
+            }
+            StreamAddress::Tcp(address) => {
+                let listener = TcpListener::bind(address).await?;
+                info!("Stream sink listening on {address}");
+                tokio::spawn(async move {
+                    loop {
+                        match listener.accept().await {
+                            Ok((stream, _)) => spawn_subscriber(stream, &subscription),
+                            Err(err) => warn!("Stream sink failed to accept connection: {err:?}"),
+                        }
+                    }
+                })
+            }
+        };
+
+        Ok(Self {
+            listen: config.listen.clone(),
+            output_storage: output_storage.clone(),
+            blocks,
+            server,
+        })
+    }
+}
+
+/// Stops accepting subscribers once the sink is stopped.
+impl Drop for StreamSink {
+    fn drop(&mut self) {
+        self.server.abort();
+        if let StreamAddress::Unix(path) = &self.listen
+            && let Err(err) = std::fs::remove_file(path)
+            && err.kind() != std::io::ErrorKind::NotFound
+        {
+            warn!("Stream sink failed to remove {}: {err:?}", path.display());
+        }
+    }
+}
+
+#[async_trait]
+impl BlockSink for StreamSink {
+    async fn write_block(&mut self, block: &AuroraBlock) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(block)?;
+        line.push('\n');
+        // Fails only if nobody is subscribed
+        let _ = self.blocks.send(Arc::new(StreamedBlock {
+            height: block.height,
+            line,
+        }));
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        // The blocks are sent to the subscribers as soon as they are written
+        Ok(())
+    }
+
+    /// The stream does not keep any block, it can resume from any block in the output storage.
+    async fn last_committed_height(&self) -> anyhow::Result<Option<u64>> {
+        store::load_last_block_height(&self.output_storage.path).await
+    }
+}
+
+fn spawn_subscriber<S>(stream: S, subscription: &Subscription)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let output_storage = subscription.output_storage.clone();
+    let stored_height = subscription.stored_height.clone();
+    let live = subscription.blocks.subscribe();
+    let policy = subscription.policy;
+    tokio::spawn(async move {
+        info!("Stream sink: subscriber connected");
+        match serve_subscriber(stream, output_storage, stored_height, live, policy).await {
+            Ok(()) => info!("Stream sink: subscriber disconnected"),
+            Err(err) => warn!("Stream sink: subscriber dropped: {err:?}"),
+        }
+    });
+}
+
+async fn serve_subscriber<S>(
+    stream: S,
+    output_storage: OutputStoreConfig,
+    mut stored_height: watch::Receiver<Option<u64>>,
+    mut live: broadcast::Receiver<Arc<StreamedBlock>>,
+    policy: SlowSubscriberPolicy,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    let mut writer = BufWriter::new(writer);
+
+    let mut request = String::new();
+    BufReader::new(reader).read_line(&mut request).await?;
+    let request: SubscribeRequest = if request.trim().is_empty() {
+        SubscribeRequest::default()
+    } else {
+        serde_json::from_str(&request)?
+    };
+
+    // Height of the next block to send, unknown until the first live block if no height is given
+    let mut next_height = None;
+    // The live blocks received while sending the stored ones are not a sign of a slow subscriber
+    let mut sending_stored = false;
+    if let Some(from_height) = request.from_height {
+        next_height = Some(send_stored_blocks(&output_storage, from_height, &mut writer).await?);
+        sending_stored = true;
+    }
+
+    loop {
+        let block = match live.recv().await {
+            Ok(block) => block,
+            Err(RecvError::Closed) => break,
+            Err(RecvError::Lagged(missed)) => {
+                if sending_stored || policy == SlowSubscriberPolicy::CatchUp {
+                    // The missed blocks are read from the storage with the next live block
+                    warn!("Stream sink: subscriber missed {missed} live blocks, catching up");
+                    continue;
+                }
+                anyhow::bail!("Subscriber is too slow, it missed {missed} blocks");
+            }
+        };
+
+        let height = next_height.unwrap_or(block.height);
+        if block.height < height {
+            continue;
+        }
+        for missing_height in height..block.height {
+            let stored =
+                wait_for_stored_block(&output_storage, &mut stored_height, missing_height).await?;
+            send_block(&mut writer, &stored).await?;
+        }
+        writer.write_all(block.line.as_bytes()).await?;
+        writer.flush().await?;
+        next_height = Some(block.height + 1);
+        sending_stored = false;
+    }
+
+    Ok(())
+}
+
+/// Sends the stored blocks from `height` on. Returns the height of the first missing block.
+async fn send_stored_blocks<W: AsyncWrite + Unpin + Send>(
+    output_storage: &OutputStoreConfig,
+    mut height: u64,
+    writer: &mut W,
+) -> anyhow::Result<u64> {
+    let mut reader = store::BlockReader::new(output_storage);
+    while let Some(block) = reader.read(height).await? {
+        send_block(writer, &block).await?;
+        height += 1;
+    }
+    writer.flush().await?;
+    Ok(height)
+}
+
+/// Reads a stored block, waiting for it to be stored if needed. The sinks write blocks
+/// concurrently, so a live block can reach the subscriber before the blocks preceding it are
+/// stored.
+async fn wait_for_stored_block(
+    output_storage: &OutputStoreConfig,
+    stored_height: &mut watch::Receiver<Option<u64>>,
+    height: u64,
+) -> anyhow::Result<AuroraBlock> {
+    if let Some(block) = store::read_block(output_storage, height).await? {
+        return Ok(block);
+    }
+    stored_height
+        .wait_for(|stored| stored.is_some_and(|stored| stored >= height))
+        .await
+        .map_err(|_| anyhow::anyhow!("Output storage stopped before block {height}"))?;
+    store::read_block(output_storage, height)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Block {height} is missing from the output storage"))
+}
+
+async fn send_block<W: AsyncWrite + Unpin + Send>(
+    writer: &mut W,
+    block: &AuroraBlock,
+) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(block)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SlowSubscriberPolicy, StreamAddress, StreamSink, StreamSinkConfig};
+    use crate::store::{self, OutputFormat, OutputStoreConfig};
+    use aurora_refiner_lib::sink::BlockSink;
+    use aurora_refiner_types::aurora_block::AuroraBlock;
+    use aurora_refiner_types::test_utils::read_aurora_block;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::sync::watch;
+
+    #[tokio::test]
+    async fn test_stream_sink() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let output_storage = OutputStoreConfig {
+            path: tmp_dir.path().join("output").to_str().unwrap().into(),
+            batch_size: 10,
+            format: OutputFormat::Json,
+        };
+        let socket_path = tmp_dir.path().join("stream.sock");
+        let config = StreamSinkConfig {
+            listen: StreamAddress::Unix(socket_path.clone()),
+            buffer_size: None,
+            on_slow_subscriber: SlowSubscriberPolicy::Disconnect,
+        };
+        let (stored_height, stored_height_rx) = watch::channel(None);
+        let mut sink = StreamSink::start(&config, &output_storage, stored_height_rx)
+            .await
+            .unwrap();
+        for height in 0..3 {
+            store::store(&output_storage, &read_aurora_block(height))
+                .await
+                .unwrap();
+            stored_height.send_replace(Some(height));
+            sink.write_block(&read_aurora_block(height)).await.unwrap();
+        }
+        assert_eq!(sink.last_committed_height().await.unwrap(), Some(2));
+
+        let mut stream = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+        stream.write_all(b"{\"from_height\": 1}\n").await.unwrap();
+        let mut lines = BufReader::new(stream).lines();
+
+        // Stored blocks first
+        for height in 1..3 {
+            let line = lines.next_line().await.unwrap().unwrap();
+            let block: AuroraBlock = serde_json::from_str(&line).unwrap();
+            assert_eq!(block.height, height);
+        }
+
+        // Then live blocks, with no gap or duplicate whatever the order of the stores
+        sink.write_block(&read_aurora_block(4)).await.unwrap();
+        for height in 3..6 {
+            store::store(&output_storage, &read_aurora_block(height))
+                .await
+                .unwrap();
+            stored_height.send_replace(Some(height));
+        }
+        sink.write_block(&read_aurora_block(5)).await.unwrap();
+        for height in 3..6 {
+            let line = lines.next_line().await.unwrap().unwrap();
+            let block: AuroraBlock = serde_json::from_str(&line).unwrap();
+            assert_eq!(block.height, height);
+        }
+        // Flushing keeps the subscribers connected, the socket is only removed once stopped
+        sink.flush().await.unwrap();
+        sink.write_block(&read_aurora_block(6)).await.unwrap();
+        store::store(&output_storage, &read_aurora_block(6))
+            .await
+            .unwrap();
+        stored_height.send_replace(Some(6));
+        let line = lines.next_line().await.unwrap().unwrap();
+        let block: AuroraBlock = serde_json::from_str(&line).unwrap();
+        assert_eq!(block.height, 6);
+        assert!(socket_path.exists());
+        drop(sink);
+        assert!(!socket_path.exists());
+    }
+}
@@ -0,0 +1,387 @@
+//! Output sink posting every refined block as JSON to an HTTP endpoint.
+//!
+//! A block is retried with exponential backoff until the endpoint accepts it with a 2xx status,
+//! so a stalled endpoint pauses the refiner instead of losing blocks. The height of the last
+//! accepted block is persisted in a cursor file and the refiner resumes after it, so every
+//! block is delivered at least once.
+//!
+//! Once the refiner is asked to stop, a block that fails is no longer retried: the sink is
+//! interrupted instead, and the block is delivered again after the restart.
+
+use std::collections::HashMap;
+use std::num::{NonZeroU32, NonZeroU64};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use aurora_refiner_lib::filter::BlockFilter;
+use aurora_refiner_lib::sink::{BlockSink, Interrupted};
+use aurora_refiner_types::aurora_block::AuroraBlock;
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast;
+use tracing::warn;
+
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct WebhookSinkConfig {
+    /// Endpoint receiving the blocks.
+    pub url: String,
+    /// Extra headers sent with every request, e.g. for authentication.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// File holding the height of the last block accepted by the endpoint.
+    pub cursor_path: PathBuf,
+    /// Only send the transactions matching this filter, in addition to the output filter.
+    /// The block is still sent when none of its transactions match.
+    #[serde(default)]
+    pub filter: Option<BlockFilter>,
+    #[serde(default)]
+    pub initial_backoff_ms: Option<NonZeroU64>,
+    #[serde(default)]
+    pub max_backoff_ms: Option<NonZeroU64>,
+    #[serde(default)]
+    pub timeout_ms: Option<NonZeroU64>,
+    /// Number of attempts after which the sink fails, which stops the refiner. Blocks are
+    /// retried until the refiner stops if not set.
+    #[serde(default)]
+    pub max_attempts: Option<NonZeroU32>,
+}
+
+pub struct WebhookSink {
+    client: reqwest::Client,
+    config: WebhookSinkConfig,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    shutdown_rx: broadcast::Receiver<()>,
+}
+
+impl WebhookSink {
+    pub fn new(
+        config: &WebhookSinkConfig,
+        shutdown_rx: broadcast::Receiver<()>,
+    ) -> anyhow::Result<Self> {
+        let timeout = config
+            .timeout_ms
+            .map_or(DEFAULT_TIMEOUT, |ms| Duration::from_millis(ms.get()));
+        let client = reqwest::Client::builder().timeout(timeout).build()?;
+
+        Ok(Self {
+            client,
+            config: config.clone(),
+            initial_backoff: config
+                .initial_backoff_ms
+                .map_or(DEFAULT_INITIAL_BACKOFF, |ms| {
+                    Duration::from_millis(ms.get())
+                }),
+            max_backoff: config
+                .max_backoff_ms
+                .map_or(DEFAULT_MAX_BACKOFF, |ms| Duration::from_millis(ms.get())),
+            shutdown_rx,
+        })
+    }
+
+    fn payload(&self, block: &AuroraBlock) -> anyhow::Result<Vec<u8>> {
+        let Some(filter) = &self.config.filter else {
+            return Ok(serde_json::to_vec(block)?);
+        };
+
+        // The blocks are shared with the other sinks, the filter is applied to a copy
+        let mut block = block.clone();
+        filter.apply(&mut block);
+        Ok(serde_json::to_vec(&block)?)
+    }
+}
+
+async fn post(
+    client: &reqwest::Client,
+    config: &WebhookSinkConfig,
+    payload: Vec<u8>,
+) -> anyhow::Result<()> {
+    let mut request = client
+        .post(&config.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(payload);
+    for (name, value) in &config.headers {
+        request = request.header(name, value);
+    }
+    request.send().await?.error_for_status()?;
+    Ok(())
+}
+
+#[async_trait]
+impl BlockSink for WebhookSink {
+    async fn write_block(&mut self, block: &AuroraBlock) -> anyhow::Result<()> {
+        let payload = self.payload(block)?;
+        let Self {
+            client,
+            config,
+            initial_backoff,
+            max_backoff,
+            shutdown_rx,
+        } = self;
+        let mut backoff = *initial_backoff;
+        let mut attempt = 1;
+        let mut result = post(client, config, payload.clone()).await;
+
+        loop {
+            match result {
+                Ok(()) => break,
+                Err(err) if config.max_attempts.is_some_and(|max| attempt >= max.get()) => {
+                    return Err(err.context(format!(
+                        "Webhook failed to deliver block {} after {attempt} attempts",
+                        block.height
+                    )));
+                }
+                Err(err) => {
+                    warn!(
+                        "Webhook failed to deliver block {} (attempt {attempt}), retrying in {backoff:?}: {err:?}",
+                        block.height
+                    );
+                    // The endpoint may stay down, the retries must not prevent the refiner
+                    // from stopping
+                    let retry = async {
+                        tokio::time::sleep(backoff).await;
+                        post(client, config, payload.clone()).await
+                    };
+                    tokio::select! {
+                        retry_result = retry => result = retry_result,
+                        _ = shutdown_rx.recv() => {
+                            return Err(Interrupted { height: block.height }.into());
+                        }
+                    }
+                    attempt += 1;
+                    backoff = (backoff * 2).min(*max_backoff);
+                }
+            }
+        }
+
+        save_cursor(&config.cursor_path, block.height).await
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        // The cursor is saved as soon as a block is delivered
+        Ok(())
+    }
+
+    async fn last_committed_height(&self) -> anyhow::Result<Option<u64>> {
+        load_cursor(&self.config.cursor_path).await
+    }
+}
+
+/// Moves the cursor back to `height` if it is above it.
+pub async fn rewind(config: &WebhookSinkConfig, height: u64) -> anyhow::Result<()> {
+    if load_cursor(&config.cursor_path)
+        .await?
+        .is_some_and(|cursor| cursor > height)
+    {
+        save_cursor(&config.cursor_path, height).await?;
+    }
+    Ok(())
+}
+
+async fn load_cursor(path: &Path) -> anyhow::Result<Option<u64>> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(cursor) => Ok(Some(cursor.trim().parse()?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+async fn save_cursor(path: &Path, height: u64) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    // Rename a synced temporary file, so the cursor is never partially written
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".PARTIAL");
+    let mut file = tokio::fs::File::create(&tmp_path).await?;
+    file.write_all(height.to_string().as_bytes()).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WebhookSink, WebhookSinkConfig, rewind};
+    use aurora_refiner_lib::filter::BlockFilter;
+    use aurora_refiner_lib::sink::{BlockSink, Interrupted};
+    use aurora_refiner_types::aurora_block::{EngineEvent, EngineEventKind};
+    use aurora_refiner_types::test_utils::read_aurora_block;
+    use std::collections::{HashMap, VecDeque};
+    use std::num::{NonZeroU32, NonZeroU64};
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    /// Minimal HTTP server answering with the given statuses, then with 200.
+    /// Returns its address and the bodies of the requests it received.
+    async fn start_stub(statuses: Vec<u16>) -> (String, Arc<Mutex<Vec<serde_json::Value>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let statuses = Arc::new(Mutex::new(VecDeque::from(statuses)));
+
+        let server_bodies = bodies.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let bodies = server_bodies.clone();
+                let statuses = statuses.clone();
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    loop {
+                        let mut content_length = 0;
+                        loop {
+                            let mut line = String::new();
+                            if stream.read_line(&mut line).await.unwrap() == 0 {
+                                return;
+                            }
+                            let line = line.trim().to_lowercase();
+                            if line.is_empty() {
+                                break;
+                            }
+                            if let Some(length) = line.strip_prefix("content-length:") {
+                                content_length = length.trim().parse().unwrap();
+                            }
+                        }
+                        let mut body = vec![0; content_length];
+                        stream.read_exact(&mut body).await.unwrap();
+
+                        let status = statuses.lock().unwrap().pop_front().unwrap_or(200);
+                        if status == 200 {
+                            bodies
+                                .lock()
+                                .unwrap()
+                                .push(serde_json::from_slice(&body).unwrap());
+                        }
+                        let response =
+                            format!("HTTP/1.1 {status} Stub\r\ncontent-length: 0\r\n\r\n");
+                        stream
+                            .get_mut()
+                            .write_all(response.as_bytes())
+                            .await
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        (format!("http://{address}/blocks"), bodies)
+    }
+
+    fn config(url: String, cursor_path: std::path::PathBuf) -> WebhookSinkConfig {
+        WebhookSinkConfig {
+            url,
+            headers: HashMap::new(),
+            cursor_path,
+            filter: None,
+            initial_backoff_ms: NonZeroU64::new(10),
+            max_backoff_ms: NonZeroU64::new(20),
+            timeout_ms: None,
+            max_attempts: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_webhook_retries() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (url, bodies) = start_stub(vec![500, 503]).await;
+        let config = config(url, tmp_dir.path().join("cursor"));
+        let (_shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel(1);
+        let mut sink = WebhookSink::new(&config, shutdown_rx).unwrap();
+        assert_eq!(sink.last_committed_height().await.unwrap(), None);
+
+        sink.write_block(&read_aurora_block(10)).await.unwrap();
+        sink.write_block(&read_aurora_block(11)).await.unwrap();
+
+        let bodies = bodies.lock().unwrap();
+        let heights: Vec<_> = bodies.iter().map(|body| body["height"].as_u64()).collect();
+        assert_eq!(heights, vec![Some(10), Some(11)]);
+        assert_eq!(bodies[0]["transactions"].as_array().unwrap().len(), 2);
+        drop(bodies);
+
+        let (_shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel(1);
+        let sink = WebhookSink::new(&config, shutdown_rx).unwrap();
+        assert_eq!(sink.last_committed_height().await.unwrap(), Some(11));
+        rewind(&config, 10).await.unwrap();
+        assert_eq!(sink.last_committed_height().await.unwrap(), Some(10));
+    }
+
+    #[tokio::test]
+    async fn test_webhook_filter_and_max_attempts() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (url, bodies) = start_stub(vec![500, 500]).await;
+        let mut block = read_aurora_block(10);
+        // Made by the transaction removed by the filter
+        block.engine_events.push(EngineEvent {
+            kind: EngineEventKind::SetOwner {
+                new_owner: "owner.near".parse().unwrap(),
+            },
+            transaction_hash: block.transactions[0].hash,
+            receipt_id: block.transactions[0].near_metadata.receipt_hash,
+            action_index: 0,
+            near_transaction_hash: None,
+            predecessor_id: "aurora".parse().unwrap(),
+            signer_id: "aurora".parse().unwrap(),
+        });
+        let mut config = config(url, tmp_dir.path().join("cursor"));
+        config.filter = Some(BlockFilter {
+            from: vec![block.transactions[1].from],
+            ..Default::default()
+        });
+        config.max_attempts = NonZeroU32::new(2);
+        let (_shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel(1);
+        let mut sink = WebhookSink::new(&config, shutdown_rx).unwrap();
+
+        assert!(sink.write_block(&block).await.is_err());
+        assert_eq!(sink.last_committed_height().await.unwrap(), None);
+
+        sink.write_block(&block).await.unwrap();
+        let bodies = bodies.lock().unwrap();
+        let transactions = bodies[0]["transactions"].as_array().unwrap();
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(
+            transactions[0]["hash"],
+            serde_json::to_value(block.transactions[1].hash).unwrap()
+        );
+        assert!(bodies[0].get("engine_events").is_none());
+        // The block given to the sink is left as is
+        assert_eq!(block.engine_events.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_webhook_stops_retrying_on_shutdown() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (url, bodies) = start_stub(vec![500; 1000]).await;
+        let config = config(url, tmp_dir.path().join("cursor"));
+        let (shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel(1);
+        let mut sink = WebhookSink::new(&config, shutdown_rx).unwrap();
+
+        let write = tokio::spawn(async move { sink.write_block(&read_aurora_block(10)).await });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        shutdown_tx.send(()).unwrap();
+
+        let result = tokio::time::timeout(std::time::Duration::from_secs(5), write)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(result.unwrap_err().is::<Interrupted>());
+        assert!(bodies.lock().unwrap().is_empty());
+        let sink = WebhookSink::new(&config, shutdown_tx.subscribe()).unwrap();
+        assert_eq!(sink.last_committed_height().await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_webhook_rejects_zero_max_attempts() {
+        let config = serde_json::json!({
+            "url": "http://localhost/blocks",
+            "cursor_path": "cursor",
+            "max_attempts": 0,
+        });
+        assert!(serde_json::from_value::<WebhookSinkConfig>(config).is_err());
+    }
+}
@@ -1,12 +1,20 @@
 use std::path::{Path, PathBuf};
 
-use aurora_refiner_lib::BlockWithMetadata;
+use async_trait::async_trait;
+use aurora_refiner_lib::sink::BlockSink;
 use aurora_refiner_types::aurora_block::AuroraBlock;
 use serde::Deserialize;
+use std::io::{Read, Write};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::watch;
 use tracing::info;
 
 const STORE_INFO_FILE: &str = ".REFINER_LAST_BLOCK";
+/// Number of blocks written between two saves of the last block height. The blocks are synced to
+/// disk as they are written, so a lagging height only makes some of them be written again.
+const SAVE_HEIGHT_INTERVAL: u64 = 100;
+
+mod batched;
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct OutputStoreConfig {
@@ -14,147 +22,640 @@ pub struct OutputStoreConfig {
     pub path: String,
     /// Number of files (blocks) to store on each folder.
     pub batch_size: u64,
+    /// How the blocks are written to the folders.
+    #[serde(default)]
+    pub format: OutputFormat,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// One `<height>.json` file per block.
+    #[default]
+    Json,
+    /// One zstd-compressed `<height>.json.zst` file per block.
+    JsonZstd,
+    /// One gzip-compressed `<height>.json.gz` file per block.
+    JsonGzip,
+    /// One zstd-compressed newline-delimited JSON file per folder, with an index.
+    BatchedZstd,
+    /// One gzip-compressed newline-delimited JSON file per folder, with an index.
+    BatchedGzip,
+}
+
+impl OutputFormat {
+    const fn compression(self) -> Option<Compression> {
+        match self {
+            Self::Json => None,
+            Self::JsonZstd | Self::BatchedZstd => Some(Compression::Zstd),
+            Self::JsonGzip | Self::BatchedGzip => Some(Compression::Gzip),
+        }
+    }
+
+    const fn is_batched(self) -> bool {
+        matches!(self, Self::BatchedZstd | Self::BatchedGzip)
+    }
+
+    fn block_file_name(self, height: u64) -> String {
+        match self.compression() {
+            None => format!("{height}.json"),
+            Some(compression) => format!("{height}.json.{}", compression.extension()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+    Gzip,
+}
+
+impl Compression {
+    const fn extension(self) -> &'static str {
+        match self {
+            Self::Zstd => "zst",
+            Self::Gzip => "gz",
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Zstd => zstd::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL),
+            Self::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    fn decompress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Zstd => zstd::decode_all(data),
+            Self::Gzip => {
+                let mut decoded = Vec::new();
+                flate2::read::GzDecoder::new(data).read_to_end(&mut decoded)?;
+                Ok(decoded)
+            }
+        }
+    }
 }
 
-pub async fn store(config: &OutputStoreConfig, block: &AuroraBlock) {
+fn batch_path(config: &OutputStoreConfig, height: u64) -> PathBuf {
+    PathBuf::from(&config.path).join(format!("{}", height - height % config.batch_size))
+}
+
+#[cfg(test)]
+pub async fn store(config: &OutputStoreConfig, block: &AuroraBlock) -> anyhow::Result<()> {
+    store_block_file(config, block).await?;
+    save_last_block_height(&config.path, block.height).await
+}
+
+/// Stores a block in its own file, without updating the last block height.
+async fn store_block_file(config: &OutputStoreConfig, block: &AuroraBlock) -> anyhow::Result<()> {
     tracing::trace!("Storing block {}", block.height);
     let folder_path = PathBuf::from(&config.path);
 
     if !folder_path.exists() {
-        std::fs::create_dir_all(&folder_path).unwrap();
+        tokio::fs::create_dir_all(&folder_path).await?;
     }
 
     let mut tmp_path = folder_path.clone();
     tmp_path.push(".PARTIAL");
 
-    let file = tokio::fs::File::create(&tmp_path).await.unwrap();
+    let file = tokio::fs::File::create(&tmp_path).await?;
 
     {
         let mut writer = tokio::io::BufWriter::new(file);
-        let data = serde_json::to_string(block).unwrap();
-        writer.write_all(data.as_bytes()).await.unwrap();
-        writer.flush().await.unwrap();
+        let data = serde_json::to_vec(block)?;
+        let data = match config.format.compression() {
+            None => data,
+            Some(compression) => compression.compress(&data)?,
+        };
+        writer.write_all(&data).await?;
+        writer.flush().await?;
+        writer.get_ref().sync_all().await?;
     }
 
-    let mut target_path = folder_path;
-    target_path.push(format!(
-        "{}",
-        block.height - block.height % config.batch_size
-    ));
+    let mut target_path = batch_path(config, block.height);
 
     if !target_path.exists() {
-        tokio::fs::create_dir_all(&target_path).await.unwrap();
+        tokio::fs::create_dir_all(&target_path).await?;
     }
 
-    target_path.push(format!("{}.json", block.height));
+    target_path.push(config.format.block_file_name(block.height));
     tracing::trace!(
         "Moving {} to {}.",
         tmp_path.display(),
         target_path.display()
     );
-    tokio::fs::rename(tmp_path, target_path).await.unwrap();
+    tokio::fs::rename(tmp_path, &target_path).await?;
+    if let Some(batch_path) = target_path.parent() {
+        sync_dir(batch_path).await?;
+    }
+
+    Ok(())
+}
+
+/// Persists the entries of the directory at `path`, e.g. a file renamed into it.
+async fn sync_dir(path: &Path) -> anyhow::Result<()> {
+    tokio::fs::File::open(path).await?.sync_all().await?;
+    Ok(())
+}
+
+/// Reads the block at `height` back from the output storage.
+pub async fn read_block(
+    config: &OutputStoreConfig,
+    height: u64,
+) -> anyhow::Result<Option<AuroraBlock>> {
+    BlockReader::new(config).read(height).await
+}
 
-    save_last_block_height(&config.path, block.height).await;
+/// Reads blocks back from the output storage. The index of a batch is kept while its blocks are
+/// read, so that reading consecutive blocks does not load it again for every block.
+pub struct BlockReader<'a> {
+    config: &'a OutputStoreConfig,
+    batch: Option<(PathBuf, batched::BatchReader)>,
 }
 
-/// Spawns a task that stores Aurora blocks to the output storage.
-/// The `shutdown_rx` is used to signal the task to stop.
-/// Returns a channel to send Aurora blocks to the task and a handle to the task.
-pub fn get_output_stream(
-    mut total_blocks: Option<u64>,
+impl<'a> BlockReader<'a> {
+    pub const fn new(config: &'a OutputStoreConfig) -> Self {
+        Self {
+            config,
+            batch: None,
+        }
+    }
+
+    /// Reads the block at `height`, or `None` if it is not stored.
+    pub async fn read(&mut self, height: u64) -> anyhow::Result<Option<AuroraBlock>> {
+        let batch_path = batch_path(self.config, height);
+        if self.config.format.is_batched() {
+            if let Some((path, reader)) = &mut self.batch
+                && *path == batch_path
+                && let Some(block) = reader.read(height).await?
+            {
+                return Ok(Some(block));
+            }
+            // The block may have been stored since the index was loaded
+            let compression = self
+                .config
+                .format
+                .compression()
+                .expect("Batched formats are compressed");
+            let mut reader = batched::BatchReader::open(&batch_path, compression).await?;
+            let block = reader.read(height).await?;
+            self.batch = Some((batch_path, reader));
+            return Ok(block);
+        }
+
+        let block_path = batch_path.join(self.config.format.block_file_name(height));
+        if !block_path.exists() {
+            return Ok(None);
+        }
+        let data = tokio::fs::read(&block_path).await?;
+        let data = match self.config.format.compression() {
+            None => data,
+            Some(compression) => compression.decompress(&data)?,
+        };
+        Ok(Some(serde_json::from_slice(&data)?))
+    }
+}
+
+/// Output sink storing the blocks in folders of `batch_size` blocks, in the configured format.
+pub struct FileSink {
     config: OutputStoreConfig,
-    mut shutdown_rx: tokio::sync::broadcast::Receiver<()>,
-) -> (
-    tokio::sync::mpsc::Sender<BlockWithMetadata<AuroraBlock, ()>>,
-    tokio::task::JoinHandle<()>,
-) {
-    tracing::info!("get_output_stream: starting output stream, total_blocks: {total_blocks:?}...");
-    let (aurora_blocks_tx, mut aurora_blocks_rx) =
-        tokio::sync::mpsc::channel::<BlockWithMetadata<AuroraBlock, ()>>(1000);
-
-    let task_handle = tokio::spawn(async move {
-        let config = config.clone();
-
-        loop {
-            tokio::select! {
-                // Handle incoming blocks
-                Some(block) = aurora_blocks_rx.recv() => {
-                    store(&config, &block.block).await;
-                    if let Some(total_blocks) = total_blocks.as_mut() {
-                        *total_blocks -= 1;
-                        if *total_blocks == 0 {
-                            break;
-                        }
-                    }
-                }
-                // Handle shutdown signal
-                _ = shutdown_rx.recv() => {
-                    // Explicitly close the channel, so the tx side should stop sending blocks
-                    aurora_blocks_rx.close();
-                    info!("get_output_stream: Received shutdown signal, Aurora blocks storage stopped");
-                    break;
-                }
+    /// Batch the blocks are appended to, for the batched formats.
+    batch_writer: Option<batched::BatchWriter>,
+    /// Height of the last block written.
+    stored_height: watch::Sender<Option<u64>>,
+    /// Number of blocks written since the last block height was saved.
+    unsaved_blocks: u64,
+}
+
+impl FileSink {
+    pub fn new(config: OutputStoreConfig) -> Self {
+        Self {
+            config,
+            batch_writer: None,
+            stored_height: watch::channel(None).0,
+            unsaved_blocks: 0,
+        }
+    }
+
+    /// Follows the height of the last block written, for the sinks reading the stored blocks.
+    pub fn stored_height(&self) -> watch::Receiver<Option<u64>> {
+        self.stored_height.subscribe()
+    }
+
+    async fn write_batched(&mut self, block: &AuroraBlock) -> anyhow::Result<()> {
+        let batch_start = block.height - block.height % self.config.batch_size;
+        let batch_writer = match self.batch_writer.take() {
+            Some(batch_writer) if batch_writer.batch_start() == batch_start => batch_writer,
+            _ => {
+                let compression = self
+                    .config
+                    .format
+                    .compression()
+                    .expect("Batched formats are compressed");
+                batched::BatchWriter::open(
+                    &batch_path(&self.config, block.height),
+                    batch_start,
+                    compression,
+                    block.height,
+                )
+                .await?
             }
+        };
+        let batch_writer = self.batch_writer.insert(batch_writer);
+        batch_writer.write(block).await
+    }
+
+    /// Saves the height of the last block written, if it changed since it was last saved.
+    async fn save_height(&mut self) -> anyhow::Result<()> {
+        let stored_height = *self.stored_height.borrow();
+        if let Some(height) = stored_height
+            && self.unsaved_blocks > 0
+        {
+            save_last_block_height(&self.config.path, height).await?;
+            self.unsaved_blocks = 0;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlockSink for FileSink {
+    async fn write_block(&mut self, block: &AuroraBlock) -> anyhow::Result<()> {
+        if self.config.format.is_batched() {
+            self.write_batched(block).await?;
+        } else {
+            store_block_file(&self.config, block).await?;
+        }
+        self.stored_height.send_replace(Some(block.height));
+        self.unsaved_blocks += 1;
+        if self.unsaved_blocks >= SAVE_HEIGHT_INTERVAL {
+            self.save_height().await?;
         }
-    });
+        Ok(())
+    }
 
-    tracing::info!("get_output_stream: output stream finished");
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        // The blocks are synced to disk as soon as they are written, only the height can lag
+        self.save_height().await
+    }
 
-    (aurora_blocks_tx, task_handle)
+    /// Height saved at the last flush, or every `SAVE_HEIGHT_INTERVAL` blocks. It can be below
+    /// the last block written after a crash.
+    async fn last_committed_height(&self) -> anyhow::Result<Option<u64>> {
+        load_last_block_height(&self.config.path).await
+    }
 }
 
-pub async fn load_last_block_height<P: AsRef<Path> + Send>(storage_path: P) -> Option<u64> {
+pub async fn load_last_block_height<P: AsRef<Path> + Send>(
+    storage_path: P,
+) -> anyhow::Result<Option<u64>> {
     let path = storage_path.as_ref();
     if !path.exists() {
-        tokio::fs::create_dir_all(path).await.unwrap();
+        tokio::fs::create_dir_all(path).await?;
     }
     let store_file = path.join(STORE_INFO_FILE);
 
     if store_file.exists() {
-        let mut file = tokio::fs::File::open(&store_file).await.unwrap();
+        let mut file = tokio::fs::File::open(&store_file).await?;
         let mut buffer = String::new();
-        file.read_to_string(&mut buffer).await.unwrap();
-        Some(buffer.trim().parse().unwrap())
+        file.read_to_string(&mut buffer).await?;
+        let height = buffer.trim().parse().map_err(|err| {
+            anyhow::anyhow!(
+                "Invalid last block height in {}: {err}",
+                store_file.display()
+            )
+        })?;
+        Ok(Some(height))
     } else {
-        None
+        Ok(None)
     }
 }
 
-async fn save_last_block_height<P: AsRef<Path> + Send>(storage_path: P, block_height: u64) {
+/// Removes every stored block above `height` and resets the last stored block height to it.
+pub async fn rewind(config: &OutputStoreConfig, height: u64) -> anyhow::Result<()> {
+    let folder_path = PathBuf::from(&config.path);
+    if !folder_path.exists() {
+        return Ok(());
+    }
+
+    let mut entries = tokio::fs::read_dir(&folder_path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        // Blocks are stored in folders named after the first height of their batch
+        let Some(batch_start) = parse_height(&entry.file_name()) else {
+            continue;
+        };
+        if !entry.file_type().await?.is_dir() {
+            continue;
+        }
+
+        if batch_start > height {
+            tracing::debug!("Removing batch {}", entry.path().display());
+            tokio::fs::remove_dir_all(entry.path()).await?;
+        } else if batch_start.saturating_add(config.batch_size) > height {
+            if config.format.is_batched() {
+                let compression = config
+                    .format
+                    .compression()
+                    .expect("Batched formats are compressed");
+                batched::rewind(&entry.path(), compression, height).await?;
+                continue;
+            }
+
+            let mut blocks = tokio::fs::read_dir(entry.path()).await?;
+            while let Some(block) = blocks.next_entry().await? {
+                // Block files are named `<height>.json`, followed by the compression extension
+                let block_height = block
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| name.split_once('.'))
+                    .and_then(|(name, _)| name.parse::<u64>().ok());
+                if matches!(block_height, Some(block_height) if block_height > height) {
+                    tokio::fs::remove_file(block.path()).await?;
+                }
+            }
+        }
+    }
+
+    save_last_block_height(&config.path, height).await?;
+    info!("Output storage rewound to height {height}");
+
+    Ok(())
+}
+
+fn parse_height(name: &std::ffi::OsStr) -> Option<u64> {
+    name.to_str().and_then(|name| name.parse().ok())
+}
+
+async fn save_last_block_height<P: AsRef<Path> + Send>(
+    storage_path: P,
+    block_height: u64,
+) -> anyhow::Result<()> {
     let path = storage_path.as_ref();
     if !path.exists() {
-        tokio::fs::create_dir_all(path).await.unwrap();
+        tokio::fs::create_dir_all(path).await?;
     }
     let file_path = path.join(STORE_INFO_FILE);
 
     // Write the data to a height-specific file to avoid clearing the main file
     let temp_path = path.join(format!(".{block_height}"));
-    let temp_file = tokio::fs::File::create(&temp_path).await.unwrap();
+    let temp_file = tokio::fs::File::create(&temp_path).await?;
 
     {
         let mut writer = tokio::io::BufWriter::new(temp_file);
         let data = block_height.to_string();
-        writer.write_all(data.as_bytes()).await.unwrap();
-        writer.flush().await.unwrap();
+        writer.write_all(data.as_bytes()).await?;
+        writer.flush().await?;
+        writer.get_ref().sync_all().await?;
     }
 
     // Move the height-specific file to the main file, thus atomically updating it.
-    tokio::fs::rename(temp_path, file_path).await.unwrap();
+    tokio::fs::rename(temp_path, file_path).await?;
+    sync_dir(path).await?;
 
     tracing::trace!("Last block height {} saved.", block_height);
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{load_last_block_height, save_last_block_height};
+    use super::{
+        BlockReader, FileSink, OutputFormat, OutputStoreConfig, SAVE_HEIGHT_INTERVAL,
+        load_last_block_height, read_block, rewind, save_last_block_height,
+    };
+    use aurora_refiner_lib::sink::BlockSink;
+    use aurora_refiner_types::test_utils::read_aurora_block;
+
+    fn test_config(tmp_dir: &tempfile::TempDir, format: OutputFormat) -> OutputStoreConfig {
+        OutputStoreConfig {
+            path: tmp_dir.path().to_str().unwrap().into(),
+            batch_size: 10,
+            format,
+        }
+    }
 
     #[tokio::test]
     async fn test_save_last_block_height() {
         const HEIGHT: u64 = 11111;
         let tmp_dir = tempfile::tempdir().unwrap();
-        save_last_block_height(tmp_dir.path(), HEIGHT).await;
-        let block_height = load_last_block_height(tmp_dir.path()).await;
+        save_last_block_height(tmp_dir.path(), HEIGHT)
+            .await
+            .unwrap();
+        let block_height = load_last_block_height(tmp_dir.path()).await.unwrap();
 
         assert_eq!(block_height, Some(HEIGHT))
     }
+
+    #[tokio::test]
+    async fn test_rewind() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = OutputStoreConfig {
+            path: tmp_dir.path().to_str().unwrap().into(),
+            batch_size: 10,
+            format: OutputFormat::Json,
+        };
+        for height in 5..25 {
+            let batch = tmp_dir.path().join(format!("{}", height - height % 10));
+            std::fs::create_dir_all(&batch).unwrap();
+            std::fs::write(batch.join(format!("{height}.json")), "{}").unwrap();
+        }
+        save_last_block_height(tmp_dir.path(), 24).await.unwrap();
+
+        rewind(&config, 12).await.unwrap();
+
+        assert_eq!(
+            load_last_block_height(tmp_dir.path()).await.unwrap(),
+            Some(12)
+        );
+        for height in 5..25 {
+            let path = tmp_dir
+                .path()
+                .join(format!("{}", height - height % 10))
+                .join(format!("{height}.json"));
+            assert_eq!(path.exists(), height <= 12, "unexpected state of {path:?}");
+        }
+        assert!(!tmp_dir.path().join("20").exists());
+    }
+
+    #[tokio::test]
+    async fn test_per_block_formats() {
+        for format in [
+            OutputFormat::Json,
+            OutputFormat::JsonZstd,
+            OutputFormat::JsonGzip,
+        ] {
+            let tmp_dir = tempfile::tempdir().unwrap();
+            let config = test_config(&tmp_dir, format);
+            let mut sink = FileSink::new(config.clone());
+            for height in 8..13 {
+                sink.write_block(&read_aurora_block(height)).await.unwrap();
+            }
+            assert_eq!(sink.last_committed_height().await.unwrap(), None);
+
+            sink.flush().await.unwrap();
+            assert_eq!(sink.last_committed_height().await.unwrap(), Some(12));
+            let block = read_block(&config, 11).await.unwrap().unwrap();
+            assert_eq!(block.height, 11);
+            assert_eq!(block.hash, read_aurora_block(11).hash);
+            assert!(read_block(&config, 13).await.unwrap().is_none());
+
+            rewind(&config, 9).await.unwrap();
+            assert!(read_block(&config, 9).await.unwrap().is_some());
+            assert!(read_block(&config, 10).await.unwrap().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_save_height_interval() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = test_config(&tmp_dir, OutputFormat::BatchedZstd);
+        let mut sink = FileSink::new(config.clone());
+        for height in 0..SAVE_HEIGHT_INTERVAL + 10 {
+            sink.write_block(&read_aurora_block(height)).await.unwrap();
+        }
+        assert_eq!(
+            sink.last_committed_height().await.unwrap(),
+            Some(SAVE_HEIGHT_INTERVAL - 1)
+        );
+
+        sink.flush().await.unwrap();
+        assert_eq!(
+            sink.last_committed_height().await.unwrap(),
+            Some(SAVE_HEIGHT_INTERVAL + 9)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_batched_formats() {
+        for format in [OutputFormat::BatchedZstd, OutputFormat::BatchedGzip] {
+            let tmp_dir = tempfile::tempdir().unwrap();
+            let config = test_config(&tmp_dir, format);
+            let mut sink = FileSink::new(config.clone());
+            for height in 5..25 {
+                sink.write_block(&read_aurora_block(height)).await.unwrap();
+            }
+            sink.flush().await.unwrap();
+
+            assert_eq!(sink.last_committed_height().await.unwrap(), Some(24));
+            for height in 5..25 {
+                let block = read_block(&config, height).await.unwrap().unwrap();
+                assert_eq!(block.height, height);
+            }
+            assert!(read_block(&config, 25).await.unwrap().is_none());
+
+            rewind(&config, 12).await.unwrap();
+            assert_eq!(
+                load_last_block_height(&config.path).await.unwrap(),
+                Some(12)
+            );
+            assert!(read_block(&config, 12).await.unwrap().is_some());
+            assert!(read_block(&config, 13).await.unwrap().is_none());
+            assert!(!tmp_dir.path().join("20").exists());
+
+            // Restarting from the last committed height overwrites the blocks written after it
+            let mut sink = FileSink::new(config.clone());
+            for height in 11..15 {
+                sink.write_block(&read_aurora_block(height)).await.unwrap();
+            }
+            for height in 5..15 {
+                let block = read_block(&config, height).await.unwrap().unwrap();
+                assert_eq!(block.height, height);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batched_block_reader() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = test_config(&tmp_dir, OutputFormat::BatchedZstd);
+        let mut sink = FileSink::new(config.clone());
+        for height in 5..15 {
+            sink.write_block(&read_aurora_block(height)).await.unwrap();
+        }
+
+        let mut reader = BlockReader::new(&config);
+        for height in 5..15 {
+            let block = reader.read(height).await.unwrap().unwrap();
+            assert_eq!(block.height, height);
+        }
+        assert!(reader.read(15).await.unwrap().is_none());
+
+        // Blocks stored after the index was loaded are read as well
+        sink.write_block(&read_aurora_block(15)).await.unwrap();
+        assert_eq!(reader.read(15).await.unwrap().unwrap().height, 15);
+    }
+
+    #[tokio::test]
+    async fn test_batched_restart_from_genesis() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = test_config(&tmp_dir, OutputFormat::BatchedZstd);
+        let mut sink = FileSink::new(config.clone());
+        for height in 0..3 {
+            sink.write_block(&read_aurora_block(height)).await.unwrap();
+        }
+
+        // Writing the first block again replaces every block of the batch
+        let mut sink = FileSink::new(config.clone());
+        sink.write_block(&read_aurora_block(0)).await.unwrap();
+        let index = std::fs::read_to_string(tmp_dir.path().join("0/blocks.index")).unwrap();
+        assert_eq!(index.lines().count(), 1);
+        assert!(read_block(&config, 0).await.unwrap().is_some());
+        assert!(read_block(&config, 1).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_batched_unindexed_data() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = test_config(&tmp_dir, OutputFormat::BatchedZstd);
+        let mut sink = FileSink::new(config.clone());
+        for height in 0..3 {
+            sink.write_block(&read_aurora_block(height)).await.unwrap();
+        }
+        drop(sink);
+
+        // A crash between writing a block and indexing it leaves trailing data
+        let data_path = tmp_dir.path().join("0").join("blocks.ndjson.zst");
+        let mut data = std::fs::read(&data_path).unwrap();
+        data.extend_from_slice(b"garbage");
+        std::fs::write(&data_path, data).unwrap();
+
+        let mut sink = FileSink::new(config.clone());
+        sink.write_block(&read_aurora_block(3)).await.unwrap();
+        for height in 0..4 {
+            let block = read_block(&config, height).await.unwrap().unwrap();
+            assert_eq!(block.height, height);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batched_torn_index_line() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = test_config(&tmp_dir, OutputFormat::BatchedZstd);
+        let mut sink = FileSink::new(config.clone());
+        for height in 0..3 {
+            sink.write_block(&read_aurora_block(height)).await.unwrap();
+        }
+        drop(sink);
+
+        // A crash while indexing the next block can leave a partial line that still parses
+        let data_path = tmp_dir.path().join("0").join("blocks.ndjson.zst");
+        let data_length = std::fs::metadata(&data_path).unwrap().len();
+        let index_path = tmp_dir.path().join("0").join("blocks.index");
+        let mut index = std::fs::read_to_string(&index_path).unwrap();
+        index.push_str(&format!("3 {data_length} 12"));
+        std::fs::write(&index_path, index).unwrap();
+
+        assert!(read_block(&config, 3).await.unwrap().is_none());
+
+        let mut sink = FileSink::new(config.clone());
+        sink.write_block(&read_aurora_block(3)).await.unwrap();
+        for height in 0..4 {
+            let block = read_block(&config, height).await.unwrap().unwrap();
+            assert_eq!(block.height, height);
+        }
+    }
 }
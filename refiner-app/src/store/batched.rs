@@ -0,0 +1,218 @@
+//! Batched output format: all the blocks of a batch are stored in a single data file, each one
+//! as its own compressed frame containing a line of JSON. Both zstd and gzip allow concatenated
+//! frames, so the data file can be decompressed as a whole into newline-delimited JSON.
+//!
+//! Next to the data file, an index file lists the height, offset and length of every frame,
+//! which gives random access to a block. A block is first appended to the data file, then to the
+//! index, so a crash can only leave data that is not indexed yet; it is discarded the next time
+//! the batch is opened for writing.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use aurora_refiner_types::aurora_block::AuroraBlock;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use super::Compression;
+
+const DATA_FILE: &str = "blocks.ndjson";
+const INDEX_FILE: &str = "blocks.index";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct IndexEntry {
+    height: u64,
+    offset: u64,
+    length: u64,
+}
+
+impl IndexEntry {
+    const fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+
+    fn to_line(self) -> String {
+        format!("{} {} {}\n", self.height, self.offset, self.length)
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        let mut parts = line.split_whitespace().map(str::parse::<u64>);
+        let entry = Self {
+            height: parts.next()?.ok()?,
+            offset: parts.next()?.ok()?,
+            length: parts.next()?.ok()?,
+        };
+        parts.next().is_none().then_some(entry)
+    }
+}
+
+fn data_path(batch_path: &Path, compression: Compression) -> PathBuf {
+    batch_path.join(format!("{DATA_FILE}.{}", compression.extension()))
+}
+
+async fn read_index(batch_path: &Path) -> anyhow::Result<Vec<IndexEntry>> {
+    let index_path = batch_path.join(INDEX_FILE);
+    if !index_path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = tokio::fs::read_to_string(&index_path).await?;
+    // A crash while appending can leave a partial last line, which may still parse, so only the
+    // lines ending in a newline are read
+    let complete = content.rfind('\n').map_or("", |end| &content[..=end]);
+    Ok(complete.lines().map_while(IndexEntry::parse).collect())
+}
+
+/// Keeps only the blocks of the batch that are below `height`, and drops the data that is not
+/// indexed. Returns the remaining index.
+async fn truncate(
+    batch_path: &Path,
+    compression: Compression,
+    height: u64,
+) -> anyhow::Result<Vec<IndexEntry>> {
+    let mut index = read_index(batch_path).await?;
+    index.retain(|entry| entry.height < height);
+    let data_length = index.last().map_or(0, IndexEntry::end);
+
+    let data_path = data_path(batch_path, compression);
+    if data_path.exists() {
+        let data_file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(&data_path)
+            .await?;
+        data_file.set_len(data_length).await?;
+        data_file.sync_all().await?;
+    }
+
+    let content: String = index.iter().map(|entry| entry.to_line()).collect();
+    let tmp_path = batch_path.join(format!("{INDEX_FILE}.PARTIAL"));
+    let mut tmp_file = tokio::fs::File::create(&tmp_path).await?;
+    tmp_file.write_all(content.as_bytes()).await?;
+    tmp_file.sync_all().await?;
+    tokio::fs::rename(&tmp_path, batch_path.join(INDEX_FILE)).await?;
+    tokio::fs::File::open(batch_path).await?.sync_all().await?;
+
+    Ok(index)
+}
+
+/// Appends blocks to the data and index files of a batch.
+pub struct BatchWriter {
+    batch_start: u64,
+    compression: Compression,
+    data_file: tokio::fs::File,
+    index_file: tokio::fs::File,
+    offset: u64,
+}
+
+impl BatchWriter {
+    /// Opens the batch stored in `batch_path` to append blocks from `height` on.
+    /// Any block already stored at or above `height` is removed.
+    pub async fn open(
+        batch_path: &Path,
+        batch_start: u64,
+        compression: Compression,
+        height: u64,
+    ) -> anyhow::Result<Self> {
+        tokio::fs::create_dir_all(batch_path).await?;
+        let index = truncate(batch_path, compression, height).await?;
+
+        let data_file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(data_path(batch_path, compression))
+            .await?;
+        let index_file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(batch_path.join(INDEX_FILE))
+            .await?;
+
+        Ok(Self {
+            batch_start,
+            compression,
+            data_file,
+            index_file,
+            offset: index.last().map_or(0, IndexEntry::end),
+        })
+    }
+
+    pub const fn batch_start(&self) -> u64 {
+        self.batch_start
+    }
+
+    pub async fn write(&mut self, block: &AuroraBlock) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(block)?;
+        line.push(b'\n');
+        let frame = self.compression.compress(&line)?;
+
+        self.data_file.write_all(&frame).await?;
+        self.data_file.sync_data().await?;
+
+        let entry = IndexEntry {
+            height: block.height,
+            offset: self.offset,
+            length: frame.len() as u64,
+        };
+        self.index_file
+            .write_all(entry.to_line().as_bytes())
+            .await?;
+        self.index_file.sync_data().await?;
+        self.offset = entry.end();
+
+        Ok(())
+    }
+}
+
+/// Reads blocks from a batch. The index is loaded once, when the reader is opened, so blocks
+/// appended to the batch afterwards are not visible to it.
+pub struct BatchReader {
+    data_path: PathBuf,
+    compression: Compression,
+    index: BTreeMap<u64, IndexEntry>,
+    data_file: Option<tokio::fs::File>,
+}
+
+impl BatchReader {
+    /// Opens the batch stored in `batch_path`, which may not exist yet.
+    pub async fn open(batch_path: &Path, compression: Compression) -> anyhow::Result<Self> {
+        let index = read_index(batch_path)
+            .await?
+            .into_iter()
+            .map(|entry| (entry.height, entry))
+            .collect();
+        Ok(Self {
+            data_path: data_path(batch_path, compression),
+            compression,
+            index,
+            data_file: None,
+        })
+    }
+
+    /// Reads the block at `height`, if it was indexed when the reader was opened.
+    pub async fn read(&mut self, height: u64) -> anyhow::Result<Option<AuroraBlock>> {
+        let Some(entry) = self.index.get(&height).copied() else {
+            return Ok(None);
+        };
+
+        let data_file = match &mut self.data_file {
+            Some(data_file) => data_file,
+            data_file => data_file.insert(tokio::fs::File::open(&self.data_path).await?),
+        };
+        data_file
+            .seek(std::io::SeekFrom::Start(entry.offset))
+            .await?;
+        let mut frame = vec![0; usize::try_from(entry.length)?];
+        data_file.read_exact(&mut frame).await?;
+
+        let line = self.compression.decompress(&frame)?;
+        Ok(Some(serde_json::from_slice(&line)?))
+    }
+}
+
+/// Removes the blocks above `height` from the batch stored in `batch_path`.
+pub async fn rewind(
+    batch_path: &Path,
+    compression: Compression,
+    height: u64,
+) -> anyhow::Result<()> {
+    truncate(batch_path, compression, height.saturating_add(1)).await?;
+    Ok(())
+}
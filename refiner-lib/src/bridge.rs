@@ -0,0 +1,300 @@
+//! Decoding of the tokens bridged out of Aurora from the logs of the exit precompiles, and from
+//! the NEAR receipts the exits spawn.
+//!
+//! The tokens bridged in are recorded while refining `ft_on_transfer` calls, see `refiner_inner`.
+
+use aurora_engine::parameters::ResultLog;
+use aurora_engine_sdk::types::near_account_to_evm_address;
+use aurora_engine_types::borsh::BorshDeserialize;
+use aurora_engine_types::parameters::connector::WithdrawCallArgs;
+use aurora_engine_types::types::Address;
+use aurora_engine_types::{H160, H256, U256};
+use aurora_refiner_types::aurora_block::{
+    AuroraTransaction, BridgeAccount, BridgeEvent, BridgeEventKind, BridgeToken,
+};
+use aurora_refiner_types::near_primitives::types::AccountId;
+use aurora_refiner_types::near_primitives::views::{ActionView, ReceiptEnumView, ReceiptView};
+use aurora_refiner_types::utils::keccak256;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+
+lazy_static! {
+    static ref EXIT_TO_NEAR_ADDRESS: Address = near_account_to_evm_address(b"exitToNear");
+    static ref EXIT_TO_ETHEREUM_ADDRESS: Address = near_account_to_evm_address(b"exitToEthereum");
+    static ref EXIT_TO_NEAR_SIGNATURE: H256 =
+        keccak256(b"ExitToNear(address,address,string,uint256)");
+    static ref EXIT_TO_ETHEREUM_SIGNATURE: H256 =
+        keccak256(b"ExitToEth(address,address,address,uint256)");
+}
+
+/// Exits to NEAR and Ethereum recorded in the logs of `tx`.
+pub fn exit_events(tx: &AuroraTransaction) -> Vec<BridgeEvent> {
+    tx.logs
+        .iter()
+        .filter_map(|log| exit_event(tx.hash, log))
+        .collect()
+}
+
+/// Withdrawal made by `receipt`, a receipt spawned by the exit of the Aurora transaction
+/// `transaction_hash`: a `ft_transfer` of the NEP-141 tokens exited to NEAR, or a `withdraw` of
+/// the ones exited to Ethereum. The ERC-20 address of the token is not known from the receipt.
+pub fn withdrawal_event(transaction_hash: H256, receipt: &ReceiptView) -> Option<BridgeEvent> {
+    let ReceiptEnumView::Action { actions, .. } = &receipt.receipt else {
+        return None;
+    };
+    let (kind, amount, recipient) = actions.iter().find_map(|action| match action {
+        ActionView::FunctionCall {
+            method_name, args, ..
+        } => match method_name.as_str() {
+            "ft_transfer" => {
+                let args: FtTransferArgs = serde_json::from_slice(args).ok()?;
+                Some((
+                    BridgeEventKind::NearWithdrawal,
+                    args.amount.parse::<u128>().ok()?,
+                    BridgeAccount::Near(args.receiver_id),
+                ))
+            }
+            "withdraw" => {
+                let (amount, recipient) = withdraw_args(args)?;
+                Some((
+                    BridgeEventKind::EthereumWithdrawal,
+                    amount,
+                    BridgeAccount::Ethereum(recipient),
+                ))
+            }
+            _ => None,
+        },
+        _ => None,
+    })?;
+
+    Some(BridgeEvent {
+        kind,
+        transaction_hash,
+        token: BridgeToken {
+            nep141: Some(receipt.receiver_id.clone()),
+            erc20: None,
+        },
+        amount: U256::from(amount),
+        sender: BridgeAccount::Near(receipt.predecessor_id.clone()),
+        recipient,
+    })
+}
+
+/// Arguments of the NEP-141 `ft_transfer` method.
+#[derive(Deserialize)]
+struct FtTransferArgs {
+    receiver_id: AccountId,
+    amount: String,
+}
+
+/// Arguments of the `withdraw` method of the bridged NEP-141 tokens.
+#[derive(Deserialize)]
+struct BridgedTokenWithdrawArgs {
+    amount: String,
+    recipient: String,
+}
+
+/// Amount and Ethereum recipient of a `withdraw` call. The bridged NEP-141 tokens take JSON
+/// arguments, the ETH connector takes borsh arguments.
+fn withdraw_args(args: &[u8]) -> Option<(u128, Address)> {
+    if let Ok(args) = serde_json::from_slice::<BridgedTokenWithdrawArgs>(args) {
+        let recipient = args.recipient.strip_prefix("0x").unwrap_or(&args.recipient);
+        return Some((args.amount.parse().ok()?, Address::decode(recipient).ok()?));
+    }
+    let args = WithdrawCallArgs::try_from_slice(args).ok()?;
+    Some((args.amount.as_u128(), args.recipient_address))
+}
+
+/// Recipient of an ERC-20 mint, given as an hexadecimal address in the `ft_on_transfer` message.
+pub fn mint_recipient(msg: &str) -> Option<Address> {
+    Address::decode(msg.strip_prefix("0x").unwrap_or(msg)).ok()
+}
+
+/// Decodes the log emitted by an exit precompile. The topics are the event signature, the
+/// sender, the ERC-20 address (zero for ETH) and the recipient; the data is the amount.
+fn exit_event(transaction_hash: H256, log: &ResultLog) -> Option<BridgeEvent> {
+    let [signature, sender, erc20, recipient] = log.topics.as_slice() else {
+        return None;
+    };
+    let signature = H256(*signature);
+
+    let (kind, recipient) = if log.address == *EXIT_TO_NEAR_ADDRESS
+        && signature == *EXIT_TO_NEAR_SIGNATURE
+    {
+        (
+            BridgeEventKind::ExitToNear,
+            BridgeAccount::NearHash(H256(*recipient)),
+        )
+    } else if log.address == *EXIT_TO_ETHEREUM_ADDRESS && signature == *EXIT_TO_ETHEREUM_SIGNATURE {
+        (
+            BridgeEventKind::ExitToEthereum,
+            BridgeAccount::Ethereum(topic_address(recipient)),
+        )
+    } else {
+        return None;
+    };
+
+    let erc20 = topic_address(erc20);
+    Some(BridgeEvent {
+        kind,
+        transaction_hash,
+        token: BridgeToken {
+            nep141: None,
+            erc20: (erc20 != Address::zero()).then_some(erc20),
+        },
+        amount: U256::from_big_endian(log.data.get(..32)?),
+        sender: BridgeAccount::Aurora(topic_address(sender)),
+        recipient,
+    })
+}
+
+/// Address stored in the last 20 bytes of a topic.
+fn topic_address(topic: &[u8; 32]) -> Address {
+    Address::new(H160::from_slice(&topic[12..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        EXIT_TO_ETHEREUM_ADDRESS, EXIT_TO_ETHEREUM_SIGNATURE, EXIT_TO_NEAR_ADDRESS,
+        EXIT_TO_NEAR_SIGNATURE, exit_events, withdrawal_event,
+    };
+    use aurora_engine::parameters::ResultLog;
+    use aurora_engine_types::types::{Address, u256_to_arr};
+    use aurora_engine_types::{H256, U256};
+    use aurora_refiner_types::aurora_block::{BridgeAccount, BridgeEventKind, BridgeToken};
+    use aurora_refiner_types::near_primitives::serialize::to_base64;
+    use aurora_refiner_types::near_primitives::views::ReceiptView;
+    use aurora_refiner_types::test_utils::read_aurora_block;
+
+    /// Receipt of the engine calling `method_name` of the token contract.
+    fn token_receipt(method_name: &str, args: &[u8]) -> ReceiptView {
+        serde_json::from_value(serde_json::json!({
+            "predecessor_id": "aurora",
+            "receiver_id": "token.near",
+            "receipt_id": "DP6rWtdG4B4PvgQhLmijNtU6u51s57wZTjxPz5JfBdfG",
+            "receipt": {"Action": {
+                "signer_id": "relayer.near",
+                "signer_public_key": "ed25519:6MP4bCPHEud33eKXM9kg7f9fVNhmn97CNUyn5ZwM375U",
+                "gas_price": "0",
+                "output_data_receivers": [],
+                "input_data_ids": [],
+                "actions": [{"FunctionCall": {
+                    "method_name": method_name,
+                    "args": to_base64(args),
+                    "gas": 100000000000000u64,
+                    "deposit": "1",
+                }}],
+            }},
+        }))
+        .unwrap()
+    }
+
+    fn topic(address: Address) -> [u8; 32] {
+        let mut topic = [0; 32];
+        topic[12..].copy_from_slice(address.as_bytes());
+        topic
+    }
+
+    #[test]
+    fn test_exit_events() {
+        let mut block = read_aurora_block(100);
+        let tx = &mut block.transactions[1];
+        // The log of the transaction is not an exit
+        assert!(exit_events(tx).is_empty());
+
+        let sender = Address::decode(&"01".repeat(20)).unwrap();
+        let erc20 = Address::decode(&"02".repeat(20)).unwrap();
+        let eth_recipient = Address::decode(&"03".repeat(20)).unwrap();
+        let near_recipient = H256::repeat_byte(4);
+        let amount = U256::from(1_000_000);
+        tx.logs.push(
+            ResultLog {
+                address: *EXIT_TO_NEAR_ADDRESS,
+                topics: vec![
+                    EXIT_TO_NEAR_SIGNATURE.0,
+                    topic(sender),
+                    topic(erc20),
+                    near_recipient.0,
+                ],
+                data: u256_to_arr(&amount).to_vec(),
+            }
+            .into(),
+        );
+        tx.logs.push(
+            ResultLog {
+                address: *EXIT_TO_ETHEREUM_ADDRESS,
+                topics: vec![
+                    EXIT_TO_ETHEREUM_SIGNATURE.0,
+                    topic(sender),
+                    topic(Address::zero()),
+                    topic(eth_recipient),
+                ],
+                data: u256_to_arr(&amount).to_vec(),
+            }
+            .into(),
+        );
+
+        let events = exit_events(tx);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].kind, BridgeEventKind::ExitToNear);
+        assert_eq!(events[0].transaction_hash, tx.hash);
+        assert_eq!(
+            events[0].token,
+            BridgeToken {
+                nep141: None,
+                erc20: Some(erc20),
+            }
+        );
+        assert_eq!(events[0].amount, amount);
+        assert_eq!(events[0].sender, BridgeAccount::Aurora(sender));
+        assert_eq!(events[0].recipient, BridgeAccount::NearHash(near_recipient));
+        assert_eq!(events[1].kind, BridgeEventKind::ExitToEthereum);
+        assert_eq!(events[1].token.erc20, None);
+        assert_eq!(events[1].recipient, BridgeAccount::Ethereum(eth_recipient));
+    }
+
+    #[test]
+    fn test_withdrawal_events() {
+        let tx_hash = H256::repeat_byte(5);
+        let token = BridgeToken {
+            nep141: Some("token.near".parse().unwrap()),
+            erc20: None,
+        };
+        let sender = BridgeAccount::Near("aurora".parse().unwrap());
+        let eth_recipient = Address::decode(&"03".repeat(20)).unwrap();
+
+        let receipt = token_receipt(
+            "ft_transfer",
+            br#"{"receiver_id":"alice.near","amount":"1000","memo":null}"#,
+        );
+        let event = withdrawal_event(tx_hash, &receipt).unwrap();
+        assert_eq!(event.kind, BridgeEventKind::NearWithdrawal);
+        assert_eq!(event.transaction_hash, tx_hash);
+        assert_eq!(event.token, token);
+        assert_eq!(event.amount, U256::from(1000));
+        assert_eq!(event.sender, sender);
+        assert_eq!(
+            event.recipient,
+            BridgeAccount::Near("alice.near".parse().unwrap())
+        );
+
+        // Bridged tokens take JSON arguments
+        let args = format!(r#"{{"amount":"2000","recipient":"{}"}}"#, "03".repeat(20));
+        let event = withdrawal_event(tx_hash, &token_receipt("withdraw", args.as_bytes())).unwrap();
+        assert_eq!(event.kind, BridgeEventKind::EthereumWithdrawal);
+        assert_eq!(event.amount, U256::from(2000));
+        assert_eq!(event.recipient, BridgeAccount::Ethereum(eth_recipient));
+
+        // And the ETH connector borsh ones
+        let args = [eth_recipient.as_bytes(), &3000u128.to_le_bytes()].concat();
+        let event = withdrawal_event(tx_hash, &token_receipt("withdraw", &args)).unwrap();
+        assert_eq!(event.kind, BridgeEventKind::EthereumWithdrawal);
+        assert_eq!(event.amount, U256::from(3000));
+        assert_eq!(event.recipient, BridgeAccount::Ethereum(eth_recipient));
+
+        // Other calls of the engine are not withdrawals
+        let receipt = token_receipt("storage_deposit", b"{}");
+        assert!(withdrawal_event(tx_hash, &receipt).is_none());
+    }
+}
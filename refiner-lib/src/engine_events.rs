@@ -0,0 +1,277 @@
+//! Decoding of the administrative calls of the engine into typed events.
+//!
+//! The arguments are decoded with the engine's argument types. Calls whose arguments cannot be
+//! decoded are still refined as transactions, without an event.
+
+use aurora_engine_types::borsh::BorshDeserialize;
+use aurora_engine_types::parameters::connector::{Erc20Identifier, SetErc20MetadataArgs};
+use aurora_engine_types::parameters::engine::UpgradeParams;
+use aurora_engine_types::parameters::silo::{
+    self, SiloParamsArgs, WhitelistArgs, WhitelistStatusArgs,
+};
+use aurora_engine_types::types::Address;
+use aurora_refiner_types::aurora_block::{
+    AuroraTransaction, EngineEvent, EngineEventKind, Erc20Token, SiloParams, WhitelistEntry,
+    WhitelistKind, WhitelistStatus,
+};
+use aurora_refiner_types::near_primitives::hash::CryptoHash;
+use aurora_refiner_types::near_primitives::types::AccountId;
+use aurora_refiner_types::near_primitives::views::ActionView;
+use aurora_standalone_engine::upgrade::UpgradeCall;
+use engine_standalone_storage::sync::types::TransactionKindTag;
+use std::str::FromStr;
+
+/// Administrative call of the engine made by `action`, refined as `tx`.
+pub fn engine_event(action: &ActionView, tx: &AuroraTransaction) -> Option<EngineEvent> {
+    let kind = match action {
+        ActionView::FunctionCall {
+            method_name, args, ..
+        } => decode_call(method_name, args)?,
+        ActionView::DeployContract { code } => EngineEventKind::DeployContract {
+            code_hash: CryptoHash::hash_bytes(code),
+        },
+        _ => return None,
+    };
+    let near_metadata = &tx.near_metadata;
+
+    Some(EngineEvent {
+        kind,
+        transaction_hash: tx.hash,
+        receipt_id: near_metadata.receipt_hash,
+        action_index: near_metadata.action_index,
+        near_transaction_hash: near_metadata.transaction_hash,
+        predecessor_id: near_metadata.predecessor_id.clone()?,
+        signer_id: near_metadata.signer_id.clone()?,
+    })
+}
+
+/// Decodes the arguments of an administrative call of the engine. Returns `None` for the other
+/// methods, and for arguments that cannot be decoded.
+fn decode_call(method_name: &str, args: &[u8]) -> Option<EngineEventKind> {
+    let tag = TransactionKindTag::from_str(method_name).unwrap_or(TransactionKindTag::Unknown);
+    let kind = match tag {
+        TransactionKindTag::SetOwner => EngineEventKind::SetOwner {
+            new_owner: AccountId::try_from_slice(args).ok()?,
+        },
+        TransactionKindTag::PausePrecompiles => EngineEventKind::PausePrecompiles {
+            paused_mask: u32::try_from_slice(args).ok()?,
+        },
+        TransactionKindTag::ResumePrecompiles => EngineEventKind::ResumePrecompiles {
+            paused_mask: u32::try_from_slice(args).ok()?,
+        },
+        // The relayer address is passed as raw bytes
+        TransactionKindTag::RegisterRelayer => EngineEventKind::RegisterRelayer {
+            relayer: Address::try_from_slice(args).ok()?,
+        },
+        TransactionKindTag::SetUpgradeDelayBlocks => EngineEventKind::SetUpgradeDelayBlocks {
+            upgrade_delay_blocks: u64::try_from_slice(args).ok()?,
+        },
+        // The router code is passed as raw bytes
+        TransactionKindTag::FactoryUpdate => EngineEventKind::FactoryUpdate {
+            code_hash: CryptoHash::hash_bytes(args),
+        },
+        TransactionKindTag::SetFixedGas => EngineEventKind::SetFixedGas {
+            fixed_gas: Option::<u64>::try_from_slice(args).ok()?,
+        },
+        TransactionKindTag::SetErc20FallbackAddress => EngineEventKind::SetErc20FallbackAddress {
+            address: Option::<Address>::try_from_slice(args).ok()?,
+        },
+        TransactionKindTag::SetSiloParams => EngineEventKind::SetSiloParams {
+            params: Option::<SiloParamsArgs>::try_from_slice(args)
+                .ok()?
+                .map(silo_params),
+        },
+        TransactionKindTag::AddEntryToWhitelist => EngineEventKind::AddEntryToWhitelist(
+            whitelist_entry(WhitelistArgs::try_from_slice(args).ok()?)?,
+        ),
+        TransactionKindTag::AddEntryToWhitelistBatch => EngineEventKind::AddEntryToWhitelistBatch(
+            Vec::<WhitelistArgs>::try_from_slice(args)
+                .ok()?
+                .into_iter()
+                .map(whitelist_entry)
+                .collect::<Option<_>>()?,
+        ),
+        TransactionKindTag::RemoveEntryFromWhitelist => EngineEventKind::RemoveEntryFromWhitelist(
+            whitelist_entry(WhitelistArgs::try_from_slice(args).ok()?)?,
+        ),
+        TransactionKindTag::SetWhitelistStatus => EngineEventKind::SetWhitelistStatus(
+            whitelist_status(WhitelistStatusArgs::try_from_slice(args).ok()?),
+        ),
+        TransactionKindTag::SetWhitelistsStatuses => EngineEventKind::SetWhitelistsStatuses(
+            Vec::<WhitelistStatusArgs>::try_from_slice(args)
+                .ok()?
+                .into_iter()
+                .map(whitelist_status)
+                .collect(),
+        ),
+        // The only call with JSON arguments
+        TransactionKindTag::SetErc20Metadata => {
+            let SetErc20MetadataArgs {
+                erc20_identifier,
+                metadata,
+            } = serde_json::from_slice(args).ok()?;
+            let token = match erc20_identifier {
+                Erc20Identifier::Erc20 { address } => Erc20Token::Erc20(address),
+                Erc20Identifier::Nep141 { nep141 } => {
+                    Erc20Token::Nep141(nep141.as_ref().parse().ok()?)
+                }
+            };
+            EngineEventKind::SetErc20Metadata {
+                token,
+                name: metadata.name,
+                symbol: metadata.symbol,
+                decimals: metadata.decimals,
+            }
+        }
+        // The upgrade methods are not transactions of the standalone engine
+        TransactionKindTag::Unknown => match UpgradeCall::parse(method_name, args)? {
+            UpgradeCall::Stage { code_hash } => EngineEventKind::StageUpgrade { code_hash },
+            UpgradeCall::DeployStaged => EngineEventKind::DeployUpgrade,
+            UpgradeCall::Upgrade(params) => {
+                let UpgradeParams {
+                    code,
+                    state_migration_gas,
+                } = params?;
+                EngineEventKind::Upgrade {
+                    code_hash: CryptoHash::hash_bytes(&code),
+                    state_migration_gas,
+                }
+            }
+        },
+        _ => return None,
+    };
+
+    Some(kind)
+}
+
+fn silo_params(args: SiloParamsArgs) -> SiloParams {
+    SiloParams {
+        fixed_gas: args.fixed_gas.as_u64(),
+        erc20_fallback_address: args.erc20_fallback_address,
+    }
+}
+
+const fn whitelist_kind(kind: silo::WhitelistKind) -> WhitelistKind {
+    match kind {
+        silo::WhitelistKind::Admin => WhitelistKind::Admin,
+        silo::WhitelistKind::EvmAdmin => WhitelistKind::EvmAdmin,
+        silo::WhitelistKind::Account => WhitelistKind::Account,
+        silo::WhitelistKind::Address => WhitelistKind::Address,
+    }
+}
+
+fn whitelist_entry(args: WhitelistArgs) -> Option<WhitelistEntry> {
+    let entry = match args {
+        WhitelistArgs::WhitelistAddressArgs(args) => WhitelistEntry::Address {
+            kind: whitelist_kind(args.kind),
+            address: args.address,
+        },
+        WhitelistArgs::WhitelistAccountArgs(args) => WhitelistEntry::Account {
+            kind: whitelist_kind(args.kind),
+            account_id: args.account_id.as_ref().parse().ok()?,
+        },
+    };
+
+    Some(entry)
+}
+
+const fn whitelist_status(args: WhitelistStatusArgs) -> WhitelistStatus {
+    WhitelistStatus {
+        kind: whitelist_kind(args.kind),
+        active: args.active,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_call;
+    use aurora_engine_types::borsh;
+    use aurora_engine_types::types::Address;
+    use aurora_refiner_types::aurora_block::{
+        EngineEventKind, Erc20Token, SiloParams, WhitelistEntry, WhitelistKind, WhitelistStatus,
+    };
+    use aurora_refiner_types::near_primitives::hash::CryptoHash;
+    use aurora_refiner_types::near_primitives::types::AccountId;
+
+    #[test]
+    fn test_decode_call() {
+        let account_id: AccountId = "admin.aurora".parse().unwrap();
+        let address = Address::decode(&"01".repeat(20)).unwrap();
+        let code = vec![0, 97, 115, 109];
+
+        assert_eq!(
+            decode_call("set_owner", &borsh::to_vec(&account_id).unwrap()),
+            Some(EngineEventKind::SetOwner {
+                new_owner: account_id.clone()
+            })
+        );
+        assert_eq!(
+            decode_call("pause_precompiles", &borsh::to_vec(&3u32).unwrap()),
+            Some(EngineEventKind::PausePrecompiles { paused_mask: 3 })
+        );
+        assert_eq!(
+            decode_call("register_relayer", address.as_bytes()),
+            Some(EngineEventKind::RegisterRelayer { relayer: address })
+        );
+        assert_eq!(
+            decode_call(
+                "upgrade",
+                &borsh::to_vec(&(code.clone(), Some(100u64))).unwrap()
+            ),
+            Some(EngineEventKind::Upgrade {
+                code_hash: CryptoHash::hash_bytes(&code),
+                state_migration_gas: Some(100),
+            })
+        );
+        assert_eq!(
+            decode_call(
+                "set_silo_params",
+                &borsh::to_vec(&Some((21_000u64, address))).unwrap()
+            ),
+            Some(EngineEventKind::SetSiloParams {
+                params: Some(SiloParams {
+                    fixed_gas: 21_000,
+                    erc20_fallback_address: address,
+                })
+            })
+        );
+
+        // `WhitelistArgs::WhitelistAccountArgs` with the `Account` kind, then `WhitelistStatusArgs`
+        let mut entry = vec![1, 2];
+        entry.extend(borsh::to_vec(&account_id).unwrap());
+        assert_eq!(
+            decode_call("add_entry_to_whitelist", &entry),
+            Some(EngineEventKind::AddEntryToWhitelist(
+                WhitelistEntry::Account {
+                    kind: WhitelistKind::Account,
+                    account_id: account_id.clone(),
+                }
+            ))
+        );
+        assert_eq!(
+            decode_call("set_whitelist_status", &[3, 1]),
+            Some(EngineEventKind::SetWhitelistStatus(WhitelistStatus {
+                kind: WhitelistKind::Address,
+                active: true,
+            }))
+        );
+
+        let metadata = format!(
+            r#"{{"erc20_identifier":{{"address":"0x{}"}},"metadata":{{"name":"Token","symbol":"TKN","decimals":18}}}}"#,
+            address.encode()
+        );
+        assert_eq!(
+            decode_call("set_erc20_metadata", metadata.as_bytes()),
+            Some(EngineEventKind::SetErc20Metadata {
+                token: Erc20Token::Erc20(address),
+                name: "Token".to_string(),
+                symbol: "TKN".to_string(),
+                decimals: 18,
+            })
+        );
+
+        // Invalid arguments and other methods are ignored
+        assert_eq!(decode_call("set_owner", &[1, 2]), None);
+        assert_eq!(decode_call("submit", &[]), None);
+    }
+}
@@ -0,0 +1,142 @@
+use aurora_engine_types::H256;
+use aurora_engine_types::types::Address;
+use aurora_refiner_types::aurora_block::{AuroraBlock, AuroraTransaction, BridgeEventKind};
+use serde::Deserialize;
+
+/// Selects the transactions of the refined blocks that are kept in the output.
+///
+/// A transaction is kept if it matches any of the criteria. A filter without criteria keeps
+/// every transaction. Blocks are never removed, only their transactions, so the heights of the
+/// output stay consecutive. The block headers are not modified: the gas used, the roots, the
+/// logs bloom and the state diff still describe all the transactions of the block.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlockFilter {
+    /// Senders of the transactions to keep.
+    #[serde(default)]
+    pub from: Vec<Address>,
+    /// Targets of the transactions to keep.
+    #[serde(default)]
+    pub to: Vec<Address>,
+    /// Addresses of the contracts whose deployment transactions are kept.
+    #[serde(default)]
+    pub created_contracts: Vec<Address>,
+    /// Keep the transactions with a log emitted by one of these addresses.
+    #[serde(default)]
+    pub log_addresses: Vec<Address>,
+    /// Keep the transactions with a log having one of these topics, at any position.
+    #[serde(default)]
+    pub log_topics: Vec<H256>,
+}
+
+impl BlockFilter {
+    pub fn is_empty(&self) -> bool {
+        self.from.is_empty()
+            && self.to.is_empty()
+            && self.created_contracts.is_empty()
+            && self.log_addresses.is_empty()
+            && self.log_topics.is_empty()
+    }
+
+    pub fn matches(&self, tx: &AuroraTransaction) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+
+        let contains = |addresses: &[Address], address: Option<Address>| {
+            address.is_some_and(|address| addresses.contains(&address))
+        };
+        contains(&self.from, Some(tx.from))
+            || contains(&self.to, tx.to)
+            || contains(&self.created_contracts, tx.contract_address)
+            || tx.logs.iter().any(|log| {
+                self.log_addresses.contains(&log.address)
+                    || log
+                        .topics
+                        .iter()
+                        .any(|topic| self.log_topics.contains(&H256::from(*topic)))
+            })
+    }
+
+    /// Removes the transactions that do not match the filter from `block`, with their bridge
+    /// and engine events. The withdrawals are kept: their transaction is in an earlier block, so
+    /// it cannot be matched.
+    pub fn apply(&self, block: &mut AuroraBlock) {
+        block.transactions.retain(|tx| self.matches(tx));
+        let transactions = &block.transactions;
+        block.bridge_events.retain(|event| {
+            matches!(
+                event.kind,
+                BridgeEventKind::NearWithdrawal | BridgeEventKind::EthereumWithdrawal
+            ) || transactions
+                .iter()
+                .any(|tx| tx.hash == event.transaction_hash)
+        });
+        block.engine_events.retain(|event| {
+            transactions
+                .iter()
+                .any(|tx| tx.hash == event.transaction_hash)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlockFilter;
+    use aurora_engine::parameters::ResultLog;
+    use aurora_engine_types::H256;
+    use aurora_engine_types::types::Address;
+    use aurora_refiner_types::test_utils::read_aurora_block;
+
+    #[test]
+    fn test_block_filter() {
+        let block = read_aurora_block(100);
+        let tx_0 = &block.transactions[0];
+        let tx_1 = &block.transactions[1];
+
+        assert!(BlockFilter::default().matches(tx_0));
+
+        let filter = BlockFilter {
+            from: vec![tx_0.from],
+            ..Default::default()
+        };
+        assert!(filter.matches(tx_0));
+        assert!(!filter.matches(tx_1));
+
+        let filter = BlockFilter {
+            to: vec![tx_1.to.unwrap()],
+            ..Default::default()
+        };
+        assert!(!filter.matches(tx_0));
+        assert!(filter.matches(tx_1));
+
+        let mut block = read_aurora_block(100);
+        let emitter = Address::decode(&"01".repeat(20)).unwrap();
+        let topic = H256::repeat_byte(2);
+        block.transactions[1].logs.push(
+            ResultLog {
+                address: emitter,
+                topics: vec![[3; 32], topic.0],
+                data: Vec::new(),
+            }
+            .into(),
+        );
+        let filter = BlockFilter {
+            log_topics: vec![topic],
+            ..Default::default()
+        };
+        assert!(!filter.matches(&block.transactions[0]));
+        assert!(filter.matches(&block.transactions[1]));
+        let filter = BlockFilter {
+            log_addresses: vec![emitter],
+            ..Default::default()
+        };
+        assert!(filter.matches(&block.transactions[1]));
+
+        let height = block.height;
+        let hash = block.transactions[1].hash;
+        filter.apply(&mut block);
+        assert_eq!(block.height, height);
+        assert_eq!(block.transactions.len(), 1);
+        assert_eq!(block.transactions[0].hash, hash);
+    }
+}
@@ -96,7 +96,7 @@ pub fn validate_tx_hashchain(
             let result = crate::legacy::SubmitResultLegacyV1 {
                 status,
                 gas_used: transaction.gas_used,
-                logs: transaction.logs.clone(),
+                logs: transaction.logs.iter().map(|log| log.log.clone()).collect(),
             };
             Cow::Owned(borsh::to_vec(&result).expect(MUST_BORSH_SERIALIZE))
         }
@@ -120,7 +120,8 @@ pub fn validate_tx_hashchain(
         }
         HashchainOutputKind::SubmitResultV7(tag) => {
             let status = tag_to_status(tag, transaction);
-            let result = SubmitResult::new(status, transaction.gas_used, transaction.logs.clone());
+            let logs = transaction.logs.iter().map(|log| log.log.clone()).collect();
+            let result = SubmitResult::new(status, transaction.gas_used, logs);
             Cow::Owned(borsh::to_vec(&result).expect(MUST_BORSH_SERIALIZE))
         }
         HashchainOutputKind::Explicit => Cow::Borrowed(transaction.output.as_slice()),
@@ -302,7 +303,7 @@ mod tests {
             let near_block = read_block(file);
             let ctx = TestContext::new(&db_dir);
             let mut stream = ctx.create_stream();
-            let aurora_blocks = stream.next_block(&near_block).await;
+            let aurora_blocks = stream.next_block(&near_block).await.unwrap();
 
             for block in aurora_blocks {
                 assert!(compute_hashchain(H256::default(), &block).is_ok());
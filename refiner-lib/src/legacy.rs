@@ -1,7 +1,7 @@
 use aurora_engine::parameters::{ResultLog, SubmitResult, TransactionStatus};
 use aurora_engine_types::borsh::{self, BorshDeserialize, BorshSerialize};
 use aurora_engine_types::types::RawU256;
-use aurora_refiner_types::aurora_block::HashchainOutputKind;
+use aurora_refiner_types::aurora_block::{AuroraLog, HashchainOutputKind};
 use std::io::Result;
 
 #[derive(BorshSerialize, BorshDeserialize)]
@@ -75,7 +75,7 @@ impl From<SubmitResultLegacyV3> for SubmitResult {
     }
 }
 
-pub fn to_v1_logs(logs: &[ResultLog]) -> Vec<ResultLogV1> {
+pub fn to_v1_logs(logs: &[AuroraLog]) -> Vec<ResultLogV1> {
     logs.iter()
         .map(|l| ResultLogV1 {
             topics: l.topics.clone(),
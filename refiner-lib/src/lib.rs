@@ -1,5 +1,8 @@
 #![allow(clippy::literal_string_with_formatting_args)]
 
+pub mod bridge;
+pub mod engine_events;
+pub mod filter;
 pub mod hashchain;
 mod metrics;
 pub mod near_stream;
@@ -11,3 +14,5 @@ mod utils;
 pub use refiner::*;
 mod legacy;
 pub mod signal_handlers;
+pub mod sink;
+pub mod source;
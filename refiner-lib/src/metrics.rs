@@ -1,6 +1,10 @@
+use aurora_standalone_engine::upgrade::EngineVersion;
 use engine_standalone_storage::sync::types::TransactionKindTag;
 use lazy_static::lazy_static;
-use prometheus::{self, IntCounter, IntGauge, Opts, register_int_counter, register_int_gauge};
+use prometheus::{
+    self, IntCounter, IntGauge, IntGaugeVec, Opts, register_int_counter, register_int_gauge,
+    register_int_gauge_vec,
+};
 
 lazy_static! {
     pub static ref MISSING_SHARDS: IntCounter =
@@ -155,6 +159,27 @@ lazy_static! {
         "refiner_unknown_tx_for_receipt",
         "Number of receipts where the transaction provenance was not known (should be 0)"
     );
+    pub static ref SOURCE_TIME_LAG: IntGauge = gauge(
+        "refiner_source_time_lag_ms",
+        "Time elapsed, in milliseconds, since the last block received from the input was produced"
+    );
+    pub static ref SOURCE_BLOCK_LAG: IntGauge = gauge(
+        "refiner_source_block_lag",
+        "Number of blocks between the last block received from the input and its head"
+    );
+    pub static ref FAILED_BLOCKS: IntCounter = counter(
+        "refiner_failed_blocks",
+        "Number of blocks that could not be refined after applying the failure policy"
+    );
+    pub static ref DIFF_DIVERGENCES: IntCounter = counter(
+        "refiner_diff_divergences",
+        "Number of receipts whose computed diff diverged from the NEAR state changes (should be 0)"
+    );
+    pub static ref ENGINE_VERSION: IntGaugeVec = gauge_vec(
+        "refiner_engine_version",
+        "Version of the engine contract on chain, given by the labels (always 1)",
+        &["engine_account_id", "engine_version", "code_hash"]
+    );
     pub static ref TRANSACTION_TYPE_FACTORY_UPDATE: IntCounter = counter(
         "refiner_tx_type_factory_update",
         "Number of transactions of type: factory_update"
@@ -403,6 +428,31 @@ pub fn record_metric(tx_kind: &TransactionKindTag) {
     }
 }
 
+/// Exposes `version` as the engine version of `engine_account_id`, in place of `previous`.
+pub fn set_engine_version(
+    engine_account_id: &str,
+    previous: Option<&EngineVersion>,
+    version: &EngineVersion,
+) {
+    let labels = |version: &EngineVersion| {
+        [
+            engine_account_id.to_string(),
+            version.version.as_deref().unwrap_or("unknown").to_string(),
+            version
+                .code_hash
+                .map_or_else(|| "unknown".to_string(), |code_hash| code_hash.to_string()),
+        ]
+    };
+    if let Some(previous) = previous {
+        let previous = labels(previous);
+        let previous: Vec<&str> = previous.iter().map(String::as_str).collect();
+        let _ = ENGINE_VERSION.remove_label_values(&previous);
+    }
+    let version = labels(version);
+    let version: Vec<&str> = version.iter().map(String::as_str).collect();
+    ENGINE_VERSION.with_label_values(&version).set(1);
+}
+
 fn counter(name: &str, help: &str) -> IntCounter {
     register_int_counter!(opts(name, help)).unwrap()
 }
@@ -411,6 +461,10 @@ fn gauge(name: &str, help: &str) -> IntGauge {
     register_int_gauge!(opts(name, help)).unwrap()
 }
 
+fn gauge_vec(name: &str, help: &str, labels: &[&str]) -> IntGaugeVec {
+    register_int_gauge_vec!(opts(name, help), labels).unwrap()
+}
+
 fn opts(name: &str, help: &str) -> Opts {
     let version = version();
     prometheus::opts!(name, help, prometheus::labels! {"version" => &version })
@@ -1,3 +1,4 @@
+use crate::RefinerOptions;
 use crate::metrics::{PROCESSED_BLOCKS, SKIP_BLOCKS};
 use crate::refiner_inner::Refiner;
 use crate::tx_hash_tracker::TxHashTracker;
@@ -5,6 +6,11 @@ use aurora_engine_modexp::AuroraModExp;
 use aurora_refiner_types::aurora_block::AuroraBlock;
 use aurora_refiner_types::near_block::NEARBlock;
 use aurora_standalone_engine::EngineContext;
+use aurora_standalone_engine::state_diff::block_state_diff;
+use aurora_standalone_engine::sync::SyncError;
+use aurora_standalone_engine::tracing::lib::to_refined_call_frame;
+use aurora_standalone_engine::upgrade::EngineVersion;
+use std::collections::HashMap;
 
 pub struct NearStream {
     /// Keep track of last block seen, to report empty blocks
@@ -15,47 +21,84 @@ pub struct NearStream {
     context: EngineContext,
     /// Helper to track the NEAR transaction hash associated with each NEAR receipt.
     tx_tracker: TxHashTracker,
+    /// Engine version last exposed in the metrics
+    engine_version: EngineVersion,
+    /// Trace the EVM execution of the transactions, and attach the call frames to them
+    call_traces: bool,
+    /// Attach the changes to the state of the EVM accounts to the blocks
+    state_diffs: bool,
 }
 
 impl NearStream {
     pub fn new(
-        chain_id: u64,
         last_block_height: Option<u64>,
         context: EngineContext,
         tx_tracker: TxHashTracker,
+        options: &RefinerOptions,
     ) -> Self {
         let engine_account_id = context
             .engine_account_id
             .as_ref()
             .parse()
             .expect("Engine account ID must be valid");
+        let engine_version = context.engine_version().clone();
+        crate::metrics::set_engine_version(
+            context.engine_account_id.as_ref(),
+            None,
+            &engine_version,
+        );
         Self {
             last_block_height,
-            handler: Refiner::new(chain_id, engine_account_id),
+            handler: Refiner::new(
+                context.chain_id(),
+                engine_account_id,
+                options.include_failed_receipts,
+            ),
             context,
             tx_tracker,
+            engine_version,
+            call_traces: options.call_traces,
+            state_diffs: options.state_diffs,
         }
     }
 
-    async fn handle_block(&mut self, near_block: &NEARBlock) -> AuroraBlock {
+    /// Refines a single block. On error, including when the transaction tracker fails after the
+    /// engine consumed the block, the transactions of the block committed to the engine storage
+    /// are reverted and the data receipts they consumed are given back. The block metadata, the
+    /// data receipts and the receipts recorded by the transaction tracker are kept, but they are
+    /// written again identically when the block is retried. The high-water mark of the tracker
+    /// is only advanced once the whole block is refined.
+    async fn handle_block(&mut self, near_block: &NEARBlock) -> Result<AuroraBlock, BlockError> {
         self.handler.on_block_start(near_block);
 
+        self.tx_tracker
+            .consume_near_block(near_block)
+            .map_err(BlockError::TxTracker)?;
+
         let mut txs = Default::default();
+        let mut traces = HashMap::new();
 
         // Can specify a concrete modexp algorithm here because only transactions
         // that executed successfully on-chain are executed again here.
-        aurora_standalone_engine::consume_near_block::<AuroraModExp>(
+        let result = aurora_standalone_engine::consume_near_block::<AuroraModExp>(
             near_block,
             &mut self.context,
             Some(&mut txs),
+            self.call_traces.then_some(&mut traces),
         )
-        .await
-        .unwrap(); // Panic if the engine can't consume this block
+        .await;
+        crate::metrics::DIFF_DIVERGENCES.inc_by(self.context.take_diff_divergences());
+        if self.context.engine_version() != &self.engine_version {
+            let engine_version = self.context.engine_version().clone();
+            crate::metrics::set_engine_version(
+                self.context.engine_account_id.as_ref(),
+                Some(&self.engine_version),
+                &engine_version,
+            );
+            self.engine_version = engine_version;
+        }
+        let committed = result.map_err(BlockError::Engine)?;
 
-        // Panic if the transaction hash tracker cannot consume the block
-        self.tx_tracker
-            .consume_near_block(near_block)
-            .expect("Transaction tracker consume_near_block error");
         let storage = self.context.storage.as_ref().write().await;
         near_block
             .shards
@@ -66,50 +109,142 @@ impl NearStream {
             })
             .for_each(|outcome| {
                 let rx_hash = &outcome.receipt.receipt_id;
-                let near_tx_hash = self.tx_tracker.get_tx_hash(rx_hash);
-                if near_tx_hash.is_none() {
+                let lineage = self.tx_tracker.get_lineage(rx_hash);
+                if lineage.is_none() {
                     tracing::warn!("Transaction provenance unknown for receipt {}", rx_hash);
                     crate::metrics::UNKNOWN_TX_FOR_RECEIPT.inc();
                 }
-                self.handler.on_execution_outcome(
-                    near_block,
-                    near_tx_hash,
-                    outcome,
-                    &txs,
-                    &storage,
-                );
+                self.handler
+                    .on_execution_outcome(near_block, lineage, outcome, &txs, &storage);
+            });
+        // Receipts spawned by the exits of earlier transactions carry the withdrawals
+        near_block
+            .shards
+            .iter()
+            .flat_map(|shard| shard.receipt_execution_outcomes.as_slice())
+            .filter(|outcome| {
+                outcome.receipt.predecessor_id.as_bytes()
+                    == self.context.engine_account_id.as_bytes()
+            })
+            .for_each(|outcome| {
+                let lineage = self.tx_tracker.get_lineage(&outcome.receipt.receipt_id);
+                self.handler
+                    .on_exit_receipt(near_block, lineage, outcome, &storage);
             });
 
-        let aurora_block = self.handler.on_block_end(near_block);
-        self.tx_tracker
-            .on_block_end(near_block.block.header.height)
-            .expect("Transaction tracker on_block_end error");
-        aurora_block
+        let mut aurora_block = self.handler.on_block_end(near_block);
+        for tx in &mut aurora_block.transactions {
+            if let Some(call_stack) = traces.remove(&tx.hash) {
+                tx.call_trace = call_stack.into_iter().map(to_refined_call_frame).collect();
+            }
+        }
+        if self.state_diffs {
+            aurora_block.state_diff = block_state_diff(
+                &storage,
+                near_block.block.header.height,
+                committed.changed_keys(),
+            );
+        }
+        drop(storage);
+        if let Err(err) = self.record_block(near_block.block.header.height, &aurora_block) {
+            // The block must not stay in the engine storage if it is not emitted, otherwise
+            // retrying it would apply its transactions twice
+            if let Err(revert_err) = self.context.revert_block(committed).await {
+                tracing::warn!("Failed to revert block after error: {err:#}");
+                return Err(BlockError::Engine(revert_err));
+            }
+            return Err(BlockError::TxTracker(err));
+        }
+
+        Ok(aurora_block)
     }
 
-    pub async fn next_block(&mut self, near_block: &NEARBlock) -> Vec<AuroraBlock> {
-        let mut blocks = vec![];
+    /// Records in the transaction tracker the receipts spawned by the transactions of a refined
+    /// block, and marks the block as done.
+    fn record_block(&mut self, height: u64, aurora_block: &AuroraBlock) -> anyhow::Result<()> {
+        // The receipts spawned by the transactions, and the ones they spawn in turn, are linked
+        // back to the Aurora transaction at the root of the call tree
+        let origins = aurora_block.transactions.iter().flat_map(|tx| {
+            let origin = tx.near_metadata.origin_transaction_hash.unwrap_or(tx.hash);
+            tx.near_metadata
+                .spawned_receipt_ids
+                .iter()
+                .map(move |rx_hash| (*rx_hash, origin))
+        });
+        self.tx_tracker.record_aurora_origins(height, origins)?;
+        self.tx_tracker.on_block_end(height)
+    }
 
+    /// Emits the skip blocks between the last block seen and `near_block`.
+    fn skip_blocks(&self, near_block: &NEARBlock) -> Vec<AuroraBlock> {
         let height = near_block.block.header.height;
-
-        // Emit events for all skip blocks
+        let mut blocks = vec![];
         let mut last_height = self.last_block_height.unwrap_or(height);
         while last_height + 1 < height {
             last_height += 1;
-            let skip_block = self.handler.on_block_skip(last_height, near_block);
-            blocks.push(skip_block);
-            SKIP_BLOCKS.inc();
+            blocks.push(self.handler.on_block_skip(last_height, near_block));
         }
+        blocks
+    }
 
-        self.last_block_height = Some(height);
-        let block = self.handle_block(near_block).await;
+    /// Refines `near_block`, preceded by the skip blocks since the last block seen. On error,
+    /// the stream is left unchanged, so the same block can be passed again.
+    pub async fn next_block(
+        &mut self,
+        near_block: &NEARBlock,
+    ) -> Result<Vec<AuroraBlock>, BlockError> {
+        let mut blocks = self.skip_blocks(near_block);
+        let block = self.handle_block(near_block).await?;
+        SKIP_BLOCKS.inc_by(blocks.len() as u64);
+
+        self.last_block_height = Some(near_block.block.header.height);
         blocks.push(block);
         PROCESSED_BLOCKS.inc();
 
+        Ok(blocks)
+    }
+
+    /// Gives up on `near_block`: it is marked as seen without being refined, so the output will
+    /// have a gap at its height. Returns the skip blocks that precede it.
+    pub fn drop_block(&mut self, near_block: &NEARBlock) -> Vec<AuroraBlock> {
+        let blocks = self.skip_blocks(near_block);
+        SKIP_BLOCKS.inc_by(blocks.len() as u64);
+        self.last_block_height = Some(near_block.block.header.height);
         blocks
     }
 }
 
+/// Errors that can happen while refining a block.
+#[derive(Debug)]
+pub enum BlockError {
+    /// The engine failed to consume the block.
+    Engine(SyncError),
+    /// The transaction hash tracker failed to consume the block.
+    TxTracker(anyhow::Error),
+}
+
+impl BlockError {
+    /// Errors that must stop the refiner whatever the failure policy: retrying cannot help, and
+    /// skipping the block would continue with the wrong engine logic, or with part of the block
+    /// left in the engine storage. All the other errors leave nothing committed, so the block
+    /// can safely be refined again.
+    pub const fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            Self::Engine(SyncError::IncompatibleUpgrade { .. } | SyncError::Revert(_))
+        )
+    }
+}
+
+impl std::fmt::Display for BlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Engine(err) => write!(f, "engine error: {err}"),
+            Self::TxTracker(err) => write!(f, "transaction tracker error: {err:#}"),
+        }
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     use aurora_engine::{engine::setup_receive_erc20_tokens_input, state::EngineStateError};
@@ -120,7 +255,11 @@ pub mod tests {
         account_id::AccountId,
         types::{Address, Wei},
     };
-    use aurora_refiner_types::aurora_block::NearBlock;
+    use aurora_refiner_types::aurora_block::{
+        BridgeAccount, BridgeEventKind, BridgeToken, NearBlock, ValueChange,
+    };
+    use aurora_standalone_engine::sync::last_processed_height;
+    use aurora_standalone_engine::upgrade::UpgradeConfig;
     use engine_standalone_storage::json_snapshot::{initialize_engine_state, types::JsonSnapshot};
     use std::{collections::HashSet, matches};
 
@@ -136,7 +275,7 @@ pub mod tests {
         let mut stream = ctx.create_stream();
         let block = read_block("tests/res/testnet-block-120572296.json");
 
-        let mut aurora_blocks = stream.next_block(&block).await;
+        let mut aurora_blocks = stream.next_block(&block).await.unwrap();
 
         assert_eq!(aurora_blocks.len(), 1);
         let aurora_block = aurora_blocks.pop().unwrap();
@@ -168,7 +307,7 @@ pub mod tests {
         }
 
         let block = read_block("tests/res/block_131407300.json");
-        let _ = stream.next_block(&block).await;
+        stream.next_block(&block).await.unwrap();
         let chain_id_from_state = stream
             .context
             .storage
@@ -188,7 +327,7 @@ pub mod tests {
         let mut stream = ctx.create_stream();
         let block = read_block("tests/res/block-89402026.json");
 
-        let mut aurora_blocks = stream.next_block(&block).await;
+        let mut aurora_blocks = stream.next_block(&block).await.unwrap();
         assert_eq!(aurora_blocks.len(), 1);
         let aurora_block = aurora_blocks.pop().unwrap();
 
@@ -207,7 +346,7 @@ pub mod tests {
         let mut stream = ctx.create_stream();
         let block = read_block("tests/res/block-84423722.json");
 
-        let mut aurora_blocks = stream.next_block(&block).await;
+        let mut aurora_blocks = stream.next_block(&block).await.unwrap();
 
         assert_eq!(aurora_blocks.len(), 1);
         let aurora_block = aurora_blocks.pop().unwrap();
@@ -221,7 +360,7 @@ pub mod tests {
         let mut stream = ctx.create_stream();
         let block = read_block("tests/res/block-81206675.json");
 
-        let mut aurora_blocks = stream.next_block(&block).await;
+        let mut aurora_blocks = stream.next_block(&block).await.unwrap();
 
         assert_eq!(aurora_blocks.len(), 1);
         let aurora_block = aurora_blocks.pop().unwrap();
@@ -253,6 +392,14 @@ pub mod tests {
             "713e400b032b89db9f68105e501ff13260398490"
         );
         assert_eq!(tx_1.logs.len(), 3);
+        assert!(tx_1.near_metadata.gas_burnt.is_some_and(|gas| gas > 0));
+        assert!(
+            tx_1.near_metadata
+                .tokens_burnt
+                .is_some_and(|tokens| tokens > 0)
+        );
+        assert!(tx_1.near_metadata.signer_id.is_some());
+        assert!(tx_1.near_metadata.predecessor_id.is_some());
 
         let tx_2 = &aurora_block.transactions[1];
         assert_eq!(
@@ -268,6 +415,39 @@ pub mod tests {
             "c6e5185438e1730959c1ef3551059a3fec744e90"
         );
         assert_eq!(tx_2.logs.len(), 1);
+
+        // Receipt fields numbered across the block
+        assert_eq!(tx_1.cumulative_gas_used, tx_1.gas_used);
+        assert_eq!(tx_2.cumulative_gas_used, tx_1.gas_used + tx_2.gas_used);
+        let last_tx = aurora_block.transactions.last().unwrap();
+        assert_eq!(last_tx.cumulative_gas_used, aurora_block.gas_used);
+        let log_indexes: Vec<_> = aurora_block
+            .transactions
+            .iter()
+            .flat_map(|tx| tx.indexed_logs().map(|(log_index, _)| log_index))
+            .collect();
+        assert_eq!(log_indexes[..4], [0, 1, 2, 3]);
+        assert!(log_indexes.windows(2).all(|w| w[1] == w[0] + 1));
+
+        // Every log of the JSON output carries its position
+        let json = serde_json::to_value(&aurora_block).unwrap();
+        let mut log_index = 0_u32;
+        for (tx, tx_json) in aurora_block
+            .transactions
+            .iter()
+            .zip(json["transactions"].as_array().unwrap())
+        {
+            for log in tx_json["logs"].as_array().unwrap() {
+                assert_eq!(log["log_index"], log_index);
+                assert_eq!(log["transaction_index"], tx.transaction_index);
+                assert_eq!(
+                    log["transaction_hash"],
+                    serde_json::to_value(tx.hash).unwrap()
+                );
+                log_index += 1;
+            }
+        }
+        assert_eq!(log_index as usize, log_indexes.len());
     }
 
     #[tokio::test]
@@ -284,12 +464,121 @@ pub mod tests {
         let expected_nonce = 12773;
 
         // run and assert
-        let aurora_block = stream.next_block(&block).await.pop().unwrap();
+        let aurora_block = stream.next_block(&block).await.unwrap().pop().unwrap();
 
         assert_eq!(aurora_block.transactions.len(), 1);
         let target_aurora_tx = aurora_block.transactions.first().unwrap();
 
         assert_eq!(target_aurora_tx.nonce, expected_nonce);
+        assert!(target_aurora_tx.call_trace.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_block_82654651_tracker_failure_after_commit() {
+        let snapshot = "tests/res/sate_H7Bfh9qCzWbJW9acao8B2jFMTrkfc31toczmTcMv7hY7.json";
+        let block = read_block("tests/res/block-82654651.json");
+
+        let db_dir = tempfile::tempdir().unwrap();
+        let mut ctx = TestContext::new(&db_dir);
+        ctx.init_with_snapshot(snapshot).await;
+        let expected = ctx
+            .create_stream()
+            .next_block(&block)
+            .await
+            .unwrap()
+            .pop()
+            .unwrap();
+        assert_eq!(expected.transactions.len(), 1);
+        let tx_hash = expected.transactions[0].hash;
+
+        let db_dir = tempfile::tempdir().unwrap();
+        let mut ctx = TestContext::new(&db_dir);
+        ctx.init_with_snapshot(snapshot).await;
+        let mut stream = ctx.create_stream();
+
+        // The tracker fails once the engine has committed the transaction of the block
+        stream.tx_tracker.fail_on_block_end = true;
+        let result = stream.next_block(&block).await;
+        assert!(matches!(result, Err(BlockError::TxTracker(_))));
+        assert_eq!(stream.last_block_height, None);
+
+        // The transaction is reverted, so the engine state is the same as before the block
+        let storage = stream.context.storage.as_ref().read().await;
+        assert!(storage.get_transaction_data(tx_hash).is_err());
+        assert_eq!(last_processed_height(&storage).unwrap(), None);
+        drop(storage);
+
+        // And the block is refined on retry as if it was the first attempt
+        stream.tx_tracker.fail_on_block_end = false;
+        let aurora_block = stream.next_block(&block).await.unwrap().pop().unwrap();
+        assert_eq!(
+            serde_json::to_value(&aurora_block).unwrap(),
+            serde_json::to_value(&expected).unwrap()
+        );
+        let storage = stream.context.storage.as_ref().read().await;
+        assert!(storage.get_transaction_data(tx_hash).is_ok());
+        assert_eq!(
+            last_processed_height(&storage).unwrap(),
+            Some(block.block.header.height)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_block_82654651_state_diff() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let mut ctx = TestContext::new(&db_dir);
+        ctx.init_with_snapshot("tests/res/sate_H7Bfh9qCzWbJW9acao8B2jFMTrkfc31toczmTcMv7hY7.json")
+            .await;
+        let mut stream = ctx.create_stream_with_options(&RefinerOptions {
+            state_diffs: true,
+            ..Default::default()
+        });
+
+        let block = read_block("tests/res/block-82654651.json");
+        let aurora_block = stream.next_block(&block).await.unwrap().pop().unwrap();
+
+        assert_eq!(aurora_block.transactions.len(), 1);
+        let tx = aurora_block.transactions.first().unwrap();
+        let sender = aurora_block
+            .state_diff
+            .iter()
+            .find(|diff| diff.address == tx.from)
+            .unwrap();
+        assert_eq!(
+            sender.nonce,
+            Some(ValueChange {
+                old: U256::from(tx.nonce),
+                new: U256::from(tx.nonce + 1),
+            })
+        );
+        assert!(
+            aurora_block
+                .state_diff
+                .windows(2)
+                .all(|w| w[0].address.raw() < w[1].address.raw())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_block_82654651_call_trace() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let mut ctx = TestContext::new(&db_dir);
+        ctx.init_with_snapshot("tests/res/sate_H7Bfh9qCzWbJW9acao8B2jFMTrkfc31toczmTcMv7hY7.json")
+            .await;
+        let mut stream = ctx.create_stream_with_options(&RefinerOptions {
+            call_traces: true,
+            ..Default::default()
+        });
+
+        let block = read_block("tests/res/block-82654651.json");
+        let aurora_block = stream.next_block(&block).await.unwrap().pop().unwrap();
+
+        assert_eq!(aurora_block.transactions.len(), 1);
+        let tx = aurora_block.transactions.first().unwrap();
+        assert_eq!(tx.call_trace.len(), 1);
+        let frame = &tx.call_trace[0];
+        assert_eq!(frame.from, tx.from);
+        assert_eq!(frame.output, tx.output);
     }
 
     // Tests processing the transaction https://explorer.mainnet.near.org/transactions/964KbgjnkCfyUS1kaHVJNGuXAMsahdNHiP1jWkMnx1Bk
@@ -308,7 +597,7 @@ pub mod tests {
         let block = read_block("tests/res/block-75306841.json");
 
         // run and assert
-        let mut aurora_block = stream.next_block(&block).await.pop().unwrap();
+        let mut aurora_block = stream.next_block(&block).await.unwrap().pop().unwrap();
 
         assert_eq!(aurora_block.transactions.len(), 1);
         let mut target_aurora_tx = aurora_block.transactions.pop().unwrap();
@@ -348,7 +637,7 @@ pub mod tests {
         // near block 70834059
         let near_block = read_block("tests/res/block-70834059.json");
 
-        let aurora_blocks = stream.next_block(&near_block).await;
+        let aurora_blocks = stream.next_block(&near_block).await.unwrap();
 
         assert_eq!(aurora_blocks.len(), 1);
         assert_eq!(aurora_blocks[0].height, 70834059);
@@ -360,7 +649,7 @@ pub mod tests {
         // near skip block 70834061; 70834060 does not exist
         let near_skip_block = read_block("tests/res/block-70834061.json");
 
-        let aurora_blocks = stream.next_block(&near_skip_block).await;
+        let aurora_blocks = stream.next_block(&near_skip_block).await.unwrap();
 
         assert_eq!(aurora_blocks.len(), 2);
         assert_eq!(aurora_blocks[0].height, 70834060); // dummy skip aurora block
@@ -375,6 +664,74 @@ pub mod tests {
         ));
     }
 
+    #[tokio::test]
+    async fn test_block_70834059_failed_receipts() {
+        // The block at height 70834059 contains five `submit` receipts that failed on NEAR.
+        let near_block = read_block("tests/res/block-70834059.json");
+
+        let db_dir = tempfile::tempdir().unwrap();
+        let mut stream = TestContext::new(&db_dir).create_stream();
+        let aurora_block = stream.next_block(&near_block).await.unwrap().pop().unwrap();
+        assert!(
+            aurora_block
+                .transactions
+                .iter()
+                .all(|tx| tx.near_metadata.error.is_none())
+        );
+
+        let db_dir = tempfile::tempdir().unwrap();
+        let mut stream = TestContext::new(&db_dir).create_stream_with_options(&RefinerOptions {
+            include_failed_receipts: true,
+            ..Default::default()
+        });
+        let with_failed = stream.next_block(&near_block).await.unwrap().pop().unwrap();
+        let failed: Vec<_> = with_failed
+            .transactions
+            .iter()
+            .filter(|tx| tx.near_metadata.error.is_some())
+            .collect();
+        assert_eq!(failed.len(), 5);
+        assert_eq!(
+            with_failed.transactions.len(),
+            aurora_block.transactions.len() + failed.len()
+        );
+        assert_eq!(with_failed.gas_used, aurora_block.gas_used);
+        for tx in failed {
+            assert!(!tx.status);
+            assert_eq!(tx.gas_used, 0);
+            assert!(tx.logs.is_empty());
+            assert!(tx.near_metadata.hashchain_metadata.is_none());
+            assert!(
+                tx.near_metadata
+                    .error
+                    .as_ref()
+                    .unwrap()
+                    .contains("Smart contract panicked")
+            );
+        }
+
+        // The failed transactions are not part of the merkle trees and come after the others
+        assert_eq!(
+            with_failed.transactions_root,
+            aurora_block.transactions_root
+        );
+        assert_eq!(with_failed.receipts_root, aurora_block.receipts_root);
+        let num_txs = aurora_block.transactions.len();
+        for (tx, expected) in with_failed
+            .transactions
+            .iter()
+            .zip(&aurora_block.transactions)
+        {
+            assert_eq!(tx.hash, expected.hash);
+            assert_eq!(tx.transaction_index, expected.transaction_index);
+            assert_eq!(tx.first_log_index, expected.first_log_index);
+        }
+        for (index, tx) in with_failed.transactions.iter().enumerate().skip(num_txs) {
+            assert!(tx.near_metadata.error.is_some());
+            assert_eq!(tx.transaction_index as usize, index);
+        }
+    }
+
     #[tokio::test]
     async fn test_block_34834052_block_before_aurora_genesis() {
         let db_dir = tempfile::tempdir().unwrap();
@@ -384,7 +741,7 @@ pub mod tests {
         // near block 34834052; aurora block genesis is 34834053
         let near_block = read_block("tests/res/block-34834052.json");
 
-        let aurora_blocks = stream.next_block(&near_block).await;
+        let aurora_blocks = stream.next_block(&near_block).await.unwrap();
 
         assert_eq!(aurora_blocks.len(), 1);
         assert_eq!(aurora_blocks[0].height, 34834052);
@@ -402,7 +759,7 @@ pub mod tests {
 
         let near_block = read_block("tests/res/block_128945880.json");
 
-        let aurora_blocks = stream.next_block(&near_block).await;
+        let aurora_blocks = stream.next_block(&near_block).await.unwrap();
         assert_eq!(aurora_blocks.len(), 1);
         assert_eq!(aurora_blocks[0].height, 128945880);
         assert!(matches!(
@@ -429,6 +786,23 @@ pub mod tests {
             ft_on_transfer_eth_tx.is_some(),
             "Expected ft_on_transfer ETH mint transaction not found in block 128945880"
         );
+
+        let deposit = aurora_block
+            .bridge_events
+            .iter()
+            .find(|event| event.kind == BridgeEventKind::EthDeposit)
+            .expect("Expected ETH deposit event in block 128945880");
+        assert_eq!(
+            deposit.transaction_hash,
+            ft_on_transfer_eth_tx.unwrap().hash
+        );
+        assert_eq!(deposit.token.erc20, None);
+        assert_eq!(deposit.amount, expected_amount.raw());
+        assert_eq!(
+            deposit.sender,
+            BridgeAccount::Near("aurora".parse().unwrap())
+        );
+        assert_eq!(deposit.recipient, BridgeAccount::Aurora(expected_recipient));
     }
 
     #[tokio::test]
@@ -439,7 +813,10 @@ pub mod tests {
 
         // Read the block where the wNEAR contract is created to obtain a state that contains a key-value pair representing the wrap.near and ERC20 addresses.
         let near_block_wnear_contract_create = read_block("tests/res/block_42598892.json");
-        let aurora_blocks = stream.next_block(&near_block_wnear_contract_create).await;
+        let aurora_blocks = stream
+            .next_block(&near_block_wnear_contract_create)
+            .await
+            .unwrap();
         assert_eq!(aurora_blocks.len(), 1);
         assert_eq!(aurora_blocks[0].height, 42598892);
         assert!(matches!(
@@ -451,7 +828,7 @@ pub mod tests {
         stream.last_block_height = Some(125229394);
         // Read the block that contains the ERC20 token mint transaction.
         let near_block = read_block("tests/res/block_125229395.json");
-        let aurora_blocks = stream.next_block(&near_block).await;
+        let aurora_blocks = stream.next_block(&near_block).await.unwrap();
         assert_eq!(aurora_blocks.len(), 1);
         assert_eq!(aurora_blocks[0].height, 125229395);
         assert!(matches!(
@@ -487,6 +864,35 @@ pub mod tests {
             ft_on_transfer_erc20_tx.is_some(),
             "Expected ft_on_transfer ERC20 mint transaction not found in block 125229395"
         );
+
+        let mint = aurora_block
+            .bridge_events
+            .iter()
+            .find(|event| event.kind == BridgeEventKind::Nep141Mint)
+            .expect("Expected NEP-141 mint event in block 125229395");
+        assert_eq!(mint.transaction_hash, ft_on_transfer_erc20_tx.unwrap().hash);
+        assert_eq!(
+            mint.token,
+            BridgeToken {
+                nep141: Some("wrap.near".parse().unwrap()),
+                erc20: Some(expected_recipient),
+            }
+        );
+        assert_eq!(mint.amount, U256::from(11633748708758676689950_u128));
+        assert_eq!(
+            mint.sender,
+            BridgeAccount::Near(
+                "66fb1d3d0c8b3893b1b53a4a964a8b03586cc0db5c9621014f54efb11068b72e"
+                    .parse()
+                    .unwrap()
+            )
+        );
+        assert_eq!(
+            mint.recipient,
+            BridgeAccount::Aurora(
+                Address::decode("0fe957e6acbb4fd935cee5ba033e00088df86adb").unwrap()
+            )
+        );
     }
 
     #[tokio::test]
@@ -498,7 +904,7 @@ pub mod tests {
         let mut stream = ctx.create_stream();
 
         let block = read_block("tests/res/testnet_block_182018895.json");
-        let aurora_blocks = stream.next_block(&block).await;
+        let aurora_blocks = stream.next_block(&block).await.unwrap();
         assert_eq!(aurora_blocks.len(), 1);
         assert_eq!(aurora_blocks[0].height, 182018895);
         assert!(matches!(
@@ -536,7 +942,7 @@ pub mod tests {
         let mut stream = ctx.create_stream();
 
         let block = read_block("tests/res/testnet-block-232952651.json");
-        let aurora_blocks = stream.next_block(&block).await;
+        let aurora_blocks = stream.next_block(&block).await.unwrap();
         assert_eq!(aurora_blocks.len(), 1);
         assert_eq!(aurora_blocks[0].height, 232952651);
         assert!(matches!(
@@ -578,7 +984,7 @@ pub mod tests {
         let mut stream = ctx.create_stream();
 
         let block = read_block("tests/res/block-134585465.json");
-        let aurora_blocks = stream.next_block(&block).await;
+        let aurora_blocks = stream.next_block(&block).await.unwrap();
         assert_eq!(aurora_blocks.len(), 1);
         assert_eq!(aurora_blocks[0].height, 134585465);
         assert!(matches!(
@@ -620,7 +1026,7 @@ pub mod tests {
         let mut stream = ctx.create_stream();
 
         let block = read_block("tests/res/testnet-block-234187695.json");
-        let aurora_blocks = stream.next_block(&block).await;
+        let aurora_blocks = stream.next_block(&block).await.unwrap();
         assert_eq!(aurora_blocks.len(), 1);
         assert_eq!(aurora_blocks[0].height, 234187695);
         assert!(matches!(
@@ -641,7 +1047,6 @@ pub mod tests {
     }
 
     pub struct TestContext {
-        chain_id: u64,
         engine_context: EngineContext,
         tx_tracker: TxHashTracker,
     }
@@ -658,12 +1063,11 @@ pub mod tests {
         ) -> Self {
             let engine_path = db_dir.path().join("engine");
             let tracker_path = db_dir.path().join("tracker");
-            crate::storage::init_storage(&engine_path, &account_id, chain_id);
+            crate::storage::init_storage(&engine_path, &account_id, chain_id, false);
             let engine_context = EngineContext::new(&engine_path, account_id, chain_id).unwrap();
             let tx_tracker = TxHashTracker::new(tracker_path, 0).unwrap();
 
             Self {
-                chain_id,
                 engine_context,
                 tx_tracker,
             }
@@ -678,8 +1082,17 @@ pub mod tests {
             initialize_engine_state(&storage, json_snapshot).unwrap();
         }
 
+        pub fn with_upgrade_config(mut self, config: UpgradeConfig) -> Self {
+            self.engine_context = self.engine_context.with_upgrade_config(config);
+            self
+        }
+
         pub fn create_stream(self) -> NearStream {
-            NearStream::new(self.chain_id, None, self.engine_context, self.tx_tracker)
+            self.create_stream_with_options(&RefinerOptions::default())
+        }
+
+        pub fn create_stream_with_options(self, options: &RefinerOptions) -> NearStream {
+            NearStream::new(None, self.engine_context, self.tx_tracker, options)
         }
     }
 
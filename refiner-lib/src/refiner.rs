@@ -1,11 +1,19 @@
-use crate::near_stream::NearStream;
+use crate::near_stream::{BlockError, NearStream};
 use crate::tx_hash_tracker;
 use aurora_refiner_types::aurora_block::AuroraBlock;
 use aurora_refiner_types::near_block::NEARBlock;
 use aurora_standalone_engine::EngineContext;
+use serde::Deserialize;
 use std::fmt::Debug;
-use std::path::Path;
-use tracing::{info, warn};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Delay before the first attempt to refine a block again. It doubles after every attempt.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay between two attempts to refine the same block.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
 
 #[derive(Debug)]
 pub struct BlockWithMetadata<B: Debug, M: Debug + Clone> {
@@ -19,19 +27,86 @@ impl<B: Debug, M: Debug + Clone> BlockWithMetadata<B, M> {
     }
 }
 
+/// What the refiner does with a block it fails to refine.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FailurePolicy {
+    /// Stop the refiner.
+    #[default]
+    Halt,
+    /// Try to refine the block again, up to `attempts` more times with an exponential backoff,
+    /// then stop the refiner.
+    Retry { attempts: u32 },
+    /// Leave a gap in the output at the height of the block and continue with the next one.
+    Skip,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct FailureConfig {
+    #[serde(default)]
+    pub policy: FailurePolicy,
+    /// Folder where every failed block is written, as `<height>/block.json`, together with
+    /// the error in `<height>/error.txt`.
+    #[serde(default)]
+    pub quarantine_path: Option<PathBuf>,
+}
+
+/// How the blocks are refined.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct RefinerOptions {
+    /// What to do with blocks that cannot be refined.
+    #[serde(default)]
+    pub on_failure: FailureConfig,
+    /// Also output the receipts that failed on NEAR, as failed transactions without effects.
+    #[serde(default)]
+    pub include_failed_receipts: bool,
+    /// Attach the call frames of their EVM execution to the transactions. Every transaction is
+    /// executed under the call tracer, which slows the refiner down.
+    #[serde(default)]
+    pub call_traces: bool,
+    /// Attach to the blocks the changes they make to the state of the EVM accounts.
+    #[serde(default)]
+    pub state_diffs: bool,
+}
+
+/// Errors that stop the refiner.
+#[derive(Debug)]
+pub enum RunRefinerError {
+    /// The transaction hash tracker could not be opened.
+    TxTracker(anyhow::Error),
+    /// A block could not be refined and the failure policy does not allow to continue.
+    Block { height: u64, error: BlockError },
+    /// The output channel was closed before the block at `height` could be sent.
+    OutputClosed { height: u64 },
+}
+
+impl std::fmt::Display for RunRefinerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TxTracker(err) => write!(f, "failed to start transaction tracker: {err:#}"),
+            Self::Block { height, error } => write!(f, "failed to refine block {height}: {error}"),
+            Self::OutputClosed { height } => {
+                write!(f, "output closed before block {height} was sent")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RunRefinerError {}
+
+/// Refines the blocks of `input` for the engine of `ctx`, and sends them to `output`.
 pub async fn run_refiner<P: AsRef<Path> + Send, M: Debug + Clone + Send + Sync>(
     ctx: EngineContext,
-    chain_id: u64,
     tx_storage_path: P,
     mut input: tokio::sync::mpsc::Receiver<BlockWithMetadata<NEARBlock, M>>,
     output: tokio::sync::mpsc::Sender<BlockWithMetadata<AuroraBlock, M>>,
     last_block: Option<u64>,
+    options: &RefinerOptions,
     stop_signal: &mut tokio::sync::broadcast::Receiver<()>,
-) {
+) -> Result<(), RunRefinerError> {
     let tx_tracker =
         tx_hash_tracker::TxHashTracker::new(tx_storage_path, last_block.unwrap_or_default())
-            .expect("Failed to start transaction tracker");
-    let mut stream = NearStream::new(chain_id, last_block, ctx, tx_tracker);
+            .map_err(RunRefinerError::TxTracker)?;
+    let mut stream = NearStream::new(last_block, ctx, tx_tracker, options);
     let mut last_received_block: Option<u64> = None;
 
     info!(
@@ -48,14 +123,18 @@ pub async fn run_refiner<P: AsRef<Path> + Send, M: Debug + Clone + Send + Sync>(
             maybe_message = input.recv() => {
                 if let Some(message) = maybe_message {
                     let BlockWithMetadata { block, metadata } = message;
-                    for block in stream.next_block(&block).await {
+                    // The input can start earlier when it is shared with other refiners
+                    if last_block.is_some_and(|last_block| block.block.header.height <= last_block) {
+                        continue;
+                    }
+                    let blocks = refine_block(&mut stream, &block, &options.on_failure).await?;
+                    for block in blocks {
                         let block_height = block.height;
-                        // Unwrapping here, since it is better to crash the refiner than to make progress missing blocks.
+                        // It is better to stop the refiner than to make progress missing blocks.
                         output
                             .send(BlockWithMetadata::new(block, metadata.clone()))
                             .await
-                            .map_err(|_| ())
-                            .expect("Failed to send output message");
+                            .map_err(|_| RunRefinerError::OutputClosed { height: block_height })?;
                         last_received_block = Some(block_height);
                     }
                 } else {
@@ -71,4 +150,132 @@ pub async fn run_refiner<P: AsRef<Path> + Send, M: Debug + Clone + Send + Sync>(
     } else {
         warn!("Refiner stopped, no blocks received");
     }
+
+    Ok(())
+}
+
+/// Refines a block, applying the failure policy if it cannot be refined.
+async fn refine_block(
+    stream: &mut NearStream,
+    near_block: &NEARBlock,
+    failure_config: &FailureConfig,
+) -> Result<Vec<AuroraBlock>, RunRefinerError> {
+    let height = near_block.block.header.height;
+    let mut attempt = 0;
+    let mut delay = RETRY_DELAY;
+
+    let error = loop {
+        match stream.next_block(near_block).await {
+            Ok(blocks) => return Ok(blocks),
+            // Only the errors that leave nothing of the block committed can be retried
+            Err(err) => match failure_config.policy {
+                FailurePolicy::Retry { attempts } if attempt < attempts && !err.is_fatal() => {
+                    attempt += 1;
+                    warn!(
+                        "Failed to refine block {height} (attempt {attempt}), retrying in {delay:?}: {err}"
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+                _ => break err,
+            },
+        }
+    };
+
+    error!("Failed to refine block {height}: {error}");
+    crate::metrics::FAILED_BLOCKS.inc();
+    if let Some(quarantine_path) = &failure_config.quarantine_path {
+        match quarantine(quarantine_path, near_block, &error) {
+            Ok(path) => warn!("Block {height} quarantined in {}", path.display()),
+            Err(err) => error!("Failed to quarantine block {height}: {err:?}"),
+        }
+    }
+
+    if failure_config.policy == FailurePolicy::Skip && !error.is_fatal() {
+        warn!("Skipping block {height}, the output will have a gap at this height");
+        Ok(stream.drop_block(near_block))
+    } else {
+        Err(RunRefinerError::Block { height, error })
+    }
+}
+
+/// Writes the failed block and its error to `<quarantine_path>/<height>`.
+fn quarantine(
+    quarantine_path: &Path,
+    near_block: &NEARBlock,
+    error: &BlockError,
+) -> anyhow::Result<PathBuf> {
+    let path = quarantine_path.join(near_block.block.header.height.to_string());
+    std::fs::create_dir_all(&path)?;
+    let file = std::fs::File::create(path.join("block.json"))?;
+    serde_json::to_writer(std::io::BufWriter::new(file), near_block)?;
+    std::fs::write(path.join("error.txt"), format!("{error}\n\n{error:#?}\n"))?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        BlockError, FailureConfig, FailurePolicy, RunRefinerError, quarantine, refine_block,
+    };
+    use crate::near_stream::tests::{TestContext, read_block};
+    use aurora_refiner_types::near_block::NEARBlock;
+    use aurora_refiner_types::near_primitives::views::{ActionView, ReceiptEnumView};
+    use aurora_standalone_engine::sync::SyncError;
+    use aurora_standalone_engine::upgrade::{UpgradeConfig, UpgradePolicy};
+
+    #[test]
+    fn test_quarantine() {
+        let dir = tempfile::tempdir().unwrap();
+        let near_block = read_block("tests/res/block-51188689.json");
+        let error = BlockError::TxTracker(anyhow::anyhow!("disk full"));
+
+        let path = quarantine(dir.path(), &near_block, &error).unwrap();
+        assert_eq!(path, dir.path().join("51188689"));
+
+        let file = std::fs::File::open(path.join("block.json")).unwrap();
+        let quarantined: NEARBlock = serde_json::from_reader(file).unwrap();
+        assert_eq!(quarantined.block.header.hash, near_block.block.header.hash);
+        let error = std::fs::read_to_string(path.join("error.txt")).unwrap();
+        assert!(error.starts_with("transaction tracker error: disk full"));
+    }
+
+    #[tokio::test]
+    async fn test_incompatible_upgrade_is_never_skipped() {
+        // The engine receipt of the block is replaced by the deployment of an unknown code
+        let mut near_block = read_block("tests/res/block-82654651.json");
+        let outcome = near_block
+            .shards
+            .iter_mut()
+            .flat_map(|shard| shard.receipt_execution_outcomes.iter_mut())
+            .find(|outcome| outcome.receipt.receiver_id.as_str() == "aurora")
+            .unwrap();
+        if let ReceiptEnumView::Action { actions, .. } = &mut outcome.receipt.receipt {
+            *actions = vec![ActionView::DeployContract {
+                code: vec![0, 97, 115, 109],
+            }];
+        }
+
+        let db_dir = tempfile::tempdir().unwrap();
+        let mut stream = TestContext::new(&db_dir)
+            .with_upgrade_config(UpgradeConfig {
+                policy: UpgradePolicy::Halt,
+                ..Default::default()
+            })
+            .create_stream();
+        let failure_config = FailureConfig {
+            policy: FailurePolicy::Skip,
+            quarantine_path: None,
+        };
+
+        let result = refine_block(&mut stream, &near_block, &failure_config).await;
+        assert!(matches!(
+            result,
+            Err(RunRefinerError::Block {
+                error: BlockError::Engine(SyncError::IncompatibleUpgrade { .. }),
+                ..
+            })
+        ));
+    }
 }
@@ -17,8 +17,9 @@ use aurora_engine_types::types::{Address, Wei, WeiU256};
 use aurora_engine_types::{H256, U256};
 use aurora_refiner_types::aurora_block::{
     AdditionalSubmitArgs, AuroraBlock, AuroraTransaction, AuroraTransactionBuilder,
-    AuroraTransactionBuilderError, CallArgsVersion, HashchainInputKind, HashchainMetadata,
-    HashchainOutputKind, NearBlock, NearBlockHeader, NearTransaction,
+    AuroraTransactionBuilderError, BridgeAccount, BridgeEvent, BridgeEventKind, BridgeToken,
+    CallArgsVersion, EngineEvent, HashchainInputKind, HashchainMetadata, HashchainOutputKind,
+    NearBlock, NearBlockHeader, NearTransaction,
 };
 use aurora_refiner_types::bloom::Bloom;
 use aurora_refiner_types::near_block::{BlockView, ExecutionOutcomeWithReceipt, NEARBlock, Shard};
@@ -41,6 +42,7 @@ use triehash_ethereum::ordered_trie_root;
 
 use crate::legacy::decode_submit_result;
 use crate::metrics::{LATEST_BLOCK_PROCESSED, record_metric};
+use crate::tx_hash_tracker::ReceiptLineage;
 use crate::utils::{TxMetadata, as_h256, keccak256};
 
 /// The least amount of gas any EVM transaction could spend is 21_000.
@@ -88,6 +90,8 @@ pub struct Refiner {
     prev_state_root: H256,
     /// Partial state used during the computation of a block.
     partial_state: PartialState,
+    /// Whether the receipts that failed on NEAR are refined, as failed transactions
+    include_failed_receipts: bool,
 }
 
 /// Data that must be recomputed on every block
@@ -101,6 +105,13 @@ struct PartialState {
     bloom_filter: Bloom,
     /// List of all current transactions
     transactions: Vec<AuroraTransaction>,
+    /// Transactions of the receipts that failed on NEAR, appended to the others at the end of
+    /// the block. They are not part of the transactions and receipts merkle trees.
+    failed_transactions: Vec<AuroraTransaction>,
+    /// Tokens bridged by the current transactions
+    bridge_events: Vec<BridgeEvent>,
+    /// Administrative calls of the engine made by the current transactions
+    engine_events: Vec<EngineEvent>,
     /// Transactions data used to build transactions and receipts merkle tree
     transactions_extra_data: Vec<TxExtraData>,
     /// List with all observed receipts. A Receipt can be seen multiple times, one per action
@@ -108,7 +119,9 @@ struct PartialState {
 }
 
 impl Refiner {
-    pub fn new(chain_id: u64, engine_account_id: AccountId) -> Self {
+    /// With `include_failed_receipts`, the receipts that failed on NEAR are also refined, as
+    /// failed transactions without effects.
+    pub fn new(chain_id: u64, engine_account_id: AccountId, include_failed_receipts: bool) -> Self {
         Self {
             chain_id,
             engine_account_id,
@@ -123,6 +136,7 @@ impl Refiner {
             ),
             prev_state_root: H256::zero(),
             partial_state: Default::default(),
+            include_failed_receipts,
         }
     }
 }
@@ -147,6 +161,9 @@ impl Refiner {
             transactions_root: self.empty_merkle_tree_root,
             receipts_root: self.empty_merkle_tree_root,
             transactions: vec![],
+            bridge_events: vec![],
+            engine_events: vec![],
+            state_diff: vec![],
             near_metadata: NearBlock::SkipBlock,
             state_root: self.prev_state_root,
             logs_bloom: Default::default(),
@@ -167,7 +184,7 @@ impl Refiner {
     pub fn on_execution_outcome(
         &mut self,
         block: &NEARBlock,
-        near_tx_hash: Option<CryptoHash>,
+        lineage: Option<ReceiptLineage>,
         execution_outcome: &ExecutionOutcomeWithReceipt,
         txs: &HashMap<H256, TransactionIncludedOutcome>,
         storage: &Storage,
@@ -187,6 +204,7 @@ impl Refiner {
 
         match &execution_outcome.receipt.receipt {
             ReceiptEnumView::Action {
+                signer_id,
                 actions,
                 input_data_ids, // Results of the promises execution.
                 ..
@@ -195,15 +213,25 @@ impl Refiner {
 
                 // Receipts with multiple actions are atomic; they either entirely succeed or
                 // there no state changes from any action. If the execution outcome is
-                // a failure then we can skip the receipt (regardless of how many actions it has)
-                if let ExecutionStatusView::Unknown | ExecutionStatusView::Failure(_) =
-                    &execution_outcome.execution_outcome.outcome.status
-                {
-                    tracing::trace!(target: "transactions", "Failing NEAR Transaction at block: {}", block.header.hash);
-                    return;
-                }
+                // a failure then we can skip the receipt (regardless of how many actions it has),
+                // unless failed receipts are included, as transactions without effects.
+                let failure = match &execution_outcome.execution_outcome.outcome.status {
+                    ExecutionStatusView::Unknown | ExecutionStatusView::Failure(_)
+                        if !self.include_failed_receipts =>
+                    {
+                        tracing::trace!(target: "transactions", "Failing NEAR Transaction at block: {}", block.header.hash);
+                        return;
+                    }
+                    ExecutionStatusView::Unknown => Some("Unknown execution status".to_string()),
+                    ExecutionStatusView::Failure(err) => Some(err.to_string()),
+                    ExecutionStatusView::SuccessValue(_)
+                    | ExecutionStatusView::SuccessReceiptId(_) => None,
+                };
 
                 let num_actions = actions.len();
+                let outcome = &execution_outcome.execution_outcome.outcome;
+                let gas_burnt = u128::from(outcome.gas_burnt.as_gas());
+                let tokens_burnt = outcome.tokens_burnt.as_yoctonear();
 
                 // Create one transaction per action
                 for (index, action) in actions.iter().enumerate() {
@@ -212,12 +240,27 @@ impl Refiner {
                     let near_metadata = NearTransaction {
                         action_index: index,
                         receipt_hash: execution_outcome.receipt.receipt_id,
-                        transaction_hash: near_tx_hash,
+                        transaction_hash: lineage.map(|lineage| lineage.tx_hash),
                         hashchain_metadata: None, // Value filled during `build_transaction`
+                        gas_burnt: u64::try_from(split_cost(gas_burnt, index, num_actions)).ok(),
+                        tokens_burnt: Some(split_cost(tokens_burnt, index, num_actions)),
+                        signer_id: Some(signer_id.clone()),
+                        predecessor_id: Some(execution_outcome.receipt.predecessor_id.clone()),
+                        error: failure.clone(),
+                        // The outcome only applies to the last action in the batch
+                        spawned_receipt_ids: if index + 1 == num_actions {
+                            outcome.receipt_ids.clone()
+                        } else {
+                            vec![]
+                        },
+                        parent_receipt_id: lineage.and_then(|lineage| lineage.parent_receipt_id),
+                        origin_transaction_hash: lineage
+                            .and_then(|lineage| lineage.origin_aurora_tx_hash),
                     };
 
-                    // The execution outcome only applies to the last action in the batch
-                    let status = if index + 1 == num_actions {
+                    // The execution outcome only applies to the last action in the batch. The
+                    // actions of a failed receipt are built as if they had no outcome.
+                    let status = if index + 1 == num_actions && failure.is_none() {
                         Some(&execution_outcome.execution_outcome.outcome.status)
                     } else {
                         None
@@ -245,9 +288,15 @@ impl Refiner {
                     ) {
                         Ok(tx) => {
                             let BuiltTransaction {
-                                transaction,
+                                mut transaction,
                                 transaction_hash,
+                                bridge_event,
                             } = tx;
+                            if failure.is_some() {
+                                mark_failed(&mut transaction);
+                                self.partial_state.failed_transactions.push(transaction);
+                                continue;
+                            }
 
                             let result_hash = sha256(transaction.output.as_slice());
                             tracing::trace!(target: "transactions", "New transaction: {}", transaction.hash);
@@ -255,9 +304,25 @@ impl Refiner {
                                 .partial_state
                                 .total_gas
                                 .saturating_add(transaction.gas_used);
+                            transaction.cumulative_gas_used = self.partial_state.total_gas;
+                            // The logs are numbered across all the transactions of the block
+                            transaction.first_log_index =
+                                self.partial_state.transactions.last().map_or(0, |prev_tx| {
+                                    prev_tx.first_log_index + prev_tx.logs.len() as u32
+                                });
+                            transaction.index_logs();
                             self.partial_state
                                 .bloom_filter
                                 .accrue_bloom(&transaction.logs_bloom);
+                            if transaction.status {
+                                self.partial_state.bridge_events.extend(bridge_event);
+                                self.partial_state
+                                    .bridge_events
+                                    .extend(crate::bridge::exit_events(&transaction));
+                                self.partial_state.engine_events.extend(
+                                    crate::engine_events::engine_event(action, &transaction),
+                                );
+                            }
                             self.partial_state.transactions.push(transaction);
                             self.partial_state
                                 .transactions_extra_data
@@ -290,6 +355,46 @@ impl Refiner {
         }
     }
 
+    /// Records the withdrawal made by a receipt the engine spawned, if it comes from the exit of
+    /// an Aurora transaction. The ERC-20 address of the token is read from the engine storage.
+    pub fn on_exit_receipt(
+        &mut self,
+        block: &NEARBlock,
+        lineage: Option<ReceiptLineage>,
+        execution_outcome: &ExecutionOutcomeWithReceipt,
+        storage: &Storage,
+    ) {
+        let Some(origin) = lineage.and_then(|lineage| lineage.origin_aurora_tx_hash) else {
+            return;
+        };
+        if !matches!(
+            execution_outcome.execution_outcome.outcome.status,
+            ExecutionStatusView::SuccessValue(_) | ExecutionStatusView::SuccessReceiptId(_)
+        ) {
+            return;
+        }
+        let Some(mut event) = crate::bridge::withdrawal_event(origin, &execution_outcome.receipt)
+        else {
+            return;
+        };
+        let nep141 = aurora_engine_types::account_id::AccountId::new(
+            execution_outcome.receipt.receiver_id.as_str(),
+        );
+        let transaction_index = self.partial_state.transactions.len();
+        event.token.erc20 = nep141.ok().and_then(|nep141| {
+            storage
+                .with_engine_access(
+                    block.block.header.height,
+                    transaction_index.try_into().unwrap_or(u16::MAX),
+                    &[],
+                    |io| aurora_engine::engine::get_erc20_from_nep141(&io, &nep141),
+                )
+                .result
+                .ok()
+        });
+        self.partial_state.bridge_events.push(event);
+    }
+
     pub fn on_block_end(&mut self, block: &NEARBlock) -> AuroraBlock {
         let NEARBlock { block, .. } = &block;
 
@@ -322,6 +427,20 @@ impl Refiner {
 
         self.prev_state_root = H256::from(block.header.prev_state_root.0);
 
+        // Failed transactions come last, so the other transactions have the same index whether
+        // failed receipts are included or not
+        let mut transactions: Vec<_> = self.partial_state.transactions.drain(..).collect();
+        let first_log_index = transactions
+            .last()
+            .map_or(0, |tx| tx.first_log_index + tx.logs.len() as u32);
+        for mut transaction in self.partial_state.failed_transactions.drain(..) {
+            transaction.transaction_index = transactions.len() as u32;
+            transaction.first_log_index = first_log_index;
+            transaction.index_logs();
+            transaction.cumulative_gas_used = self.partial_state.total_gas;
+            transactions.push(transaction);
+        }
+
         let aurora_block = AuroraBlock {
             chain_id: self.chain_id,
             engine_account_id: self.engine_account_id.clone(),
@@ -344,7 +463,10 @@ impl Refiner {
             gas_used: self.partial_state.total_gas,
             transactions_root,
             receipts_root,
-            transactions: self.partial_state.transactions.drain(..).collect(),
+            transactions,
+            bridge_events: self.partial_state.bridge_events.drain(..).collect(),
+            engine_events: self.partial_state.engine_events.drain(..).collect(),
+            state_diff: vec![],
             near_metadata: NearBlock::ExistingBlock(near_header),
             logs_bloom: self.partial_state.bloom_filter,
         };
@@ -374,9 +496,37 @@ fn build_virtual_receipt_id(
     }
 }
 
+/// Turns the transaction of an action of a receipt that failed on NEAR into a failed transaction
+/// without effects.
+fn mark_failed(transaction: &mut AuroraTransaction) {
+    transaction.status = false;
+    transaction.gas_used = 0;
+    transaction.output = vec![];
+    transaction.logs = vec![];
+    transaction.logs_bloom = Bloom::default();
+    transaction.contract_address = None;
+    // Failed receipts are not part of the hashchain
+    transaction.near_metadata.hashchain_metadata = None;
+}
+
+/// Share of the cost of a receipt attributed to the action at `action_index`. The cost is split
+/// evenly among the actions, the remainder of the division going to the last action, so the
+/// shares of all the actions add up to the cost of the receipt.
+fn split_cost(cost: u128, action_index: usize, total_actions: usize) -> u128 {
+    let total_actions = total_actions.max(1) as u128;
+    let share = cost / total_actions;
+    if action_index as u128 + 1 == total_actions {
+        share + cost % total_actions
+    } else {
+        share
+    }
+}
+
 struct BuiltTransaction {
     transaction: AuroraTransaction,
     transaction_hash: H256,
+    /// Tokens bridged in by the transaction, if it succeeds
+    bridge_event: Option<BridgeEvent>,
 }
 
 /// Given the raw `execution_status` from Near and `engine_outcome` from Borealis Engine,
@@ -599,6 +749,7 @@ fn build_transaction(
 
     // Hash used to build a transactions merkle tree
     let mut transaction_hash = H256::zero();
+    let mut bridge_event = None;
     let from_address = near_account_to_evm_address(predecessor_id.as_bytes());
 
     match action {
@@ -1023,6 +1174,8 @@ fn build_transaction(
                         );
                         let amount = args.amount.as_u128();
                         let nonce = get_nonce(storage, block_height, tx_index, &from_address);
+                        bridge_event =
+                            mint_event(hash, &token_mint_kind, predecessor_id, to, &args);
 
                         // Differentiate between ETH and ERC-20 for setting transaction value and input
                         let (value, aurora_tx_input) = match token_mint_kind {
@@ -1154,6 +1307,7 @@ fn build_transaction(
     Ok(BuiltTransaction {
         transaction: tx.build().map_err(RefinerError::BuilderError)?,
         transaction_hash,
+        bridge_event,
     })
 }
 
@@ -1214,7 +1368,9 @@ fn fill_with_submit_result(
         blooms.accrue_bloom(&get_log_blooms(log));
     }
 
-    tx = tx.gas_used(result.gas_used).logs(result.logs);
+    tx = tx
+        .gas_used(result.gas_used)
+        .logs(result.logs.into_iter().map(Into::into).collect());
 
     match result.status {
         aurora_engine::parameters::TransactionStatus::Succeed(output) => {
@@ -1244,6 +1400,38 @@ fn fill_tx(tx: AuroraTransactionBuilder, input: Vec<u8>) -> AuroraTransactionBui
         .s(U256::zero())
 }
 
+/// Tokens minted on Aurora by an `ft_on_transfer` call of the `nep141` contract. For ETH, `to` is
+/// the recipient of the deposit, for ERC-20 tokens it is the token contract.
+fn mint_event(
+    transaction_hash: H256,
+    token_mint_kind: &TokenMintKind,
+    nep141: &AccountId,
+    to: Address,
+    args: &FtOnTransferArgs,
+) -> Option<BridgeEvent> {
+    let (kind, erc20, recipient) = match token_mint_kind {
+        TokenMintKind::Eth => (BridgeEventKind::EthDeposit, None, to),
+        TokenMintKind::Erc20 => (
+            BridgeEventKind::Nep141Mint,
+            Some(to),
+            crate::bridge::mint_recipient(&args.msg)?,
+        ),
+    };
+    let sender = AccountId::from_str(args.sender_id.as_ref()).ok()?;
+
+    Some(BridgeEvent {
+        kind,
+        transaction_hash,
+        token: BridgeToken {
+            nep141: Some(nep141.clone()),
+            erc20,
+        },
+        amount: U256::from(args.amount.as_u128()),
+        sender: BridgeAccount::Near(sender),
+        recipient: BridgeAccount::Aurora(recipient),
+    })
+}
+
 /// Describes the type of a specific mint transaction.
 enum TokenMintKind {
     Eth,
@@ -1387,6 +1575,15 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_split_cost() {
+        assert_eq!(super::split_cost(10, 0, 1), 10);
+        let shares: Vec<_> = (0..3)
+            .map(|index| super::split_cost(10, index, 3))
+            .collect();
+        assert_eq!(shares, vec![3, 3, 4]);
+    }
+
     #[test]
     fn test_block_hash() {
         // Example of block: https://explorer.mainnet.aurora.dev/block/62482103/transactions
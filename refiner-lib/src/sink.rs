@@ -0,0 +1,401 @@
+use crate::BlockWithMetadata;
+use crate::filter::BlockFilter;
+use async_trait::async_trait;
+use aurora_refiner_types::aurora_block::AuroraBlock;
+use std::fmt::Debug;
+use std::sync::Arc;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+/// Destination of the refined blocks.
+///
+/// Blocks are written in increasing height order. A block is only acknowledged once
+/// `write_block` returns, so an implementation that buffers blocks must make sure that it
+/// does not lose them; `flush` is called when the refiner stops.
+///
+/// A sink that gives up a block because the refiner is stopping fails with [`Interrupted`].
+#[async_trait]
+pub trait BlockSink: Send + Sync {
+    /// Writes a refined block.
+    async fn write_block(&mut self, block: &AuroraBlock) -> anyhow::Result<()>;
+
+    /// Persists every block written so far.
+    async fn flush(&mut self) -> anyhow::Result<()>;
+
+    /// Height of the last block persisted by the sink, used to decide where to resume
+    /// after a restart. `None` if the sink has no blocks.
+    async fn last_committed_height(&self) -> anyhow::Result<Option<u64>>;
+}
+
+/// Error of a sink that gave up writing a block because the refiner is stopping. It is a clean
+/// stop rather than a failure: the block is written again after the restart, as it is above the
+/// last committed height of the sink.
+#[derive(Debug)]
+pub struct Interrupted {
+    pub height: u64,
+}
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Interrupted before block {}", self.height)
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// A sink and the number of blocks that can be queued for it before the refiner is slowed down.
+pub struct SinkConfig {
+    pub name: String,
+    pub sink: Box<dyn BlockSink>,
+    pub buffer_size: usize,
+}
+
+/// Handle on a sink running in its own task.
+pub struct SinkHandle {
+    name: String,
+    sender: mpsc::Sender<Arc<AuroraBlock>>,
+    acknowledged: watch::Receiver<Option<u64>>,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+impl SinkHandle {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Height of the last block written by the sink.
+    pub fn acknowledged_height(&self) -> Option<u64> {
+        *self.acknowledged.borrow()
+    }
+}
+
+/// Starts a task which writes the blocks it receives to `sink`, in order, and acknowledges
+/// each of them once written. The sink is flushed when the channel is closed. Once the sink is
+/// interrupted, the blocks it receives are skipped.
+pub fn spawn_sink(config: SinkConfig) -> SinkHandle {
+    let SinkConfig {
+        name,
+        mut sink,
+        buffer_size,
+    } = config;
+    let (sender, mut receiver) = mpsc::channel::<Arc<AuroraBlock>>(buffer_size);
+    let (ack_sender, acknowledged) = watch::channel(None);
+    let task_name = name.clone();
+
+    let task = tokio::spawn(async move {
+        while let Some(block) = receiver.recv().await {
+            match sink.write_block(&block).await {
+                Ok(()) => {
+                    ack_sender.send_replace(Some(block.height));
+                }
+                Err(err) if err.is::<Interrupted>() => {
+                    info!("Sink {task_name} interrupted before block {}", block.height);
+                    // The dispatcher still writes the queued blocks to the other sinks
+                    while receiver.recv().await.is_some() {}
+                    break;
+                }
+                Err(err) => {
+                    error!(
+                        "Sink {task_name} failed to write block {}: {err:?}",
+                        block.height
+                    );
+                    return Err(err);
+                }
+            }
+        }
+        sink.flush().await?;
+        info!("Sink {task_name} stopped");
+        Ok(())
+    });
+
+    SinkHandle {
+        name,
+        sender,
+        acknowledged,
+        task,
+    }
+}
+
+/// Spawns one task per sink, plus a task that forwards every refined block to all of them.
+/// Each sink has its own queue, so a slow sink only slows the refiner down once its queue is
+/// full. If any sink fails, the dispatcher stops, which makes the refiner stop as well.
+///
+/// When `filter` is set, the transactions that do not match it are removed from the blocks
+/// before they are written to any sink.
+///
+/// The dispatcher stops after `total_blocks` blocks if set, or when `shutdown_rx` fires, once
+/// the blocks already queued are written.
+/// Returns the channel to send the refined blocks to, and a handle to the dispatcher task.
+pub fn spawn_sinks<M: Debug + Clone + Send + 'static>(
+    sinks: Vec<SinkConfig>,
+    filter: Option<BlockFilter>,
+    mut total_blocks: Option<u64>,
+    mut shutdown_rx: tokio::sync::broadcast::Receiver<()>,
+) -> (
+    mpsc::Sender<BlockWithMetadata<AuroraBlock, M>>,
+    JoinHandle<anyhow::Result<()>>,
+) {
+    let (blocks_tx, mut blocks_rx) = mpsc::channel::<BlockWithMetadata<AuroraBlock, M>>(1000);
+    let handles: Vec<SinkHandle> = sinks.into_iter().map(spawn_sink).collect();
+
+    let task = tokio::spawn(async move {
+        let mut result = Ok(());
+
+        while total_blocks != Some(0) {
+            tokio::select! {
+                maybe_block = blocks_rx.recv() => {
+                    let Some(BlockWithMetadata { block, .. }) = maybe_block else {
+                        break;
+                    };
+                    result = dispatch(&handles, filter.as_ref(), block).await;
+                    if result.is_err() {
+                        break;
+                    }
+                    if let Some(total_blocks) = total_blocks.as_mut() {
+                        *total_blocks -= 1;
+                    }
+                }
+                _ = shutdown_rx.recv() => {
+                    info!("Sinks: received shutdown signal");
+                    break;
+                }
+            }
+        }
+
+        // The refiner must not send any more blocks, but the ones already queued are committed
+        // by the engine, so they are written before the sinks stop
+        blocks_rx.close();
+        while result.is_ok() && total_blocks != Some(0) {
+            let Some(BlockWithMetadata { block, .. }) = blocks_rx.recv().await else {
+                break;
+            };
+            result = dispatch(&handles, filter.as_ref(), block).await;
+            if let Some(total_blocks) = total_blocks.as_mut() {
+                *total_blocks -= 1;
+            }
+        }
+
+        for handle in handles {
+            let SinkHandle {
+                name,
+                sender,
+                acknowledged,
+                task,
+            } = handle;
+            // Closing the channel makes the sink flush and stop
+            drop(sender);
+            let sink_result = task
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|sink_result| sink_result);
+            info!(
+                "Sink {name} last acknowledged block: {:?}",
+                *acknowledged.borrow()
+            );
+            if let Err(err) = sink_result {
+                error!("Sink {name} failed: {err:?}");
+                if result.is_ok() {
+                    result = Err(err.context(format!("Sink {name} failed")));
+                }
+            }
+        }
+
+        result
+    });
+
+    (blocks_tx, task)
+}
+
+/// Filters a refined block and sends it to every sink, waiting for each of them to have room
+/// for it.
+async fn dispatch(
+    handles: &[SinkHandle],
+    filter: Option<&BlockFilter>,
+    mut block: AuroraBlock,
+) -> anyhow::Result<()> {
+    if let Some(filter) = filter {
+        filter.apply(&mut block);
+    }
+    let height = block.height;
+    let block = Arc::new(block);
+    for handle in handles {
+        if handle.sender.send(block.clone()).await.is_err() {
+            return Err(anyhow::anyhow!(
+                "Sink {} stopped before block {height}",
+                handle.name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Height to resume from: the lowest last committed height over the sinks that have blocks, so
+/// that none of them misses a block. Sinks are idempotent per height, so the others just write
+/// some blocks again. A sink without blocks, e.g. one just added to the config, starts at the
+/// resume height instead of making the refiner start over from genesis. `None` if no sink has
+/// any block.
+pub async fn last_committed_height(sinks: &[SinkConfig]) -> anyhow::Result<Option<u64>> {
+    let mut min_height: Option<u64> = None;
+    let mut empty_sinks = Vec::new();
+    for config in sinks {
+        match config.sink.last_committed_height().await? {
+            Some(height) => min_height = Some(min_height.map_or(height, |min| min.min(height))),
+            None => empty_sinks.push(config.name.as_str()),
+        }
+    }
+    if let Some(height) = min_height {
+        for name in empty_sinks {
+            warn!("Sink {name} has no blocks, it starts after block {height}");
+        }
+    }
+    Ok(min_height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockSink, Interrupted, SinkConfig, last_committed_height, spawn_sinks};
+    use crate::BlockWithMetadata;
+    use async_trait::async_trait;
+    use aurora_refiner_types::aurora_block::AuroraBlock;
+    use aurora_refiner_types::test_utils::read_aurora_block;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct MemorySink {
+        blocks: Arc<Mutex<Vec<u64>>>,
+        flushed: Arc<Mutex<bool>>,
+        /// Height of the block the sink is interrupted at, if any.
+        interrupted_at: Option<u64>,
+    }
+
+    #[async_trait]
+    impl BlockSink for MemorySink {
+        async fn write_block(&mut self, block: &AuroraBlock) -> anyhow::Result<()> {
+            if self
+                .interrupted_at
+                .is_some_and(|height| height <= block.height)
+            {
+                return Err(Interrupted {
+                    height: block.height,
+                }
+                .into());
+            }
+            self.blocks.lock().unwrap().push(block.height);
+            Ok(())
+        }
+
+        async fn flush(&mut self) -> anyhow::Result<()> {
+            *self.flushed.lock().unwrap() = true;
+            Ok(())
+        }
+
+        async fn last_committed_height(&self) -> anyhow::Result<Option<u64>> {
+            Ok(self.blocks.lock().unwrap().last().copied())
+        }
+    }
+
+    fn sink_config(sink: &MemorySink, buffer_size: usize) -> SinkConfig {
+        SinkConfig {
+            name: "memory".to_string(),
+            sink: Box::new(sink.clone()),
+            buffer_size,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fan_out() {
+        let sink_1 = MemorySink::default();
+        let sink_2 = MemorySink::default();
+        let (_shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel(1);
+        let (blocks_tx, task) = spawn_sinks::<()>(
+            vec![sink_config(&sink_1, 1), sink_config(&sink_2, 10)],
+            None,
+            Some(3),
+            shutdown_rx,
+        );
+
+        for height in 100..103 {
+            blocks_tx
+                .send(BlockWithMetadata::new(read_aurora_block(height), ()))
+                .await
+                .unwrap();
+        }
+        task.await.unwrap().unwrap();
+
+        // The dispatcher stops after the 3 blocks
+        let result = blocks_tx
+            .send(BlockWithMetadata::new(read_aurora_block(100), ()))
+            .await;
+        assert!(result.is_err());
+        for sink in [&sink_1, &sink_2] {
+            assert_eq!(*sink.blocks.lock().unwrap(), vec![100, 101, 102]);
+            assert!(*sink.flushed.lock().unwrap());
+        }
+
+        sink_1.blocks.lock().unwrap().pop();
+        let sinks = vec![sink_config(&sink_1, 1), sink_config(&sink_2, 1)];
+        assert_eq!(last_committed_height(&sinks).await.unwrap(), Some(101));
+        let sinks = vec![sink_config(&MemorySink::default(), 1)];
+        assert_eq!(last_committed_height(&sinks).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_writes_queued_blocks() {
+        let sink = MemorySink::default();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel(1);
+        let (blocks_tx, task) =
+            spawn_sinks::<()>(vec![sink_config(&sink, 1)], None, None, shutdown_rx);
+
+        for height in 100..103 {
+            blocks_tx
+                .send(BlockWithMetadata::new(read_aurora_block(height), ()))
+                .await
+                .unwrap();
+        }
+        shutdown_tx.send(()).unwrap();
+        task.await.unwrap().unwrap();
+
+        assert_eq!(*sink.blocks.lock().unwrap(), vec![100, 101, 102]);
+        assert!(*sink.flushed.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_sink() {
+        let interrupted = MemorySink {
+            interrupted_at: Some(101),
+            ..Default::default()
+        };
+        let other = MemorySink::default();
+        let (_shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel(1);
+        let (blocks_tx, task) = spawn_sinks::<()>(
+            vec![sink_config(&interrupted, 1), sink_config(&other, 1)],
+            None,
+            Some(3),
+            shutdown_rx,
+        );
+
+        for height in 100..103 {
+            blocks_tx
+                .send(BlockWithMetadata::new(read_aurora_block(height), ()))
+                .await
+                .unwrap();
+        }
+        // An interrupted sink is not a failure, and does not stop the other sinks
+        task.await.unwrap().unwrap();
+        assert_eq!(*interrupted.blocks.lock().unwrap(), vec![100]);
+        assert!(*interrupted.flushed.lock().unwrap());
+        assert_eq!(*other.blocks.lock().unwrap(), vec![100, 101, 102]);
+    }
+
+    #[tokio::test]
+    async fn test_new_sink_next_to_populated_one() {
+        let populated = MemorySink::default();
+        populated.blocks.lock().unwrap().extend([100, 101, 102]);
+        let empty = MemorySink::default();
+
+        // The new sink does not make the refiner start over from genesis
+        let sinks = vec![sink_config(&empty, 1), sink_config(&populated, 1)];
+        assert_eq!(last_committed_height(&sinks).await.unwrap(), Some(102));
+    }
+}
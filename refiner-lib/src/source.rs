@@ -0,0 +1,258 @@
+use crate::BlockWithMetadata;
+use async_trait::async_trait;
+use aurora_refiner_types::near_block::NEARBlock;
+use std::fmt::Debug;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// Feed of NEAR blocks for the refiner.
+///
+/// Blocks must be returned in increasing height order, starting at the height given to
+/// `start`. Heights can be missing (NEAR skips some heights), the refiner handles them.
+#[async_trait]
+pub trait BlockSource: Send {
+    /// Starts the feed. The first block returned by `next_block` is the one at `height`,
+    /// or the first one after it if there is no block at `height`.
+    async fn start(&mut self, height: u64) -> anyhow::Result<()>;
+
+    /// Waits for the next block. Returns `None` once the feed is exhausted or shut down.
+    async fn next_block(&mut self) -> anyhow::Result<Option<NEARBlock>>;
+
+    /// Height of the latest block known to the source, if it knows it. It is used together
+    /// with the height of the last block returned to report how far behind the refiner is.
+    async fn head_height(&mut self) -> Option<u64> {
+        None
+    }
+
+    /// Stops the feed and releases its resources. `next_block` must not be called afterwards.
+    async fn shutdown(&mut self) -> anyhow::Result<()>;
+}
+
+/// Progress of a block source.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SourceStatus {
+    /// Height of the last block returned by the source.
+    pub last_block_height: Option<u64>,
+    /// Timestamp, in nanoseconds, of the last block returned by the source.
+    pub last_block_timestamp: Option<u64>,
+    /// Height of the latest block known to the source.
+    pub head_height: Option<u64>,
+}
+
+impl SourceStatus {
+    /// Number of blocks between the last block returned and the head, if the head is known.
+    pub fn block_lag(&self) -> Option<u64> {
+        self.head_height
+            .zip(self.last_block_height)
+            .map(|(head, last)| head.saturating_sub(last))
+    }
+
+    /// Time elapsed since the last block returned was produced.
+    pub fn time_lag(&self) -> Option<Duration> {
+        let timestamp = Duration::from_nanos(self.last_block_timestamp?);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+        Some(now.saturating_sub(timestamp))
+    }
+}
+
+/// Handle on a block source running in its own task.
+pub struct SourceHandle {
+    /// Blocks produced by the source.
+    pub blocks: mpsc::Receiver<BlockWithMetadata<NEARBlock, ()>>,
+    /// Progress of the source, updated after every block.
+    pub status: watch::Receiver<SourceStatus>,
+    /// The source task, it finishes once the source is exhausted or shut down.
+    pub task: JoinHandle<anyhow::Result<()>>,
+}
+
+/// Starts `source` at `height` and spawns a task that forwards its blocks to a channel.
+/// The source is shut down when `shutdown_rx` fires or when the receiving side is dropped.
+pub async fn spawn_source<S: BlockSource + 'static>(
+    mut source: S,
+    height: u64,
+    mut shutdown_rx: tokio::sync::broadcast::Receiver<()>,
+) -> anyhow::Result<SourceHandle> {
+    source.start(height).await?;
+    info!("Block source started at height {height}");
+
+    let (sender, blocks) = mpsc::channel(1000);
+    let (status_sender, status) = watch::channel(SourceStatus::default());
+
+    let task = tokio::spawn(async move {
+        let result = loop {
+            tokio::select! {
+                maybe_block = source.next_block() => {
+                    let block = match maybe_block {
+                        Ok(Some(block)) => block,
+                        Ok(None) => {
+                            info!("Block source exhausted");
+                            break Ok(());
+                        }
+                        Err(err) => break Err(err),
+                    };
+
+                    let header = &block.block.header;
+                    let mut source_status = SourceStatus {
+                        last_block_height: Some(header.height),
+                        last_block_timestamp: Some(header.timestamp),
+                        head_height: source.head_height().await,
+                    };
+                    // The head cannot be behind a block that was already produced
+                    source_status.head_height = source_status
+                        .head_height
+                        .map(|head| head.max(header.height));
+                    update_metrics(&source_status);
+                    status_sender.send_replace(source_status);
+
+                    if sender.send(BlockWithMetadata::new(block, ())).await.is_err() {
+                        warn!("Receiver dropped, stopping block source");
+                        break Ok(());
+                    }
+                }
+                _ = shutdown_rx.recv() => {
+                    info!("Block source received shutdown signal");
+                    break Ok(());
+                }
+            }
+        };
+
+        if let Err(err) = source.shutdown().await {
+            warn!("Failed to shut down block source: {err:?}");
+        }
+        result
+    });
+
+    Ok(SourceHandle {
+        blocks,
+        status,
+        task,
+    })
+}
+
+/// Forwards every block of `input` to `count` channels, so that several refiners can share a
+/// single source. A channel whose receiver is dropped is skipped from then on. The task stops
+/// once `input` is closed or every receiver is dropped.
+pub fn fan_out<M: Debug + Clone + Send + 'static>(
+    mut input: mpsc::Receiver<BlockWithMetadata<NEARBlock, M>>,
+    count: usize,
+) -> (
+    Vec<mpsc::Receiver<BlockWithMetadata<NEARBlock, M>>>,
+    JoinHandle<()>,
+) {
+    let (mut senders, receivers): (Vec<_>, Vec<_>) =
+        (0..count).map(|_| mpsc::channel(1000)).unzip();
+
+    let task = tokio::spawn(async move {
+        while let Some(BlockWithMetadata { block, metadata }) = input.recv().await {
+            let mut closed = Vec::new();
+            for (i, sender) in senders.iter().enumerate() {
+                let message = BlockWithMetadata::new(block.clone(), metadata.clone());
+                if sender.send(message).await.is_err() {
+                    closed.push(i);
+                }
+            }
+            for i in closed.into_iter().rev() {
+                warn!("Fan-out: receiver {i} dropped");
+                senders.remove(i);
+            }
+            if senders.is_empty() {
+                break;
+            }
+        }
+    });
+
+    (receivers, task)
+}
+
+fn update_metrics(status: &SourceStatus) {
+    if let Some(lag) = status.time_lag() {
+        crate::metrics::SOURCE_TIME_LAG.set(i64::try_from(lag.as_millis()).unwrap_or(i64::MAX));
+    }
+    if let Some(lag) = status.block_lag() {
+        crate::metrics::SOURCE_BLOCK_LAG.set(i64::try_from(lag).unwrap_or(i64::MAX));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockSource, SourceStatus, fan_out, spawn_source};
+    use crate::BlockWithMetadata;
+    use crate::near_stream::tests::read_block;
+    use async_trait::async_trait;
+    use aurora_refiner_types::near_block::NEARBlock;
+    use std::collections::VecDeque;
+
+    struct FileSource {
+        blocks: VecDeque<NEARBlock>,
+    }
+
+    #[async_trait]
+    impl BlockSource for FileSource {
+        async fn start(&mut self, height: u64) -> anyhow::Result<()> {
+            self.blocks
+                .retain(|block| block.block.header.height >= height);
+            Ok(())
+        }
+
+        async fn next_block(&mut self) -> anyhow::Result<Option<NEARBlock>> {
+            Ok(self.blocks.pop_front())
+        }
+
+        async fn head_height(&mut self) -> Option<u64> {
+            Some(51188690)
+        }
+
+        async fn shutdown(&mut self) -> anyhow::Result<()> {
+            self.blocks.clear();
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spawn_source() {
+        let source = FileSource {
+            blocks: [
+                "tests/res/block-51188689.json",
+                "tests/res/block-51188690.json",
+            ]
+            .into_iter()
+            .map(read_block)
+            .collect(),
+        };
+        let (_shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel(1);
+
+        let mut handle = spawn_source(source, 51188690, shutdown_rx).await.unwrap();
+
+        let block = handle.blocks.recv().await.unwrap();
+        assert_eq!(block.block.block.header.height, 51188690);
+        assert!(handle.blocks.recv().await.is_none());
+        handle.task.await.unwrap().unwrap();
+
+        let status: SourceStatus = *handle.status.borrow();
+        assert_eq!(status.last_block_height, Some(51188690));
+        assert_eq!(status.block_lag(), Some(0));
+        assert!(status.time_lag().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_fan_out() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(10);
+        let (mut receivers, task) = fan_out(receiver, 2);
+
+        let block = read_block("tests/res/block-51188689.json");
+        sender
+            .send(BlockWithMetadata::new(block, ()))
+            .await
+            .unwrap();
+        drop(sender);
+        task.await.unwrap();
+
+        for receiver in &mut receivers {
+            let message = receiver.recv().await.unwrap();
+            assert_eq!(message.block.block.header.height, 51188689);
+            assert!(receiver.recv().await.is_none());
+        }
+    }
+}
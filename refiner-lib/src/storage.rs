@@ -1,15 +1,33 @@
 use std::path::Path;
 
 use aurora_engine_types::{H256, U256, account_id::AccountId};
-use engine_standalone_storage::{Storage, StoragePrefix};
+use aurora_standalone_engine::data_id_mapping;
+use aurora_standalone_engine::sync::{
+    LAST_PROCESSED_HEIGHT_KEY, last_processed_height, set_last_processed_height,
+};
+use aurora_standalone_engine::upgrade::rewind_upgrades;
+use engine_standalone_storage::{Storage, StoragePrefix, TransactionIncluded};
 
 /// Must match the VERSION in `engine_standalone_storage`
 const VERSION: u8 = 0;
 /// Write to the DB in batches of 100k heights at a time
 const BATCH_SIZE: usize = 100_000;
 
-pub fn init_storage<P: AsRef<Path>>(storage_path: P, account_id: &AccountId, chain_id: u64) {
-    let mut storage = migrate_block_hash(storage_path, account_id, chain_id);
+/// Key of the chain id in the custom data of the engine storage.
+const CHAIN_ID_KEY: &[u8] = b"chain_id";
+
+/// Checks that the engine storage at `storage_path` belongs to the configured engine account and
+/// chain, and records them if it does not have them yet. The block hashes of a DB created before
+/// the chain id was recorded are only recomputed for the configured chain when
+/// `migrate_block_hashes` is set; otherwise such a DB is rejected, as it may have been refined
+/// for another chain.
+pub fn init_storage<P: AsRef<Path>>(
+    storage_path: P,
+    account_id: &AccountId,
+    chain_id: u64,
+    migrate_block_hashes: bool,
+) {
+    let mut storage = Storage::open(&storage_path).unwrap();
 
     match storage.get_engine_account_id() {
         Ok(stored_id) => {
@@ -33,44 +51,268 @@ pub fn init_storage<P: AsRef<Path>>(storage_path: P, account_id: &AccountId, cha
             panic!("Error reading engine_account_id from DB: {other:?}");
         }
     };
+
+    // The block hashes depend on the chain id, so a DB is only valid for the chain it was
+    // created for.
+    let stored_chain_id = get_chain_id(&storage);
+    if let Some(stored_chain_id) = stored_chain_id
+        && stored_chain_id != chain_id
+    {
+        panic!("Provided chain_id={chain_id} is not equal to chain_id_stored={stored_chain_id}");
+    }
+
+    if let Ok((block_hash, block_height)) = storage.get_latest_block()
+        && block_hash != compute_block_hash(chain_id, block_height, account_id)
+    {
+        if stored_chain_id.is_some() {
+            panic!(
+                "Block hash at height {block_height} does not match the stored chain_id={chain_id}. The DB may be corrupted."
+            );
+        }
+        // Without a stored chain id, the hashes may be those of an older scheme, or of another
+        // chain. Only the operator can tell.
+        if !migrate_block_hashes {
+            panic!(
+                "Block hash at height {block_height} does not match the configured chain_id={chain_id}. Set migrate_block_hashes if the DB was refined for this chain with an older block hash scheme."
+            );
+        }
+        migrate_block_hash(storage, &storage_path, account_id, chain_id);
+    } else {
+        drop(storage);
+    }
+
+    let mut storage = Storage::open(&storage_path).unwrap();
+    if stored_chain_id.is_none() {
+        tracing::info!("No chain_id set in DB. Setting to configured chain_id={chain_id}");
+        storage
+            .set_custom_data(CHAIN_ID_KEY, &chain_id.to_be_bytes())
+            .unwrap();
+    }
+
+    // DBs written before the last processed height was recorded are assumed to have fully
+    // processed their latest block
+    let processed_height_set = storage
+        .get_custom_data(LAST_PROCESSED_HEIGHT_KEY)
+        .unwrap_or_else(|err| panic!("Error reading last processed height from DB: {err:?}"))
+        .is_some();
+    if !processed_height_set {
+        let latest_height = storage.get_latest_block().ok().map(|(_, height)| height);
+        tracing::info!("No last processed height set in DB. Setting to {latest_height:?}");
+        set_last_processed_height(&mut storage, latest_height).unwrap();
+    }
+}
+
+fn compute_block_hash(chain_id: u64, block_height: u64, account_id: &AccountId) -> H256 {
+    aurora_engine::engine::compute_block_hash(
+        aurora_engine_types::types::u256_to_arr(&U256::from(chain_id)),
+        block_height,
+        account_id.as_bytes(),
+    )
 }
 
+/// Chain id stored in the engine storage, if it was set.
+fn get_chain_id(storage: &Storage) -> Option<u64> {
+    let value = storage
+        .get_custom_data(CHAIN_ID_KEY)
+        .unwrap_or_else(|err| panic!("Error reading chain_id from DB: {err:?}"))?;
+    let bytes = value.as_slice().try_into().unwrap_or_else(|_| {
+        panic!("Fatal error, cannot read chain_id from DB. The DB may be corrupted.")
+    });
+    Some(u64::from_be_bytes(bytes))
+}
+
+/// Height of the last block the engine fully processed, if any. It is written once all the
+/// transactions of the block are committed, so blocks above it were interrupted midway.
+pub fn last_block_height<P: AsRef<Path>>(storage_path: P) -> anyhow::Result<Option<u64>> {
+    let storage = Storage::open(storage_path)
+        .map_err(|e| anyhow::anyhow!("Failed to open engine storage: {e:?}"))?;
+    Ok(last_processed_height(&storage)?)
+}
+
+/// Rewinds the blocks the engine started to process but did not finish, e.g. because the process
+/// was killed, so that they are processed again from scratch.
+pub fn discard_unfinished_blocks<P: AsRef<Path>, Q: AsRef<Path>>(
+    storage_path: P,
+    data_id_mapping_path: Q,
+) -> anyhow::Result<()> {
+    let storage = Storage::open(&storage_path)
+        .map_err(|e| anyhow::anyhow!("Failed to open engine storage: {e:?}"))?;
+    let processed_height = last_processed_height(&storage)?;
+    // An error here means there are no blocks in the storage yet
+    let latest_height = storage.get_latest_block().ok().map(|(_, height)| height);
+    drop(storage);
+
+    if latest_height > processed_height {
+        tracing::warn!(
+            "Engine storage has blocks up to {latest_height:?} but only processed them up to \
+            {processed_height:?}, discarding the unfinished ones"
+        );
+        rewind_storage(
+            &storage_path,
+            data_id_mapping_path,
+            processed_height.unwrap_or_default(),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Rolls the engine storage back to the state it had right after the block at `height` was
+/// processed. Every transaction included in a later block is reverted (this removes its diff and
+/// the engine keys it wrote), and the block hash, height and metadata entries above `height`
+/// are deleted, as well as the engine upgrades recorded for those blocks, and the data receipts
+/// stored at `data_id_mapping_path`.
+pub fn rewind_storage<P: AsRef<Path>, Q: AsRef<Path>>(
+    storage_path: P,
+    data_id_mapping_path: Q,
+    height: u64,
+) -> anyhow::Result<()> {
+    // Collect everything that needs to be reverted using low-level access to the DB because
+    // `Storage` does not expose iteration over blocks or transactions.
+    let db = rocksdb::DB::open_default(&storage_path)?;
+    let blocks = blocks_above(&db, height)?;
+    let mut transactions = Vec::new();
+    for (_, block_hash) in &blocks {
+        transactions.extend(block_transactions(&db, block_hash)?);
+    }
+    drop(db);
+
+    tracing::info!(
+        "Rewinding engine storage to height {height}: reverting {} transactions from {} blocks",
+        transactions.len(),
+        blocks.len(),
+    );
+
+    let mut storage = Storage::open(&storage_path)
+        .map_err(|e| anyhow::anyhow!("Failed to open engine storage: {e:?}"))?;
+    for (tx_hash, tx_included) in transactions.iter().rev() {
+        storage
+            .revert_transaction_included(*tx_hash, tx_included)
+            .map_err(|e| anyhow::anyhow!("Failed to revert transaction {tx_hash:?}: {e:?}"))?;
+    }
+    let processed_height = last_processed_height(&storage)?;
+    if processed_height > Some(height) {
+        set_last_processed_height(&mut storage, Some(height))
+            .map_err(|e| anyhow::anyhow!("Failed to set last processed height: {e:?}"))?;
+    }
+    rewind_upgrades(&mut storage, height)?;
+    drop(storage);
+
+    let db = rocksdb::DB::open_default(&storage_path)?;
+    let mut write_batch = rocksdb::WriteBatch::default();
+    for (block_height, block_hash) in blocks {
+        write_batch.delete(construct_storage_key(
+            StoragePrefix::BlockHash,
+            &block_height.to_be_bytes(),
+        ));
+        write_batch.delete(construct_storage_key(
+            StoragePrefix::BlockHeight,
+            block_hash.as_bytes(),
+        ));
+        write_batch.delete(construct_storage_key(
+            StoragePrefix::BlockMetadata,
+            block_hash.as_bytes(),
+        ));
+    }
+    db.write(write_batch)?;
+    drop(db);
+
+    if data_id_mapping_path.as_ref().exists() {
+        data_id_mapping::rewind_storage(data_id_mapping_path, height)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the heights and hashes of all the blocks stored above `height`.
+fn blocks_above(db: &rocksdb::DB, height: u64) -> anyhow::Result<Vec<(u64, H256)>> {
+    let Some(start_height) = height.checked_add(1) else {
+        return Ok(Vec::new());
+    };
+    let start_key = construct_storage_key(StoragePrefix::BlockHash, &start_height.to_be_bytes());
+    let iter = db.iterator(rocksdb::IteratorMode::From(
+        &start_key,
+        rocksdb::Direction::Forward,
+    ));
+    let mut blocks = Vec::new();
+
+    for entry in iter {
+        let (key, value) = entry?;
+        let is_block_hash_key = key
+            .get(1)
+            .map(|b| b == &(StoragePrefix::BlockHash as u8))
+            .unwrap_or(false);
+        if !is_block_hash_key || key.len() != 10 {
+            break;
+        }
+        let mut buf = [0u8; 8];
+        // First two bytes are VERSION and StoragePrefix::BlockHash, remaining 8 bytes are the height
+        buf.copy_from_slice(&key[2..10]);
+        blocks.push((u64::from_be_bytes(buf), H256::from_slice(&value)));
+    }
+
+    Ok(blocks)
+}
+
+/// Returns the hashes and positions of all the transactions included in the given block.
+fn block_transactions(
+    db: &rocksdb::DB,
+    block_hash: &H256,
+) -> anyhow::Result<Vec<(H256, TransactionIncluded)>> {
+    let prefix = construct_storage_key(StoragePrefix::TransactionHash, block_hash.as_bytes());
+    let iter = db.iterator(rocksdb::IteratorMode::From(
+        &prefix,
+        rocksdb::Direction::Forward,
+    ));
+    let mut transactions = Vec::new();
+
+    for entry in iter {
+        let (key, value) = entry?;
+        if !key.starts_with(&prefix) {
+            break;
+        }
+        // Keys are the prefix followed by the big-endian `u16` position of the transaction
+        let position = key
+            .get(prefix.len()..prefix.len() + 2)
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(u16::from_be_bytes)
+            .ok_or_else(|| {
+                anyhow::anyhow!("Invalid transaction hash key: {}", hex::encode(&key))
+            })?;
+        let tx_included = TransactionIncluded {
+            block_hash: *block_hash,
+            position,
+        };
+        transactions.push((H256::from_slice(&value), tx_included));
+    }
+
+    Ok(transactions)
+}
+
+/// Recomputes the block hashes that do not match the account id and the chain id.
 fn migrate_block_hash<P: AsRef<Path>>(
+    storage: Storage,
     storage_path: P,
     account_id: &AccountId,
     chain_id: u64,
-) -> Storage {
+) {
+    tracing::info!("Detected incorrect blockhash. Performing migration");
     let chain_id = aurora_engine_types::types::u256_to_arr(&U256::from(chain_id));
-    let mut storage = Storage::open(&storage_path).unwrap();
-    let (block_hash, block_height) = match storage.get_latest_block() {
-        Ok(x) => x,
-        // If there are no blocks then there is nothing to migrate
-        Err(_) => return storage,
-    };
-    let computed_block_hash =
-        aurora_engine::engine::compute_block_hash(chain_id, block_height, account_id.as_bytes());
-    if computed_block_hash != block_hash {
-        // Block hash is not what is expected, so we need to migrate
-        tracing::info!("Detected incorrect blockhash. Performing migration");
-
-        // Close the current storage instance because we're going to need low-level access to the DB.
-        let (_, mut block_height) = storage.get_earliest_block().unwrap();
-        drop(storage);
-        let db = rocksdb::DB::open_default(&storage_path).unwrap();
 
-        while let MigrationStatus::Continue(height) =
-            block_hash_migration_batch(&db, block_height, account_id.as_bytes(), chain_id)
-        {
-            block_height = height;
-            tracing::debug!("Migrated up to height {}", block_height);
-        }
+    // Close the current storage instance because we're going to need low-level access to the DB.
+    let (_, mut block_height) = storage.get_earliest_block().unwrap();
+    drop(storage);
+    let db = rocksdb::DB::open_default(&storage_path).unwrap();
 
-        // Close low-level access, and open new Storage instance
-        drop(db);
-        tracing::info!("Migration complete.");
-        storage = Storage::open(storage_path).unwrap();
+    while let MigrationStatus::Continue(height) =
+        block_hash_migration_batch(&db, block_height, account_id.as_bytes(), chain_id)
+    {
+        block_height = height;
+        tracing::debug!("Migrated up to height {}", block_height);
     }
-    storage
+
+    drop(db);
+    tracing::info!("Migration complete.");
 }
 
 fn block_hash_migration_batch(
@@ -150,3 +392,146 @@ enum MigrationStatus {
 fn construct_storage_key(prefix: StoragePrefix, key: &[u8]) -> Vec<u8> {
     [&[VERSION], &[prefix as u8], key].concat()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        compute_block_hash, discard_unfinished_blocks, get_chain_id, init_storage,
+        last_block_height,
+    };
+    use aurora_engine_types::H256;
+    use aurora_engine_types::account_id::AccountId;
+    use engine_standalone_storage::{BlockMetadata, Storage};
+
+    const HEIGHT: u64 = 100;
+
+    /// Storage created before the chain id was stored, with a single block.
+    fn storage_with_block(path: &std::path::Path, account_id: &AccountId, block_hash: H256) {
+        let mut storage = Storage::open(path).unwrap();
+        storage.set_engine_account_id(account_id).unwrap();
+        add_block(&mut storage, block_hash, HEIGHT);
+    }
+
+    fn add_block(storage: &mut Storage, block_hash: H256, height: u64) {
+        let metadata = BlockMetadata {
+            timestamp: aurora_engine_sdk::env::Timestamp::new(0),
+            random_seed: Default::default(),
+        };
+        storage
+            .set_block_data(block_hash, height, &metadata)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_init_storage_persists_chain_id() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let account_id: AccountId = "aurora".parse().unwrap();
+
+        init_storage(db_dir.path(), &account_id, 1_313_161_554, false);
+        // Starting again with the same config is fine
+        init_storage(db_dir.path(), &account_id, 1_313_161_554, false);
+
+        let storage = Storage::open(db_dir.path()).unwrap();
+        assert_eq!(get_chain_id(&storage), Some(1_313_161_554));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Provided chain_id=1313161555 is not equal to chain_id_stored=1313161554"
+    )]
+    fn test_init_storage_rejects_other_chain_id() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let account_id: AccountId = "aurora".parse().unwrap();
+
+        init_storage(db_dir.path(), &account_id, 1_313_161_554, false);
+        init_storage(db_dir.path(), &account_id, 1_313_161_555, false);
+    }
+
+    #[test]
+    fn test_init_storage_records_chain_id_of_existing_db() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let account_id: AccountId = "aurora".parse().unwrap();
+        let block_hash = compute_block_hash(1_313_161_554, HEIGHT, &account_id);
+        storage_with_block(db_dir.path(), &account_id, block_hash);
+
+        init_storage(db_dir.path(), &account_id, 1_313_161_554, false);
+
+        let storage = Storage::open(db_dir.path()).unwrap();
+        assert_eq!(get_chain_id(&storage), Some(1_313_161_554));
+        assert_eq!(storage.get_latest_block().unwrap(), (block_hash, HEIGHT));
+    }
+
+    #[test]
+    fn test_init_storage_migrates_legacy_db() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let account_id: AccountId = "aurora".parse().unwrap();
+        // Hash computed with an older scheme, before the chain id was stored
+        let legacy_hash = aurora_engine_sdk::sha256(&HEIGHT.to_be_bytes());
+        storage_with_block(db_dir.path(), &account_id, legacy_hash);
+
+        init_storage(db_dir.path(), &account_id, 1_313_161_554, true);
+
+        let storage = Storage::open(db_dir.path()).unwrap();
+        assert_eq!(get_chain_id(&storage), Some(1_313_161_554));
+        let block_hash = compute_block_hash(1_313_161_554, HEIGHT, &account_id);
+        assert_eq!(storage.get_latest_block().unwrap(), (block_hash, HEIGHT));
+    }
+
+    #[test]
+    fn test_init_storage_rejects_existing_db_of_other_chain_id() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let account_id: AccountId = "aurora".parse().unwrap();
+        let block_hash = compute_block_hash(1_313_161_554, HEIGHT, &account_id);
+        storage_with_block(db_dir.path(), &account_id, block_hash);
+
+        let result = std::panic::catch_unwind(|| {
+            init_storage(db_dir.path(), &account_id, 1_313_161_555, false);
+        });
+        let message = *result.unwrap_err().downcast::<String>().unwrap();
+        assert!(message.starts_with(
+            "Block hash at height 100 does not match the configured chain_id=1313161555"
+        ));
+
+        let storage = Storage::open(db_dir.path()).unwrap();
+        assert_eq!(get_chain_id(&storage), None);
+        assert_eq!(storage.get_latest_block().unwrap(), (block_hash, HEIGHT));
+    }
+
+    #[test]
+    fn test_discard_unfinished_blocks() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let account_id: AccountId = "aurora".parse().unwrap();
+        let block_hash = compute_block_hash(1_313_161_554, HEIGHT, &account_id);
+        storage_with_block(db_dir.path(), &account_id, block_hash);
+
+        // The latest block of a DB from before the last processed height was recorded is
+        // assumed to be fully processed
+        init_storage(db_dir.path(), &account_id, 1_313_161_554, false);
+        assert_eq!(last_block_height(db_dir.path()).unwrap(), Some(HEIGHT));
+
+        // The next block was interrupted after its data was written
+        let mut storage = Storage::open(db_dir.path()).unwrap();
+        let next_hash = compute_block_hash(1_313_161_554, HEIGHT + 1, &account_id);
+        add_block(&mut storage, next_hash, HEIGHT + 1);
+        drop(storage);
+        assert_eq!(last_block_height(db_dir.path()).unwrap(), Some(HEIGHT));
+
+        let data_id_dir = tempfile::tempdir().unwrap();
+        discard_unfinished_blocks(db_dir.path(), data_id_dir.path()).unwrap();
+
+        let storage = Storage::open(db_dir.path()).unwrap();
+        assert_eq!(storage.get_latest_block().unwrap(), (block_hash, HEIGHT));
+        drop(storage);
+        assert_eq!(last_block_height(db_dir.path()).unwrap(), Some(HEIGHT));
+    }
+
+    #[test]
+    fn test_new_storage_has_no_processed_block() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let account_id: AccountId = "aurora".parse().unwrap();
+
+        init_storage(db_dir.path(), &account_id, 1_313_161_554, false);
+
+        assert_eq!(last_block_height(db_dir.path()).unwrap(), None);
+    }
+}
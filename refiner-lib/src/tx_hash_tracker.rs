@@ -1,8 +1,93 @@
+use aurora_engine_types::H256;
 use aurora_refiner_types::{near_block::NEARBlock, near_primitives::hash::CryptoHash};
+use aurora_standalone_engine::history::{
+    DEFAULT_CACHE_SIZE, PERSISTENT_HISTORY_SIZE, PRUNE_FREQUENCY,
+};
 use std::path::Path;
 
+/// Where a NEAR receipt comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReceiptLineage {
+    /// NEAR transaction that caused the receipt to be produced (potentially indirectly via a
+    /// number of other receipts).
+    pub tx_hash: CryptoHash,
+    /// Receipt whose execution produced the receipt. `None` if it was produced by the
+    /// transaction itself.
+    pub parent_receipt_id: Option<CryptoHash>,
+    /// Aurora transaction that (potentially indirectly) produced the receipt, e.g. through a
+    /// cross-contract call. Only known once the Aurora transaction is refined, see
+    /// `TxHashTracker::record_aurora_origins`.
+    pub origin_aurora_tx_hash: Option<H256>,
+}
+
+impl ReceiptLineage {
+    const fn from_transaction(tx_hash: CryptoHash) -> Self {
+        Self {
+            tx_hash,
+            parent_receipt_id: None,
+            origin_aurora_tx_hash: None,
+        }
+    }
+
+    /// Lineage of a receipt produced by the execution of the receipt `parent_receipt_id`,
+    /// whose lineage is `self`.
+    const fn child(&self, parent_receipt_id: CryptoHash) -> Self {
+        Self {
+            tx_hash: self.tx_hash,
+            parent_receipt_id: Some(parent_receipt_id),
+            origin_aurora_tx_hash: self.origin_aurora_tx_hash,
+        }
+    }
+
+    /// Stored as the transaction hash, followed by the parent receipt and the Aurora transaction
+    /// if they are known, with one byte of flags in between. Entries written before the lineage
+    /// was tracked only contain the transaction hash.
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(97);
+        bytes.extend_from_slice(self.tx_hash.as_ref());
+        bytes.push(
+            u8::from(self.parent_receipt_id.is_some())
+                | (u8::from(self.origin_aurora_tx_hash.is_some()) << 1),
+        );
+        if let Some(parent_receipt_id) = &self.parent_receipt_id {
+            bytes.extend_from_slice(parent_receipt_id.as_ref());
+        }
+        if let Some(origin_aurora_tx_hash) = &self.origin_aurora_tx_hash {
+            bytes.extend_from_slice(origin_aurora_tx_hash.as_bytes());
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let invalid = || anyhow::anyhow!("Invalid receipt lineage: {}", hex::encode(bytes));
+        let Some((tx_hash, rest)) = bytes.split_at_checked(32) else {
+            return Err(invalid());
+        };
+        let mut lineage = Self::from_transaction(slice_to_crypto_hash(tx_hash)?);
+        let Some((flags, mut rest)) = rest.split_first() else {
+            return Ok(lineage);
+        };
+        if flags & 1 != 0 {
+            let (parent_receipt_id, tail) = rest.split_at_checked(32).ok_or_else(invalid)?;
+            lineage.parent_receipt_id = Some(slice_to_crypto_hash(parent_receipt_id)?);
+            rest = tail;
+        }
+        if flags & 2 != 0 {
+            let (origin_aurora_tx_hash, tail) = rest.split_at_checked(32).ok_or_else(invalid)?;
+            lineage.origin_aurora_tx_hash = Some(H256::from_slice(origin_aurora_tx_hash));
+            rest = tail;
+        }
+        if !rest.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(lineage)
+    }
+}
+
 /// A helper object for tracking the NEAR transaction hash that caused each NEAR receipt
-/// to be produced (potentially indirectly via a number of other receipts).
+/// to be produced (potentially indirectly via a number of other receipts), together with the
+/// rest of its lineage (see `ReceiptLineage`).
 ///
 /// The main interface includes the `get_tx_hash` function to query the helper for the transaction hash associated
 /// with a given receipt hash, and two functions to mutate the tracker's state:
@@ -12,16 +97,53 @@ use std::path::Path;
 /// longer needed since the block at the given height is finished processing.
 pub struct TxHashTracker {
     inner: TxHashTrackerImpl,
+    /// Makes `on_block_end` fail, to test how the callers recover from it.
+    #[cfg(test)]
+    pub(crate) fail_on_block_end: bool,
 }
 
 impl TxHashTracker {
     pub fn new<P: AsRef<Path>>(storage_path: P, start_height: u64) -> anyhow::Result<Self> {
         let inner = TxHashTrackerImpl::new(storage_path, start_height)?;
-        Ok(Self { inner })
+        Ok(Self {
+            inner,
+            #[cfg(test)]
+            fail_on_block_end: false,
+        })
     }
 
     pub fn get_tx_hash(&mut self, rx_hash: &CryptoHash) -> Option<CryptoHash> {
-        self.inner.get_tx_hash(rx_hash)
+        self.inner
+            .get_lineage(rx_hash)
+            .map(|lineage| lineage.tx_hash)
+    }
+
+    pub fn get_lineage(&mut self, rx_hash: &CryptoHash) -> Option<ReceiptLineage> {
+        self.inner.get_lineage(rx_hash)
+    }
+
+    /// Records the Aurora transaction that produced each receipt, for receipts produced in the
+    /// block at `block_height`. The receipts they produce in turn inherit it.
+    pub fn record_aurora_origins(
+        &mut self,
+        block_height: u64,
+        origins: impl IntoIterator<Item = (CryptoHash, H256)>,
+    ) -> anyhow::Result<()> {
+        let mut batch = self.inner.start_write_batch();
+        for (rx_hash, origin_aurora_tx_hash) in origins {
+            let Some(lineage) = batch.get_lineage(&rx_hash) else {
+                tracing::warn!("Transaction provenance unknown for receipt {}", rx_hash);
+                continue;
+            };
+            let lineage = ReceiptLineage {
+                origin_aurora_tx_hash: Some(origin_aurora_tx_hash),
+                ..lineage
+            };
+            batch.record_rx(rx_hash, lineage, block_height);
+        }
+
+        let batch = batch.write_batch;
+        self.inner.commit_write_batch(batch)
     }
 
     pub fn consume_near_block(&mut self, near_block: &NEARBlock) -> anyhow::Result<()> {
@@ -39,7 +161,11 @@ impl TxHashTracker {
         for tx in tx_iter {
             let tx_hash = tx.transaction.hash;
             for rx_hash in tx.outcome.execution_outcome.outcome.receipt_ids.iter() {
-                batch.record_rx(*rx_hash, tx_hash, block_height);
+                batch.record_rx(
+                    *rx_hash,
+                    ReceiptLineage::from_transaction(tx_hash),
+                    block_height,
+                );
             }
         }
 
@@ -51,15 +177,15 @@ impl TxHashTracker {
         // Track receipts created from other receipts
         for rx in rx_iter {
             let rx_hash = &rx.receipt.receipt_id;
-            let tx_hash = match batch.get_tx_hash(rx_hash) {
-                Some(tx_hash) => tx_hash,
+            let lineage = match batch.get_lineage(rx_hash) {
+                Some(lineage) => lineage,
                 None => {
                     tracing::warn!("Transaction provenance unknown for receipt {}", rx_hash);
                     continue;
                 }
             };
-            for rx_hash in rx.execution_outcome.outcome.receipt_ids.iter() {
-                batch.record_rx(*rx_hash, tx_hash, block_height);
+            for child_rx_hash in rx.execution_outcome.outcome.receipt_ids.iter() {
+                batch.record_rx(*child_rx_hash, lineage.child(*rx_hash), block_height);
             }
         }
 
@@ -70,7 +196,26 @@ impl TxHashTracker {
     }
 
     pub fn on_block_end(&mut self, block_height: u64) -> anyhow::Result<()> {
-        self.inner.prune_state(block_height)
+        #[cfg(test)]
+        if self.fail_on_block_end {
+            anyhow::bail!("Failed to end block {block_height}");
+        }
+        self.inner.prune_state(block_height)?;
+        self.inner.set_last_block_height(block_height)
+    }
+
+    /// Height of the last block the tracker at `storage_path` finished processing, if any.
+    /// This is the tracker's high-water mark used to check it is consistent with the other
+    /// stores of the refiner on start-up.
+    pub fn last_block_height<P: AsRef<Path>>(storage_path: P) -> anyhow::Result<Option<u64>> {
+        let persistent_storage = rocksdb::DB::open_default(storage_path)?;
+        read_last_block_height(&persistent_storage)
+    }
+
+    /// Forget all receipts recorded in blocks above `block_height`, as if the tracker had
+    /// never consumed those blocks.
+    pub fn rewind(&mut self, block_height: u64) -> anyhow::Result<()> {
+        self.inner.rewind(block_height)
     }
 }
 
@@ -81,66 +226,37 @@ impl TxHashTracker {
 /// The cache in the implementation allows for fast (in-memory) look-ups, while the rocksdb
 /// storage layer enables crash recovery without data loss.
 struct TxHashTrackerImpl {
-    cache: lru::LruCache<CryptoHash, CryptoHash>,
+    cache: lru::LruCache<CryptoHash, ReceiptLineage>,
     persistent_storage: rocksdb::DB,
     last_prune_height: u64,
 }
 
-/// At 64 bytes per entry (two 32-byte hashes), this caps the memory footprint of the tracker
-/// at around 70 MB, which seems reasonable. One million entries should also be sufficient to
-/// ensure the cache never miss under normal conditions; it would require a receipt to be
-/// created and then not included in a block before one million other receipts we created first.
-/// With the maximum daily number of transactions ever observed on NEAR at just over two million,
-/// this means the receipt would not have been included in a block for at least 8 hours; an
-/// extremely unlikely event (typically receipts are included in the next block after they are
-/// created, less than 2 seconds later).
-const CACHE_SIZE: usize = 1_000_000;
-
-/// This is the number of block heights into the past the DB will remember receipt hashes.
-/// With a 1 second block time, this corresponds to 5 days of transactions, or 10 "epochs"
-/// since NEAR epochs tend to be around 12 hours. Keeping data for 5 epochs is the standard for
-/// non-archival nearcore nodes, so 10 epochs should be more than enough for us. At NEAR's peak of
-/// two million transactions per day, and assuming each transaction has 5 receipts on average
-/// (likely an overestimate), then this will mean fifty million entries in the DB at most.
-/// With 72 bytes per entry (two 32-byte hashes plus one 8-byte height), this will cap the
-/// storage used by this DB at under 4 GB.
-const PERSISTENT_HISTORY_SIZE: u64 = 432_000;
-
-/// Range delete operations are cheap to write (they are essentially one tombstone key in the DB),
-/// however they are expensive to resolve during compaction. We cannot write one pruning
-/// operation per block and keep reasonable performance of the DB when compaction happens.
-/// Therefore, we will only prune old data once every `PRUNE_FREQUENCY` blocks. This means the
-/// DB may grow a little larger than `PERSISTENT_HISTORY_SIZE` sometimes.
-const PRUNE_FREQUENCY: u64 = 50_000;
+/// Key under which the height of the last processed block is stored. It starts with the
+/// largest possible height so that it sorts after all the receipt keys, and it is therefore
+/// never loaded into the cache nor removed by pruning.
+fn last_block_height_key() -> Vec<u8> {
+    [u64::MAX.to_be_bytes().as_slice(), b"last_block_height"].concat()
+}
+
+fn read_last_block_height(persistent_storage: &rocksdb::DB) -> anyhow::Result<Option<u64>> {
+    persistent_storage
+        .get(last_block_height_key())?
+        .map(|value| {
+            value
+                .as_slice()
+                .try_into()
+                .map(u64::from_be_bytes)
+                .map_err(|_| anyhow::anyhow!("Invalid last block height: {}", hex::encode(&value)))
+        })
+        .transpose()
+}
 
 impl TxHashTrackerImpl {
     fn new<P: AsRef<Path>>(storage_path: P, start_height: u64) -> anyhow::Result<Self> {
-        let mut cache = lru::LruCache::new(CACHE_SIZE.try_into()?);
+        let mut cache = lru::LruCache::new(DEFAULT_CACHE_SIZE);
         let persistent_storage = rocksdb::DB::open_default(storage_path)?;
 
-        // Read out enough data from the DB to fill the cache
-        let opts = {
-            let mut tmp = rocksdb::ReadOptions::default();
-            let db_key = [start_height.to_be_bytes().as_slice(), &[0xff_u8; 32]].concat();
-            tmp.set_iterate_upper_bound(db_key);
-            tmp
-        };
-        let iter = persistent_storage
-            .iterator_opt(rocksdb::IteratorMode::End, opts)
-            .take(CACHE_SIZE);
-        let mut cache_data = iter.collect::<Vec<_>>();
-        // Consume the data from the DB in reverse order to get right LRU structure
-        cache_data.reverse();
-        for entry in cache_data {
-            let (k, v) = entry?;
-            if k.len() < 8 {
-                return Err(anyhow::anyhow!(
-                    "Invalid transaction tracker DB key: {}",
-                    hex::encode(k)
-                ));
-            }
-            cache.put(slice_to_crypto_hash(&k[8..])?, slice_to_crypto_hash(&v)?);
-        }
+        fill_cache(&mut cache, &persistent_storage, start_height)?;
 
         let mut result = Self {
             cache,
@@ -154,7 +270,7 @@ impl TxHashTrackerImpl {
         Ok(result)
     }
 
-    /// Uses the LRU cache to get the transaction hash associated with the given receipt hash.
+    /// Uses the LRU cache to get the lineage of the given receipt hash.
     ///
     /// We intentionally do not fall back on the rocksdb storage layer in the event of a cache
     /// miss. This is because the rocksdb storage layer is optimized for fast pruning by
@@ -168,7 +284,7 @@ impl TxHashTrackerImpl {
     /// Therefore, the cache must be large enough such that misses never happen under normal
     /// conditions and the cache must be populated eagerly from the rocksdb storage layer
     /// on start-up.
-    fn get_tx_hash(&mut self, rx_hash: &CryptoHash) -> Option<CryptoHash> {
+    fn get_lineage(&mut self, rx_hash: &CryptoHash) -> Option<ReceiptLineage> {
         self.cache.get(rx_hash).copied()
     }
 
@@ -205,23 +321,84 @@ impl TxHashTrackerImpl {
 
         Ok(())
     }
+
+    fn set_last_block_height(&self, block_height: u64) -> anyhow::Result<()> {
+        self.persistent_storage
+            .put(last_block_height_key(), block_height.to_be_bytes())?;
+
+        Ok(())
+    }
+
+    fn rewind(&mut self, block_height: u64) -> anyhow::Result<()> {
+        let Some(start_height) = block_height.checked_add(1) else {
+            return Ok(());
+        };
+        let start_key = [start_height.to_be_bytes().as_slice(), &[0x00_u8; 32]].concat();
+        // Exclusive upper bound, so the last block height key is not removed.
+        let end_key = u64::MAX.to_be_bytes().to_vec();
+        let mut batch = rocksdb::WriteBatch::default();
+        batch.delete_range(start_key, end_key);
+        batch.put(last_block_height_key(), block_height.to_be_bytes());
+        self.persistent_storage.write(batch)?;
+
+        // The cache does not know at which height each entry was recorded,
+        // so it is rebuilt from what is left in the DB.
+        self.cache.clear();
+        fill_cache(&mut self.cache, &self.persistent_storage, start_height)
+    }
+}
+
+/// Read out enough data from the DB to fill the cache. Only receipts recorded in blocks
+/// below `start_height` are loaded.
+fn fill_cache(
+    cache: &mut lru::LruCache<CryptoHash, ReceiptLineage>,
+    persistent_storage: &rocksdb::DB,
+    start_height: u64,
+) -> anyhow::Result<()> {
+    let opts = {
+        let mut tmp = rocksdb::ReadOptions::default();
+        let db_key = [start_height.to_be_bytes().as_slice(), &[0xff_u8; 32]].concat();
+        tmp.set_iterate_upper_bound(db_key);
+        tmp
+    };
+    let iter = persistent_storage
+        .iterator_opt(rocksdb::IteratorMode::End, opts)
+        .take(DEFAULT_CACHE_SIZE.get());
+    let mut cache_data = iter.collect::<Vec<_>>();
+    // Consume the data from the DB in reverse order to get right LRU structure
+    cache_data.reverse();
+    for entry in cache_data {
+        let (k, v) = entry?;
+        if k.len() < 8 {
+            return Err(anyhow::anyhow!(
+                "Invalid transaction tracker DB key: {}",
+                hex::encode(k)
+            ));
+        }
+        cache.put(
+            slice_to_crypto_hash(&k[8..])?,
+            ReceiptLineage::from_bytes(&v)?,
+        );
+    }
+
+    Ok(())
 }
 
 struct TxHashTrackerWriteBatch<'a> {
-    cache: &'a mut lru::LruCache<CryptoHash, CryptoHash>,
+    cache: &'a mut lru::LruCache<CryptoHash, ReceiptLineage>,
     write_batch: rocksdb::WriteBatch,
 }
 
 impl TxHashTrackerWriteBatch<'_> {
-    fn get_tx_hash(&mut self, rx_hash: &CryptoHash) -> Option<CryptoHash> {
+    fn get_lineage(&mut self, rx_hash: &CryptoHash) -> Option<ReceiptLineage> {
         self.cache.get(rx_hash).copied()
     }
 
-    fn record_rx(&mut self, rx_hash: CryptoHash, tx_hash: CryptoHash, block_height: u64) {
-        self.cache.put(rx_hash, tx_hash);
+    fn record_rx(&mut self, rx_hash: CryptoHash, lineage: ReceiptLineage, block_height: u64) {
+        self.cache.put(rx_hash, lineage);
 
         let db_key = [&block_height.to_be_bytes(), rx_hash.as_ref()].concat();
-        self.write_batch.put(db_key, tx_hash);
+        self.write_batch.put(db_key, lineage.to_bytes());
     }
 }
 
@@ -287,14 +464,119 @@ mod tests {
         let rx_hash_3 =
             CryptoHash::from_str("3d43nGKmmbXbCCtt12NAAPLfEoaRo3j31CEKaiQCK3Bt").unwrap();
         assert_eq!(tracker.get_tx_hash(&rx_hash_3).unwrap(), expected_tx_hash_2);
-
+        assert_eq!(
+            tracker.get_lineage(&rx_hash_3).unwrap().parent_receipt_id,
+            Some(rx_hash_2)
+        );
+        assert_eq!(
+            tracker.get_lineage(&rx_hash_2).unwrap().parent_receipt_id,
+            None
+        );
+
+        // The Aurora transaction recorded as the origin of a receipt is kept on restart
+        let origin = H256::repeat_byte(1);
+        tracker
+            .record_aurora_origins(51188690, [(rx_hash_3, origin)])
+            .unwrap();
         tracker.on_block_end(51188690).unwrap();
 
         // And both receipts are still present after restart
         drop(tracker);
         let mut tracker = TxHashTracker::new(db_dir.path(), 51188690).unwrap();
         assert_eq!(tracker.get_tx_hash(&rx_hash_2).unwrap(), expected_tx_hash_2);
-        assert_eq!(tracker.get_tx_hash(&rx_hash_3).unwrap(), expected_tx_hash_2);
+        assert_eq!(
+            tracker.get_lineage(&rx_hash_3).unwrap(),
+            ReceiptLineage {
+                tx_hash: expected_tx_hash_2,
+                parent_receipt_id: Some(rx_hash_2),
+                origin_aurora_tx_hash: Some(origin),
+            }
+        );
+    }
+
+    #[test]
+    fn test_receipt_lineage_bytes() {
+        let tx_hash = CryptoHash::hash_bytes(b"tx");
+        let lineage = ReceiptLineage::from_transaction(tx_hash);
+        assert_eq!(
+            ReceiptLineage::from_bytes(&lineage.to_bytes()).unwrap(),
+            lineage
+        );
+        let lineage = ReceiptLineage {
+            origin_aurora_tx_hash: Some(H256::repeat_byte(2)),
+            ..lineage.child(CryptoHash::hash_bytes(b"rx"))
+        };
+        assert_eq!(
+            ReceiptLineage::from_bytes(&lineage.to_bytes()).unwrap(),
+            lineage
+        );
+        // Entries written before the lineage was tracked
+        assert_eq!(
+            ReceiptLineage::from_bytes(tx_hash.as_ref()).unwrap(),
+            ReceiptLineage::from_transaction(tx_hash)
+        );
+        assert!(ReceiptLineage::from_bytes(&[0; 33 + 16]).is_err());
+    }
+
+    #[test]
+    fn test_transaction_hash_tracker_rewind() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let mut tracker = TxHashTracker::new(db_dir.path(), 0).unwrap();
+
+        let block_2 = read_block("tests/res/block-51188689.json");
+        let block_3 = read_block("tests/res/block-51188690.json");
+
+        tracker.consume_near_block(&block_2).unwrap();
+        tracker.on_block_end(51188689).unwrap();
+        tracker.consume_near_block(&block_3).unwrap();
+        tracker.on_block_end(51188690).unwrap();
+
+        let rx_hash_2 =
+            CryptoHash::from_str("9qNqNxE6LenxsFMFmzf9RdQwH6MqhU7Hfqnq7GoibYK8").unwrap();
+        let rx_hash_3 =
+            CryptoHash::from_str("3d43nGKmmbXbCCtt12NAAPLfEoaRo3j31CEKaiQCK3Bt").unwrap();
+        let expected_tx_hash =
+            CryptoHash::from_str("DEtAE5d6M8NtBMsCaZVCzjg8C2a5wqduhwVkioseUhT4").unwrap();
+
+        // Rewinding forgets the receipts created in the later block only
+        tracker.rewind(51188689).unwrap();
+        assert_eq!(tracker.get_tx_hash(&rx_hash_2).unwrap(), expected_tx_hash);
+        assert_eq!(tracker.get_tx_hash(&rx_hash_3), None);
+
+        // And the removal is persisted
+        drop(tracker);
+        assert_eq!(
+            TxHashTracker::last_block_height(db_dir.path()).unwrap(),
+            Some(51188689)
+        );
+        let mut tracker = TxHashTracker::new(db_dir.path(), 51188690).unwrap();
+        assert_eq!(tracker.get_tx_hash(&rx_hash_2).unwrap(), expected_tx_hash);
+        assert_eq!(tracker.get_tx_hash(&rx_hash_3), None);
+    }
+
+    #[test]
+    fn test_transaction_hash_tracker_last_block_height() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let mut tracker = TxHashTracker::new(db_dir.path(), 0).unwrap();
+        tracker
+            .consume_near_block(&read_block("tests/res/block-51188689.json"))
+            .unwrap();
+        drop(tracker);
+
+        // The height is only recorded once the block is finished
+        assert_eq!(
+            TxHashTracker::last_block_height(db_dir.path()).unwrap(),
+            None
+        );
+
+        let mut tracker = TxHashTracker::new(db_dir.path(), 0).unwrap();
+        tracker.on_block_end(51188689).unwrap();
+        drop(tracker);
+
+        assert_eq!(
+            TxHashTracker::last_block_height(db_dir.path()).unwrap(),
+            Some(51188689)
+        );
     }
 
     fn read_block(path: &str) -> NEARBlock {
@@ -1,4 +1,4 @@
-use crate::utils::{u64_hex_serde, u128_dec_serde};
+use crate::utils::{bytes_hex_serde, u64_hex_serde, u128_dec_serde};
 use aurora_engine::parameters::{ResultLog, TransactionStatus};
 use aurora_engine_transactions::eip_2930::AccessTuple;
 use aurora_engine_transactions::eip_7702::AuthorizationTuple;
@@ -22,7 +22,7 @@ use crate::bloom::Bloom;
 ///
 /// Note that some blocks on NEAR are skipped, and in this case we are creating a boilerplate block
 /// with unique hash, and consistent parent_hash and height.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuroraBlock {
     /// Chain where this block belongs to
     pub chain_id: u64,
@@ -65,12 +65,231 @@ pub struct AuroraBlock {
     /// contract calls, potentially hitting aurora several times, a different Ethereum tx will be
     /// created for each receipt.
     pub transactions: Vec<AuroraTransaction>,
+    /// Tokens bridged in and out of Aurora by the transactions of the block, in the order of
+    /// the transactions, and the withdrawals of the tokens exited in earlier transactions, once
+    /// their NEAR receipt is executed in the block.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bridge_events: Vec<BridgeEvent>,
+    /// Administrative calls of the engine made by the transactions of the block, in the order of
+    /// the transactions.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub engine_events: Vec<EngineEvent>,
+    /// Changes made by the block to the state of the EVM accounts, sorted by address. Only set if
+    /// state diffs are enabled in the refiner configuration.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub state_diff: Vec<AccountStateDiff>,
     /// Metadata to recover the block on NEAR
     pub near_metadata: NearBlock,
 }
 
+/// Tokens moved between Aurora and NEAR or Ethereum by a successful transaction, or by the NEAR
+/// receipt spawned by the exit of a transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BridgeEvent {
+    pub kind: BridgeEventKind,
+    /// Hash of the transaction that bridged the tokens.
+    pub transaction_hash: H256,
+    pub token: BridgeToken,
+    pub amount: U256,
+    pub sender: BridgeAccount,
+    pub recipient: BridgeAccount,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BridgeEventKind {
+    /// NEP-141 tokens transferred to the engine with `ft_on_transfer`, minting ERC-20 tokens.
+    Nep141Mint,
+    /// ETH transferred to the engine with `ft_on_transfer`, minting ETH on Aurora.
+    EthDeposit,
+    /// ERC-20 tokens or ETH sent to NEAR through the exit to NEAR precompile.
+    ExitToNear,
+    /// ERC-20 tokens or ETH sent to Ethereum through the exit to Ethereum precompile.
+    ExitToEthereum,
+    /// NEP-141 tokens transferred with `ft_transfer` to the NEAR recipient of an exit to NEAR.
+    NearWithdrawal,
+    /// NEP-141 tokens burnt with `withdraw` to be released to the Ethereum recipient of an exit to
+    /// Ethereum.
+    EthereumWithdrawal,
+}
+
+/// The bridged token on both sides of the bridge.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BridgeToken {
+    /// NEP-141 contract of the token on NEAR. It is only known for the tokens bridged in and the
+    /// withdrawals, the exit logs only record the ERC-20 address.
+    pub nep141: Option<AccountId>,
+    /// ERC-20 contract of the token on Aurora. `None` for ETH, the base token of Aurora.
+    pub erc20: Option<Address>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BridgeAccount {
+    Near(AccountId),
+    /// NEAR account only known by the hash of its id: the exit to NEAR logs index the recipient,
+    /// so they only contain the keccak hash of its ABI encoding. The withdrawal of the exit
+    /// gives the account id.
+    NearHash(H256),
+    Aurora(Address),
+    Ethereum(Address),
+}
+
+/// Administrative call of the engine made by a successful transaction, with its decoded
+/// arguments and the NEAR receipt it comes from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EngineEvent {
+    pub kind: EngineEventKind,
+    /// Hash of the transaction that made the call.
+    pub transaction_hash: H256,
+    /// Receipt on NEAR that made the call.
+    pub receipt_id: CryptoHash,
+    /// Index of the call in the actions of the receipt.
+    pub action_index: usize,
+    /// Transaction on NEAR that caused the receipt to be produced, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub near_transaction_hash: Option<CryptoHash>,
+    /// NEAR account that sent the receipt.
+    pub predecessor_id: AccountId,
+    /// NEAR account that signed the transaction that caused the receipt to be produced.
+    pub signer_id: AccountId,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EngineEventKind {
+    SetOwner {
+        new_owner: AccountId,
+    },
+    /// Pauses the precompiles of the bits set in `paused_mask`.
+    PausePrecompiles {
+        paused_mask: u32,
+    },
+    /// Resumes the precompiles of the bits set in `paused_mask`.
+    ResumePrecompiles {
+        paused_mask: u32,
+    },
+    RegisterRelayer {
+        relayer: Address,
+    },
+    /// New engine code staged with `stage_upgrade`, deployed later by `deploy_upgrade`.
+    StageUpgrade {
+        code_hash: CryptoHash,
+    },
+    DeployUpgrade,
+    /// New engine code deployed at once with `upgrade`.
+    Upgrade {
+        code_hash: CryptoHash,
+        state_migration_gas: Option<u64>,
+    },
+    /// New engine code deployed with a NEAR `DeployContract` action.
+    DeployContract {
+        code_hash: CryptoHash,
+    },
+    SetUpgradeDelayBlocks {
+        upgrade_delay_blocks: u64,
+    },
+    /// New code of the cross-contract call router deployed by the engine.
+    FactoryUpdate {
+        code_hash: CryptoHash,
+    },
+    /// Fixed amount of EVM gas charged by a silo for every transaction, `None` to disable it.
+    SetFixedGas {
+        fixed_gas: Option<u64>,
+    },
+    SetErc20FallbackAddress {
+        address: Option<Address>,
+    },
+    /// Fixed gas and ERC-20 fallback address of a silo, `None` to disable both.
+    SetSiloParams {
+        params: Option<SiloParams>,
+    },
+    AddEntryToWhitelist(WhitelistEntry),
+    AddEntryToWhitelistBatch(Vec<WhitelistEntry>),
+    RemoveEntryFromWhitelist(WhitelistEntry),
+    SetWhitelistStatus(WhitelistStatus),
+    SetWhitelistsStatuses(Vec<WhitelistStatus>),
+    SetErc20Metadata {
+        token: Erc20Token,
+        name: String,
+        symbol: String,
+        decimals: u8,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SiloParams {
+    pub fixed_gas: u64,
+    pub erc20_fallback_address: Address,
+}
+
+/// Silo whitelists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WhitelistKind {
+    /// NEAR accounts allowed to deploy contracts.
+    Admin,
+    /// EVM addresses allowed to deploy contracts.
+    EvmAdmin,
+    /// NEAR accounts allowed to submit transactions.
+    Account,
+    /// EVM addresses allowed to submit transactions.
+    Address,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WhitelistEntry {
+    Account {
+        kind: WhitelistKind,
+        account_id: AccountId,
+    },
+    Address {
+        kind: WhitelistKind,
+        address: Address,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WhitelistStatus {
+    pub kind: WhitelistKind,
+    pub active: bool,
+}
+
+/// Token whose metadata is changed, identified on either side of the bridge.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Erc20Token {
+    Erc20(Address),
+    Nep141(AccountId),
+}
+
+/// Changes made by a block to the state of an EVM account. Only the changed fields are set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountStateDiff {
+    pub address: Address,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<ValueChange<U256>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub balance: Option<ValueChange<Wei>>,
+    /// Keccak hash of the code, which is the hash of the empty code for accounts without code.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code_hash: Option<ValueChange<H256>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub storage: Vec<StorageChange>,
+}
+
+/// Values before and after a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValueChange<T> {
+    pub old: T,
+    pub new: T,
+}
+
+/// Change of a storage slot of a contract. Unset slots are zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageChange {
+    pub slot: H256,
+    pub old: H256,
+    pub new: H256,
+}
+
 /// Near block metadata
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum NearBlock {
     /// No block is known at this height.
     SkipBlock,
@@ -78,7 +297,7 @@ pub enum NearBlock {
     ExistingBlock(NearBlockHeader),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NearBlockHeader {
     /// Hash of the block on NEAR
     pub near_hash: CryptoHash,
@@ -93,10 +312,12 @@ pub struct NearBlockHeader {
 /// It includes the information of the receipt after executing the transaction as well. In addition it contains
 /// extra metadata to map it into a NEAR transaction.
 ///
-/// ## Fields from Ethereum transactions and receipts not included:
+/// The block-wide fields of Ethereum receipts and logs, `cumulativeGasUsed` and `logIndex`, are
+/// computed by the refiner. They are zero in blocks refined before they were introduced.
 ///
-/// `cumulativeGasUsed, logsBloom`
-#[derive(Builder, Debug, Serialize, Deserialize)]
+/// Logs are stored in the format of the engine, which consumers of the refined blocks already
+/// parse, with their position added, see [`AuroraLog`].
+#[derive(Builder, Debug, Clone, Serialize, Deserialize)]
 #[builder(pattern = "owned")]
 pub struct AuroraTransaction {
     /// Hash of the transaction and the receipt
@@ -128,6 +349,10 @@ pub struct AuroraTransaction {
     pub gas_limit: u64,
     /// Gas used by the transaction
     pub gas_used: u64,
+    /// Gas used by this transaction and all the previous transactions of the block.
+    #[serde(default)]
+    #[builder(default)]
+    pub cumulative_gas_used: u64,
     /// Represents the maximum priority fee per gas (in wei) that a transaction sender
     /// is willing to pay for their transaction to be prioritized by miners/validators
     /// over others in the transaction pool. This value is typically used in dynamic fee
@@ -157,7 +382,14 @@ pub struct AuroraTransaction {
     pub status: bool,
     /// Logs recorded during transaction execution. For now, they will be empty, since it can't be
     /// computed without access to the storage.
-    pub logs: Vec<ResultLog>,
+    pub logs: Vec<AuroraLog>,
+    /// Index in the block of the first log of the transaction. The logs of a block are numbered
+    /// consecutively across its transactions, so the index of every log follows from this offset,
+    /// see [`AuroraTransaction::indexed_logs`]. Filtering transactions out of a block keeps the
+    /// offsets, so the indexes of the remaining logs do not change.
+    #[serde(default)]
+    #[builder(default)]
+    pub first_log_index: u32,
     /// Logs bloom of the transaction. Aggregation of bloom filters from logs
     pub logs_bloom: Bloom,
     /// Address of the deployed contract. It will be different from `None` in case it is a
@@ -167,11 +399,110 @@ pub struct AuroraTransaction {
     pub v: u64,
     pub r: U256,
     pub s: U256,
+    /// Calls made during the EVM execution of the transaction, as a tree of call frames like the
+    /// ones returned by the `callTracer` of `debug_traceTransaction`. Only set if call traces are
+    /// enabled in the refiner configuration, and empty for transactions without EVM execution.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[builder(default)]
+    pub call_trace: Vec<CallFrame>,
     /// Metadata to recover the NEAR transaction/receipt associated with this transaction
     pub near_metadata: NearTransaction,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl AuroraTransaction {
+    /// Logs of the transaction with their index in the block (`logIndex`).
+    pub fn indexed_logs(&self) -> impl Iterator<Item = (u32, &ResultLog)> {
+        self.logs.iter().map(|log| (log.log_index, &log.log))
+    }
+
+    /// Sets the position of the logs from the hash, the index and the first log index of the
+    /// transaction. Must be called again whenever one of them changes.
+    pub fn index_logs(&mut self) {
+        for (log_index, log) in (self.first_log_index..).zip(&mut self.logs) {
+            log.log_index = log_index;
+            log.transaction_index = self.transaction_index;
+            log.transaction_hash = self.hash;
+        }
+    }
+}
+
+/// Log of a transaction, in the format of the engine, with its position. A log always belongs to
+/// its transaction: its `transaction_hash` and `transaction_index` are the ones of the
+/// transaction, and its `log_index` is `first_log_index` of the transaction plus its position in
+/// `logs`. The position is zero in blocks refined before it was introduced.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuroraLog {
+    #[serde(flatten)]
+    pub log: ResultLog,
+    /// Index of the log in the block.
+    #[serde(default)]
+    pub log_index: u32,
+    /// Index of the transaction of the log in the block.
+    #[serde(default)]
+    pub transaction_index: u32,
+    /// Hash of the transaction of the log.
+    #[serde(default)]
+    pub transaction_hash: H256,
+}
+
+impl From<ResultLog> for AuroraLog {
+    fn from(log: ResultLog) -> Self {
+        Self {
+            log,
+            log_index: 0,
+            transaction_index: 0,
+            transaction_hash: H256::zero(),
+        }
+    }
+}
+
+impl std::ops::Deref for AuroraLog {
+    type Target = ResultLog;
+
+    fn deref(&self) -> &Self::Target {
+        &self.log
+    }
+}
+
+/// Call made during the execution of a transaction, with the calls it made in turn. Serialized
+/// like a frame of the `callTracer` of `debug_traceTransaction`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CallFrame {
+    #[serde(rename = "type")]
+    pub call_type: CallType,
+    pub from: Address,
+    /// Callee, or address of the created contract. `None` if the creation failed.
+    pub to: Option<Address>,
+    pub value: U256,
+    #[serde(with = "u64_hex_serde")]
+    pub gas: u64,
+    #[serde(with = "u64_hex_serde")]
+    pub gas_used: u64,
+    #[serde(with = "bytes_hex_serde")]
+    pub input: Vec<u8>,
+    #[serde(with = "bytes_hex_serde")]
+    pub output: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub calls: Vec<CallFrame>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum CallType {
+    Call,
+    StaticCall,
+    DelegateCall,
+    CallCode,
+    Create,
+    Create2,
+    /// The `to` of the frame is the beneficiary of the balance of the destroyed contract.
+    SelfDestruct,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NearTransaction {
     /// Index of the action on action list
     pub action_index: usize,
@@ -188,9 +519,49 @@ pub struct NearTransaction {
     /// See [AIP-008](https://github.com/aurora-is-near/AIPs/pull/8) for details.
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub hashchain_metadata: Option<HashchainMetadata>,
+    /// NEAR gas burnt to execute the action. The receipt's gas burnt is split evenly among its
+    /// actions, the remainder of the division going to the last action. It does not include
+    /// the cost of converting the NEAR transaction into the receipt.
+    /// Field is optional for backwards compatibility.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gas_burnt: Option<u64>,
+    /// NEAR tokens (in yoctoNEAR) burnt to execute the action, split like `gas_burnt`.
+    /// Field is optional for backwards compatibility.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "u128_dec_serde"
+    )]
+    pub tokens_burnt: Option<u128>,
+    /// NEAR account that signed the transaction that caused the receipt to be produced.
+    /// Field is optional for backwards compatibility.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signer_id: Option<AccountId>,
+    /// NEAR account that sent the receipt.
+    /// Field is optional for backwards compatibility.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub predecessor_id: Option<AccountId>,
+    /// Error of the receipt, if it failed on NEAR. Failed receipts are only refined if enabled
+    /// in the refiner configuration.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// NEAR receipts produced by the receipt, e.g. by cross-contract calls or exits. They are
+    /// only attached to the transaction of the last action of the receipt.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub spawned_receipt_ids: Vec<CryptoHash>,
+    /// NEAR receipt whose execution produced the receipt, if it was not produced by the
+    /// transaction itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_receipt_id: Option<CryptoHash>,
+    /// Aurora transaction that (potentially indirectly) produced the receipt, e.g. for the
+    /// callbacks of cross-contract calls. Following the `spawned_receipt_ids`,
+    /// `parent_receipt_id` and `origin_transaction_hash` of the transactions gives the full
+    /// call tree, across blocks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin_transaction_hash: Option<H256>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HashchainMetadata {
     /// Name of the method called on the Engine in the original Near transaction.
     pub method_name: String,
@@ -206,7 +577,7 @@ pub struct HashchainMetadata {
     pub intrinsic_hash: CryptoHash,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum HashchainInputKind {
     /// Standard RLP-encoded Ethereum transaction.
     Rlp,
@@ -221,20 +592,20 @@ pub enum HashchainInputKind {
     Explicit,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CallArgsVersion {
     V1,
     V2,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AdditionalSubmitArgs {
     #[serde(with = "u128_dec_serde")]
     pub max_gas_price: Option<u128>,
     pub gas_token_address: Option<Address>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum HashchainOutputKind {
     /// Borsh-encoding of SubmitResultLegacyV1 (fields populated with data from `AuroraTransaction`).
     SubmitResultLegacyV1(ResultStatusTag),
@@ -250,7 +621,7 @@ pub enum HashchainOutputKind {
     None,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ResultStatusTag {
     Success,
     Revert,
@@ -303,7 +674,10 @@ impl From<&TransactionStatus> for ResultStatusTag {
 
 #[cfg(test)]
 mod tests {
-    use super::AuroraBlock;
+    use super::{AuroraBlock, AuroraLog, CallFrame, CallType};
+    use aurora_engine::parameters::ResultLog;
+    use aurora_engine_types::types::Address;
+    use aurora_engine_types::{H256, U256};
 
     #[test]
     fn test_aurora_block_deserialization() {
@@ -333,9 +707,87 @@ mod tests {
                     "0xffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff".into(),
                 ),
             );
+            // The block was refined before the block-wide receipt fields were introduced
+            for tx in tmp["transactions"].as_array_mut().unwrap() {
+                let tx = tx.as_object_mut().unwrap();
+                assert_eq!(tx.remove("cumulative_gas_used").unwrap(), 0);
+                assert_eq!(tx.remove("first_log_index").unwrap(), 0);
+                for log in tx["logs"].as_array_mut().unwrap() {
+                    let log = log.as_object_mut().unwrap();
+                    assert_eq!(log.remove("log_index").unwrap(), 0);
+                    assert_eq!(log.remove("transaction_index").unwrap(), 0);
+                    assert_eq!(
+                        log.remove("transaction_hash").unwrap(),
+                        serde_json::to_value(H256::zero()).unwrap()
+                    );
+                }
+            }
             tmp
         };
 
         assert_eq!(computed_block_json, given_block_json);
     }
+
+    #[test]
+    fn test_call_frame_serialization() {
+        let frame = CallFrame {
+            call_type: CallType::StaticCall,
+            from: Address::from_array([1; 20]),
+            to: Some(Address::from_array([2; 20])),
+            value: U256::from(1000),
+            gas: 30000,
+            gas_used: 2100,
+            input: vec![0x70, 0xa0, 0x82, 0x31],
+            output: vec![],
+            error: None,
+            calls: vec![],
+        };
+        let json = serde_json::to_value(&frame).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "type": "STATICCALL",
+                "from": "0x0101010101010101010101010101010101010101",
+                "to": "0x0202020202020202020202020202020202020202",
+                "value": "0x3e8",
+                "gas": "0x7530",
+                "gasUsed": "0x834",
+                "input": "0x70a08231",
+                "output": "0x",
+            })
+        );
+        assert_eq!(serde_json::from_value::<CallFrame>(json).unwrap(), frame);
+    }
+
+    #[test]
+    fn test_log_serialization() {
+        let log = AuroraLog {
+            log: ResultLog {
+                address: Address::from_array([1; 20]),
+                topics: vec![[2; 32]],
+                data: vec![3],
+            },
+            log_index: 5,
+            transaction_index: 2,
+            transaction_hash: H256::repeat_byte(4),
+        };
+        let json = serde_json::to_value(&log).unwrap();
+        assert_eq!(json["log_index"], 5);
+        assert_eq!(json["transaction_index"], 2);
+        assert_eq!(
+            json["transaction_hash"],
+            serde_json::to_value(H256::repeat_byte(4)).unwrap()
+        );
+        // The fields of the engine log are kept as they are
+        let engine_log = serde_json::to_value(&log.log).unwrap();
+        for (key, value) in engine_log.as_object().unwrap() {
+            assert_eq!(&json[key], value);
+        }
+        assert_eq!(serde_json::from_value::<AuroraLog>(json).unwrap(), log);
+
+        // Logs refined before their position was introduced have it zero
+        let log = serde_json::from_value::<AuroraLog>(engine_log).unwrap();
+        assert_eq!(log.log_index, 0);
+        assert_eq!(log.transaction_hash, H256::zero());
+    }
 }
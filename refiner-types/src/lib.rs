@@ -6,6 +6,9 @@ pub mod aurora_block;
 pub mod bloom;
 pub mod conversion;
 pub mod near_block;
+pub mod rpc;
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
 pub mod utils;
 
 /// Trait that converts a data_lake type to a same nearcore type.
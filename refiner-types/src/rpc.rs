@@ -0,0 +1,417 @@
+//! Views of the refined blocks in the format of the Ethereum JSON-RPC responses:
+//! `eth_getBlockByNumber`, `eth_getTransactionByHash` and `eth_getTransactionReceipt`.
+//!
+//! Quantities are encoded as hexadecimal without leading zeros and binary data as `0x` prefixed
+//! hexadecimal, as required by the [Ethereum JSON-RPC specification](https://ethereum.org/en/developers/docs/apis/json-rpc/#hex-encoding).
+//! Transactions that are not signed Ethereum transactions (NEAR actions like deposits or
+//! contract deployments) are shown as legacy transactions without chain id.
+
+use aurora_engine::parameters::ResultLog;
+use aurora_engine_types::types::Address;
+use aurora_engine_types::{H160, H256, U256};
+use serde::Serialize;
+
+use crate::aurora_block::{AuroraBlock, AuroraTransaction};
+use crate::bloom::Bloom;
+use crate::utils::{bytes_hex_serde, u64_hex_serde};
+
+/// Keccak hash of the RLP encoding of an empty list, the uncles hash of a block without uncles.
+pub const EMPTY_UNCLES_HASH: H256 = H256([
+    0x1d, 0xcc, 0x4d, 0xe8, 0xde, 0xc7, 0x5d, 0x7a, 0xab, 0x85, 0xb5, 0x67, 0xb6, 0xcc, 0xd4, 0x1a,
+    0xd3, 0x12, 0x45, 0x1b, 0x94, 0x8a, 0x74, 0x13, 0xf0, 0xa1, 0x42, 0xfd, 0x40, 0xd4, 0x93, 0x47,
+]);
+
+const LEGACY_TX_TYPE: u8 = 0;
+const DYNAMIC_FEE_TX_TYPE: u8 = 2;
+const SET_CODE_TX_TYPE: u8 = 4;
+
+/// Response of `eth_getBlockByNumber` and `eth_getBlockByHash`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcBlock {
+    #[serde(with = "u64_hex_serde")]
+    pub number: u64,
+    pub hash: H256,
+    pub parent_hash: H256,
+    #[serde(with = "bytes_hex_serde")]
+    pub nonce: [u8; 8],
+    pub sha3_uncles: H256,
+    pub logs_bloom: Bloom,
+    pub transactions_root: H256,
+    pub state_root: H256,
+    pub receipts_root: H256,
+    pub miner: Address,
+    pub difficulty: U256,
+    pub total_difficulty: U256,
+    #[serde(with = "bytes_hex_serde")]
+    pub extra_data: Vec<u8>,
+    #[serde(with = "u64_hex_serde")]
+    pub size: u64,
+    #[serde(with = "u64_hex_serde")]
+    pub gas_limit: u64,
+    #[serde(with = "u64_hex_serde")]
+    pub gas_used: u64,
+    /// Timestamp of the block in seconds.
+    #[serde(with = "u64_hex_serde")]
+    pub timestamp: u64,
+    pub transactions: RpcBlockTransactions,
+    pub uncles: Vec<H256>,
+    pub mix_hash: H256,
+    pub base_fee_per_gas: U256,
+}
+
+/// Transactions of a block, either their hashes or the full transactions, depending on the
+/// second parameter of `eth_getBlockByNumber`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum RpcBlockTransactions {
+    Hashes(Vec<H256>),
+    Full(Vec<RpcTransaction>),
+}
+
+/// Response of `eth_getTransactionByHash`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcTransaction {
+    pub block_hash: H256,
+    #[serde(with = "u64_hex_serde")]
+    pub block_number: u64,
+    pub hash: H256,
+    #[serde(with = "u64_hex_serde")]
+    pub transaction_index: u64,
+    #[serde(rename = "type", with = "u64_hex_serde")]
+    pub tx_type: u64,
+    pub from: Address,
+    pub to: Option<Address>,
+    #[serde(with = "u64_hex_serde")]
+    pub nonce: u64,
+    pub value: U256,
+    #[serde(with = "u64_hex_serde")]
+    pub gas: u64,
+    /// Price paid per unit of gas, which is the effective gas price for dynamic fee transactions.
+    pub gas_price: U256,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_fee_per_gas: Option<U256>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_priority_fee_per_gas: Option<U256>,
+    #[serde(with = "bytes_hex_serde")]
+    pub input: Vec<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chain_id: Option<U256>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access_list: Option<Vec<RpcAccessListItem>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authorization_list: Option<Vec<RpcAuthorization>>,
+    pub v: U256,
+    pub r: U256,
+    pub s: U256,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub y_parity: Option<U256>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcAccessListItem {
+    pub address: H160,
+    pub storage_keys: Vec<H256>,
+}
+
+/// Signed authorization of an EIP-7702 transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcAuthorization {
+    pub chain_id: U256,
+    pub address: H160,
+    #[serde(with = "u64_hex_serde")]
+    pub nonce: u64,
+    pub y_parity: U256,
+    pub r: U256,
+    pub s: U256,
+}
+
+/// Response of `eth_getTransactionReceipt`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcReceipt {
+    pub transaction_hash: H256,
+    #[serde(with = "u64_hex_serde")]
+    pub transaction_index: u64,
+    pub block_hash: H256,
+    #[serde(with = "u64_hex_serde")]
+    pub block_number: u64,
+    pub from: Address,
+    pub to: Option<Address>,
+    #[serde(with = "u64_hex_serde")]
+    pub cumulative_gas_used: u64,
+    #[serde(with = "u64_hex_serde")]
+    pub gas_used: u64,
+    pub effective_gas_price: U256,
+    pub contract_address: Option<Address>,
+    pub logs: Vec<RpcLog>,
+    pub logs_bloom: Bloom,
+    #[serde(rename = "type", with = "u64_hex_serde")]
+    pub tx_type: u64,
+    /// `0x1` if the transaction succeeded, `0x0` otherwise.
+    #[serde(with = "u64_hex_serde")]
+    pub status: u64,
+}
+
+/// Log of a transaction receipt, as returned by `eth_getTransactionReceipt` and `eth_getLogs`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcLog {
+    pub address: Address,
+    pub topics: Vec<H256>,
+    #[serde(with = "bytes_hex_serde")]
+    pub data: Vec<u8>,
+    pub block_hash: H256,
+    #[serde(with = "u64_hex_serde")]
+    pub block_number: u64,
+    pub transaction_hash: H256,
+    #[serde(with = "u64_hex_serde")]
+    pub transaction_index: u64,
+    #[serde(with = "u64_hex_serde")]
+    pub log_index: u64,
+    pub removed: bool,
+}
+
+impl RpcBlock {
+    /// Builds the view of `block`, with the full transactions if `full_transactions` is set,
+    /// otherwise only their hashes.
+    pub fn new(block: &AuroraBlock, full_transactions: bool) -> Self {
+        let transactions = if full_transactions {
+            RpcBlockTransactions::Full(block.transactions.iter().map(RpcTransaction::new).collect())
+        } else {
+            RpcBlockTransactions::Hashes(block.transactions.iter().map(|tx| tx.hash).collect())
+        };
+
+        Self {
+            number: block.height,
+            hash: block.hash,
+            parent_hash: block.parent_hash,
+            nonce: [0; 8],
+            sha3_uncles: EMPTY_UNCLES_HASH,
+            logs_bloom: block.logs_bloom,
+            transactions_root: block.transactions_root,
+            state_root: block.state_root,
+            receipts_root: block.receipts_root,
+            miner: block.miner,
+            difficulty: U256::zero(),
+            total_difficulty: U256::zero(),
+            extra_data: Vec::new(),
+            size: block.size,
+            gas_limit: block.gas_limit,
+            gas_used: block.gas_used,
+            // NEAR timestamps are in nanoseconds
+            timestamp: block.timestamp / 1_000_000_000,
+            transactions,
+            uncles: Vec::new(),
+            mix_hash: H256::zero(),
+            base_fee_per_gas: U256::zero(),
+        }
+    }
+}
+
+impl RpcTransaction {
+    pub fn new(tx: &AuroraTransaction) -> Self {
+        let tx_type = rpc_tx_type(tx);
+        let is_typed = tx_type != LEGACY_TX_TYPE;
+        let has_dynamic_fee = tx_type == DYNAMIC_FEE_TX_TYPE || tx_type == SET_CODE_TX_TYPE;
+        // Legacy transactions only carry a chain id if they are signed with EIP-155
+        let chain_id = if is_typed || (is_ethereum_tx(tx) && tx.v > 28) {
+            Some(U256::from(tx.chain_id))
+        } else {
+            None
+        };
+
+        Self {
+            block_hash: tx.block_hash,
+            block_number: tx.block_height,
+            hash: tx.hash,
+            transaction_index: u64::from(tx.transaction_index),
+            tx_type: u64::from(tx_type),
+            from: tx.from,
+            to: tx.to,
+            nonce: tx.nonce,
+            value: tx.value.raw(),
+            gas: tx.gas_limit,
+            gas_price: if has_dynamic_fee {
+                tx.effective_gas_price
+            } else {
+                tx.gas_price
+            },
+            max_fee_per_gas: has_dynamic_fee.then_some(tx.max_fee_per_gas),
+            max_priority_fee_per_gas: has_dynamic_fee.then_some(tx.max_priority_fee_per_gas),
+            input: tx.input.clone(),
+            chain_id,
+            access_list: is_typed.then(|| {
+                tx.access_list
+                    .iter()
+                    .map(|item| RpcAccessListItem {
+                        address: item.address,
+                        storage_keys: item.storage_keys.clone(),
+                    })
+                    .collect()
+            }),
+            authorization_list: (tx_type == SET_CODE_TX_TYPE).then(|| {
+                tx.authorization_list
+                    .iter()
+                    .map(|authorization| RpcAuthorization {
+                        chain_id: authorization.chain_id,
+                        address: authorization.address,
+                        nonce: authorization.nonce,
+                        y_parity: authorization.parity,
+                        r: authorization.r,
+                        s: authorization.s,
+                    })
+                    .collect()
+            }),
+            v: U256::from(tx.v),
+            r: tx.r,
+            s: tx.s,
+            // The `v` of typed transactions is the parity of the signature
+            y_parity: is_typed.then(|| U256::from(tx.v)),
+        }
+    }
+}
+
+impl RpcReceipt {
+    pub fn new(tx: &AuroraTransaction) -> Self {
+        Self {
+            transaction_hash: tx.hash,
+            transaction_index: u64::from(tx.transaction_index),
+            block_hash: tx.block_hash,
+            block_number: tx.block_height,
+            from: tx.from,
+            to: tx.to,
+            cumulative_gas_used: tx.cumulative_gas_used,
+            gas_used: tx.gas_used,
+            effective_gas_price: tx.effective_gas_price,
+            contract_address: tx.contract_address,
+            logs: tx
+                .indexed_logs()
+                .map(|(log_index, log)| RpcLog::new(tx, log_index, log))
+                .collect(),
+            logs_bloom: tx.logs_bloom,
+            tx_type: u64::from(rpc_tx_type(tx)),
+            status: u64::from(tx.status),
+        }
+    }
+}
+
+impl RpcLog {
+    fn new(tx: &AuroraTransaction, log_index: u32, log: &ResultLog) -> Self {
+        Self {
+            address: log.address,
+            topics: log.topics.iter().map(|topic| H256(*topic)).collect(),
+            data: log.data.clone(),
+            block_hash: tx.block_hash,
+            block_number: tx.block_height,
+            transaction_hash: tx.hash,
+            transaction_index: u64::from(tx.transaction_index),
+            log_index: u64::from(log_index),
+            removed: false,
+        }
+    }
+}
+
+/// The refiner uses transaction types above the EIP-2718 range for NEAR actions.
+const fn is_ethereum_tx(tx: &AuroraTransaction) -> bool {
+    tx.tx_type <= SET_CODE_TX_TYPE
+}
+
+const fn rpc_tx_type(tx: &AuroraTransaction) -> u8 {
+    if is_ethereum_tx(tx) {
+        tx.tx_type
+    } else {
+        LEGACY_TX_TYPE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RpcBlock, RpcReceipt, RpcTransaction};
+    use crate::aurora_block::AuroraBlock;
+    use aurora_engine_types::U256;
+
+    fn read_block() -> AuroraBlock {
+        let mut block: AuroraBlock = serde_json::from_str(include_str!(
+            "../tests/res/aurora_block/aurora_70077007.json"
+        ))
+        .unwrap();
+        // The block was refined before the block-wide receipt fields were introduced
+        let mut cumulative_gas_used = 0;
+        for tx in &mut block.transactions {
+            cumulative_gas_used += tx.gas_used;
+            tx.cumulative_gas_used = cumulative_gas_used;
+        }
+        block
+    }
+
+    fn golden(json: &str) -> serde_json::Value {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_rpc_block() {
+        let block = read_block();
+
+        let full = serde_json::to_value(RpcBlock::new(&block, true)).unwrap();
+        assert_eq!(
+            full,
+            golden(include_str!("../tests/res/rpc/block_70077007_full.json"))
+        );
+
+        let hashes = serde_json::to_value(RpcBlock::new(&block, false)).unwrap();
+        assert_eq!(
+            hashes,
+            golden(include_str!("../tests/res/rpc/block_70077007.json"))
+        );
+    }
+
+    #[test]
+    fn test_rpc_transactions_and_receipts() {
+        let block = read_block();
+
+        let transactions: Vec<_> = block.transactions.iter().map(RpcTransaction::new).collect();
+        assert_eq!(
+            serde_json::to_value(transactions).unwrap(),
+            golden(include_str!("../tests/res/rpc/transactions_70077007.json"))
+        );
+
+        let receipts: Vec<_> = block.transactions.iter().map(RpcReceipt::new).collect();
+        assert_eq!(
+            serde_json::to_value(receipts).unwrap(),
+            golden(include_str!("../tests/res/rpc/receipts_70077007.json"))
+        );
+    }
+
+    #[test]
+    fn test_rpc_typed_transaction() {
+        let mut block = read_block();
+        let tx = &mut block.transactions[0];
+        tx.tx_type = 2;
+        tx.v = 1;
+        tx.effective_gas_price = U256::from(7);
+        tx.max_fee_per_gas = U256::from(9);
+
+        let view = serde_json::to_value(RpcTransaction::new(tx)).unwrap();
+        assert_eq!(view["type"], "0x2");
+        assert_eq!(view["chainId"], "0x4e454152");
+        assert_eq!(view["gasPrice"], "0x7");
+        assert_eq!(view["maxFeePerGas"], "0x9");
+        assert_eq!(view["maxPriorityFeePerGas"], "0x0");
+        assert_eq!(view["accessList"], serde_json::json!([]));
+        assert_eq!(view["yParity"], "0x1");
+        assert!(view.get("authorizationList").is_none());
+
+        // NEAR actions are shown as legacy transactions
+        tx.tx_type = 0xff;
+        let view = serde_json::to_value(RpcTransaction::new(tx)).unwrap();
+        assert_eq!(view["type"], "0x0");
+        assert!(view.get("chainId").is_none());
+        assert!(view.get("yParity").is_none());
+        let receipt = serde_json::to_value(RpcReceipt::new(tx)).unwrap();
+        assert_eq!(receipt["type"], "0x0");
+    }
+}
@@ -0,0 +1,16 @@
+use crate::aurora_block::AuroraBlock;
+
+/// Aurora block of the test resources, moved to `height` along with its transactions.
+pub fn read_aurora_block(height: u64) -> AuroraBlock {
+    let data = std::fs::read_to_string(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/res/aurora_block/aurora_70077007.json"
+    ))
+    .unwrap();
+    let mut block: AuroraBlock = serde_json::from_str(&data).unwrap();
+    block.height = height;
+    for tx in &mut block.transactions {
+        tx.block_height = height;
+    }
+    block
+}
@@ -37,6 +37,34 @@ pub mod u64_hex_serde {
     }
 }
 
+pub mod bytes_hex_serde {
+    //! This module provides serde serialization for byte vectors with `0x` prefixed hexadecimal
+    //! encoding.
+    //!
+    //! It can be used with the field attribute `#[serde(with = "bytes_hex_serde")]` on `Vec<u8>`
+    //! inside structs deriving serde Serialize and Deserialize traits.
+
+    use serde::de::Error;
+
+    pub fn serialize<S>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&format!("0x{}", hex::encode(bytes)))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let hex_str: String = serde::Deserialize::deserialize(deserializer)?;
+        let hex_str = hex_str
+            .strip_prefix("0x")
+            .ok_or_else(|| D::Error::custom("Missing 0x Prefix"))?;
+        hex::decode(hex_str).map_err(D::Error::custom)
+    }
+}
+
 pub mod u128_dec_serde {
     //! This module provides serde serialization for optional u128 numbers with base-10 strings.
     //!
@@ -155,7 +183,7 @@ pub fn keccak256(input: &[u8]) -> H256 {
 
 #[cfg(test)]
 mod tests {
-    use super::{u64_hex_serde, u128_dec_serde};
+    use super::{bytes_hex_serde, u64_hex_serde, u128_dec_serde};
     use crate::utils::balance_u128_or_string_serde;
     use near_primitives::types::Balance;
     use serde::{Deserialize, Serialize};
@@ -166,6 +194,12 @@ mod tests {
         inner: u64,
     }
 
+    #[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+    struct HexBytes {
+        #[serde(with = "bytes_hex_serde")]
+        inner: Vec<u8>,
+    }
+
     #[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
     struct DecU128 {
         #[serde(with = "u128_dec_serde")]
@@ -192,6 +226,23 @@ mod tests {
         assert!(format!("{err:?}").contains("Invalid character 'q'"));
     }
 
+    #[test]
+    fn test_bytes_hex_serde() {
+        let x = HexBytes {
+            inner: vec![0xca, 0xfe],
+        };
+        let x_ser = serde_json::to_string(&x).unwrap();
+        let x_desr: HexBytes = serde_json::from_str(&x_ser).unwrap();
+        assert_eq!(x, x_desr);
+        assert_eq!(x_ser, r#"{"inner":"0xcafe"}"#);
+
+        let empty: HexBytes = serde_json::from_str(r#"{"inner":"0x"}"#).unwrap();
+        assert!(empty.inner.is_empty());
+
+        let err: Result<HexBytes, _> = serde_json::from_str(r#"{"inner":"cafe"}"#);
+        assert!(format!("{err:?}").contains("Missing 0x Prefix"));
+    }
+
     #[test]
     fn test_u128_dec_serde() {
         let x = DecU128 { inner: None };